@@ -0,0 +1,38 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#![no_main]
+
+use led_oxide::led_strip_controller::decoder::PacketDecoder;
+use led_oxide::led_strip_controller::protocol::{LedscTeensy001, ProtocolVersion};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let protocol_instance = LedscTeensy001 {};
+
+    // Raw input straight into the parser
+    let _ = protocol_instance.parse_response_sting(String::from_utf8_lossy(data).into_owned());
+
+    // Input framed by the decoder the way the controller receives it
+    let mut decoder = PacketDecoder::new();
+    decoder.push(data);
+    while let Some(packet) = decoder.next_packet() {
+        let _ = protocol_instance.parse_response_sting(packet);
+    }
+});
@@ -0,0 +1,1921 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! Versioned JSON API, mounted at /api/v1. Effects and fire palettes are accepted by name or by
+//! firmware id.
+//!
+
+use crate::openapi::{ApiSchema, Components, Operation};
+use crate::{error_status, group_response, GroupCmdResponse, SimpleCmdResponse};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use led_oxide::led_strip_controller::batch::{run_batch, BatchError, BatchStep, StepOutcome};
+use led_oxide::led_strip_controller::color::Color24;
+use led_oxide::led_strip_controller::controller::{ControllerError, DeviceManager, TaskKind};
+use led_oxide::led_strip_controller::firmware::{
+    FirmwareError, FirmwareFlasher, FirmwareImage, FlashStatus, MAX_FIRMWARE_SIZE,
+};
+use led_oxide::led_strip_controller::group::{for_each_member, GroupStore};
+use led_oxide::led_strip_controller::protocol::*;
+use led_oxide::led_strip_controller::registry::DeviceRegistry;
+use led_oxide::led_strip_controller::scene::{
+    apply_scene_to_group, SceneError, SceneFormat, SceneStore,
+};
+use led_oxide::led_strip_controller::schedule::{
+    MissedRuns, Schedule, ScheduleAction, ScheduleStore, ScheduleTarget, Trigger,
+};
+use led_oxide::led_strip_controller::script::{
+    compile_script, run_script, ScriptError, ScriptStore, DEFAULT_SCRIPT_INTERVAL,
+    MAX_SCRIPT_INTERVAL_MS,
+};
+use led_oxide::led_strip_controller::sequence::{
+    play_sequence, Keyframe, PlayMode, Sequence, SequenceError, SequenceStore,
+};
+use led_oxide::led_strip_controller::state::DeviceState;
+use led_oxide::led_strip_controller::sun::{sun_event_time, SunEvent};
+use led_oxide::led_strip_controller::transition::{
+    start_transition, Easing, Transition, MAX_TRANSITION_MS,
+};
+use rocket::http::{ContentType, Status};
+use rocket::response::{status, Content};
+use rocket::{Data, Route, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Error response of the JSON API
+type ApiError = status::Custom<Json<SimpleCmdResponse>>;
+
+/// Result of a JSON API endpoint
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Upcoming runs listed for a schedule when no count is given
+const DEFAULT_UPCOMING_RUNS: usize = 10;
+
+/// Most upcoming runs listed for a schedule
+const MAX_UPCOMING_RUNS: usize = 100;
+
+api_schema! {
+    ///
+    /// Firmware id and name of an effect or fire palette.
+    ///
+    #[derive(Serialize)]
+    struct NamedId {
+        id: u8,
+        name: &'static str,
+    }
+}
+
+///
+/// Effect or fire palette given by name or by firmware id.
+///
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum NameOrId {
+    Id(u8),
+    Name(String),
+}
+
+impl ApiSchema for NameOrId {
+    fn schema(components: &mut Components) -> Value {
+        json!({ "oneOf": [u8::schema(components), String::schema(components)] })
+    }
+}
+
+api_schema! {
+    ///
+    /// State of a device as reported by its firmware.
+    ///
+    #[derive(Serialize)]
+    struct DeviceStateResponse {
+        effect: NamedId,
+        fire_palette: NamedId,
+        color: Color24,
+        brightness_percent: f32,
+        debugging: bool,
+    }
+}
+
+api_schema! {
+    ///
+    /// Partial state update. Only the given settings are changed. With transition_ms the color
+    /// and brightness fade over that many milliseconds, the other settings change at once.
+    ///
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct DeviceStateUpdate {
+        effect: Option<NameOrId>,
+        fire_palette: Option<NameOrId>,
+        color: Option<Color24>,
+        brightness_percent: Option<f32>,
+        transition_ms: Option<u32>,
+        easing: Option<Easing>,
+    }
+}
+
+///
+/// Command of a batch. Settings are given as in a device state update, with effect and fire
+/// palette ids those of the step's device.
+///
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchCommand {
+    PrintVersion,
+    FullReset,
+    EnterBootloader,
+    GetStatus,
+    SetDebugging(bool),
+    SetEffect(NameOrId),
+    SetFirePalette(NameOrId),
+    SetColor(Color24),
+    SetBrightnessPercent(f32),
+}
+
+impl ApiSchema for BatchCommand {
+    fn schema(components: &mut Components) -> Value {
+        if !components.contains_key("BatchCommand") {
+            let setting = |name: &str, schema: Value| {
+                json!({
+                    "type": "object",
+                    "properties": { name: schema },
+                    "required": [name],
+                    "additionalProperties": false,
+                })
+            };
+            let percent = json!({ "type": "number", "minimum": 0, "maximum": 100 });
+            let schema = json!({
+                "oneOf": [
+                    {
+                        "type": "string",
+                        "enum": ["print_version", "full_reset", "enter_bootloader", "get_status"],
+                    },
+                    setting("set_debugging", bool::schema(components)),
+                    setting("set_effect", NameOrId::schema(components)),
+                    setting("set_fire_palette", NameOrId::schema(components)),
+                    setting("set_color", Color24::schema(components)),
+                    setting("set_brightness_percent", percent),
+                ],
+            });
+            components.insert(String::from("BatchCommand"), schema);
+        }
+
+        json!({ "$ref": "#/components/schemas/BatchCommand" })
+    }
+}
+
+api_schema! {
+    ///
+    /// A command of a batch and the device it is sent to.
+    ///
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct BatchStepRequest {
+        device_id: String,
+        command: BatchCommand,
+    }
+}
+
+api_schema! {
+    ///
+    /// Commands run in order over the held connections of their devices. With rollback the
+    /// devices are restored to their previous state if a command fails.
+    ///
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct BatchRequest {
+        steps: Vec<BatchStepRequest>,
+        rollback: Option<bool>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Outcome of a batch command: applied, failed or skipped after an earlier failure.
+    ///
+    #[derive(Serialize)]
+    struct BatchStepResponse {
+        device_id: String,
+        command: BatchCommand,
+        outcome: &'static str,
+        status_str: Option<String>,
+        error_code: Option<i16>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Outcome of restoring a device's previous state after a failed batch.
+    ///
+    #[derive(Serialize)]
+    struct RollbackResponse {
+        device_id: String,
+        success: bool,
+        status_str: Option<String>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Result of a batch, with the outcome of each command in batch order.
+    ///
+    #[derive(Serialize)]
+    struct BatchResponse {
+        success: bool,
+        status_str: String,
+        steps: Vec<BatchStepResponse>,
+        rollbacks: Vec<RollbackResponse>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Named combination of settings. Used as the request body when saving a scene, as the
+    /// response when listing scenes and, without the name, in exports. Settings are given as in a
+    /// device state update, with ids those of the newest known firmware. Listed and exported
+    /// scenes name their effect and fire palette.
+    ///
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct SceneData {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        effect: Option<NameOrId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fire_palette: Option<NameOrId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        color: Option<Color24>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        brightness_percent: Option<f32>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Keyframes played by led_oxide. Used as the request body when saving a sequence and as the
+    /// response when listing sequences.
+    ///
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct SequenceData {
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        mode: PlayMode,
+        keyframes: Vec<Keyframe>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Used as the response when listing scripts.
+    ///
+    #[derive(Serialize)]
+    struct ScriptData {
+        name: String,
+        source: String,
+    }
+}
+
+api_schema! {
+    ///
+    /// Schedule definition. Used as the request body when saving a schedule.
+    ///
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ScheduleRequest {
+        trigger: Trigger,
+        action: ScheduleAction,
+        target: ScheduleTarget,
+        enabled: Option<bool>,
+        missed_runs: Option<MissedRuns>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Used as the response when listing schedules.
+    ///
+    #[derive(Serialize)]
+    struct ScheduleResponse {
+        name: String,
+        trigger: Trigger,
+        action: ScheduleAction,
+        target: ScheduleTarget,
+        enabled: bool,
+        missed_runs: MissedRuns,
+        next_run: Option<DateTime<Utc>>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Times of the sun's events on a date at the configured location. Events the sun does not
+    /// reach that day, such as sunrise during a polar night, are null.
+    ///
+    #[derive(Serialize)]
+    struct SunTimesResponse {
+        date: String,
+        latitude: f64,
+        longitude: f64,
+        civil_dawn: Option<DateTime<Utc>>,
+        sunrise: Option<DateTime<Utc>>,
+        sunset: Option<DateTime<Utc>>,
+        civil_dusk: Option<DateTime<Utc>>,
+    }
+}
+
+///
+/// Builds an error response.
+///
+fn api_error(status: Status, status_str: String, error_code: Option<i16>) -> ApiError {
+    println!("{}", status_str);
+    status::Custom(
+        status,
+        Json(SimpleCmdResponse {
+            success: false,
+            status_str,
+            error_code,
+        }),
+    )
+}
+
+///
+/// Builds the error response for a failed device exchange.
+///
+fn device_error(action: &str, e: ControllerError) -> ApiError {
+    api_error(
+        error_status(&e),
+        format!("Failed to {} - {}", action, e),
+        e.protocol_error().map(|pe| pe.code()),
+    )
+}
+
+///
+/// Returns the device with the given id, or a 404 error response.
+///
+fn find_device(registry: &DeviceRegistry, id: &str) -> Result<Arc<DeviceManager>, ApiError> {
+    registry.device(id).ok_or_else(|| {
+        device_error(
+            "find device",
+            ControllerError::UnknownDevice(String::from(id)),
+        )
+    })
+}
+
+///
+/// Returns the protocol used to list effect and palette ids. That of the given device, or the
+/// newest known protocol without one.
+///
+fn listing_protocol(
+    registry: &DeviceRegistry,
+    device: Option<String>,
+) -> Result<Arc<dyn ProtocolVersion>, ApiError> {
+    match device {
+        Some(id) => find_device(registry, &id)?
+            .protocol()
+            .map_err(|e| device_error("read protocol version", e)),
+        None => Ok(Arc::from(latest_protocol_version())),
+    }
+}
+
+///
+/// Resolves an effect name or id against the device's protocol.
+///
+fn resolve_effect(protocol: &dyn ProtocolVersion, effect: &NameOrId) -> Option<Effect> {
+    match effect {
+        NameOrId::Id(effect_id) => protocol.find_effect(*effect_id),
+        NameOrId::Name(name) => {
+            Effect::from_name(name).filter(|effect| protocol.is_effect_supported(effect))
+        }
+    }
+}
+
+///
+/// Resolves a fire palette name or id against the device's protocol.
+///
+fn resolve_fire_palette(
+    protocol: &dyn ProtocolVersion,
+    palette: &NameOrId,
+) -> Option<FireColorPallet> {
+    match palette {
+        NameOrId::Id(pallet_id) => protocol.find_fire_color_pallet(*pallet_id),
+        NameOrId::Name(name) => FireColorPallet::from_name(name),
+    }
+}
+
+///
+/// Converts a brightness percentage to the firmware's 0-255 scale, rejecting values outside 0 to
+/// 100. Shared with the form routes.
+///
+pub(crate) fn brightness_from_percent(brightness_percent: f32) -> Result<u8, ApiError> {
+    if !(0.0..=100.0).contains(&brightness_percent) {
+        return Err(api_error(
+            Status::BadRequest,
+            format!("Brightness {} is not between 0 and 100", brightness_percent),
+            None,
+        ));
+    }
+
+    Ok(((brightness_percent / 100.0) * 255.0).round() as u8)
+}
+
+///
+/// Converts a brightness on the firmware's 0-255 scale to a percentage.
+///
+fn brightness_percent(brightness: u8) -> f32 {
+    (f32::from(brightness) / 255.0) * 100.0
+}
+
+///
+/// Builds the settings given by a state update or scene. The effect and fire palette are resolved
+/// against the protocol.
+///
+fn resolve_state(
+    protocol: &dyn ProtocolVersion,
+    effect: &Option<NameOrId>,
+    fire_palette: &Option<NameOrId>,
+    color: Option<Color24>,
+    brightness_percent: Option<f32>,
+) -> Result<DeviceState, ApiError> {
+    let mut state = DeviceState::default();
+
+    if let Some(effect) = effect {
+        state.effect =
+            Some(resolve_effect(protocol, effect).ok_or_else(|| {
+                api_error(Status::BadRequest, String::from("Unknown effect"), None)
+            })?);
+    }
+
+    if let Some(palette) = fire_palette {
+        state.fire_pallet = Some(resolve_fire_palette(protocol, palette).ok_or_else(|| {
+            api_error(
+                Status::BadRequest,
+                String::from("Unknown fire palette"),
+                None,
+            )
+        })?);
+    }
+
+    state.color = color;
+    state.brightness = brightness_percent
+        .map(brightness_from_percent)
+        .transpose()?;
+
+    Ok(state)
+}
+
+///
+/// Reads the device's status.
+///
+fn read_state(device_manager: &DeviceManager) -> ApiResult<DeviceStateResponse> {
+    let protocol = device_manager
+        .protocol()
+        .map_err(|e| device_error("read status", e))?;
+    let pkt = device_manager
+        .send_command(Command::GetStatus)
+        .map_err(|e| device_error("read status", e))?;
+    let status = protocol.parse_device_status(&pkt).map_err(|e| {
+        api_error(
+            Status::BadGateway,
+            format!("Failed to decode status - {}", e),
+            None,
+        )
+    })?;
+
+    Ok(Json(DeviceStateResponse {
+        effect: NamedId {
+            id: protocol.get_effect_cmd_value(&status.effect),
+            name: status.effect.name(),
+        },
+        fire_palette: NamedId {
+            id: protocol.get_fire_color_pallet_value(&status.fire_pallet),
+            name: status.fire_pallet.name(),
+        },
+        color: status.color,
+        brightness_percent: status.brightness_percent(),
+        debugging: status.debugging,
+    }))
+}
+
+///
+/// Gets the state of a device
+///
+#[get("/devices/<id>/state")]
+fn get_state(id: String, registry: State<Arc<DeviceRegistry>>) -> ApiResult<DeviceStateResponse> {
+    let device_manager = find_device(&registry, &id)?;
+    read_state(&device_manager)
+}
+
+///
+/// Changes the given settings of a device and returns its new state. Every setting is checked
+/// before any command is sent. With a transition the state returned is the one the fade starts
+/// from.
+///
+#[put("/devices/<id>/state", format = "json", data = "<update>")]
+fn put_state(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+    update: Json<DeviceStateUpdate>,
+) -> ApiResult<DeviceStateResponse> {
+    let device_manager = find_device(&registry, &id)?;
+    let protocol = device_manager
+        .protocol()
+        .map_err(|e| device_error("read protocol version", e))?;
+
+    let mut state = resolve_state(
+        protocol.as_ref(),
+        &update.effect,
+        &update.fire_palette,
+        update.color,
+        update.brightness_percent,
+    )?;
+
+    let transition_ms = update.transition_ms.unwrap_or(0);
+    if transition_ms > MAX_TRANSITION_MS {
+        return Err(api_error(
+            Status::BadRequest,
+            format!(
+                "Transition of {} ms is longer than {} ms",
+                transition_ms, MAX_TRANSITION_MS
+            ),
+            None,
+        ));
+    }
+    let transition = if transition_ms > 0 && (state.color.is_some() || state.brightness.is_some()) {
+        let transition = Transition {
+            color: state.color.take(),
+            brightness: state.brightness.take(),
+            duration: Duration::from_millis(u64::from(transition_ms)),
+            easing: update.easing.unwrap_or_default(),
+        };
+        Some(transition)
+    } else {
+        None
+    };
+
+    for command in state.commands() {
+        device_manager
+            .send_command(command)
+            .map_err(|e| device_error(&format!("apply {:?}", command), e))?;
+    }
+
+    if let Some(transition) = transition {
+        start_transition(&device_manager, transition)
+            .map_err(|e| device_error("start transition", e))?;
+    }
+
+    read_state(&device_manager)
+}
+
+///
+/// Checks an uploaded Intel HEX firmware image and starts flashing it onto the device. Responds
+/// 202 with the flash's initial status.
+///
+pub(crate) fn start_flash(
+    registry: &Arc<DeviceRegistry>,
+    flasher: &Arc<FirmwareFlasher>,
+    device_id: &str,
+    data: Data,
+) -> Result<status::Custom<Json<FlashStatus>>, ApiError> {
+    let mut upload: Vec<u8> = vec![];
+    data.open()
+        .take(MAX_FIRMWARE_SIZE + 1)
+        .read_to_end(&mut upload)
+        .map_err(|e| {
+            api_error(
+                Status::InternalServerError,
+                format!("Failed to read firmware image - {}", e),
+                None,
+            )
+        })?;
+    if upload.len() as u64 > MAX_FIRMWARE_SIZE {
+        return Err(api_error(
+            Status::PayloadTooLarge,
+            format!("Firmware image is larger than {} bytes", MAX_FIRMWARE_SIZE),
+            None,
+        ));
+    }
+
+    let image = FirmwareImage::parse(&upload)
+        .map_err(|e| api_error(Status::BadRequest, e.to_string(), None))?;
+
+    let flash = flasher
+        .start(registry, device_id, image)
+        .map_err(|e| match e {
+            FirmwareError::Device(e) => device_error("flash firmware", e),
+            FirmwareError::Busy(..) => api_error(Status::Conflict, e.to_string(), None),
+            _ => api_error(
+                Status::InternalServerError,
+                format!("Failed to flash firmware - {}", e),
+                None,
+            ),
+        })?;
+
+    println!(
+        "Flashing {} bytes of firmware onto device {}",
+        flash.data_size, device_id
+    );
+    Ok(status::Custom(Status::Accepted, Json(flash)))
+}
+
+///
+/// Flashes an Intel HEX firmware image onto a device. The flash runs in the background, follow
+/// it with get_firmware_flash.
+///
+#[post("/devices/<id>/firmware", format = "plain", data = "<data>")]
+fn flash_firmware(
+    id: String,
+    data: Data,
+    registry: State<Arc<DeviceRegistry>>,
+    flasher: State<Arc<FirmwareFlasher>>,
+) -> Result<status::Custom<Json<FlashStatus>>, ApiError> {
+    start_flash(&registry, &flasher, &id, data)
+}
+
+///
+/// Gets the progress of a device's latest firmware flash, or its result once finished
+///
+#[get("/devices/<id>/firmware")]
+fn get_firmware_flash(id: String, flasher: State<Arc<FirmwareFlasher>>) -> ApiResult<FlashStatus> {
+    flasher.status(&id).map(Json).ok_or_else(|| {
+        api_error(
+            Status::NotFound,
+            format!("Device {} has not been flashed", id),
+            None,
+        )
+    })
+}
+
+///
+/// Returns the command a batch step sends to its device.
+///
+fn batch_step_command(
+    registry: &DeviceRegistry,
+    step: &BatchStepRequest,
+) -> Result<Command, ApiError> {
+    let protocol = || {
+        find_device(registry, &step.device_id)?
+            .protocol()
+            .map_err(|e| device_error("read protocol version", e))
+    };
+
+    Ok(match &step.command {
+        BatchCommand::PrintVersion => Command::PrintVersion,
+        BatchCommand::FullReset => Command::FullReset,
+        BatchCommand::EnterBootloader => Command::EnterBootloader,
+        BatchCommand::GetStatus => Command::GetStatus,
+        BatchCommand::SetDebugging(debugging) => Command::SetDebugging(*debugging),
+        BatchCommand::SetEffect(effect) => {
+            Command::SetEffect(resolve_effect(protocol()?.as_ref(), effect).ok_or_else(|| {
+                api_error(Status::BadRequest, String::from("Unknown effect"), None)
+            })?)
+        }
+        BatchCommand::SetFirePalette(palette) => Command::SetFireColorPallet(
+            resolve_fire_palette(protocol()?.as_ref(), palette).ok_or_else(|| {
+                api_error(
+                    Status::BadRequest,
+                    String::from("Unknown fire palette"),
+                    None,
+                )
+            })?,
+        ),
+        BatchCommand::SetColor(color) => Command::SetColor(*color),
+        BatchCommand::SetBrightnessPercent(brightness_percent) => {
+            Command::SetBrightness(brightness_from_percent(*brightness_percent)?)
+        }
+    })
+}
+
+///
+/// Adds the batch step an error response is about to its status.
+///
+fn in_batch_step(mut e: ApiError, index: usize) -> ApiError {
+    (e.1).0.status_str = format!("Step {} of the batch - {}", index + 1, (e.1).0.status_str);
+    e
+}
+
+///
+/// Runs a batch of commands for one or more devices. Responds 207 if a command failed.
+///
+#[post("/batch", format = "json", data = "<batch>")]
+fn post_batch(
+    registry: State<Arc<DeviceRegistry>>,
+    batch: Json<BatchRequest>,
+) -> Result<status::Custom<Json<BatchResponse>>, ApiError> {
+    let mut steps: Vec<BatchStep> = vec![];
+    for (index, step) in batch.steps.iter().enumerate() {
+        steps.push(BatchStep {
+            device_id: step.device_id.clone(),
+            command: batch_step_command(&registry, step).map_err(|e| in_batch_step(e, index))?,
+        });
+    }
+
+    let report =
+        run_batch(&registry, &steps, batch.rollback.unwrap_or(false)).map_err(|e| match e {
+            BatchError::UnknownDevice(id) => {
+                device_error("run batch", ControllerError::UnknownDevice(id))
+            }
+            BatchError::StatusFailed { source, .. } => {
+                device_error("read status before batch", source)
+            }
+            BatchError::StatusMalformed { .. } => api_error(
+                Status::BadGateway,
+                format!("Failed to run batch - {}", e),
+                None,
+            ),
+        })?;
+
+    let success = report.succeeded();
+    let step_responses: Vec<BatchStepResponse> = report
+        .steps
+        .into_iter()
+        .zip(&batch.steps)
+        .map(|(step, requested)| {
+            let (outcome, status_str, error_code) = match step.outcome {
+                StepOutcome::Applied(_pkt) => ("applied", None, None),
+                StepOutcome::Failed(e) => (
+                    "failed",
+                    Some(e.to_string()),
+                    e.protocol_error().map(|pe| pe.code()),
+                ),
+                StepOutcome::Skipped => ("skipped", None, None),
+            };
+            BatchStepResponse {
+                device_id: step.device_id,
+                command: requested.command.clone(),
+                outcome,
+                status_str,
+                error_code,
+            }
+        })
+        .collect();
+    let rollbacks: Vec<RollbackResponse> = report
+        .rollbacks
+        .into_iter()
+        .map(|rollback| RollbackResponse {
+            device_id: rollback.device_id,
+            success: rollback.result.is_ok(),
+            status_str: rollback.result.err().map(|e| e.to_string()),
+        })
+        .collect();
+
+    let applied = step_responses
+        .iter()
+        .filter(|step| step.outcome == "applied")
+        .count();
+    let status_str = if rollbacks.is_empty() {
+        format!(
+            "Batch applied {} of {} commands",
+            applied,
+            step_responses.len()
+        )
+    } else {
+        format!(
+            "Batch applied {} of {} commands, rolled back {} devices",
+            applied,
+            step_responses.len(),
+            rollbacks.len()
+        )
+    };
+    println!("{}", status_str);
+
+    let http_status = if success {
+        Status::Ok
+    } else {
+        Status::MultiStatus
+    };
+    Ok(status::Custom(
+        http_status,
+        Json(BatchResponse {
+            success,
+            status_str,
+            steps: step_responses,
+            rollbacks,
+        }),
+    ))
+}
+
+///
+/// Returns a stored scene as given to the API.
+///
+fn scene_data(name: String, scene: &DeviceState) -> SceneData {
+    SceneData {
+        name,
+        effect: scene
+            .effect
+            .map(|effect| NameOrId::Name(String::from(effect.name()))),
+        fire_palette: scene
+            .fire_pallet
+            .map(|pallet| NameOrId::Name(String::from(pallet.name()))),
+        color: scene.color,
+        brightness_percent: scene.brightness.map(brightness_percent),
+    }
+}
+
+///
+/// Returns the settings of a scene given to the API. Scenes are not bound to a device, so ids
+/// are those of the newest known firmware.
+///
+fn scene_state(scene: &SceneData) -> Result<DeviceState, ApiError> {
+    resolve_state(
+        latest_protocol_version().as_ref(),
+        &scene.effect,
+        &scene.fire_palette,
+        scene.color,
+        scene.brightness_percent,
+    )
+}
+
+///
+/// Lists the scenes
+///
+#[get("/scenes")]
+fn get_scenes(scenes: State<Arc<SceneStore>>) -> Json<Vec<SceneData>> {
+    Json(
+        scenes
+            .scenes()
+            .into_iter()
+            .map(|(name, scene)| scene_data(name, &scene))
+            .collect(),
+    )
+}
+
+///
+/// Creates or replaces a scene
+///
+#[put("/scenes/<name>", format = "json", data = "<scene>")]
+fn put_scene(
+    name: String,
+    scenes: State<Arc<SceneStore>>,
+    scene: Json<SceneData>,
+) -> ApiResult<SimpleCmdResponse> {
+    let state = scene_state(&scene)?;
+
+    scenes.set_scene(&name, state).map_err(|e| {
+        api_error(
+            Status::BadRequest,
+            format!("Failed to save scene - {}", e),
+            None,
+        )
+    })?;
+
+    let status_str = format!("Saved scene {}", name);
+    println!("{}", status_str);
+    Ok(Json(SimpleCmdResponse {
+        success: true,
+        status_str,
+        error_code: None,
+    }))
+}
+
+///
+/// Deletes a scene
+///
+#[delete("/scenes/<name>")]
+fn delete_scene(name: String, scenes: State<Arc<SceneStore>>) -> ApiResult<SimpleCmdResponse> {
+    match scenes.remove_scene(&name) {
+        Ok(true) => {
+            let status_str = format!("Deleted scene {}", name);
+            println!("{}", status_str);
+            Ok(Json(SimpleCmdResponse {
+                success: true,
+                status_str,
+                error_code: None,
+            }))
+        }
+        Ok(false) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown scene {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to delete scene {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Applies a scene to a device or to every member of a group. Responds 207 if a member failed.
+///
+#[post("/scenes/<name>/apply?<device>&<group>")]
+fn apply_scene(
+    name: String,
+    device: Option<String>,
+    group: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    scenes: State<Arc<SceneStore>>,
+) -> Result<status::Custom<Json<GroupCmdResponse>>, ApiError> {
+    let scene = scenes
+        .scene(&name)
+        .ok_or_else(|| api_error(Status::NotFound, format!("Unknown scene {}", name), None))?;
+
+    let (target, members) = target_members(&registry, &groups, device, group)?;
+    let results = apply_scene_to_group(&registry, &members, &scene);
+    Ok(group_response(
+        results,
+        &format!("Applied scene {}", name),
+        &format!("apply scene {}", name),
+        &target,
+    ))
+}
+
+///
+/// Returns a description of the device or group named by query parameters and the ids of the
+/// devices it covers. Exactly one of them must be given.
+///
+fn target_members(
+    registry: &DeviceRegistry,
+    groups: &GroupStore,
+    device: Option<String>,
+    group: Option<String>,
+) -> Result<(String, Vec<String>), ApiError> {
+    match (device, group) {
+        (Some(device), None) => {
+            find_device(registry, &device)?;
+            Ok((format!("device {}", device), vec![device]))
+        }
+        (None, Some(group)) => {
+            let members = groups.members(&group).ok_or_else(|| {
+                api_error(Status::NotFound, format!("Unknown group {}", group), None)
+            })?;
+            Ok((format!("group {}", group), members))
+        }
+        _ => Err(api_error(
+            Status::BadRequest,
+            String::from("Give either a device or a group"),
+            None,
+        )),
+    }
+}
+
+///
+/// Returns the scene format named by a query parameter, JSON if none is given.
+///
+fn scene_format(format: Option<String>) -> Result<SceneFormat, ApiError> {
+    format
+        .map_or(Ok(SceneFormat::Json), |format| format.parse())
+        .map_err(|e| api_error(Status::BadRequest, e.to_string(), None))
+}
+
+///
+/// Exports every scene as JSON or TOML
+///
+#[get("/scenes/export?<format>")]
+fn export_scenes(
+    format: Option<String>,
+    scenes: State<Arc<SceneStore>>,
+) -> Result<Content<String>, ApiError> {
+    let format = scene_format(format)?;
+    let exported: BTreeMap<String, SceneData> = scenes
+        .scenes()
+        .into_iter()
+        .map(|(name, scene)| (name, scene_data(String::new(), &scene)))
+        .collect();
+    let contents = format.write(&exported).map_err(|e| {
+        api_error(
+            Status::InternalServerError,
+            format!("Failed to export scenes - {}", e),
+            None,
+        )
+    })?;
+
+    let content_type = match format {
+        SceneFormat::Json => ContentType::JSON,
+        SceneFormat::Toml => ContentType::new("application", "toml"),
+    };
+    Ok(Content(content_type, contents))
+}
+
+///
+/// Imports scenes exported as JSON or TOML, replacing scenes with the same name
+///
+#[post("/scenes/import?<format>", data = "<contents>")]
+fn import_scenes(
+    format: Option<String>,
+    contents: String,
+    scenes: State<Arc<SceneStore>>,
+) -> ApiResult<SimpleCmdResponse> {
+    let format = scene_format(format)?;
+    let import_error = |e: SceneError| {
+        api_error(
+            Status::BadRequest,
+            format!("Failed to import scenes - {}", e),
+            None,
+        )
+    };
+    let imported: BTreeMap<String, SceneData> = format.read(&contents).map_err(import_error)?;
+
+    let mut states = BTreeMap::new();
+    for (name, scene) in &imported {
+        let state = scene_state(scene).map_err(|mut e| {
+            (e.1).0.status_str =
+                format!("Failed to import scene {} - {}", name, (e.1).0.status_str);
+            e
+        })?;
+        states.insert(name.clone(), state);
+    }
+    let names: Vec<String> = states.keys().cloned().collect();
+    scenes.set_scenes(states).map_err(import_error)?;
+
+    let status_str = format!("Imported {} scenes: {}", names.len(), names.join(", "));
+    println!("{}", status_str);
+    Ok(Json(SimpleCmdResponse {
+        success: true,
+        status_str,
+        error_code: None,
+    }))
+}
+
+///
+/// Lists the sequences
+///
+#[get("/sequences")]
+fn get_sequences(sequences: State<Arc<SequenceStore>>) -> ApiResult<Vec<SequenceData>> {
+    let sequences = sequences.sequences().map_err(|e| {
+        api_error(
+            Status::InternalServerError,
+            format!("Failed to list sequences - {}", e),
+            None,
+        )
+    })?;
+
+    Ok(Json(
+        sequences
+            .into_iter()
+            .map(|(name, sequence)| SequenceData {
+                name,
+                mode: sequence.mode,
+                keyframes: sequence.keyframes,
+            })
+            .collect(),
+    ))
+}
+
+///
+/// Returns the named sequence, or a 404 error response.
+///
+fn find_sequence(sequences: &SequenceStore, name: &str) -> Result<Sequence, ApiError> {
+    match sequences.sequence(name) {
+        Ok(Some(sequence)) => Ok(sequence),
+        Ok(None) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown sequence {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to read sequence {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Gets a sequence
+///
+#[get("/sequences/<name>")]
+fn get_sequence(name: String, sequences: State<Arc<SequenceStore>>) -> ApiResult<SequenceData> {
+    let sequence = find_sequence(&sequences, &name)?;
+    Ok(Json(SequenceData {
+        name,
+        mode: sequence.mode,
+        keyframes: sequence.keyframes,
+    }))
+}
+
+///
+/// Creates or replaces a sequence
+///
+#[put("/sequences/<name>", format = "json", data = "<sequence>")]
+fn put_sequence(
+    name: String,
+    sequences: State<Arc<SequenceStore>>,
+    sequence: Json<SequenceData>,
+) -> ApiResult<SimpleCmdResponse> {
+    let sequence = sequence.into_inner();
+    let sequence = Sequence {
+        mode: sequence.mode,
+        keyframes: sequence.keyframes,
+    };
+
+    sequences.set_sequence(&name, &sequence).map_err(|e| {
+        let status = match e {
+            SequenceError::Io(..) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        };
+        api_error(status, format!("Failed to save sequence - {}", e), None)
+    })?;
+
+    let status_str = format!("Saved sequence {}", name);
+    println!("{}", status_str);
+    Ok(Json(SimpleCmdResponse {
+        success: true,
+        status_str,
+        error_code: None,
+    }))
+}
+
+///
+/// Deletes a sequence. Devices playing it keep playing until stopped.
+///
+#[delete("/sequences/<name>")]
+fn delete_sequence(
+    name: String,
+    sequences: State<Arc<SequenceStore>>,
+) -> ApiResult<SimpleCmdResponse> {
+    match sequences.remove_sequence(&name) {
+        Ok(true) => {
+            let status_str = format!("Deleted sequence {}", name);
+            println!("{}", status_str);
+            Ok(Json(SimpleCmdResponse {
+                success: true,
+                status_str,
+                error_code: None,
+            }))
+        }
+        Ok(false) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown sequence {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to delete sequence {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Starts playing a sequence on a device or on every member of a group, replacing any sequence
+/// or transition in progress. Responds 207 if a member failed.
+///
+#[post("/sequences/<name>/play?<device>&<group>")]
+fn play_sequence_on(
+    name: String,
+    device: Option<String>,
+    group: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    sequences: State<Arc<SequenceStore>>,
+) -> Result<status::Custom<Json<GroupCmdResponse>>, ApiError> {
+    let sequence = find_sequence(&sequences, &name)?;
+    let (target, members) = target_members(&registry, &groups, device, group)?;
+
+    let results = for_each_member(&registry, &members, |device_manager| {
+        play_sequence(device_manager, sequence.clone()).map(|_| ())
+    });
+    Ok(group_response(
+        results,
+        &format!("Started sequence {}", name),
+        &format!("start sequence {}", name),
+        &target,
+    ))
+}
+
+///
+/// Stops the sequence playing on a device or on every member of a group. The devices stay at the
+/// last step played. Devices running a script or transition instead are left alone.
+///
+#[post("/sequences/stop?<device>&<group>")]
+fn stop_sequence(
+    device: Option<String>,
+    group: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+) -> Result<status::Custom<Json<GroupCmdResponse>>, ApiError> {
+    let (target, members) = target_members(&registry, &groups, device, group)?;
+
+    let results = for_each_member(&registry, &members, |device_manager| {
+        device_manager.stop_task(TaskKind::Sequence);
+        Ok(())
+    });
+    Ok(group_response(
+        results,
+        "Stopped sequence",
+        "stop sequence",
+        &target,
+    ))
+}
+
+///
+/// Lists the scripts with their source
+///
+#[get("/scripts")]
+fn get_scripts(scripts: State<Arc<ScriptStore>>) -> ApiResult<Vec<ScriptData>> {
+    let scripts = scripts.scripts().map_err(|e| {
+        api_error(
+            Status::InternalServerError,
+            format!("Failed to list scripts - {}", e),
+            None,
+        )
+    })?;
+
+    Ok(Json(
+        scripts
+            .into_iter()
+            .map(|(name, source)| ScriptData { name, source })
+            .collect(),
+    ))
+}
+
+///
+/// Returns the source of the named script, or a 404 error response.
+///
+fn find_script(scripts: &ScriptStore, name: &str) -> Result<String, ApiError> {
+    match scripts.script(name) {
+        Ok(Some(source)) => Ok(source),
+        Ok(None) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown script {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to read script {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Gets the source of a script
+///
+#[get("/scripts/<name>")]
+fn get_script(name: String, scripts: State<Arc<ScriptStore>>) -> Result<String, ApiError> {
+    find_script(&scripts, &name)
+}
+
+///
+/// Creates or replaces a script. Scripts that do not compile are rejected.
+///
+#[put("/scripts/<name>", data = "<source>")]
+fn put_script(
+    name: String,
+    scripts: State<Arc<ScriptStore>>,
+    source: String,
+) -> ApiResult<SimpleCmdResponse> {
+    scripts.set_script(&name, &source).map_err(|e| {
+        let status = match e {
+            ScriptError::Io(..) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        };
+        api_error(status, format!("Failed to save script - {}", e), None)
+    })?;
+
+    let status_str = format!("Saved script {}", name);
+    println!("{}", status_str);
+    Ok(Json(SimpleCmdResponse {
+        success: true,
+        status_str,
+        error_code: None,
+    }))
+}
+
+///
+/// Deletes a script. Devices running it keep running it until stopped.
+///
+#[delete("/scripts/<name>")]
+fn delete_script(name: String, scripts: State<Arc<ScriptStore>>) -> ApiResult<SimpleCmdResponse> {
+    match scripts.remove_script(&name) {
+        Ok(true) => {
+            let status_str = format!("Deleted script {}", name);
+            println!("{}", status_str);
+            Ok(Json(SimpleCmdResponse {
+                success: true,
+                status_str,
+                error_code: None,
+            }))
+        }
+        Ok(false) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown script {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to delete script {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Starts running a script on a device or on every member of a group, once a second unless an
+/// interval is given. Replaces any script, sequence or transition in progress. Responds 207 if a
+/// member failed.
+///
+#[post("/scripts/<name>/run?<device>&<group>&<interval_ms>")]
+fn run_script_on(
+    name: String,
+    device: Option<String>,
+    group: Option<String>,
+    interval_ms: Option<u32>,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    scripts: State<Arc<ScriptStore>>,
+) -> Result<status::Custom<Json<GroupCmdResponse>>, ApiError> {
+    let interval = match interval_ms {
+        Some(interval_ms) if interval_ms > MAX_SCRIPT_INTERVAL_MS => {
+            return Err(api_error(
+                Status::BadRequest,
+                format!(
+                    "interval_ms {} is longer than {}",
+                    interval_ms, MAX_SCRIPT_INTERVAL_MS
+                ),
+                None,
+            ))
+        }
+        Some(interval_ms) => Duration::from_millis(u64::from(interval_ms)),
+        None => DEFAULT_SCRIPT_INTERVAL,
+    };
+
+    let source = find_script(&scripts, &name)?;
+    let ast = compile_script(&source).map_err(|e| {
+        api_error(
+            Status::BadRequest,
+            format!("Failed to run script {} - {}", name, e),
+            None,
+        )
+    })?;
+    let (target, members) = target_members(&registry, &groups, device, group)?;
+
+    let results = for_each_member(&registry, &members, |device_manager| {
+        run_script(device_manager, &name, ast.clone(), interval).map(|_| ())
+    });
+    Ok(group_response(
+        results,
+        &format!("Started script {}", name),
+        &format!("start script {}", name),
+        &target,
+    ))
+}
+
+///
+/// Stops the script running on a device or on every member of a group. The devices keep the
+/// settings the script last sent. Devices playing a sequence or transition instead are left
+/// alone.
+///
+#[post("/scripts/stop?<device>&<group>")]
+fn stop_script(
+    device: Option<String>,
+    group: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+) -> Result<status::Custom<Json<GroupCmdResponse>>, ApiError> {
+    let (target, members) = target_members(&registry, &groups, device, group)?;
+
+    let results = for_each_member(&registry, &members, |device_manager| {
+        device_manager.stop_task(TaskKind::Script);
+        Ok(())
+    });
+    Ok(group_response(
+        results,
+        "Stopped script",
+        "stop script",
+        &target,
+    ))
+}
+
+///
+/// Lists the schedules with their next run
+///
+#[get("/schedules")]
+fn get_schedules(schedules: State<Arc<ScheduleStore>>) -> Json<Vec<ScheduleResponse>> {
+    let now = Utc::now();
+
+    Json(
+        schedules
+            .schedules()
+            .into_iter()
+            .map(|(name, schedule)| ScheduleResponse {
+                name,
+                next_run: schedule.next_run(now, schedules.location()),
+                trigger: schedule.trigger,
+                action: schedule.action,
+                target: schedule.target,
+                enabled: schedule.enabled,
+                missed_runs: schedule.missed_runs,
+            })
+            .collect(),
+    )
+}
+
+///
+/// Lists the next times a schedule runs, 10 unless a count is given
+///
+#[get("/schedules/<name>/runs?<count>")]
+fn get_schedule_runs(
+    name: String,
+    count: Option<usize>,
+    schedules: State<Arc<ScheduleStore>>,
+) -> ApiResult<Vec<DateTime<Utc>>> {
+    let schedule = schedules
+        .schedule(&name)
+        .ok_or_else(|| api_error(Status::NotFound, format!("Unknown schedule {}", name), None))?;
+    let count = count
+        .unwrap_or(DEFAULT_UPCOMING_RUNS)
+        .min(MAX_UPCOMING_RUNS);
+
+    Ok(Json(schedule.upcoming_runs(
+        Utc::now(),
+        schedules.location(),
+        count,
+    )))
+}
+
+///
+/// Gets the times of sunrise, sunset and civil twilight used by sun triggers, for today or the
+/// given date in YYYY-MM-DD form
+///
+#[get("/sun?<date>")]
+fn get_sun(
+    date: Option<String>,
+    schedules: State<Arc<ScheduleStore>>,
+) -> ApiResult<SunTimesResponse> {
+    let location = schedules.location().ok_or_else(|| {
+        api_error(
+            Status::NotFound,
+            String::from("No location set, configure ledsc_latitude and ledsc_longitude"),
+            None,
+        )
+    })?;
+    let date = match date {
+        Some(date) => NaiveDate::parse_from_str(&date, "%Y-%m-%d").map_err(|e| {
+            api_error(
+                Status::BadRequest,
+                format!("Invalid date {} - {}", date, e),
+                None,
+            )
+        })?,
+        None => Local::today().naive_local(),
+    };
+    let time = |event: SunEvent| sun_event_time(location, date, event);
+
+    Ok(Json(SunTimesResponse {
+        date: date.to_string(),
+        latitude: location.latitude,
+        longitude: location.longitude,
+        civil_dawn: time(SunEvent::CivilDawn),
+        sunrise: time(SunEvent::Sunrise),
+        sunset: time(SunEvent::Sunset),
+        civil_dusk: time(SunEvent::CivilDusk),
+    }))
+}
+
+///
+/// Creates or replaces a schedule. The scene and group it names must exist.
+///
+#[put("/schedules/<name>", format = "json", data = "<schedule>")]
+fn put_schedule(
+    name: String,
+    schedules: State<Arc<ScheduleStore>>,
+    groups: State<Arc<GroupStore>>,
+    scenes: State<Arc<SceneStore>>,
+    schedule: Json<ScheduleRequest>,
+) -> ApiResult<SimpleCmdResponse> {
+    let schedule = schedule.into_inner();
+
+    if let ScheduleAction::Scene(scene) = &schedule.action {
+        if scenes.scene(scene).is_none() {
+            return Err(api_error(
+                Status::BadRequest,
+                format!("Unknown scene {}", scene),
+                None,
+            ));
+        }
+    }
+    if let ScheduleTarget::Group(group) = &schedule.target {
+        if groups.members(group).is_none() {
+            return Err(api_error(
+                Status::BadRequest,
+                format!("Unknown group {}", group),
+                None,
+            ));
+        }
+    }
+
+    let schedule = Schedule {
+        trigger: schedule.trigger,
+        action: schedule.action,
+        target: schedule.target,
+        enabled: schedule.enabled.unwrap_or(true),
+        missed_runs: schedule.missed_runs.unwrap_or_default(),
+        handled_until: None,
+    };
+    schedules
+        .set_schedule(&name, schedule, Utc::now())
+        .map_err(|e| {
+            api_error(
+                Status::BadRequest,
+                format!("Failed to save schedule - {}", e),
+                None,
+            )
+        })?;
+
+    let status_str = format!("Saved schedule {}", name);
+    println!("{}", status_str);
+    Ok(Json(SimpleCmdResponse {
+        success: true,
+        status_str,
+        error_code: None,
+    }))
+}
+
+///
+/// Deletes a schedule
+///
+#[delete("/schedules/<name>")]
+fn delete_schedule(
+    name: String,
+    schedules: State<Arc<ScheduleStore>>,
+) -> ApiResult<SimpleCmdResponse> {
+    match schedules.remove_schedule(&name) {
+        Ok(true) => {
+            let status_str = format!("Deleted schedule {}", name);
+            println!("{}", status_str);
+            Ok(Json(SimpleCmdResponse {
+                success: true,
+                status_str,
+                error_code: None,
+            }))
+        }
+        Ok(false) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown schedule {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to delete schedule {} - {}", name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Enables or disables a schedule.
+///
+fn set_schedule_enabled(
+    name: &str,
+    schedules: &ScheduleStore,
+    enabled: bool,
+) -> ApiResult<SimpleCmdResponse> {
+    let action = if enabled { "enable" } else { "disable" };
+
+    match schedules.set_enabled(name, enabled, Utc::now()) {
+        Ok(true) => {
+            let status_str = format!("Schedule {} {}d", name, action);
+            println!("{}", status_str);
+            Ok(Json(SimpleCmdResponse {
+                success: true,
+                status_str,
+                error_code: None,
+            }))
+        }
+        Ok(false) => Err(api_error(
+            Status::NotFound,
+            format!("Unknown schedule {}", name),
+            None,
+        )),
+        Err(e) => Err(api_error(
+            Status::InternalServerError,
+            format!("Failed to {} schedule {} - {}", action, name, e),
+            None,
+        )),
+    }
+}
+
+///
+/// Enables a schedule. Runs missed while it was disabled are dropped.
+///
+#[post("/schedules/<name>/enable")]
+fn enable_schedule(
+    name: String,
+    schedules: State<Arc<ScheduleStore>>,
+) -> ApiResult<SimpleCmdResponse> {
+    set_schedule_enabled(&name, &schedules, true)
+}
+
+///
+/// Disables a schedule
+///
+#[post("/schedules/<name>/disable")]
+fn disable_schedule(
+    name: String,
+    schedules: State<Arc<ScheduleStore>>,
+) -> ApiResult<SimpleCmdResponse> {
+    set_schedule_enabled(&name, &schedules, false)
+}
+
+///
+/// Lists the effects and their ids, for the given device's firmware or the newest known one
+///
+#[get("/effects?<device>")]
+fn get_effects(
+    device: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+) -> ApiResult<Vec<NamedId>> {
+    let protocol = listing_protocol(&registry, device)?;
+
+    Ok(Json(
+        Effect::ALL
+            .iter()
+            .filter(|effect| protocol.is_effect_supported(effect))
+            .map(|effect| NamedId {
+                id: protocol.get_effect_cmd_value(effect),
+                name: effect.name(),
+            })
+            .collect(),
+    ))
+}
+
+///
+/// Lists the fire palettes and their ids, for the given device's firmware or the newest known one
+///
+#[get("/palettes?<device>")]
+fn get_palettes(
+    device: Option<String>,
+    registry: State<Arc<DeviceRegistry>>,
+) -> ApiResult<Vec<NamedId>> {
+    let protocol = listing_protocol(&registry, device)?;
+
+    Ok(Json(
+        FireColorPallet::ALL
+            .iter()
+            .map(|pallet| NamedId {
+                id: protocol.get_fire_color_pallet_value(pallet),
+                name: pallet.name(),
+            })
+            .collect(),
+    ))
+}
+
+///
+/// Returns the JSON API routes.
+///
+pub fn routes() -> Vec<Route> {
+    routes![
+        crate::get_devices,
+        get_state,
+        put_state,
+        flash_firmware,
+        get_firmware_flash,
+        post_batch,
+        get_scenes,
+        put_scene,
+        delete_scene,
+        apply_scene,
+        export_scenes,
+        import_scenes,
+        get_sequences,
+        get_sequence,
+        put_sequence,
+        delete_sequence,
+        play_sequence_on,
+        stop_sequence,
+        get_scripts,
+        get_script,
+        put_script,
+        delete_script,
+        run_script_on,
+        stop_script,
+        get_schedules,
+        get_schedule_runs,
+        get_sun,
+        put_schedule,
+        delete_schedule,
+        enable_schedule,
+        disable_schedule,
+        get_effects,
+        get_palettes
+    ]
+}
+
+///
+/// Returns the documentation of a JSON API route by handler name.
+///
+pub fn operation(name: &str) -> Option<Operation> {
+    const JSON: &str = "application/json";
+    let operation = match name {
+        "get_state" => Operation {
+            summary: "Reads the state of a device",
+            request: None,
+            response: (JSON, DeviceStateResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "put_state" => Operation {
+            summary: "Changes the state of a device, leaving out fields keeps their value",
+            request: Some((JSON, DeviceStateUpdate::schema)),
+            response: (JSON, DeviceStateResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "flash_firmware" => Operation {
+            summary: "Flashes an Intel HEX firmware image onto a device in the background",
+            request: Some(("text/plain", String::schema)),
+            response: (JSON, FlashStatus::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_firmware_flash" => Operation {
+            summary: "Gets the progress or result of a device's latest firmware flash",
+            request: None,
+            response: (JSON, FlashStatus::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "post_batch" => Operation {
+            summary:
+                "Runs commands for one or more devices in order, optionally rolling back on failure",
+            request: Some((JSON, BatchRequest::schema)),
+            response: (JSON, BatchResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_scenes" => Operation {
+            summary: "Lists the scenes",
+            request: None,
+            response: (JSON, Vec::<SceneData>::schema),
+            error: None,
+        },
+        "put_scene" => Operation {
+            summary: "Creates or replaces a scene",
+            request: Some((JSON, SceneData::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "delete_scene" => Operation {
+            summary: "Deletes a scene",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "apply_scene" => Operation {
+            summary: "Applies a scene to a device or to every member of a group",
+            request: None,
+            response: (JSON, GroupCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "export_scenes" => Operation {
+            summary: "Exports every scene as JSON or TOML",
+            request: None,
+            response: ("text/plain", String::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "import_scenes" => Operation {
+            summary: "Imports scenes exported as JSON or TOML",
+            request: Some(("text/plain", String::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_sequences" => Operation {
+            summary: "Lists the sequences",
+            request: None,
+            response: (JSON, Vec::<SequenceData>::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_sequence" => Operation {
+            summary: "Gets a sequence",
+            request: None,
+            response: (JSON, SequenceData::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "put_sequence" => Operation {
+            summary: "Creates or replaces a sequence",
+            request: Some((JSON, SequenceData::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "delete_sequence" => Operation {
+            summary: "Deletes a sequence",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "play_sequence_on" => Operation {
+            summary: "Starts playing a sequence on a device or on every member of a group",
+            request: None,
+            response: (JSON, GroupCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "stop_sequence" => Operation {
+            summary: "Stops the sequence playing on a device or group, leaving scripts and transitions running",
+            request: None,
+            response: (JSON, GroupCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_scripts" => Operation {
+            summary: "Lists the scripts with their source",
+            request: None,
+            response: (JSON, Vec::<ScriptData>::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_script" => Operation {
+            summary: "Gets the source of a script",
+            request: None,
+            response: ("text/plain", String::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "put_script" => Operation {
+            summary: "Creates or replaces a Rhai script, rejecting scripts that do not compile",
+            request: Some(("text/plain", String::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "delete_script" => Operation {
+            summary: "Deletes a script",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "run_script_on" => Operation {
+            summary: "Starts running a script on a device or on every member of a group",
+            request: None,
+            response: (JSON, GroupCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "stop_script" => Operation {
+            summary: "Stops the script running on a device or group, leaving sequences and transitions running",
+            request: None,
+            response: (JSON, GroupCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_schedules" => Operation {
+            summary: "Lists the schedules with their next run",
+            request: None,
+            response: (JSON, Vec::<ScheduleResponse>::schema),
+            error: None,
+        },
+        "get_schedule_runs" => Operation {
+            summary: "Lists the next times a schedule runs, 10 unless a count is given",
+            request: None,
+            response: (JSON, Vec::<DateTime<Utc>>::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_sun" => Operation {
+            summary: "Gets the times of sunrise, sunset and civil twilight for today or a date",
+            request: None,
+            response: (JSON, SunTimesResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "put_schedule" => Operation {
+            summary: "Creates or replaces a schedule",
+            request: Some((JSON, ScheduleRequest::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "delete_schedule" => Operation {
+            summary: "Deletes a schedule",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "enable_schedule" => Operation {
+            summary: "Enables a schedule, dropping runs missed while it was disabled",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "disable_schedule" => Operation {
+            summary: "Disables a schedule",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_effects" => Operation {
+            summary: "Lists the effects of a device, or of the latest protocol",
+            request: None,
+            response: (JSON, Vec::<NamedId>::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_palettes" => Operation {
+            summary: "Lists the fire palettes of a device, or of the latest protocol",
+            request: None,
+            response: (JSON, Vec::<NamedId>::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        _ => return None,
+    };
+    Some(operation)
+}
@@ -0,0 +1,88 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! Serves the LEDSC firmware simulator on a pseudo-terminal so led_oxide can be run without
+//! hardware.
+//!
+//! Usage: ledsc_simulator [link_path]
+//!
+//! The pseudo-terminal path is printed on start. If link_path is given a symlink to the
+//! pseudo-terminal is created there, giving a stable path for ROCKET_LEDSC_PORT.
+//!
+
+use led_oxide::led_strip_controller::simulator::Simulator;
+use serialport::{SerialPort, TTYPort};
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+use std::{env, fs, process};
+
+fn main() {
+    let (mut master, slave) = match TTYPort::pair() {
+        Ok(pair) => pair,
+        Err(e) => {
+            eprintln!("Failed to create pseudo-terminal: {:?}", e);
+            process::exit(1);
+        }
+    };
+
+    let slave_name = slave.name().unwrap_or_default();
+    println!("LEDSC simulator listening on {}", slave_name);
+
+    let link_path = env::args().nth(1);
+    if let Some(link_path) = &link_path {
+        let _ = fs::remove_file(link_path);
+        if let Err(e) = std::os::unix::fs::symlink(&slave_name, link_path) {
+            eprintln!("Failed to link {} to {}: {:?}", link_path, slave_name, e);
+            process::exit(1);
+        }
+        println!("Linked {} to {}", link_path, slave_name);
+    }
+
+    if let Err(e) = master.set_timeout(Duration::from_secs(1)) {
+        eprintln!("Failed to set pseudo-terminal timeout: {:?}", e);
+        process::exit(1);
+    }
+
+    let mut simulator = Simulator::new();
+    let mut receive_buffer = [0; 64];
+
+    loop {
+        match master.read(&mut receive_buffer) {
+            Ok(count) => {
+                let response = simulator.receive(&receive_buffer[..count]);
+                if let Err(e) = master.write_all(&response) {
+                    eprintln!("Failed to write response: {:?}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("Pseudo-terminal closed: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    // Keep the slave open for the lifetime of the simulator so clients can come and go
+    drop(slave);
+
+    if let Some(link_path) = &link_path {
+        let _ = fs::remove_file(link_path);
+    }
+}
@@ -0,0 +1,367 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::controller::*;
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::registry::DeviceRegistry;
+use crate::led_strip_controller::state::DeviceState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+///
+/// A command of a batch and the device it is sent to.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchStep {
+    pub device_id: String,
+    pub command: Command,
+}
+
+///
+/// Outcome of a batch step.
+///
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The device accepted the command
+    Applied(ResponsePacket),
+    /// The command failed, stopping the batch
+    Failed(ControllerError),
+    /// The command was not sent because an earlier step failed
+    Skipped,
+}
+
+///
+/// Outcome of a batch step for the step's device and command.
+///
+#[derive(Debug)]
+pub struct StepResult {
+    pub device_id: String,
+    pub command: Command,
+    pub outcome: StepOutcome,
+}
+
+///
+/// Outcome of restoring a device to the state it had before the batch.
+///
+#[derive(Debug)]
+pub struct RollbackResult {
+    pub device_id: String,
+    pub result: std::result::Result<(), ControllerError>,
+}
+
+///
+/// Results of every step in batch order, followed by the rollbacks done after a failure.
+///
+#[derive(Debug)]
+pub struct BatchReport {
+    pub steps: Vec<StepResult>,
+    pub rollbacks: Vec<RollbackResult>,
+}
+
+impl BatchReport {
+    ///
+    /// Returns true if every step was applied.
+    ///
+    pub fn succeeded(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| matches!(step.outcome, StepOutcome::Applied(..)))
+    }
+}
+
+///
+/// Errors that keep a batch from starting. No command has been sent when they occur.
+///
+#[derive(Debug)]
+pub enum BatchError {
+    /// A step names a device that is not registered
+    UnknownDevice(String),
+    /// The state to roll back to could not be read from a device
+    StatusFailed {
+        device_id: String,
+        source: ControllerError,
+    },
+    /// The state to roll back to could not be decoded
+    StatusMalformed {
+        device_id: String,
+        source: DeviceStatusError,
+    },
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchError::UnknownDevice(device_id) => write!(f, "Unknown device {}", device_id),
+            BatchError::StatusFailed { device_id, source } => {
+                write!(f, "Failed to read status of {}: {}", device_id, source)
+            }
+            BatchError::StatusMalformed { device_id, source } => {
+                write!(f, "Failed to decode status of {}: {}", device_id, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BatchError::StatusFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Runs the steps in order. Every device of the batch is locked for the whole batch, so its
+/// commands run back to back over the held connection without commands of other clients in
+/// between. The first failing step stops the batch and the remaining steps are skipped. With
+/// rollback the state of each device is read first and restored on the devices the batch reached
+/// if a step fails.
+///
+pub fn run_batch(
+    registry: &DeviceRegistry,
+    steps: &[BatchStep],
+    rollback: bool,
+) -> std::result::Result<BatchReport, BatchError> {
+    let mut device_managers: BTreeMap<&str, Arc<DeviceManager>> = BTreeMap::new();
+    for step in steps {
+        if !device_managers.contains_key(step.device_id.as_str()) {
+            let device_manager = registry
+                .device(&step.device_id)
+                .ok_or_else(|| BatchError::UnknownDevice(step.device_id.clone()))?;
+            device_managers.insert(&step.device_id, device_manager);
+        }
+    }
+
+    // Sessions are taken in id order so batches sharing devices cannot deadlock
+    let mut sessions: BTreeMap<&str, DeviceSession<'_>> = device_managers
+        .iter()
+        .map(|(device_id, device_manager)| (*device_id, device_manager.session()))
+        .collect();
+
+    let mut snapshots: BTreeMap<&str, DeviceState> = BTreeMap::new();
+    if rollback {
+        for (device_id, session) in sessions.iter_mut() {
+            snapshots.insert(device_id, read_state(device_id, session)?);
+        }
+    }
+
+    let mut reached: BTreeSet<&str> = BTreeSet::new();
+    let mut failed = false;
+    let mut results: Vec<StepResult> = vec![];
+    for step in steps {
+        let outcome = if failed {
+            StepOutcome::Skipped
+        } else {
+            let device_id = step.device_id.as_str();
+            reached.insert(device_id);
+            match sessions.get_mut(device_id) {
+                Some(session) => match session.send_command(step.command) {
+                    Ok(pkt) => StepOutcome::Applied(pkt),
+                    Err(e) => StepOutcome::Failed(e),
+                },
+                None => StepOutcome::Failed(ControllerError::UnknownDevice(step.device_id.clone())),
+            }
+        };
+        failed = failed || matches!(outcome, StepOutcome::Failed(..));
+
+        results.push(StepResult {
+            device_id: step.device_id.clone(),
+            command: step.command,
+            outcome,
+        });
+    }
+
+    let mut rollbacks: Vec<RollbackResult> = vec![];
+    if failed {
+        for (device_id, state) in snapshots.iter().filter(|(id, _)| reached.contains(*id)) {
+            if let Some(session) = sessions.get_mut(device_id) {
+                rollbacks.push(RollbackResult {
+                    device_id: String::from(*device_id),
+                    result: restore(session, state),
+                });
+            }
+        }
+    }
+
+    Ok(BatchReport {
+        steps: results,
+        rollbacks,
+    })
+}
+
+///
+/// Reads the state of a device, holding every setting its status reports.
+///
+fn read_state(
+    device_id: &str,
+    session: &mut DeviceSession<'_>,
+) -> std::result::Result<DeviceState, BatchError> {
+    let status_failed = |source| BatchError::StatusFailed {
+        device_id: String::from(device_id),
+        source,
+    };
+
+    let protocol = session.protocol().map_err(status_failed)?;
+    let pkt = session
+        .send_command(Command::GetStatus)
+        .map_err(status_failed)?;
+    let status =
+        protocol
+            .parse_device_status(&pkt)
+            .map_err(|source| BatchError::StatusMalformed {
+                device_id: String::from(device_id),
+                source,
+            })?;
+
+    Ok(DeviceState::from(&status))
+}
+
+///
+/// Sends the commands bringing a device back to the given state. Settings the device's firmware
+/// does not support are skipped.
+///
+fn restore(
+    session: &mut DeviceSession<'_>,
+    state: &DeviceState,
+) -> std::result::Result<(), ControllerError> {
+    let protocol = session.protocol()?;
+
+    for command in state.commands() {
+        if protocol.is_cmd_supported(&command) {
+            session.send_command(command)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::batch::*;
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::simulator::Simulator;
+    use serialport::{SerialPortInfo, SerialPortType};
+
+    fn simulated_registry() -> DeviceRegistry {
+        DeviceRegistry::with_ports(
+            || {
+                Ok(vec![
+                    SerialPortInfo {
+                        port_name: String::from("/dev/ttyACM0"),
+                        port_type: SerialPortType::Unknown,
+                    },
+                    SerialPortInfo {
+                        port_name: String::from("/dev/ttyACM1"),
+                        port_type: SerialPortType::Unknown,
+                    },
+                ])
+            },
+            |_port_info| Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport),
+        )
+    }
+
+    fn step(device_id: &str, command: Command) -> BatchStep {
+        BatchStep {
+            device_id: String::from(device_id),
+            command,
+        }
+    }
+
+    fn status(registry: &DeviceRegistry, device_id: &str) -> DeviceStatus {
+        let device_manager = registry.device(device_id).unwrap();
+        let protocol = device_manager.protocol().unwrap();
+        let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+        protocol.parse_device_status(&pkt).unwrap()
+    }
+
+    #[test]
+    fn run_batch_test() {
+        let registry = simulated_registry();
+        registry.scan().unwrap();
+        let color = Color24::from_u32(0x00ff00);
+
+        let report = run_batch(
+            &registry,
+            &[
+                step("ttyACM0", Command::SetEffect(Effect::Fire)),
+                step("ttyACM1", Command::SetColor(color)),
+                step("ttyACM0", Command::SetBrightness(7)),
+            ],
+            true,
+        )
+        .unwrap();
+
+        assert!(report.succeeded());
+        assert!(report.rollbacks.is_empty());
+        assert_eq!(status(&registry, "ttyACM0").effect, Effect::Fire);
+        assert_eq!(status(&registry, "ttyACM0").brightness, 7);
+        assert_eq!(status(&registry, "ttyACM1").color, color);
+
+        match run_batch(&registry, &[step("missing", Command::GetStatus)], false) {
+            Err(BatchError::UnknownDevice(device_id)) => assert_eq!(device_id, "missing"),
+            result => panic!("Expected unknown device, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn run_batch_failure_test() {
+        let registry = simulated_registry();
+        registry.scan().unwrap();
+        let steps = [
+            step("ttyACM0", Command::SetBrightness(9)),
+            step("ttyACM1", Command::FullReset),
+            step("ttyACM0", Command::SetEffect(Effect::Comet)),
+        ];
+
+        // Without rollback the steps before the failure stay applied
+        let report = run_batch(&registry, &steps, false).unwrap();
+        assert!(!report.succeeded());
+        assert!(matches!(report.steps[0].outcome, StepOutcome::Applied(..)));
+        assert!(matches!(report.steps[1].outcome, StepOutcome::Failed(..)));
+        assert!(matches!(report.steps[2].outcome, StepOutcome::Skipped));
+        assert!(report.rollbacks.is_empty());
+        assert_eq!(status(&registry, "ttyACM0").brightness, 9);
+
+        // With rollback the devices the batch reached get their previous state back
+        let before = status(&registry, "ttyACM0");
+        let steps = [
+            step("ttyACM0", Command::SetBrightness(200)),
+            step("ttyACM0", Command::SetColor(Color24::from_u32(0x123456))),
+            step("ttyACM1", Command::FullReset),
+        ];
+        let report = run_batch(&registry, &steps, true).unwrap();
+        assert!(!report.succeeded());
+        let rolled_back: Vec<&str> = report
+            .rollbacks
+            .iter()
+            .map(|rollback| rollback.device_id.as_str())
+            .collect();
+        assert_eq!(rolled_back, vec!["ttyACM0", "ttyACM1"]);
+        assert!(report
+            .rollbacks
+            .iter()
+            .all(|rollback| rollback.result.is_ok()));
+        assert_eq!(status(&registry, "ttyACM0"), before);
+    }
+}
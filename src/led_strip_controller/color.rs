@@ -17,8 +17,13 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
 /// Represents a 24bit RGB Color
 ///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Color24 {
     r: u8,
     g: u8,
@@ -54,6 +59,70 @@ impl Color24 {
     }
 }
 
+///
+/// Formats the color as #rrggbb
+///
+impl fmt::Display for Color24 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:06x}", self.to_u32())
+    }
+}
+
+///
+/// Error parsing a hex color. Holds the rejected text.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseColorError(String);
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid color '{}', expected 6 or 3 hex digits with an optional leading #",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseColorError {}
+
+///
+/// Parses a hex color of 6 digits, or of 3 digits as in CSS where #f80 is #ff8800, with or
+/// without a single leading #
+///
+impl FromStr for Color24 {
+    type Err = ParseColorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix('#').unwrap_or(s);
+
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseColorError(String::from(s)));
+        }
+        let digits: String = match digits.len() {
+            6 => String::from(digits),
+            3 => digits.chars().flat_map(|c| vec![c, c]).collect(),
+            _ => return Err(ParseColorError(String::from(s))),
+        };
+
+        u32::from_str_radix(&digits, 16)
+            .map(Color24::from_u32)
+            .map_err(|_| ParseColorError(String::from(s)))
+    }
+}
+
+impl Serialize for Color24 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color24 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
 
 //
 // Color Unit Tests
@@ -178,4 +247,44 @@ mod tests {
         assert_eq!(c1.g, c2.g);
         assert_eq!(c1.b, c2.b);
     }
+
+    #[test]
+    fn color_hex_string_test() {
+        let c1 = color::Color24::from_u32(0x00c2b4f3);
+
+        assert_eq!(c1.to_string(), "#c2b4f3");
+        assert_eq!("#c2b4f3".parse::<color::Color24>().unwrap(), c1);
+        assert_eq!("C2B4F3".parse::<color::Color24>().unwrap(), c1);
+        assert_eq!(
+            "#000001".parse::<color::Color24>().unwrap().to_string(),
+            "#000001"
+        );
+        assert!("#zz0000".parse::<color::Color24>().is_err());
+
+        // 3 digit shorthand doubles each digit
+        assert_eq!("#fff".parse::<color::Color24>().unwrap().to_u32(), 0xffffff);
+        assert_eq!("F80".parse::<color::Color24>().unwrap().to_u32(), 0xff8800);
+
+        // Anything but 6 or 3 hex digits after an optional single #
+        for rejected in &[
+            "#12345678",
+            "1234567",
+            "#1FFFFFFF",
+            "#12345",
+            "#ff",
+            "1",
+            "+fff",
+            "##fff",
+            "#",
+            "",
+            " fff",
+            "#-1",
+        ] {
+            assert!(
+                rejected.parse::<color::Color24>().is_err(),
+                "{} was accepted",
+                rejected
+            );
+        }
+    }
 }
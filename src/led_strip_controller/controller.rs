@@ -17,224 +17,933 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::led_strip_controller::decoder::PacketDecoder;
+use crate::led_strip_controller::events::{DeviceEvent, EventBus};
 use crate::led_strip_controller::protocol::*;
-use serialport::*;
-use std::{thread, time};
-
-/// Default baud rate. 115200 - 8 1 none
-const LEDSC_BAUD: u32 = 115200;
+use crate::led_strip_controller::state::{DeviceState, StateKeeper};
+use crate::led_strip_controller::store::recover;
+use crate::led_strip_controller::transport::{SerialTransport, Transport};
+use serialport::{available_ports, SerialPortInfo, SerialPortType};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 /// Time in milliseconds a response will be waited after sending a command.
 const RECEIVE_TIMEOUT_MS: u64 = 500;
 
-/// No devices found user message
-const ERROR_NO_DEVICES_FOUND: &str = "No Devices Found";
+/// Times a command is resent after a corrupted exchange unless configured otherwise.
+pub const DEFAULT_COMMAND_RETRIES: u32 = 2;
 
-/// No available ports found user message
-const ERROR_NO_AVAILABLE_PORTS: &str = "No Available Ports";
+///
+/// Error talking to a LEDSC device. Keeps the underlying cause.
+///
+#[derive(Debug)]
+pub enum ControllerError {
+    /// Listing the available serial ports failed
+    NoPorts(serialport::Error),
+    /// No port answered as a LEDSC device
+    NoDevicesFound,
+    /// No device is registered under the id
+    UnknownDevice(String),
+    /// Opening a serial port failed
+    OpenFailed {
+        port_name: String,
+        source: serialport::Error,
+    },
+    /// Writing a command to the device failed
+    WriteFailed(io::Error),
+    /// Reading the response from the device failed
+    ReadFailed(io::Error),
+    /// No complete response arrived in time
+    Timeout,
+    /// The firmware answered with an error status
+    Remote(ResponsePacket),
+    /// The response could not be parsed
+    Local(ProtocolError),
+    /// The device's firmware version does not support the command
+    Unsupported { command: Command, version: String },
+    /// The device's firmware version has no effect with the id
+    UnknownEffect(u8),
+    /// The device's firmware version has no fire color pallet with the id
+    UnknownFireColorPallet(u8),
+}
 
-/// Failed to open port user message
-const ERROR_FAILED_TO_OPEN_PORT: &str = "Failed to Open Port";
+impl ControllerError {
+    ///
+    /// Returns the protocol error reported by the firmware or found parsing its response.
+    ///
+    pub fn protocol_error(&self) -> Option<ProtocolError> {
+        match self {
+            ControllerError::Remote(pkt) => pkt.error(),
+            ControllerError::Local(error) => Some(*error),
+            _ => None,
+        }
+    }
 
-/// Failed to write to port user message
-const ERROR_FAILED_TO_WRITE_TO_PORT: &str = "Failed to Write to Serial Port";
+    ///
+    /// Returns true if the response was corrupted, or if the firmware reports the command it
+    /// received was corrupted. Such exchanges are worth resending.
+    ///
+    fn is_corrupted_exchange(&self) -> bool {
+        match self {
+            ControllerError::Local(..) => true,
+            ControllerError::Remote(pkt) => matches!(
+                pkt.error(),
+                Some(ProtocolError::Crc16Mismatch) | Some(ProtocolError::MissingCrc16)
+            ),
+            _ => false,
+        }
+    }
+}
 
-/// No Response received user message
-const ERROR_NO_RESPONSE: &str = "Knock Knock - No Response";
+impl fmt::Display for ControllerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControllerError::NoPorts(e) => write!(f, "No available ports: {}", e),
+            ControllerError::NoDevicesFound => write!(f, "No devices found"),
+            ControllerError::UnknownDevice(id) => write!(f, "Unknown device {}", id),
+            ControllerError::OpenFailed { port_name, source } => {
+                write!(f, "Failed to open port {}: {}", port_name, source)
+            }
+            ControllerError::WriteFailed(e) => write!(f, "Failed to write to serial port: {}", e),
+            ControllerError::ReadFailed(e) => write!(f, "Failed to read serial port bytes: {}", e),
+            ControllerError::Timeout => write!(f, "Timed out reading serial port"),
+            ControllerError::Remote(pkt) => write!(
+                f,
+                "Firmware reported error: {}",
+                pkt.error().unwrap_or(ProtocolError::CmdParsing)
+            ),
+            ControllerError::Local(e) => write!(f, "Failed to parse response: {}", e),
+            ControllerError::Unsupported { command, version } => {
+                write!(f, "{:?} is not supported by firmware {}", command, version)
+            }
+            ControllerError::UnknownEffect(effect_id) => {
+                write!(f, "Unknown effect id {}", effect_id)
+            }
+            ControllerError::UnknownFireColorPallet(pallet_id) => {
+                write!(f, "Unknown fire color pallet id {}", pallet_id)
+            }
+        }
+    }
+}
 
-/// Firmware reported error user message
-const ERROR_FAILED_PROTOCOL_PROCESSING_REMOTE: &str = "Firmware reported error";
+impl std::error::Error for ControllerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ControllerError::NoPorts(e) => Some(e),
+            ControllerError::OpenFailed { source, .. } => Some(source),
+            ControllerError::WriteFailed(e) => Some(e),
+            ControllerError::ReadFailed(e) => Some(e),
+            ControllerError::Local(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-/// Local response processing failed user message
-const ERROR_FAILED_PROTOCOL_PROCESSING_LOCAL: &str = "Failed to parse response";
+/// Transport held by the device manager
+pub type BoxedTransport = Box<dyn Transport + Send>;
 
-/// Failed to read serial port bytes user message
-const ERROR_FAILED_TO_READ_SERIAL_PORT_BYTES: &str = "Failed to read serial port bytes";
+/// Detects a device and returns it with an open transport.
+pub type Detector = Box<
+    dyn Fn() -> std::result::Result<DetectedDevice<BoxedTransport>, ControllerError> + Send + Sync,
+>;
 
-/// Timed out reading serial port user message
-const ERROR_TIMEDOUT_READING_SERIAL_PORT: &str = "Timed out reading serial port";
+///
+/// A LEDSC device found by auto detection. Holds the open transport and the protocol version
+/// matching the firmware version the device reported.
+///
+pub struct DetectedDevice<T> {
+    pub port_info: SerialPortInfo,
+    pub transport: T,
+    pub firmware_version: String,
+    pub protocol: Arc<dyn ProtocolVersion>,
+}
 
-/// Serial port error
-const ERROR_SERIAL_PORT_ERROR: &str = "Serial Port Error";
+impl<T: Transport + Send + 'static> DetectedDevice<T> {
+    ///
+    /// Boxes the transport so the device can be held by a DeviceManager.
+    ///
+    pub fn boxed(self) -> DetectedDevice<BoxedTransport> {
+        DetectedDevice {
+            port_info: self.port_info,
+            transport: Box::new(self.transport),
+            firmware_version: self.firmware_version,
+            protocol: self.protocol,
+        }
+    }
+}
 
 ///
-/// Probes available ports for a LEDSC based device. Returns the SerialPortInfo for the first
-/// device found.
+/// Kind of background task sending steps to a device
 ///
-pub fn auto_detect_ledsc() -> std::result::Result<SerialPortInfo, &'static str> {
-    let ports = available_ports();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    Transition,
+    Sequence,
+    Script,
+}
 
-    match ports {
-        Ok(port_vect) => {
-            for p in port_vect {
-                let result = auto_detect_ledsc_on_port(p);
+///
+/// Long lived connection to a LEDSC based device. The detected port is opened once and held open
+/// between commands. Access to the port is serialized behind a lock and the device is only
+/// re-detected when the held port fails.
+///
+pub struct DeviceManager {
+    detector: Detector,
+    connection: Mutex<Option<DeviceConnection>>,
+    command_retries: u32,
+    state_keeper: Option<Arc<dyn StateKeeper>>,
+    /// Device id and bus state changes and command failures are published on
+    events: Option<(String, Arc<EventBus>)>,
+    /// Id of the transition allowed to run, bumped to cancel the one in progress
+    transition: AtomicU64,
+    /// Id and kind of the last background task started, to stop only tasks of one kind
+    task: Mutex<Option<(u64, TaskKind)>>,
+    /// Settings sent since the last known state was replayed. Includes transition steps, which
+    /// the state keeper does not record.
+    sent_state: Mutex<DeviceState>,
+}
 
-                if result.is_ok() {
-                    return Ok(result.unwrap());
-                }
+///
+/// An open transport to a detected LEDSC device.
+///
+struct DeviceConnection {
+    device: DetectedDevice<BoxedTransport>,
+    decoder: PacketDecoder,
+}
+
+impl DeviceManager {
+    ///
+    /// Creates a device manager that detects devices on the available serial ports. No ports are
+    /// probed until the first command is sent.
+    ///
+    pub fn new() -> DeviceManager {
+        DeviceManager::with_detector(|| auto_detect_ledsc().map(DetectedDevice::boxed))
+    }
+
+    ///
+    /// Creates a device manager that only probes the named serial port. Used for ports that are
+    /// not enumerated as serial devices, such as the simulator's pseudo-terminal.
+    ///
+    pub fn with_port(port_name: &str) -> DeviceManager {
+        let port_info = SerialPortInfo {
+            port_name: String::from(port_name),
+            port_type: SerialPortType::Unknown,
+        };
+
+        DeviceManager::with_detector(move || {
+            auto_detect_ledsc_on_ports(vec![port_info.clone()], open_serial_transport)
+                .map(DetectedDevice::boxed)
+        })
+    }
+
+    ///
+    /// Creates a device manager that uses the given detector to find and open a device.
+    ///
+    pub fn with_detector<F>(detector: F) -> DeviceManager
+    where
+        F: Fn() -> std::result::Result<DetectedDevice<BoxedTransport>, ControllerError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        DeviceManager {
+            detector: Box::new(detector),
+            connection: Mutex::new(None),
+            command_retries: DEFAULT_COMMAND_RETRIES,
+            state_keeper: None,
+            events: None,
+            transition: AtomicU64::new(0),
+            task: Mutex::new(None),
+            sent_state: Mutex::new(DeviceState::default()),
+        }
+    }
+
+    ///
+    /// Creates a device manager holding an already detected device. The detector is used to find
+    /// the device again if its port fails.
+    ///
+    pub fn with_device<F>(device: DetectedDevice<BoxedTransport>, detector: F) -> DeviceManager
+    where
+        F: Fn() -> std::result::Result<DetectedDevice<BoxedTransport>, ControllerError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let device_manager = DeviceManager::with_detector(detector);
+        *device_manager.lock_connection() = Some(DeviceConnection {
+            device,
+            decoder: PacketDecoder::new(),
+        });
+        device_manager
+    }
+
+    ///
+    /// Sets how many times a command is resent after a corrupted exchange before failing.
+    ///
+    pub fn set_command_retries(&mut self, command_retries: u32) {
+        self.command_retries = command_retries;
+    }
+
+    ///
+    /// Sets the keeper recording the settings the device accepts. The recorded state is replayed
+    /// whenever a device is (re)detected and when a status read disagrees with it.
+    ///
+    pub fn set_state_keeper(&mut self, state_keeper: Arc<dyn StateKeeper>) {
+        self.state_keeper = Some(state_keeper);
+    }
+
+    ///
+    /// Sets the bus state changes and command failures are published on, naming the device by
+    /// the given id.
+    ///
+    pub fn set_event_bus(&mut self, device_id: &str, events: Arc<EventBus>) {
+        self.events = Some((String::from(device_id), events));
+    }
+
+    ///
+    /// Locks the device for a sequence of commands. Other callers wait until the session is
+    /// dropped, so the commands run back to back over the held connection.
+    ///
+    pub fn session(&self) -> DeviceSession<'_> {
+        DeviceSession {
+            device_manager: self,
+            connection: self.lock_connection(),
+        }
+    }
+
+    ///
+    /// Replays the last known state on the device. Does nothing without a state keeper.
+    ///
+    pub fn restore_state(&self) -> std::result::Result<(), ControllerError> {
+        self.session().restore_state()
+    }
+
+    ///
+    /// Cancels the transition in progress, if any, and returns the id of a new one. See
+    /// DeviceSession::is_transition_current.
+    ///
+    pub fn begin_transition(&self) -> u64 {
+        self.transition.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    ///
+    /// Cancels the transition in progress, if any, and returns the id of a new background task
+    /// of the given kind. The task runs while its id is the current transition.
+    ///
+    pub fn begin_task(&self, kind: TaskKind) -> u64 {
+        let mut task = recover(self.task.lock());
+        let id = self.begin_transition();
+        *task = Some((id, kind));
+        id
+    }
+
+    ///
+    /// Cancels the background task in progress if it is of the given kind. Returns false,
+    /// leaving any other task running, if it is not.
+    ///
+    pub fn stop_task(&self, kind: TaskKind) -> bool {
+        let mut task = recover(self.task.lock());
+        match *task {
+            Some((id, running)) if running == kind && self.is_transition_current(id) => {
+                self.begin_transition();
+                *task = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// Returns true if the given transition has not been cancelled. Use the session's check
+    /// before sending a step.
+    ///
+    pub fn is_transition_current(&self, transition: u64) -> bool {
+        self.transition.load(Ordering::SeqCst) == transition
+    }
+
+    ///
+    /// Returns the port info of the currently held device, if any.
+    ///
+    pub fn port_info(&self) -> Option<SerialPortInfo> {
+        let connection = self.lock_connection();
+        connection.as_ref().map(|c| c.device.port_info.clone())
+    }
+
+    ///
+    /// Returns the firmware version reported by the currently held device, if any.
+    ///
+    pub fn firmware_version(&self) -> Option<String> {
+        let connection = self.lock_connection();
+        connection
+            .as_ref()
+            .map(|c| c.device.firmware_version.clone())
+    }
+
+    ///
+    /// Returns the protocol version of the held device. If no device is held one is detected
+    /// first.
+    ///
+    pub fn protocol(&self) -> std::result::Result<Arc<dyn ProtocolVersion>, ControllerError> {
+        self.session().protocol()
+    }
+
+    ///
+    /// Sends a command to the held device in a session of its own. See
+    /// DeviceSession::send_command.
+    ///
+    pub fn send_command(
+        &self,
+        command: Command,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        self.session().send_command(command)
+    }
+
+    ///
+    /// Sends a command string to the held device in a session of its own. See
+    /// DeviceSession::send_command_wait_for_response.
+    ///
+    pub fn send_command_wait_for_response(
+        &self,
+        cmd: String,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        self.session().send_command_wait_for_response(cmd)
+    }
+
+    ///
+    /// Sends a command on the held connection. If the held port fails it is dropped and the
+    /// command is sent once more on a freshly detected device.
+    ///
+    fn send_with_redetect(
+        &self,
+        connection: &mut Option<DeviceConnection>,
+        cmd: &str,
+    ) -> std::result::Result<String, ControllerError> {
+        let reused = connection.is_some();
+
+        let mut result = self.send_on_connection(connection, cmd);
+
+        if let Err(e) = &result {
+            if reused {
+                eprintln!("Held serial port failed, re-detecting device: {}", e);
+                *connection = None;
+                result = self.send_on_connection(connection, cmd);
             }
         }
-        Err(e) => {
-            eprintln!("Failed to get available serial ports: {:?}", e);
-            return Err(ERROR_NO_AVAILABLE_PORTS);
+
+        if result.is_err() {
+            *connection = None;
         }
+
+        result
     }
 
-    return Err(ERROR_NO_DEVICES_FOUND);
-}
+    ///
+    /// Sends a command on the given connection, detecting and opening a device first if needed.
+    ///
+    fn send_on_connection(
+        &self,
+        connection: &mut Option<DeviceConnection>,
+        cmd: &str,
+    ) -> std::result::Result<String, ControllerError> {
+        let connection = self.connect(connection)?;
+
+        send_command_with_decoder(
+            &mut connection.device.transport,
+            &mut connection.decoder,
+            cmd,
+        )
+    }
 
-///
-/// Attempts to open the given port and probe for a LEDSC based device.
-///
-fn auto_detect_ledsc_on_port(
-    port_info: SerialPortInfo,
-) -> std::result::Result<SerialPortInfo, &'static str> {
-    match serialport::new(&port_info.port_name, LEDSC_BAUD).open() {
-        Ok(mut serial_port) => {
-            let protocol_instance = LedscTeensy001 {};
-
-            // Create print version command
-            let cmd: String = protocol_instance.create_cmd_string(Command::PrintVersion);
-
-            // Write printer version command
-            let write_result = serial_port.write_all(cmd.as_bytes());
-            if write_result.is_ok() {
-                let response_option = wait_for_response(&mut serial_port, RECEIVE_TIMEOUT_MS);
-
-                if response_option.is_ok() {
-                    match protocol_instance.parse_response_sting(response_option.unwrap()) {
-                        ResponsePacketOption::Success(..) => return Ok(port_info),
-
-                        ResponsePacketOption::FailedRemote(pkt) => {
-                            eprintln!("Failed Remote: {:?}", pkt);
-                            return Err(ERROR_FAILED_PROTOCOL_PROCESSING_REMOTE);
-                        }
-
-                        ResponsePacketOption::FailedLocal(pkt) => {
-                            eprintln!("Failed Local: {:?}", pkt);
-                            return Err(ERROR_FAILED_PROTOCOL_PROCESSING_LOCAL);
-                        }
-                    }
-                } else {
-                    eprintln!(
-                        "Failed waiting for auto detect response: {:?}",
-                        response_option
-                    );
-                    return Err(ERROR_NO_RESPONSE);
-                }
-            } else {
-                eprintln!("Failed writing to serial port: {:?}", write_result);
-                return Err(ERROR_FAILED_TO_WRITE_TO_PORT);
+    ///
+    /// Returns the held connection, detecting and opening a device first if none is held.
+    ///
+    fn connect<'a>(
+        &self,
+        connection: &'a mut Option<DeviceConnection>,
+    ) -> std::result::Result<&'a mut DeviceConnection, ControllerError> {
+        if connection.is_none() {
+            let device = (self.detector)()?;
+            println!(
+                "LEDSC device {} connected on {}",
+                device.firmware_version, device.port_info.port_name
+            );
+            let mut new_connection = DeviceConnection {
+                device,
+                decoder: PacketDecoder::new(),
+            };
+            if let Err(e) = self.replay_state(&mut new_connection) {
+                eprintln!("Failed to restore device state: {}", e);
+            }
+            *connection = Some(new_connection);
+        }
+
+        connection.as_mut().ok_or(ControllerError::NoDevicesFound)
+    }
+
+    ///
+    /// Sends the commands restoring the last known state on the given connection. Commands the
+    /// device's firmware does not support are skipped.
+    ///
+    fn replay_state(
+        &self,
+        connection: &mut DeviceConnection,
+    ) -> std::result::Result<(), ControllerError> {
+        let state_keeper = match &self.state_keeper {
+            Some(state_keeper) => state_keeper,
+            None => return Ok(()),
+        };
+
+        let protocol = connection.device.protocol.clone();
+        let state = state_keeper.last_state();
+        let commands = state.commands();
+        *self.lock_sent_state() = DeviceState::default();
+
+        for &command in &commands {
+            if !protocol.is_cmd_supported(&command) {
+                continue;
             }
+
+            let response = send_command_with_decoder(
+                &mut connection.device.transport,
+                &mut connection.decoder,
+                &protocol.create_cmd_string(command),
+            )?;
+            parse_response(protocol.as_ref(), &response)?;
+        }
+
+        if !commands.is_empty() {
+            self.publish(|device_id| DeviceEvent::StateChanged { device_id, state });
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Publishes the event built from the device id, if an event bus is set.
+    ///
+    fn publish<F>(&self, event: F)
+    where
+        F: FnOnce(String) -> DeviceEvent,
+    {
+        if let Some((device_id, events)) = &self.events {
+            events.publish(event(device_id.clone()));
+        }
+    }
+
+    ///
+    /// Locks the settings sent since the last replay. A poisoned lock is recovered since the
+    /// state is only replaced whole.
+    ///
+    fn lock_sent_state(&self) -> MutexGuard<'_, DeviceState> {
+        match self.sent_state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
         }
-        Err(e) => {
-            eprintln!("Auto detect failed to open serial port: {:?}", e);
-            return Err(ERROR_FAILED_TO_OPEN_PORT);
+    }
+
+    ///
+    /// Locks the connection. A poisoned lock is recovered since the connection is re-detected on
+    /// failure anyway.
+    ///
+    fn lock_connection(&self) -> MutexGuard<'_, Option<DeviceConnection>> {
+        match self.connection.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
         }
     }
 }
 
+impl Default for DeviceManager {
+    fn default() -> Self {
+        DeviceManager::new()
+    }
+}
+
 ///
-/// Reads incoming data from serial port. Waits for data up-to timeout.This function will always
-/// take at minimum timeout_ms to return. It waits cummulatively during periods of zero bytes.
-/// Ex: timeout_ms = 500ms.
-/// - waits 100ms for the first bytes to be awaiting read.
-/// - reads available bytes in read buffer
-/// - waits 50ms for more bytes to be available
-/// - reads available bytes in read buffer
-/// - waits 350ms, no new bytes received, exits.
+/// Exclusive use of a device manager's connection, taken with DeviceManager::session. The
+/// connection stays locked until the session is dropped.
 ///
-fn wait_for_response(
-    serial_port: &mut Box<dyn serialport::SerialPort>,
-    timeout_ms: u64,
-) -> std::result::Result<String, &'static str> {
-    let sleep_ms: u64 = 10;
-    let mut timeout_count_down = timeout_ms / sleep_ms;
-    let mut receive_buffer = [0; 10];
-    let mut received_bytes: Vec<u8> = vec![];
-
-    // Count down until timeout
-    while timeout_count_down > 0 {
-        // Wait for bytes to be available
-        loop {
-            let bytes_to_read = serial_port.bytes_to_read();
+pub struct DeviceSession<'a> {
+    device_manager: &'a DeviceManager,
+    connection: MutexGuard<'a, Option<DeviceConnection>>,
+}
 
-            if bytes_to_read.is_err() {
-                eprintln!("{:?}", bytes_to_read);
-                return Err(ERROR_SERIAL_PORT_ERROR);
-            }
+impl DeviceSession<'_> {
+    ///
+    /// Returns the protocol version of the held device. If no device is held one is detected
+    /// first.
+    ///
+    pub fn protocol(&mut self) -> std::result::Result<Arc<dyn ProtocolVersion>, ControllerError> {
+        let device = self.device_manager.connect(&mut self.connection)?;
+        Ok(device.device.protocol.clone())
+    }
 
-            if bytes_to_read.unwrap() > 0 || timeout_count_down <= 0 {
-                break;
-            }
+    ///
+    /// Replays the last known state on the device. Does nothing without a state keeper.
+    ///
+    pub fn restore_state(&mut self) -> std::result::Result<(), ControllerError> {
+        let held = self.connection.is_some();
 
-            thread::sleep(time::Duration::from_millis(sleep_ms));
-            timeout_count_down -= 1;
+        // A freshly detected device is restored by connect
+        let connection = self.device_manager.connect(&mut self.connection)?;
+        if held {
+            self.device_manager.replay_state(connection)?;
         }
 
-        // Read bytes available
-        loop {
-            let bytes_to_read = serial_port.bytes_to_read();
+        Ok(())
+    }
 
-            if bytes_to_read.is_err() {
-                eprintln!("{:?}", bytes_to_read);
-                return Err(ERROR_SERIAL_PORT_ERROR);
-            }
+    ///
+    /// Returns true if the given transition has not been cancelled by a newer transition or
+    /// command. Checked within the session, so no command can slip in before the next step.
+    ///
+    pub fn is_transition_current(&self, transition: u64) -> bool {
+        self.device_manager.is_transition_current(transition)
+    }
 
-            let bytes_to_read = bytes_to_read.unwrap();
+    ///
+    /// Closes the held port and returns its info, None if no device was held. The device is
+    /// detected again by the next command.
+    ///
+    pub fn disconnect(&mut self) -> Option<SerialPortInfo> {
+        self.connection
+            .take()
+            .map(|connection| connection.device.port_info)
+    }
 
-            if bytes_to_read <= 0 || timeout_count_down <= 0 {
-                break;
+    ///
+    /// Sends a command to the held device and returns the parsed response. Commands not
+    /// supported by the device's firmware version are rejected without being sent. Accepted
+    /// settings are published as a state change and failures as a command failure. Any command
+    /// but a query cancels the transition in progress.
+    ///
+    pub fn send_command(
+        &mut self,
+        command: Command,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        match command {
+            Command::None | Command::PrintVersion | Command::GetStatus => {}
+            _ => {
+                self.device_manager.begin_transition();
             }
+        }
+
+        let result = self.send_supported_command(command);
+
+        if let Err(e) = &result {
+            self.device_manager
+                .publish(|device_id| DeviceEvent::CommandFailed {
+                    device_id,
+                    command: format!("{:?}", command),
+                    error: e.to_string(),
+                });
+        }
+
+        result
+    }
+
+    ///
+    /// Sends a command the device's firmware supports without recording or publishing it, and
+    /// without cancelling the transition in progress. Used for the intermediate steps of a
+    /// transition.
+    ///
+    pub fn send_unrecorded_command(
+        &mut self,
+        command: Command,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        let protocol = self.protocol()?;
+
+        if !protocol.is_cmd_supported(&command) {
+            return Err(ControllerError::Unsupported {
+                command,
+                version: String::from(protocol.get_version_code()),
+            });
+        }
+
+        let response = self.send_command_wait_for_response(protocol.create_cmd_string(command))?;
+        self.device_manager.lock_sent_state().apply(&command);
+
+        Ok(response)
+    }
+
+    ///
+    /// Writes a command the device's firmware supports without waiting for a response, for
+    /// commands the device may not answer. The port is dropped rather than re-detected if the
+    /// write fails. Cancels the transition in progress.
+    ///
+    pub fn write_command(&mut self, command: Command) -> std::result::Result<(), ControllerError> {
+        let protocol = self.protocol()?;
+
+        if !protocol.is_cmd_supported(&command) {
+            return Err(ControllerError::Unsupported {
+                command,
+                version: String::from(protocol.get_version_code()),
+            });
+        }
+
+        self.device_manager.begin_transition();
+        let connection = self.device_manager.connect(&mut self.connection)?;
+        let result = connection
+            .device
+            .transport
+            .write_all(protocol.create_cmd_string(command).as_bytes())
+            .map_err(ControllerError::WriteFailed);
+
+        if result.is_err() {
+            *self.connection = None;
+        }
 
-            let read_bytes_result = serial_port.read(&mut receive_buffer[..]);
+        result
+    }
 
-            if read_bytes_result.is_err() {
-                eprintln!("{:?}", read_bytes_result);
-                return Err(ERROR_FAILED_TO_READ_SERIAL_PORT_BYTES);
+    ///
+    /// Sends a command the device's firmware supports, keeping the last known state.
+    ///
+    fn send_supported_command(
+        &mut self,
+        command: Command,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        let response = self.send_unrecorded_command(command)?;
+        let protocol = self.protocol()?;
+        let device_manager = self.device_manager;
+
+        if let Some(state_keeper) = &device_manager.state_keeper {
+            if command == Command::GetStatus {
+                // Transition steps are shown by the device without being recorded
+                let mut expected = state_keeper.last_state();
+                for sent in device_manager.lock_sent_state().commands() {
+                    expected.apply(&sent);
+                }
+                let agrees = protocol
+                    .parse_device_status(&response)
+                    .map_or(true, |status| expected.matches(&status));
+
+                if !agrees {
+                    println!("Device status differs from its last known state, restoring");
+                    self.restore_state()?;
+                    return self
+                        .send_command_wait_for_response(protocol.create_cmd_string(command));
+                }
             } else {
-                received_bytes.append(&mut receive_buffer.to_vec());
+                state_keeper.command_applied(&command);
+                let state = state_keeper.last_state();
+                device_manager.publish(|device_id| DeviceEvent::StateChanged { device_id, state });
+            }
+        } else {
+            // Without a keeper only the changed setting is known
+            let mut state = DeviceState::default();
+            if state.apply(&command) {
+                device_manager.publish(|device_id| DeviceEvent::StateChanged { device_id, state });
             }
         }
+
+        Ok(response)
     }
 
-    if received_bytes.is_empty() {
-        eprintln!("{:?}", received_bytes);
-        return Err(ERROR_TIMEDOUT_READING_SERIAL_PORT);
-    } else {
-        let received_string = String::from_utf8(received_bytes).unwrap();
-        return Ok(received_string);
+    ///
+    /// Sends a command to the held device and returns the parsed response. If no device is held
+    /// one is detected first. If the held port fails it is dropped and the command is retried
+    /// once on a freshly detected device. Commands whose response is corrupted, or which the
+    /// firmware received corrupted, are resent up to the configured number of retries.
+    ///
+    pub fn send_command_wait_for_response(
+        &mut self,
+        cmd: String,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        let device_manager = self.device_manager;
+        let mut attempt: u32 = 0;
+
+        loop {
+            let response = device_manager.send_with_redetect(&mut self.connection, &cmd)?;
+            let protocol = device_manager
+                .connect(&mut self.connection)?
+                .device
+                .protocol
+                .clone();
+
+            match parse_response(protocol.as_ref(), &response) {
+                Err(e) if e.is_corrupted_exchange() && attempt < device_manager.command_retries => {
+                    attempt += 1;
+                    eprintln!(
+                        "Corrupted exchange ({}), retry {} of {}: {}",
+                        e,
+                        attempt,
+                        device_manager.command_retries,
+                        response.trim()
+                    );
+                }
+                result => return result,
+            }
+        }
     }
 }
 
 ///
-/// Sends a command and waits for the response
+/// Parses a response with the given protocol version. Fails remote with the packet when the
+/// firmware reports an error status and fails local when the response does not parse.
 ///
-pub fn send_command_wait_for_response(
+fn parse_response(
+    protocol: &dyn ProtocolVersion,
+    response: &str,
+) -> std::result::Result<ResponsePacket, ControllerError> {
+    match protocol.parse_response_sting(response.to_string()) {
+        ResponsePacketOption::Success(pkt) => Ok(pkt),
+        ResponsePacketOption::FailedRemote(pkt) => Err(ControllerError::Remote(pkt)),
+        ResponsePacketOption::FailedLocal(error) => Err(ControllerError::Local(error)),
+    }
+}
+
+///
+/// Probes available serial ports for a LEDSC based device. Returns the SerialPortInfo and an
+/// open transport for the first device found.
+///
+pub fn auto_detect_ledsc() -> std::result::Result<DetectedDevice<SerialTransport>, ControllerError>
+{
+    let port_vect = available_ports().map_err(ControllerError::NoPorts)?;
+    auto_detect_ledsc_on_ports(port_vect, open_serial_transport)
+}
+
+///
+/// Opens a serial transport on the given port.
+///
+pub fn open_serial_transport(
     port_info: &SerialPortInfo,
-    cmd: String,
-) -> std::result::Result<String, &'static str> {
-    match serialport::new(&port_info.port_name, LEDSC_BAUD).open() {
-        Ok(mut serial_port) => {
-            let write_result = serial_port.write_all(cmd.as_bytes());
+) -> std::result::Result<SerialTransport, ControllerError> {
+    SerialTransport::open(&port_info.port_name).map_err(|e| ControllerError::OpenFailed {
+        port_name: port_info.port_name.clone(),
+        source: e,
+    })
+}
 
-            if write_result.is_ok() {
-                return wait_for_response(&mut serial_port, RECEIVE_TIMEOUT_MS);
+///
+/// Opens each of the given ports with the open function and probes it for a LEDSC based device.
+/// Returns the SerialPortInfo and the open transport for the first device found. If no port
+/// could be opened the last open failure is returned.
+///
+pub fn auto_detect_ledsc_on_ports<T, F>(
+    port_infos: Vec<SerialPortInfo>,
+    open: F,
+) -> std::result::Result<DetectedDevice<T>, ControllerError>
+where
+    T: Transport,
+    F: Fn(&SerialPortInfo) -> std::result::Result<T, ControllerError>,
+{
+    let mut open_error: Option<ControllerError> = None;
+    let mut probed = false;
+
+    for port_info in port_infos {
+        match open(&port_info) {
+            Ok(mut transport) => {
+                probed = true;
+                match auto_detect_ledsc_on_port(&mut transport) {
+                    Ok(firmware_version) => {
+                        return Ok(DetectedDevice {
+                            port_info,
+                            transport,
+                            protocol: Arc::from(get_protocol_version_impl_from_str(
+                                &firmware_version,
+                            )),
+                            firmware_version,
+                        })
+                    }
+                    Err(e) => eprintln!("No LEDSC on {}: {}", port_info.port_name, e),
+                }
             }
+            Err(e) => open_error = Some(e),
+        }
+    }
 
-            eprintln!(
-                "Senc command and wait failed to write to port: {:?}",
-                write_result
-            );
-            Err(ERROR_FAILED_TO_WRITE_TO_PORT)
+    match open_error {
+        Some(e) if !probed => Err(e),
+        _ => Err(ControllerError::NoDevicesFound),
+    }
+}
+
+///
+/// Probes the given transport for a LEDSC based device. Returns the firmware version string the
+/// device reported.
+///
+fn auto_detect_ledsc_on_port<T: Transport>(
+    transport: &mut T,
+) -> std::result::Result<String, ControllerError> {
+    // Every firmware version answers print version the same way
+    let protocol_instance = LedscTeensy001 {};
+
+    // Create print version command
+    let cmd: String = protocol_instance.create_cmd_string(Command::PrintVersion);
+
+    let response = send_command_wait_for_response(transport, cmd)?;
+    let pkt = parse_response(&protocol_instance, &response)?;
+
+    Ok(pkt
+        .parameters
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| String::from(FWV_LEDSC_UNKNOWN)))
+}
+
+///
+/// Reads incoming data from the transport into the decoder until one complete response frame is
+/// received or timeout_ms passes. Returns as soon as a frame completes. Bytes received after the
+/// frame stay in the decoder for the next response.
+///
+pub fn wait_for_response<T: Transport>(
+    transport: &mut T,
+    decoder: &mut PacketDecoder,
+    timeout_ms: u64,
+) -> std::result::Result<String, ControllerError> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let mut receive_buffer = [0; 64];
+
+    loop {
+        if let Some(packet) = decoder.next_packet() {
+            return Ok(packet);
         }
-        Err(e) => {
-            eprintln!("Send command and wait failed to open serial port: {:?}", e);
-            return Err(ERROR_FAILED_TO_OPEN_PORT);
+
+        if Instant::now() >= deadline {
+            return Err(ControllerError::Timeout);
         }
+
+        let count = transport
+            .read_with_deadline(&mut receive_buffer[..], deadline)
+            .map_err(ControllerError::ReadFailed)?;
+        decoder.push(&receive_buffer[..count]);
     }
 }
 
+///
+/// Sends a command and waits for the response
+///
+pub fn send_command_wait_for_response<T: Transport>(
+    transport: &mut T,
+    cmd: String,
+) -> std::result::Result<String, ControllerError> {
+    send_command_with_decoder(transport, &mut PacketDecoder::new(), &cmd)
+}
+
+///
+/// Sends a command and waits for the response using the given decoder. Complete frames already
+/// in the decoder were never claimed by a command and are discarded as stale before sending.
+///
+fn send_command_with_decoder<T: Transport>(
+    transport: &mut T,
+    decoder: &mut PacketDecoder,
+    cmd: &str,
+) -> std::result::Result<String, ControllerError> {
+    while let Some(stale) = decoder.next_packet() {
+        eprintln!("Discarding stale response: {}", stale);
+    }
+
+    transport
+        .write_all(cmd.as_bytes())
+        .map_err(ControllerError::WriteFailed)?;
+
+    wait_for_response(transport, decoder, RECEIVE_TIMEOUT_MS)
+}
+
 /// -----------------
 /// Unit Tests
 /// -----------------
@@ -243,24 +952,429 @@ mod test {
 
     use crate::led_strip_controller::color::*;
     use crate::led_strip_controller::controller;
+    use crate::led_strip_controller::controller::TaskKind;
     use crate::led_strip_controller::protocol::*;
     use std::{thread, time};
 
+    use crate::led_strip_controller::decoder::PacketDecoder;
+    use crate::led_strip_controller::events::{DeviceEvent, EventBus};
+    use crate::led_strip_controller::simulator::Simulator;
+    use crate::led_strip_controller::state::StateStore;
+    use crate::led_strip_controller::transport::MemoryTransport;
+    use crc16::{State, XMODEM};
+    use serialport::{SerialPortInfo, SerialPortType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
     /// Tests will only pass if hardware is connected and available
     const HW_AVAILABLE: bool = true;
 
+    ///
+    /// Frames a response body the way the firmware does
+    ///
+    fn frame_response(body: &str) -> Vec<u8> {
+        format!(
+            "{}{:X}\r\n",
+            body,
+            State::<XMODEM>::calculate(body.as_bytes())
+        )
+        .into_bytes()
+    }
+
+    fn port_info(port_name: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: String::from(port_name),
+            port_type: SerialPortType::Unknown,
+        }
+    }
+
+    ///
+    /// Wraps a transport as a detected LEDSC_TEENSY_001 device
+    ///
+    fn detected_device(
+        port_name: &str,
+        transport: MemoryTransport,
+    ) -> controller::DetectedDevice<controller::BoxedTransport> {
+        controller::DetectedDevice {
+            port_info: port_info(port_name),
+            transport: Box::new(transport),
+            firmware_version: String::from(FWV_LEDSC_TEENSY_001),
+            protocol: Arc::new(LedscTeensy001 {}),
+        }
+    }
+
+    #[test]
+    fn send_command_wait_for_response_memory_test() {
+        let mut transport = MemoryTransport::with_responder(|_written| frame_response("[CSE:0]"));
+
+        let protocol_instance = LedscTeensy001 {};
+        let cmd = protocol_instance.create_cmd_string(Command::SetEffect(Effect::SolidColor));
+
+        let response = controller::send_command_wait_for_response(&mut transport, cmd.clone())
+            .expect("In-memory device should answer");
+
+        assert_eq!(transport.written(), cmd.as_bytes());
+        match protocol_instance.parse_response_sting(response) {
+            ResponsePacketOption::Success(pkt) => assert_eq!(pkt.command, "CSE"),
+            _ => panic!("Response should parse as success"),
+        }
+    }
+
+    #[test]
+    fn wait_for_response_returns_on_frame_test() {
+        let mut transport = MemoryTransport::new();
+        let mut decoder = PacketDecoder::new();
+
+        let mut incoming = frame_response("[CSE:0]");
+        incoming.extend(frame_response("[CSB:0]"));
+        transport.push_incoming(&incoming);
+
+        let start = Instant::now();
+        let response = controller::wait_for_response(&mut transport, &mut decoder, 500).unwrap();
+        assert_eq!(response, "[CSE:0]A0D8");
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Second frame was kept for the next response
+        let response = controller::wait_for_response(&mut transport, &mut decoder, 500).unwrap();
+        assert_eq!(response, "[CSB:0]F1F5");
+
+        assert!(controller::wait_for_response(&mut transport, &mut decoder, 50).is_err());
+    }
+
+    #[test]
+    fn send_command_wait_for_response_no_answer_test() {
+        let mut transport = MemoryTransport::new();
+        let cmd = LedscTeensy001 {}.create_cmd_string(Command::GetStatus);
+
+        assert!(controller::send_command_wait_for_response(&mut transport, cmd).is_err());
+    }
+
+    #[test]
+    fn auto_detect_ledsc_on_ports_test() {
+        let ports = vec![port_info("/dev/silent"), port_info("/dev/ledsc")];
+
+        let result = controller::auto_detect_ledsc_on_ports(ports, |port_info| {
+            if port_info.port_name == "/dev/ledsc" {
+                Ok(MemoryTransport::with_responder(|_written| {
+                    frame_response("[CPV:0:LEDSC_TEENSY_001]")
+                }))
+            } else {
+                Ok(MemoryTransport::new())
+            }
+        });
+
+        match result {
+            Ok(device) => {
+                assert_eq!(device.port_info.port_name, "/dev/ledsc");
+                assert_eq!(device.firmware_version, "LEDSC_TEENSY_001");
+                assert_eq!(device.protocol.get_version_code(), FWV_LEDSC_TEENSY_001);
+            }
+            Err(e) => panic!("Failed to detect in-memory LEDSC {:?}", e),
+        }
+    }
+
+    #[test]
+    fn device_manager_simulator_test() {
+        let device_manager = controller::DeviceManager::with_detector(|| {
+            controller::auto_detect_ledsc_on_ports(vec![port_info("/dev/sim")], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(controller::DetectedDevice::boxed)
+        });
+
+        let protocol_instance = LedscTeensy001 {};
+        let cmd = protocol_instance.create_cmd_string(Command::SetEffect(Effect::Comet));
+        assert!(device_manager.send_command_wait_for_response(cmd).is_ok());
+
+        let cmd = protocol_instance.create_cmd_string(Command::GetStatus);
+        let pkt = device_manager.send_command_wait_for_response(cmd).unwrap();
+        assert_eq!(pkt.parameters[1], "0|3|FF|000000|0");
+    }
+
+    #[test]
+    fn device_manager_negotiates_protocol_test() {
+        let device_manager = controller::DeviceManager::with_detector(|| {
+            controller::auto_detect_ledsc_on_ports(vec![port_info("/dev/sim")], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(controller::DetectedDevice::boxed)
+        });
+
+        assert!(device_manager.firmware_version().is_none());
+
+        let protocol = device_manager.protocol().unwrap();
+        assert_eq!(protocol.get_version_code(), FWV_LEDSC_TEENSY_001);
+        assert_eq!(
+            device_manager.firmware_version().as_deref(),
+            Some("LEDSC_TEENSY_001")
+        );
+
+        assert!(device_manager
+            .send_command(Command::SetEffect(Effect::Twinkle))
+            .is_ok());
+
+        // Rejected locally, the simulator would have answered not implemented
+        match device_manager.send_command(Command::FullReset) {
+            Err(controller::ControllerError::Unsupported { version, .. }) => {
+                assert_eq!(version, FWV_LEDSC_TEENSY_001)
+            }
+            result => panic!("Full reset should be unsupported, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn device_manager_restores_state_test() {
+        let store = Arc::new(StateStore::new());
+        store
+            .record("sim", &Command::SetEffect(Effect::Fire))
+            .unwrap();
+        store.record("sim", &Command::SetBrightness(42)).unwrap();
+
+        let mut device_manager = controller::DeviceManager::with_detector(|| {
+            controller::auto_detect_ledsc_on_ports(vec![port_info("/dev/sim")], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(controller::DetectedDevice::boxed)
+        });
+        device_manager.set_state_keeper(store.keeper("sim"));
+
+        // A freshly detected device gets its last known state
+        let protocol = device_manager.protocol().unwrap();
+        let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+        let status = protocol.parse_device_status(&pkt).unwrap();
+        assert_eq!(status.effect, Effect::Fire);
+        assert_eq!(status.brightness, 42);
+
+        // Accepted settings are recorded
+        let color = Color24::from_u32(0x112233);
+        device_manager
+            .send_command(Command::SetColor(color))
+            .unwrap();
+        assert_eq!(store.state("sim").color, Some(color));
+
+        // A status disagreeing with the last known state is corrected
+        store
+            .record("sim", &Command::SetEffect(Effect::Comet))
+            .unwrap();
+        let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+        let status = protocol.parse_device_status(&pkt).unwrap();
+        assert_eq!(status.effect, Effect::Comet);
+        assert_eq!(status.color, color);
+    }
+
+    #[test]
+    fn device_manager_publishes_events_test() {
+        let store = Arc::new(StateStore::new());
+        store.record("sim", &Command::SetBrightness(42)).unwrap();
+        let bus = Arc::new(EventBus::new());
+        let events = bus.subscribe();
+
+        let mut device_manager = controller::DeviceManager::with_detector(|| {
+            controller::auto_detect_ledsc_on_ports(vec![port_info("/dev/sim")], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(controller::DetectedDevice::boxed)
+        });
+        device_manager.set_state_keeper(store.keeper("sim"));
+        device_manager.set_event_bus("sim", bus.clone());
+
+        // Restoring the last known state on connect is a state change
+        device_manager.protocol().unwrap();
+        let mut state = store.state("sim");
+        assert_eq!(
+            events.try_recv(),
+            Ok(DeviceEvent::StateChanged {
+                device_id: String::from("sim"),
+                state
+            })
+        );
+
+        // Accepted settings publish the whole known state, status reads publish nothing
+        let color = Color24::from_u32(0x112233);
+        device_manager
+            .send_command(Command::SetColor(color))
+            .unwrap();
+        device_manager.send_command(Command::GetStatus).unwrap();
+        state.color = Some(color);
+        assert_eq!(
+            events.try_recv(),
+            Ok(DeviceEvent::StateChanged {
+                device_id: String::from("sim"),
+                state
+            })
+        );
+        assert!(events.try_recv().is_err());
+
+        // Failed commands are published with their error
+        let mut missing_device = controller::DeviceManager::with_detector(|| {
+            Err(controller::ControllerError::NoDevicesFound)
+        });
+        missing_device.set_event_bus("missing", bus);
+        let e = missing_device
+            .send_command(Command::SetBrightness(10))
+            .unwrap_err();
+        assert_eq!(
+            events.try_recv(),
+            Ok(DeviceEvent::CommandFailed {
+                device_id: String::from("missing"),
+                command: String::from("SetBrightness(10)"),
+                error: e.to_string(),
+            })
+        );
+    }
+
+    ///
+    /// Device manager whose device answers with a corrupted CRC16 for the first bad_responses
+    /// writes. Returns the manager and the count of writes.
+    ///
+    fn corrupting_device_manager(
+        bad_responses: usize,
+    ) -> (controller::DeviceManager, Arc<AtomicUsize>) {
+        let write_count = Arc::new(AtomicUsize::new(0));
+        let responder_count = write_count.clone();
+
+        let device_manager = controller::DeviceManager::with_detector(move || {
+            let responder_count = responder_count.clone();
+            let transport = MemoryTransport::with_responder(move |_written| {
+                if responder_count.fetch_add(1, Ordering::SeqCst) < bad_responses {
+                    return b"[CSC:0]0000\r\n".to_vec();
+                }
+                frame_response("[CSC:0]")
+            });
+
+            Ok(detected_device("/dev/ledsc", transport))
+        });
+
+        (device_manager, write_count)
+    }
+
+    #[test]
+    fn device_manager_retries_corrupted_response_test() {
+        let cmd = LedscTeensy001 {}.create_cmd_string(Command::SetColor(Color24::from_u32(0xff)));
+
+        // Recovers within the default retries
+        let (device_manager, write_count) = corrupting_device_manager(2);
+        assert!(device_manager
+            .send_command_wait_for_response(cmd.clone())
+            .is_ok());
+        assert_eq!(write_count.load(Ordering::SeqCst), 3);
+
+        // Gives up once retries are exhausted
+        let (mut device_manager, write_count) = corrupting_device_manager(2);
+        device_manager.set_command_retries(1);
+        assert!(device_manager.send_command_wait_for_response(cmd).is_err());
+        assert_eq!(write_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn device_manager_redetects_failed_port_test() {
+        let detect_count = Arc::new(AtomicUsize::new(0));
+        let detector_count = detect_count.clone();
+
+        let device_manager = controller::DeviceManager::with_detector(move || {
+            detector_count.fetch_add(1, Ordering::SeqCst);
+
+            // Each detected device answers once then goes silent
+            let mut answered = false;
+            let transport = MemoryTransport::with_responder(move |_written| {
+                if answered {
+                    return vec![];
+                }
+                answered = true;
+                frame_response("[CSB:0]")
+            });
+
+            Ok(detected_device("/dev/ledsc", transport))
+        });
+
+        assert!(device_manager.port_info().is_none());
+
+        let protocol_instance = LedscTeensy001 {};
+        let cmd = protocol_instance.create_cmd_string(Command::SetBrightness(0x80));
+        assert!(device_manager
+            .send_command_wait_for_response(cmd.clone())
+            .is_ok());
+        assert_eq!(device_manager.port_info().unwrap().port_name, "/dev/ledsc");
+        assert_eq!(detect_count.load(Ordering::SeqCst), 1);
+
+        // Held port is silent, the manager re-detects and retries once
+        assert!(device_manager.send_command_wait_for_response(cmd).is_ok());
+        assert_eq!(detect_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn controller_error_test() {
+        let protocol_instance = LedscTeensy001 {};
+
+        // Silent device times out
+        let mut transport = MemoryTransport::new();
+        let cmd = protocol_instance.create_cmd_string(Command::GetStatus);
+        match controller::send_command_wait_for_response(&mut transport, cmd) {
+            Err(controller::ControllerError::Timeout) => {}
+            result => panic!("Silent device should time out, got {:?}", result),
+        }
+
+        // Firmware error keeps the parsed packet
+        let device_manager = controller::DeviceManager::with_detector(|| {
+            Ok(detected_device(
+                "/dev/sim",
+                Simulator::new().into_transport(),
+            ))
+        });
+        let cmd = protocol_instance.create_cmd_string(Command::SetEffect(Effect::MaxEffect));
+        match device_manager.send_command_wait_for_response(cmd) {
+            Err(e @ controller::ControllerError::Remote(..)) => {
+                assert_eq!(e.protocol_error(), Some(ProtocolError::ParamOutOfRange))
+            }
+            result => panic!("Max effect should fail remote, got {:?}", result),
+        }
+
+        // Missing port keeps the port name and the serial port error
+        let device_manager = controller::DeviceManager::with_port("/dev/led_oxide_missing");
+        let cmd = protocol_instance.create_cmd_string(Command::GetStatus);
+        match device_manager.send_command_wait_for_response(cmd) {
+            Err(controller::ControllerError::OpenFailed { port_name, .. }) => {
+                assert_eq!(port_name, "/dev/led_oxide_missing")
+            }
+            result => panic!("Missing port should fail to open, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn stop_task_test() {
+        let device_manager = controller::DeviceManager::with_detector(|| {
+            Err(controller::ControllerError::NoDevicesFound)
+        });
+
+        // Only a task of the kind asked for is stopped
+        let sequence = device_manager.begin_task(TaskKind::Sequence);
+        assert!(!device_manager.stop_task(TaskKind::Script));
+        assert!(device_manager.is_transition_current(sequence));
+        assert!(device_manager.stop_task(TaskKind::Sequence));
+        assert!(!device_manager.is_transition_current(sequence));
+        assert!(!device_manager.stop_task(TaskKind::Sequence));
+
+        // A task already cancelled by a newer transition is not stopped again
+        let script = device_manager.begin_task(TaskKind::Script);
+        let fade = device_manager.begin_task(TaskKind::Transition);
+        assert!(!device_manager.is_transition_current(script));
+        assert!(!device_manager.stop_task(TaskKind::Script));
+        assert!(device_manager.is_transition_current(fade));
+    }
+
     #[test]
     #[ignore]
     fn send_command_wait_for_response_test() {
         match controller::auto_detect_ledsc() {
-            Ok(port_info) => {
+            Ok(controller::DetectedDevice { mut transport, .. }) => {
                 assert!(HW_AVAILABLE, "Found LEDSC");
 
                 let protocol_instance = LedscTeensy001 {};
                 let cmd =
                     protocol_instance.create_cmd_string(Command::SetEffect(Effect::SolidColor));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Effect - Solid color"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set effect {:?}", rsp_pkt);
@@ -270,7 +1384,7 @@ mod test {
                 let cmd = protocol_instance
                     .create_cmd_string(Command::SetColor(Color24::from_u32(0xff0000)));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Color - Red"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set color {:?}", rsp_pkt);
@@ -282,7 +1396,7 @@ mod test {
                 let cmd = protocol_instance
                     .create_cmd_string(Command::SetColor(Color24::from_u32(0x00ff00)));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Color - Green"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set color {:?}", rsp_pkt);
@@ -294,7 +1408,7 @@ mod test {
                 let cmd = protocol_instance
                     .create_cmd_string(Command::SetColor(Color24::from_u32(0x0000ff)));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Color - Blue"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set color {:?}", rsp_pkt);
@@ -305,7 +1419,7 @@ mod test {
 
                 let cmd = protocol_instance.create_cmd_string(Command::SetBrightness(0xff));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Brightness - 100%"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set brightness {:?}", rsp_pkt);
@@ -316,7 +1430,7 @@ mod test {
 
                 let cmd = protocol_instance.create_cmd_string(Command::SetBrightness(0x88));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Brightness - 50%"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set brightness {:?}", rsp_pkt);
@@ -327,7 +1441,7 @@ mod test {
 
                 let cmd = protocol_instance.create_cmd_string(Command::SetBrightness(0x22));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Brightness - 13%"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set brightness {:?}", rsp_pkt);
@@ -339,7 +1453,7 @@ mod test {
                 let cmd =
                     protocol_instance.create_cmd_string(Command::SetEffect(Effect::CometRainbow));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Effect CometRainbow"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set effect CometRainbow {:?}", rsp_pkt);
@@ -351,7 +1465,7 @@ mod test {
                 let cmd =
                     protocol_instance.create_cmd_string(Command::SetEffect(Effect::RainbowCycle));
 
-                match controller::send_command_wait_for_response(&port_info, cmd) {
+                match controller::send_command_wait_for_response(&mut transport, cmd) {
                     Ok(_rsp_pkt) => assert!(HW_AVAILABLE, "Set Effect RainbowCycle"),
                     Err(rsp_pkt) => {
                         assert!(false, "Failed to set effect rainbowcycle {:?}", rsp_pkt);
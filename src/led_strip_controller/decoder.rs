@@ -0,0 +1,150 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::protocol::*;
+
+///
+/// Streaming decoder splitting received bytes into response frames. A frame runs from STX up to
+/// the carriage return following ETX and the CRC16. Bytes may arrive in any number of partial
+/// reads. Bytes after a complete frame are kept for the next one.
+///
+#[derive(Default)]
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+}
+
+impl PacketDecoder {
+    ///
+    /// Creates an empty decoder.
+    ///
+    pub fn new() -> PacketDecoder {
+        PacketDecoder::default()
+    }
+
+    ///
+    /// Appends received bytes to the decoder.
+    ///
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    ///
+    /// Returns the next complete frame without its line ending, if one has been received. Noise
+    /// before STX is discarded. A frame interrupted by a new STX is dropped in favour of the new
+    /// frame.
+    ///
+    pub fn next_packet(&mut self) -> Option<String> {
+        loop {
+            // Resync on STX
+            match self.buffer.iter().position(|b| *b == PROTO_STX as u8) {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                }
+                None => {
+                    self.buffer.clear();
+                    return None;
+                }
+            }
+
+            let end = self.buffer[1..]
+                .iter()
+                .position(|b| *b == PROTO_CR as u8 || *b == PROTO_STX as u8)
+                .map(|index| index + 1);
+
+            match end {
+                Some(end) if self.buffer[end] == PROTO_STX as u8 => {
+                    // Frame was cut short, start over at the new STX
+                    self.buffer.drain(..end);
+                }
+                Some(end) => {
+                    let packet = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+
+                    // Drop the frame and its line ending
+                    let mut consumed = end + 1;
+                    if self.buffer.get(consumed) == Some(&(PROTO_NL as u8)) {
+                        consumed += 1;
+                    }
+                    self.buffer.drain(..consumed);
+
+                    return Some(packet);
+                }
+                None => {
+                    if self.buffer.len() > MAX_PROTO_PACKET_LEN as usize {
+                        // Oversized frame will never complete
+                        self.buffer.clear();
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    ///
+    /// Returns the number of buffered bytes not yet returned as a frame.
+    ///
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::decoder::PacketDecoder;
+
+    #[test]
+    fn packet_decoder_partial_reads_test() {
+        let mut decoder = PacketDecoder::new();
+
+        decoder.push(b"[CS");
+        assert_eq!(decoder.next_packet(), None);
+        decoder.push(b"E:0]A0");
+        assert_eq!(decoder.next_packet(), None);
+        decoder.push(b"D8\r");
+        assert_eq!(decoder.next_packet(), Some(String::from("[CSE:0]A0D8")));
+
+        // Line feed arriving late is discarded as noise
+        decoder.push(b"\n");
+        assert_eq!(decoder.next_packet(), None);
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn packet_decoder_leftover_bytes_test() {
+        let mut decoder = PacketDecoder::new();
+
+        decoder.push(b"[CSE:0]A0D8\r\n[CSB:0]F1F5\r\n[CG");
+        assert_eq!(decoder.next_packet(), Some(String::from("[CSE:0]A0D8")));
+        assert_eq!(decoder.next_packet(), Some(String::from("[CSB:0]F1F5")));
+        assert_eq!(decoder.next_packet(), None);
+        assert_eq!(decoder.pending(), 3);
+    }
+
+    #[test]
+    fn packet_decoder_resync_test() {
+        let mut decoder = PacketDecoder::new();
+
+        // Noise before STX and a frame cut short by a new STX
+        decoder.push(b"\x00garbage[CSE:0[CSB:0]F1F5\r\n");
+        assert_eq!(decoder.next_packet(), Some(String::from("[CSB:0]F1F5")));
+        assert_eq!(decoder.next_packet(), None);
+    }
+}
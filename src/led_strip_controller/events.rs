@@ -0,0 +1,143 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::state::DeviceState;
+use serde::Serialize;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+
+///
+/// Event about a LEDSC device, published to every subscriber.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    /// A device was found and probed
+    Connected {
+        device_id: String,
+        port_name: String,
+        firmware_version: String,
+    },
+    /// A device's port went away
+    Disconnected {
+        device_id: String,
+        port_name: Option<String>,
+    },
+    /// Settings of a device changed, through a command or by restoring its last known state.
+    /// Without a state store only the changed setting is known.
+    StateChanged {
+        device_id: String,
+        state: DeviceState,
+    },
+    /// A command sent to a device failed
+    CommandFailed {
+        device_id: String,
+        command: String,
+        error: String,
+    },
+}
+
+impl DeviceEvent {
+    ///
+    /// Returns the event name, matching the serialized event tag.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEvent::Connected { .. } => "connected",
+            DeviceEvent::Disconnected { .. } => "disconnected",
+            DeviceEvent::StateChanged { .. } => "state_changed",
+            DeviceEvent::CommandFailed { .. } => "command_failed",
+        }
+    }
+}
+
+///
+/// Fans device events out to subscribers. Subscribers that hang up are dropped on the next
+/// publish.
+///
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<DeviceEvent>>>,
+}
+
+impl EventBus {
+    ///
+    /// Creates a bus without subscribers.
+    ///
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    ///
+    /// Returns a receiver for every event published from now on.
+    ///
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = channel();
+        self.lock_subscribers().push(sender);
+        receiver
+    }
+
+    ///
+    /// Sends the event to every subscriber.
+    ///
+    pub fn publish(&self, event: DeviceEvent) {
+        self.lock_subscribers()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    ///
+    /// Locks the subscribers. A poisoned lock is recovered since senders are only added and
+    /// removed whole.
+    ///
+    fn lock_subscribers(&self) -> MutexGuard<'_, Vec<Sender<DeviceEvent>>> {
+        match self.subscribers.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::events::{DeviceEvent, EventBus};
+
+    #[test]
+    fn event_bus_test() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+
+        let event = DeviceEvent::Disconnected {
+            device_id: String::from("porch"),
+            port_name: None,
+        };
+        bus.publish(event.clone());
+        assert_eq!(first.recv().unwrap(), event);
+        assert_eq!(second.recv().unwrap(), event);
+
+        // Hung up subscribers are dropped
+        drop(first);
+        bus.publish(event.clone());
+        assert_eq!(second.try_recv().unwrap(), event);
+        assert!(second.try_recv().is_err());
+    }
+}
@@ -0,0 +1,804 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::controller::{ControllerError, DeviceManager};
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::registry::DeviceRegistry;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command as Process, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, thread};
+
+/// Largest firmware image accepted, in bytes of Intel HEX
+pub const MAX_FIRMWARE_SIZE: u64 = 524288;
+
+/// Loader run when none is configured, built from the bundled submodule
+pub const DEFAULT_LOADER_PATH: &str = "lib/teensy_loader_cli/teensy_loader_cli";
+
+/// MCU passed to the loader when none is configured. LedStripController runs on a Teensy 3.2.
+pub const DEFAULT_LOADER_MCU: &str = "TEENSY32";
+
+/// Longest time the loader may take to find the bootloader and program the device
+const LOADER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time the device has to come back with its new firmware when none is configured
+pub const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between checks for the device after flashing, and for the loader to exit
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Baud rate that makes Teensyduino's USB serial reboot into the bootloader
+const TEENSY_REBOOT_BAUD: u32 = 134;
+
+/// Most lines of loader output kept in a flash's status
+const MAX_OUTPUT_LINES: usize = 200;
+
+///
+/// A firmware image checked to be well formed Intel HEX, the format the Teensy loader takes.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareImage {
+    hex: String,
+    data_size: usize,
+}
+
+impl FirmwareImage {
+    ///
+    /// Parses an uploaded image. Every record must have a valid checksum and the image must hold
+    /// data and end with an end of file record.
+    ///
+    pub fn parse(bytes: &[u8]) -> std::result::Result<FirmwareImage, FirmwareError> {
+        let hex = std::str::from_utf8(bytes).map_err(|_| FirmwareError::InvalidRecord {
+            line: 0,
+            reason: "image is not text",
+        })?;
+
+        let mut data_size = 0;
+        let mut ended = false;
+
+        for (index, line) in hex.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason| FirmwareError::InvalidRecord {
+                line: line_number,
+                reason,
+            };
+            if ended {
+                return Err(invalid("record after the end of file record"));
+            }
+
+            let digits = line
+                .strip_prefix(':')
+                .ok_or_else(|| invalid("record does not start with ':'"))?;
+            if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid("record is not hex byte pairs"));
+            }
+            let record: Vec<u8> = (0..digits.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or(0))
+                .collect();
+
+            // Byte count, 2 address bytes, record type, data and checksum
+            if record.len() < 5 || record.len() != usize::from(record[0]) + 5 {
+                return Err(invalid("record length does not match its byte count"));
+            }
+            if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(invalid("record checksum mismatch"));
+            }
+
+            match record[3] {
+                0x00 => data_size += usize::from(record[0]),
+                0x01 => ended = true,
+                0x02..=0x05 => {}
+                _ => return Err(invalid("unknown record type")),
+            }
+        }
+
+        if !ended {
+            return Err(FirmwareError::MissingEnd);
+        }
+        if data_size == 0 {
+            return Err(FirmwareError::NoData);
+        }
+
+        Ok(FirmwareImage {
+            hex: String::from(hex),
+            data_size,
+        })
+    }
+
+    ///
+    /// Returns the number of data bytes the image programs.
+    ///
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+}
+
+///
+/// Errors starting a firmware flash.
+///
+#[derive(Debug)]
+pub enum FirmwareError {
+    /// The record on the line, counting from 1, is not valid Intel HEX
+    InvalidRecord { line: usize, reason: &'static str },
+    /// The image has no end of file record
+    MissingEnd,
+    /// The image has no data records
+    NoData,
+    /// A flash is already in progress, on the device with the id
+    Busy(String),
+    /// The device to flash could not be found
+    Device(ControllerError),
+    /// Writing the image for the loader failed
+    Io(io::Error),
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FirmwareError::InvalidRecord { line, reason } => {
+                write!(f, "Invalid Intel HEX on line {}: {}", line, reason)
+            }
+            FirmwareError::MissingEnd => write!(f, "Intel HEX has no end of file record"),
+            FirmwareError::NoData => write!(f, "Intel HEX has no data records"),
+            FirmwareError::Busy(id) => write!(f, "Device {} is already being flashed", id),
+            FirmwareError::Device(e) => write!(f, "{}", e),
+            FirmwareError::Io(e) => write!(f, "Failed to write firmware image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FirmwareError::Device(e) => Some(e),
+            FirmwareError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Step a firmware flash is at.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashStage {
+    EnteringBootloader,
+    Flashing,
+    WaitingForDevice,
+    Done,
+    Failed,
+}
+
+impl FlashStage {
+    ///
+    /// Returns true once the flash has succeeded or failed.
+    ///
+    pub fn is_finished(&self) -> bool {
+        matches!(self, FlashStage::Done | FlashStage::Failed)
+    }
+}
+
+///
+/// Progress of a firmware flash, kept after it finishes until the device is flashed again.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlashStatus {
+    pub device_id: String,
+    pub stage: FlashStage,
+    pub data_size: usize,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Version the device reported after flashing
+    pub firmware_version: Option<String>,
+    pub error: Option<String>,
+    /// Output of the loader, latest lines last
+    pub output: Vec<String>,
+}
+
+/// Puts a device in its bootloader
+type BootloaderEntry =
+    Box<dyn Fn(&DeviceManager) -> std::result::Result<(), ControllerError> + Send + Sync>;
+
+///
+/// Puts the device in its bootloader and closes its port. Firmware supporting EnterBootloader
+/// is sent the command, otherwise the port is reopened at 134 baud, which Teensyduino's USB
+/// serial takes as a request to reboot into the bootloader.
+///
+pub fn enter_bootloader(
+    device_manager: &DeviceManager,
+) -> std::result::Result<(), ControllerError> {
+    let mut session = device_manager.session();
+    let protocol = session.protocol()?;
+
+    if protocol.is_cmd_supported(&Command::EnterBootloader) {
+        // The device reboots without answering, and would not be found again by re-detection
+        session.write_command(Command::EnterBootloader)?;
+        session.disconnect();
+        return Ok(());
+    }
+
+    let port_info = session
+        .disconnect()
+        .ok_or(ControllerError::NoDevicesFound)?;
+    serialport::new(&port_info.port_name, TEENSY_REBOOT_BAUD)
+        .open()
+        .map(drop)
+        .map_err(|e| ControllerError::OpenFailed {
+            port_name: port_info.port_name.clone(),
+            source: e,
+        })
+}
+
+///
+/// Flashes firmware images onto registered devices with teensy_loader_cli, one device at a time
+/// since the loader programs whichever Teensy is in its bootloader. Each flash runs in a
+/// background thread and its progress is kept per device.
+///
+pub struct FirmwareFlasher {
+    loader_path: PathBuf,
+    mcu: String,
+    reconnect_timeout: Duration,
+    bootloader_entry: BootloaderEntry,
+    flashes: Arc<Mutex<HashMap<String, FlashStatus>>>,
+}
+
+impl FirmwareFlasher {
+    ///
+    /// Creates a flasher running the loader at the given path for the given MCU.
+    ///
+    pub fn new<P: Into<PathBuf>>(loader_path: P, mcu: &str) -> FirmwareFlasher {
+        FirmwareFlasher {
+            loader_path: loader_path.into(),
+            mcu: String::from(mcu),
+            reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+            bootloader_entry: Box::new(enter_bootloader),
+            flashes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    ///
+    /// Sets how long the device has to come back with its new firmware.
+    ///
+    pub fn set_reconnect_timeout(&mut self, reconnect_timeout: Duration) {
+        self.reconnect_timeout = reconnect_timeout;
+    }
+
+    ///
+    /// Replaces how devices are put in their bootloader.
+    ///
+    pub fn set_bootloader_entry<F>(&mut self, bootloader_entry: F)
+    where
+        F: Fn(&DeviceManager) -> std::result::Result<(), ControllerError> + Send + Sync + 'static,
+    {
+        self.bootloader_entry = Box::new(bootloader_entry);
+    }
+
+    ///
+    /// Returns the progress of the device's latest flash, None if it was not flashed.
+    ///
+    pub fn status(&self, device_id: &str) -> Option<FlashStatus> {
+        self.lock_flashes().get(device_id).cloned()
+    }
+
+    ///
+    /// Starts flashing the image onto the device. Returns the initial status, or an error if the
+    /// device is unknown or a flash is already in progress.
+    ///
+    pub fn start(
+        self: &Arc<Self>,
+        registry: &Arc<DeviceRegistry>,
+        device_id: &str,
+        image: FirmwareImage,
+    ) -> std::result::Result<FlashStatus, FirmwareError> {
+        let device_manager = registry.device(device_id).ok_or_else(|| {
+            FirmwareError::Device(ControllerError::UnknownDevice(String::from(device_id)))
+        })?;
+
+        let status = {
+            let mut flashes = self.lock_flashes();
+            if let Some(busy) = flashes.values().find(|status| !status.stage.is_finished()) {
+                return Err(FirmwareError::Busy(busy.device_id.clone()));
+            }
+
+            let status = FlashStatus {
+                device_id: String::from(device_id),
+                stage: FlashStage::EnteringBootloader,
+                data_size: image.data_size,
+                started: Utc::now(),
+                finished: None,
+                firmware_version: None,
+                error: None,
+                output: vec![],
+            };
+            flashes.insert(String::from(device_id), status.clone());
+            status
+        };
+
+        let hex_path = std::env::temp_dir().join(format!(
+            "fw_teensy_{}.hex",
+            status.started.format("%Y%m%d%H%M%S%f")
+        ));
+        if let Err(e) = fs::write(&hex_path, &image.hex) {
+            self.finish(
+                device_id,
+                Err(format!("Failed to write {}", hex_path.display())),
+            );
+            return Err(FirmwareError::Io(e));
+        }
+
+        let flasher = self.clone();
+        let registry = registry.clone();
+        let device_id = String::from(device_id);
+        let spawned = thread::Builder::new()
+            .name(String::from("firmware flash"))
+            .spawn(move || {
+                let result = flasher.flash(&registry, &device_manager, &device_id, &hex_path);
+                if let Err(e) = fs::remove_file(&hex_path) {
+                    eprintln!("Failed to remove {} - {}", hex_path.display(), e);
+                }
+                flasher.finish(&device_id, result);
+            });
+
+        if let Err(e) = spawned {
+            self.finish(&status.device_id, Err(e.to_string()));
+            return Err(FirmwareError::Io(e));
+        }
+
+        Ok(status)
+    }
+
+    ///
+    /// Runs every step of a flash. Returns the firmware version the device reported afterwards.
+    ///
+    fn flash(
+        &self,
+        registry: &DeviceRegistry,
+        device_manager: &DeviceManager,
+        device_id: &str,
+        hex_path: &Path,
+    ) -> std::result::Result<String, String> {
+        println!("Flashing device {}: entering bootloader", device_id);
+        // Transitions, sequences and scripts would reopen the port
+        device_manager.begin_transition();
+        (self.bootloader_entry)(device_manager)
+            .map_err(|e| format!("Failed to enter bootloader - {}", e))?;
+
+        self.set_stage(device_id, FlashStage::Flashing);
+        println!(
+            "Flashing device {}: running {}",
+            device_id,
+            self.loader_path.display()
+        );
+        self.run_loader(device_id, hex_path)?;
+
+        self.set_stage(device_id, FlashStage::WaitingForDevice);
+        println!("Flashing device {}: waiting for device", device_id);
+        self.wait_for_device(registry, device_id)
+    }
+
+    ///
+    /// Runs the loader on the image, collecting its output, and fails if it does not exit
+    /// successfully in time.
+    ///
+    fn run_loader(&self, device_id: &str, hex_path: &Path) -> std::result::Result<(), String> {
+        let mut child = Process::new(&self.loader_path)
+            .arg(format!("--mcu={}", self.mcu))
+            .arg("-w")
+            .arg("-v")
+            .arg(hex_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run {} - {}", self.loader_path.display(), e))?;
+
+        let readers: Vec<thread::JoinHandle<()>> = vec![
+            child
+                .stdout
+                .take()
+                .map(|out| Box::new(out) as Box<dyn Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|err| Box::new(err) as Box<dyn Read + Send>),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|output| self.collect_output(device_id, output))
+        .collect();
+
+        let exit_status = wait_with_timeout(&mut child, LOADER_TIMEOUT);
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        match exit_status {
+            Ok(Some(exit_status)) if exit_status.success() => Ok(()),
+            Ok(Some(exit_status)) => Err(format!("Loader failed with {}", exit_status)),
+            Ok(None) => Err(format!(
+                "Loader did not finish within {} s",
+                LOADER_TIMEOUT.as_secs()
+            )),
+            Err(e) => Err(format!("Failed to wait for loader - {}", e)),
+        }
+    }
+
+    ///
+    /// Appends each line of the loader's output to the device's status from a thread of its
+    /// own, so the loader never blocks on a full pipe.
+    ///
+    fn collect_output(
+        &self,
+        device_id: &str,
+        output: Box<dyn Read + Send>,
+    ) -> thread::JoinHandle<()> {
+        let flashes = self.flashes.clone();
+        let device_id = String::from(device_id);
+
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                println!("Flashing device {}: {}", device_id, line);
+
+                if let Some(status) = lock(&flashes).get_mut(&device_id) {
+                    if status.output.len() == MAX_OUTPUT_LINES {
+                        status.output.remove(0);
+                    }
+                    status.output.push(line);
+                }
+            }
+        })
+    }
+
+    ///
+    /// Waits for the device to be found again after flashing and returns the firmware version
+    /// it reports.
+    ///
+    fn wait_for_device(
+        &self,
+        registry: &DeviceRegistry,
+        device_id: &str,
+    ) -> std::result::Result<String, String> {
+        let deadline = Instant::now() + self.reconnect_timeout;
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+
+            // Picks the device up on its new port, without waiting for the hotplug watcher
+            if let Err(e) = registry.sync() {
+                eprintln!("Failed to scan ports - {}", e);
+            }
+            let response = registry
+                .device(device_id)
+                .map(|device_manager| device_manager.send_command(Command::PrintVersion));
+            if let Some(Ok(pkt)) = response {
+                // The version follows the command echo, as in auto detection
+                return Ok(pkt
+                    .parameters
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| String::from(FWV_LEDSC_UNKNOWN)));
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "Device did not come back within {} s",
+                    self.reconnect_timeout.as_secs()
+                ));
+            }
+        }
+    }
+
+    ///
+    /// Moves the device's flash on to the given stage.
+    ///
+    fn set_stage(&self, device_id: &str, stage: FlashStage) {
+        if let Some(status) = self.lock_flashes().get_mut(device_id) {
+            status.stage = stage;
+        }
+    }
+
+    ///
+    /// Records the outcome of the device's flash.
+    ///
+    fn finish(&self, device_id: &str, result: std::result::Result<String, String>) {
+        let mut flashes = self.lock_flashes();
+        let status = match flashes.get_mut(device_id) {
+            Some(status) => status,
+            None => return,
+        };
+
+        status.finished = Some(Utc::now());
+        match result {
+            Ok(firmware_version) => {
+                println!(
+                    "Flashed device {}, now running {}",
+                    device_id, firmware_version
+                );
+                status.stage = FlashStage::Done;
+                status.firmware_version = Some(firmware_version);
+            }
+            Err(e) => {
+                eprintln!("Flashing device {} failed - {}", device_id, e);
+                status.stage = FlashStage::Failed;
+                status.error = Some(e);
+            }
+        }
+    }
+
+    ///
+    /// Locks the flash statuses.
+    ///
+    fn lock_flashes(&self) -> MutexGuard<'_, HashMap<String, FlashStatus>> {
+        lock(&self.flashes)
+    }
+}
+
+///
+/// Locks the flash statuses. A poisoned lock is recovered since statuses are only updated field
+/// by field.
+///
+fn lock(
+    flashes: &Mutex<HashMap<String, FlashStatus>>,
+) -> MutexGuard<'_, HashMap<String, FlashStatus>> {
+    match flashes.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+///
+/// Waits for the child to exit, killing it once the timeout passes. Returns None if it was
+/// killed.
+///
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Duration,
+) -> std::result::Result<Option<ExitStatus>, io::Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(exit_status) = child.try_wait()? {
+            return Ok(Some(exit_status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::controller::{BoxedTransport, DetectedDevice};
+    use crate::led_strip_controller::firmware::*;
+    use crate::led_strip_controller::simulator::Simulator;
+    use crate::led_strip_controller::transport::MemoryTransport;
+    use serialport::{SerialPortInfo, SerialPortType};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Two data records, an extended address record and the end of file record
+    const HEX: &str = ":10000000000800200D04000039040000390400003D\n\
+                       :0400100039040000AF\n\
+                       :020000040000FA\n\
+                       :00000001FF\n";
+
+    fn simulated_registry() -> Arc<DeviceRegistry> {
+        Arc::new(DeviceRegistry::with_ports(
+            || {
+                Ok(vec![SerialPortInfo {
+                    port_name: String::from("/dev/sim"),
+                    port_type: SerialPortType::Unknown,
+                }])
+            },
+            |_port_info| Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport),
+        ))
+    }
+
+    ///
+    /// Writes a stand-in loader script that prints its arguments and exits with the code.
+    ///
+    fn fake_loader(dir: &Path, exit_code: i32) -> PathBuf {
+        let path = dir.join(format!("loader_{}.sh", exit_code));
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho \"args $*\"\necho oops >&2\nexit {}\n",
+                exit_code
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn wait_until_finished(flasher: &FirmwareFlasher, device_id: &str) -> FlashStatus {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let status = flasher.status(device_id).unwrap();
+            if status.stage.is_finished() || Instant::now() > deadline {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn firmware_image_parse_test() {
+        let image = FirmwareImage::parse(HEX.as_bytes()).unwrap();
+        assert_eq!(image.data_size(), 20);
+        assert!(FirmwareImage::parse(HEX.replace('\n', "\r\n").as_bytes()).is_ok());
+
+        let invalid = |hex: &str| FirmwareImage::parse(hex.as_bytes()).unwrap_err();
+        assert!(matches!(
+            invalid(&HEX.replace("3D\n", "3E\n")),
+            FirmwareError::InvalidRecord { line: 1, .. }
+        ));
+        assert!(matches!(
+            invalid(&HEX.replace(":0400", "0400")),
+            FirmwareError::InvalidRecord { line: 2, .. }
+        ));
+        assert!(matches!(
+            invalid(":04001000390400\n:00000001FF\n"),
+            FirmwareError::InvalidRecord { line: 1, .. }
+        ));
+        assert!(matches!(
+            invalid(&format!("{}:0400100039040000AF\n", HEX)),
+            FirmwareError::InvalidRecord { line: 5, .. }
+        ));
+        assert!(matches!(
+            invalid(":0400100039040000AF\n"),
+            FirmwareError::MissingEnd
+        ));
+        assert!(matches!(invalid(":00000001FF\n"), FirmwareError::NoData));
+        assert!(matches!(
+            FirmwareImage::parse(&[0xff, 0xfe]),
+            Err(FirmwareError::InvalidRecord { line: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn flash_test() {
+        let dir = std::env::temp_dir().join(format!("led_oxide_flash_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let registry = simulated_registry();
+        let (device_id, _) = registry.devices().remove(0);
+        let entries = Arc::new(AtomicUsize::new(0));
+
+        let mut flasher = FirmwareFlasher::new(fake_loader(&dir, 0), "TEENSY32");
+        let counter = entries.clone();
+        flasher.set_bootloader_entry(move |_device_manager| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let flasher = Arc::new(flasher);
+        assert!(flasher.status(&device_id).is_none());
+
+        let image = FirmwareImage::parse(HEX.as_bytes()).unwrap();
+        let status = flasher.start(&registry, &device_id, image.clone()).unwrap();
+        assert_eq!(status.stage, FlashStage::EnteringBootloader);
+        assert!(matches!(
+            flasher.start(&registry, &device_id, image.clone()),
+            Err(FirmwareError::Busy(..))
+        ));
+
+        let status = wait_until_finished(&flasher, &device_id);
+        assert_eq!(status.stage, FlashStage::Done, "{:?}", status.error);
+        assert_eq!(status.firmware_version.as_deref(), Some("LEDSC_TEENSY_001"));
+        assert_eq!(entries.load(Ordering::SeqCst), 1);
+        assert!(status.output[0].starts_with("args --mcu=TEENSY32 -w -v "));
+        assert!(status.output.contains(&String::from("oops")));
+
+        assert!(matches!(
+            flasher.start(&registry, "nope", image),
+            Err(FirmwareError::Device(ControllerError::UnknownDevice(..)))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flash_failure_test() {
+        let dir = std::env::temp_dir().join(format!("led_oxide_flash_fail_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let registry = simulated_registry();
+        let (device_id, _) = registry.devices().remove(0);
+        let image = FirmwareImage::parse(HEX.as_bytes()).unwrap();
+
+        // The loader fails
+        let mut flasher = FirmwareFlasher::new(fake_loader(&dir, 1), "TEENSY32");
+        flasher.set_bootloader_entry(|_device_manager| Ok(()));
+        let flasher = Arc::new(flasher);
+        flasher.start(&registry, &device_id, image.clone()).unwrap();
+        let status = wait_until_finished(&flasher, &device_id);
+        assert_eq!(status.stage, FlashStage::Failed);
+        assert!(status.error.unwrap().starts_with("Loader failed"));
+        assert!(status.finished.is_some());
+
+        // The loader is missing, and a failed flash does not block the next one
+        let mut flasher = FirmwareFlasher::new(dir.join("missing"), "TEENSY32");
+        flasher.set_bootloader_entry(|_device_manager| Ok(()));
+        let flasher = Arc::new(flasher);
+        flasher.start(&registry, &device_id, image.clone()).unwrap();
+        let status = wait_until_finished(&flasher, &device_id);
+        assert!(status.error.unwrap().starts_with("Failed to run"));
+
+        // The bootloader cannot be entered
+        let mut flasher = FirmwareFlasher::new(fake_loader(&dir, 0), "TEENSY32");
+        flasher.set_bootloader_entry(|_device_manager| Err(ControllerError::NoDevicesFound));
+        let flasher = Arc::new(flasher);
+        flasher.start(&registry, &device_id, image).unwrap();
+        let status = wait_until_finished(&flasher, &device_id);
+        assert_eq!(status.stage, FlashStage::Failed);
+        assert!(status
+            .error
+            .unwrap()
+            .starts_with("Failed to enter bootloader"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn enter_bootloader_test() {
+        let simulator = Arc::new(Mutex::new(Simulator::new()));
+        let responder = simulator.clone();
+        let device = DetectedDevice {
+            port_info: SerialPortInfo {
+                port_name: String::from("/dev/sim"),
+                port_type: SerialPortType::Unknown,
+            },
+            transport: MemoryTransport::with_responder(move |bytes| {
+                responder.lock().unwrap().receive(bytes)
+            }),
+            firmware_version: String::from(FWV_LEDSC_TEENSY_001),
+            protocol: Arc::new(LedscTeensy001 {}),
+        };
+        // The device is not found again while it is in its bootloader
+        let device_manager =
+            DeviceManager::with_device(device.boxed(), || Err(ControllerError::NoDevicesFound));
+
+        enter_bootloader(&device_manager).unwrap();
+        assert!(simulator.lock().unwrap().in_bootloader());
+        assert!(device_manager.port_info().is_none());
+    }
+}
@@ -0,0 +1,440 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::color::Color24;
+use crate::led_strip_controller::controller::*;
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::registry::DeviceRegistry;
+use crate::led_strip_controller::store::{recover, write_atomically};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::{fmt, fs, io, panic, thread};
+
+///
+/// Command that can be sent to a whole group. Effect and pallet ids are resolved against each
+/// member's own firmware version, so members running different firmware get the right command.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupCommand {
+    SetBrightness(u8),
+    SetEffect(u8),
+    SetColor(Color24),
+    SetFireColorPallet(u8),
+}
+
+impl GroupCommand {
+    ///
+    /// Returns the command for a device speaking the given protocol version. Fails if the
+    /// version has no effect or pallet with the id.
+    ///
+    pub fn command(
+        &self,
+        protocol: &dyn ProtocolVersion,
+    ) -> std::result::Result<Command, ControllerError> {
+        match *self {
+            GroupCommand::SetBrightness(brightness) => Ok(Command::SetBrightness(brightness)),
+            GroupCommand::SetEffect(effect_id) => protocol
+                .find_effect(effect_id)
+                .map(Command::SetEffect)
+                .ok_or(ControllerError::UnknownEffect(effect_id)),
+            GroupCommand::SetColor(color) => Ok(Command::SetColor(color)),
+            GroupCommand::SetFireColorPallet(pallet_id) => protocol
+                .find_fire_color_pallet(pallet_id)
+                .map(Command::SetFireColorPallet)
+                .ok_or(ControllerError::UnknownFireColorPallet(pallet_id)),
+        }
+    }
+
+    ///
+    /// Sends the command to a single device.
+    ///
+    pub fn send(
+        &self,
+        device_manager: &DeviceManager,
+    ) -> std::result::Result<ResponsePacket, ControllerError> {
+        let protocol = device_manager.protocol()?;
+        device_manager.send_command(self.command(protocol.as_ref())?)
+    }
+}
+
+///
+/// Outcome of a group command for one member.
+///
+#[derive(Debug)]
+pub struct MemberResult<T = ResponsePacket> {
+    pub device_id: String,
+    pub result: std::result::Result<T, ControllerError>,
+}
+
+///
+/// Sends the command to every member in parallel and waits for all of them. Results are returned
+/// in member order. Members that are not registered fail with UnknownDevice.
+///
+pub fn send_to_group(
+    registry: &DeviceRegistry,
+    members: &[String],
+    command: GroupCommand,
+) -> Vec<MemberResult> {
+    for_each_member(registry, members, |device_manager| {
+        command.send(device_manager)
+    })
+}
+
+///
+/// Runs the action on every member in parallel and waits for all of them. Results are returned
+/// in member order. Members that are not registered fail with UnknownDevice.
+///
+pub fn for_each_member<T, F>(
+    registry: &DeviceRegistry,
+    members: &[String],
+    action: F,
+) -> Vec<MemberResult<T>>
+where
+    T: Send,
+    F: Fn(&Arc<DeviceManager>) -> std::result::Result<T, ControllerError> + Sync,
+{
+    let action = &action;
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = members
+            .iter()
+            .map(|device_id| {
+                scope.spawn(move || {
+                    registry
+                        .device(device_id)
+                        .ok_or_else(|| ControllerError::UnknownDevice(device_id.clone()))
+                        .and_then(|device_manager| action(&device_manager))
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .zip(members)
+            .map(|(handle, device_id)| MemberResult {
+                device_id: device_id.clone(),
+                result: handle
+                    .join()
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload)),
+            })
+            .collect()
+    })
+}
+
+///
+/// Errors managing groups.
+///
+#[derive(Debug)]
+pub enum GroupError {
+    /// Group names may only use letters, digits, '-' and '_'
+    InvalidName(String),
+    /// A group needs at least one member
+    NoMembers,
+    /// Reading or writing the groups file failed
+    Io(io::Error),
+    /// The groups file is not valid JSON
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupError::InvalidName(name) => write!(f, "Invalid group name '{}'", name),
+            GroupError::NoMembers => write!(f, "Group has no members"),
+            GroupError::Io(e) => write!(f, "Failed to access groups file: {}", e),
+            GroupError::Format(e) => write!(f, "Malformed groups file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GroupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GroupError::Io(e) => Some(e),
+            GroupError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Named groups of device ids. Groups are saved to a JSON file, if one is given, every time they
+/// change.
+///
+pub struct GroupStore {
+    path: Option<PathBuf>,
+    groups: RwLock<BTreeMap<String, Vec<String>>>,
+}
+
+impl GroupStore {
+    ///
+    /// Creates a store that only keeps groups in memory.
+    ///
+    pub fn new() -> GroupStore {
+        GroupStore {
+            path: None,
+            groups: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    ///
+    /// Creates a store saved to the given file, loading the groups already in it. A missing file
+    /// starts out with no groups.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> std::result::Result<GroupStore, GroupError> {
+        let path = path.as_ref().to_path_buf();
+
+        let groups = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(GroupError::Format)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(GroupError::Io(e)),
+        };
+
+        Ok(GroupStore {
+            path: Some(path),
+            groups: RwLock::new(groups),
+        })
+    }
+
+    ///
+    /// Returns every group with its members, ordered by name.
+    ///
+    pub fn groups(&self) -> Vec<(String, Vec<String>)> {
+        recover(self.groups.read())
+            .iter()
+            .map(|(name, members)| (name.clone(), members.clone()))
+            .collect()
+    }
+
+    ///
+    /// Returns the members of the named group.
+    ///
+    pub fn members(&self, name: &str) -> Option<Vec<String>> {
+        recover(self.groups.read()).get(name).cloned()
+    }
+
+    ///
+    /// Creates or replaces a group. Duplicate members are dropped.
+    ///
+    pub fn set_group(
+        &self,
+        name: &str,
+        members: Vec<String>,
+    ) -> std::result::Result<(), GroupError> {
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(GroupError::InvalidName(String::from(name)));
+        }
+
+        let mut unique_members: Vec<String> = Vec::new();
+        for member in members {
+            if !unique_members.contains(&member) {
+                unique_members.push(member);
+            }
+        }
+        if unique_members.is_empty() {
+            return Err(GroupError::NoMembers);
+        }
+
+        let mut groups = recover(self.groups.write());
+        let mut updated = groups.clone();
+        updated.insert(String::from(name), unique_members);
+        self.save(&updated)?;
+        *groups = updated;
+
+        Ok(())
+    }
+
+    ///
+    /// Removes a group. Returns false if there was no such group.
+    ///
+    pub fn remove_group(&self, name: &str) -> std::result::Result<bool, GroupError> {
+        let mut groups = recover(self.groups.write());
+        if !groups.contains_key(name) {
+            return Ok(false);
+        }
+
+        let mut updated = groups.clone();
+        updated.remove(name);
+        self.save(&updated)?;
+        *groups = updated;
+
+        Ok(true)
+    }
+
+    ///
+    /// Writes the groups to the store's file.
+    ///
+    fn save(&self, groups: &BTreeMap<String, Vec<String>>) -> std::result::Result<(), GroupError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = serde_json::to_string_pretty(groups).map_err(GroupError::Format)?;
+
+        write_atomically(path, contents).map_err(GroupError::Io)
+    }
+}
+
+impl Default for GroupStore {
+    fn default() -> Self {
+        GroupStore::new()
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::controller::{BoxedTransport, ControllerError};
+    use crate::led_strip_controller::group::*;
+    use crate::led_strip_controller::registry::DeviceRegistry;
+    use crate::led_strip_controller::simulator::Simulator;
+    use serialport::{SerialPortInfo, SerialPortType};
+    use std::fs;
+
+    fn simulated_registry(port_names: &'static [&'static str]) -> DeviceRegistry {
+        DeviceRegistry::with_ports(
+            move || {
+                Ok(port_names
+                    .iter()
+                    .map(|port_name| SerialPortInfo {
+                        port_name: String::from(*port_name),
+                        port_type: SerialPortType::Unknown,
+                    })
+                    .collect())
+            },
+            |_port_info| Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport),
+        )
+    }
+
+    #[test]
+    fn send_to_group_test() {
+        let registry = simulated_registry(&["/dev/ttyACM0", "/dev/ttyACM1"]);
+        let members = vec![
+            String::from("ttyACM0"),
+            String::from("missing"),
+            String::from("ttyACM1"),
+        ];
+
+        let results = send_to_group(&registry, &members, GroupCommand::SetEffect(1));
+
+        let ids: Vec<&str> = results.iter().map(|r| r.device_id.as_str()).collect();
+        assert_eq!(ids, vec!["ttyACM0", "missing", "ttyACM1"]);
+        assert!(results[0].result.is_ok());
+        assert!(matches!(
+            results[1].result,
+            Err(ControllerError::UnknownDevice(ref id)) if id == "missing"
+        ));
+        assert!(results[2].result.is_ok());
+
+        for device_id in &["ttyACM0", "ttyACM1"] {
+            let device_manager = registry.device(device_id).unwrap();
+            let protocol = device_manager.protocol().unwrap();
+            let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+            assert_eq!(
+                protocol.parse_device_status(&pkt).unwrap().effect,
+                protocol.get_effect_from_cmd_value(&1)
+            );
+        }
+
+        let results = send_to_group(
+            &registry,
+            &members[..1],
+            GroupCommand::SetColor(Color24::from_u32(0x00ff00)),
+        );
+        assert!(results[0].result.is_ok());
+
+        // Ids the members' firmware does not know fail on each member without changing it
+        let results = send_to_group(&registry, &members[..1], GroupCommand::SetEffect(200));
+        assert!(matches!(
+            results[0].result,
+            Err(ControllerError::UnknownEffect(200))
+        ));
+        let results = send_to_group(&registry, &members[..1], GroupCommand::SetEffect(0x0a));
+        assert!(matches!(
+            results[0].result,
+            Err(ControllerError::UnknownEffect(0x0a))
+        ));
+        let results = send_to_group(
+            &registry,
+            &members[..1],
+            GroupCommand::SetFireColorPallet(200),
+        );
+        assert!(matches!(
+            results[0].result,
+            Err(ControllerError::UnknownFireColorPallet(200))
+        ));
+
+        let device_manager = registry.device("ttyACM0").unwrap();
+        let protocol = device_manager.protocol().unwrap();
+        let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+        assert_eq!(
+            protocol.parse_device_status(&pkt).unwrap().effect,
+            protocol.get_effect_from_cmd_value(&1)
+        );
+    }
+
+    #[test]
+    fn group_store_test() {
+        let path =
+            std::env::temp_dir().join(format!("led_oxide_groups_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = GroupStore::open(&path).unwrap();
+        assert!(store.groups().is_empty());
+
+        store
+            .set_group(
+                "kitchen",
+                vec![String::from("a"), String::from("b"), String::from("a")],
+            )
+            .unwrap();
+        store.set_group("porch", vec![String::from("c")]).unwrap();
+        assert!(matches!(
+            store.set_group("bad name", vec![String::from("a")]),
+            Err(GroupError::InvalidName(..))
+        ));
+        assert!(matches!(
+            store.set_group("empty", vec![]),
+            Err(GroupError::NoMembers)
+        ));
+
+        // Groups survive reopening the store
+        let reopened = GroupStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.members("kitchen"),
+            Some(vec![String::from("a"), String::from("b")])
+        );
+        assert_eq!(reopened.groups().len(), 2);
+
+        assert!(reopened.remove_group("porch").unwrap());
+        assert!(!reopened.remove_group("porch").unwrap());
+        assert_eq!(GroupStore::open(&path).unwrap().members("porch"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
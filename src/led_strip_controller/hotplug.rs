@@ -0,0 +1,173 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::registry::DeviceRegistry;
+use std::io;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Default time between port list checks
+pub const DEFAULT_HOTPLUG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time given to a device node to settle after a udev event before probing it
+#[cfg(target_os = "linux")]
+const UDEV_SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+///
+/// Starts a background thread keeping the registry in line with the attached devices. On Linux
+/// udev tty events wake the watcher as soon as a device arrives or leaves. The port list is also
+/// checked every poll_interval, which is the only trigger where udev is unavailable. The thread
+/// ends once the registry is dropped.
+///
+pub fn spawn_hotplug_watcher(
+    registry: &Arc<DeviceRegistry>,
+    poll_interval: Duration,
+) -> io::Result<thread::JoinHandle<()>> {
+    let registry = Arc::downgrade(registry);
+
+    thread::Builder::new()
+        .name(String::from("hotplug"))
+        .spawn(move || watch(&registry, poll_interval))
+}
+
+///
+/// Watches for port changes until the registry is dropped.
+///
+fn watch(registry: &Weak<DeviceRegistry>, poll_interval: Duration) {
+    #[cfg(target_os = "linux")]
+    match watch_udev(registry, poll_interval) {
+        Ok(()) => return,
+        Err(e) => eprintln!("udev monitor unavailable, polling serial ports: {}", e),
+    }
+
+    while sync(registry) {
+        thread::sleep(poll_interval);
+    }
+}
+
+///
+/// Syncs the registry once. Returns false if the registry is gone.
+///
+fn sync(registry: &Weak<DeviceRegistry>) -> bool {
+    match registry.upgrade() {
+        Some(registry) => {
+            if let Err(e) = registry.sync() {
+                eprintln!("Failed to check serial ports: {}", e);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+///
+/// Syncs the registry on every udev tty event, or after poll_interval without one.
+///
+#[cfg(target_os = "linux")]
+fn watch_udev(registry: &Weak<DeviceRegistry>, poll_interval: Duration) -> libudev::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let context = libudev::Context::new()?;
+    let mut monitor = libudev::Monitor::new(&context)?;
+    monitor.match_subsystem("tty")?;
+    let mut socket = monitor.listen()?;
+
+    while sync(registry) {
+        if wait_readable(socket.as_raw_fd(), poll_interval) {
+            // A device usually raises several events, handle them in one sync
+            thread::sleep(UDEV_SETTLE_DELAY);
+            while socket.receive_event().is_some() {}
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Waits until the file descriptor is readable. Returns false on timeout or error.
+///
+#[cfg(target_os = "linux")]
+fn wait_readable(fd: std::os::unix::io::RawFd, timeout: Duration) -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+    // Safety: poll_fd is a single valid pollfd for the duration of the call
+    unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) > 0 }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::controller::BoxedTransport;
+    use crate::led_strip_controller::events::DeviceEvent;
+    use crate::led_strip_controller::hotplug::spawn_hotplug_watcher;
+    use crate::led_strip_controller::registry::DeviceRegistry;
+    use crate::led_strip_controller::simulator::Simulator;
+    use serialport::{SerialPortInfo, SerialPortType};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn hotplug_watcher_test() {
+        let ports: Arc<Mutex<Vec<SerialPortInfo>>> = Arc::new(Mutex::new(vec![]));
+        let listed_ports = ports.clone();
+
+        let registry = Arc::new(DeviceRegistry::with_ports(
+            move || Ok(listed_ports.lock().unwrap().clone()),
+            |_port_info| Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport),
+        ));
+        let events = registry.events().subscribe();
+
+        let watcher = spawn_hotplug_watcher(&registry, Duration::from_millis(10)).unwrap();
+
+        ports.lock().unwrap().push(SerialPortInfo {
+            port_name: String::from("/dev/ttyACM0"),
+            port_type: SerialPortType::Unknown,
+        });
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            DeviceEvent::Connected {
+                device_id: String::from("ttyACM0"),
+                port_name: String::from("/dev/ttyACM0"),
+                firmware_version: String::from("LEDSC_TEENSY_001"),
+            }
+        );
+
+        ports.lock().unwrap().clear();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            DeviceEvent::Disconnected {
+                device_id: String::from("ttyACM0"),
+                port_name: Some(String::from("/dev/ttyACM0")),
+            }
+        );
+        assert!(registry.device("ttyACM0").is_none());
+
+        // The watcher stops with the registry
+        drop(registry);
+        watcher.join().unwrap();
+    }
+}
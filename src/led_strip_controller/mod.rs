@@ -19,5 +19,22 @@
 
 #[allow(dead_code)]
 pub mod protocol;
+pub mod batch;
 pub mod color;
 pub mod controller;
+pub mod decoder;
+pub mod events;
+pub mod firmware;
+pub mod group;
+pub mod hotplug;
+pub mod registry;
+pub mod scene;
+pub mod schedule;
+pub mod script;
+pub mod sequence;
+pub mod simulator;
+pub mod state;
+pub(crate) mod store;
+pub mod sun;
+pub mod transition;
+pub mod transport;
@@ -19,7 +19,8 @@
 
 use crate::led_strip_controller::color;
 use crc16::*;
-use std::str::Chars;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /**
 * Input Packet structure
@@ -43,150 +44,275 @@ use std::str::Chars;
 // --------------------------------------
 
 /// Start Transmission character
-const PROTO_STX: char = '[';
+pub(crate) const PROTO_STX: char = '[';
 
 /// End Transmission character
-const PROTO_ETX: char = ']';
+pub(crate) const PROTO_ETX: char = ']';
 
 /// Parameter separator character
-const PROTO_PSC: char = ':';
+pub(crate) const PROTO_PSC: char = ':';
+
+/// Get status field separator character
+pub(crate) const PROTO_STATUS_SEPARATOR: char = '|';
 
 /// Carriage Return character
-const PROTO_CR: char = '\r';
+pub(crate) const PROTO_CR: char = '\r';
 
 /// New line character
-const PROTO_NL: char = '\n';
+pub(crate) const PROTO_NL: char = '\n';
 
 // --------------------------------------
 // - Limits
 // --------------------------------------
 
 /// Max length of a packet
-const MAX_PROTO_PACKET_LEN: i16 = 256;
+pub(crate) const MAX_PROTO_PACKET_LEN: i16 = 256;
 
 /// Max length of a command
-const MAX_PROTO_CMD: i16 = 10;
+pub(crate) const MAX_PROTO_CMD: i16 = 10;
 
 /// Max number of parameters for any one command packet
-const MAX_PROTO_PARAM_COUNT: i16 = 4;
+pub(crate) const MAX_PROTO_PARAM_COUNT: i16 = 4;
 
 /// Max number of characters for any one parameter assuming there is only one param
-const MAX_PROTO_PARAM_LEN: i16 = 50;
+pub(crate) const MAX_PROTO_PARAM_LEN: i16 = 50;
 
 // --------------------------------------
 // - Known Protocol Versions
 // --------------------------------------
 
 /// Unknown Firmware
-const FWV_LEDSC_UNKNOWN: &str = "UNKNOWN";
+pub(crate) const FWV_LEDSC_UNKNOWN: &str = "UNKNOWN";
 
 /// LEDSC_Teensy_ prefix
-const FWV_LEDSC_TEENSY: &str = "LEDSC_TEENSY_";
+pub(crate) const FWV_LEDSC_TEENSY: &str = "LEDSC_TEENSY_";
 
 /// LEDSC_Teensy_001
-const FWV_LEDSC_TEENSY_001: &str = "LEDSC_TEENSY_001";
+pub(crate) const FWV_LEDSC_TEENSY_001: &str = "LEDSC_TEENSY_001";
 
 // --------------------------------------
 // - Commands
 // --------------------------------------
 
 /// Command print version
-const CMD_PRINT_VERSION: &str = "CPV";
+pub(crate) const CMD_PRINT_VERSION: &str = "CPV";
 
 /// Command full firmware reset
-const CMD_FULL_RESET: &str = "CFR";
+pub(crate) const CMD_FULL_RESET: &str = "CFR";
 
 /// Command enter bootloader
-const CMD_ENTER_BOOTLOADER: &str = "CEB";
+pub(crate) const CMD_ENTER_BOOTLOADER: &str = "CEB";
 
 /// Command set debugging
-const CMD_SET_DEBUGGING: &str = "CSD";
+pub(crate) const CMD_SET_DEBUGGING: &str = "CSD";
 
 /// Command set LED effect
-const CMD_SET_EFFECT: &str = "CSE";
+pub(crate) const CMD_SET_EFFECT: &str = "CSE";
 
 /// Command set color
-const CMD_SET_COLOR: &str = "CSC";
+pub(crate) const CMD_SET_COLOR: &str = "CSC";
 
 /// Command set brightness
-const CMD_SET_BRIGHTNESS: &str = "CSB";
+pub(crate) const CMD_SET_BRIGHTNESS: &str = "CSB";
 
 /// Command set fire pallet
-const CMD_SET_FIRE_PALLET: &str = "CSFP";
+pub(crate) const CMD_SET_FIRE_PALLET: &str = "CSFP";
 
 /// Command get status
-const CMD_GET_STATUS: &str = "CGS";
+pub(crate) const CMD_GET_STATUS: &str = "CGS";
 
 // --------------------------------------
 // - Error Codes
 // --------------------------------------
 
 /// Code for success no error.
-const ERR_PROTO_SUCCESS: i16 = 0;
+pub(crate) const ERR_PROTO_SUCCESS: i16 = 0;
 
 /// Generic command processing error
-const ERR_PROTO_CMD_PARSING: i16 = -100;
+pub(crate) const ERR_PROTO_CMD_PARSING: i16 = -100;
 
 /// Missing expected STX character
-const ERR_PROTO_CP_MISSING_STX: i16 = -101;
+pub(crate) const ERR_PROTO_CP_MISSING_STX: i16 = -101;
 
 /// Missing expected ETX character
-const ERR_PROTO_CP_MISSING_ETX: i16 = -102;
+pub(crate) const ERR_PROTO_CP_MISSING_ETX: i16 = -102;
 
 /// Missing expected PSC character
-const ERR_PROTO_CP_MISSING_PSC: i16 = -103;
+pub(crate) const ERR_PROTO_CP_MISSING_PSC: i16 = -103;
 
 /// Missing expected framing character
-const ERR_PROTO_CP_MISSING_EFC: i16 = -104;
+pub(crate) const ERR_PROTO_CP_MISSING_EFC: i16 = -104;
 
 /// Command buffer overflow
-const ERR_PROTO_CP_CMD_OVERFLOW: i16 = -105;
+pub(crate) const ERR_PROTO_CP_CMD_OVERFLOW: i16 = -105;
 
 /// Command not implemented
-const ERR_PROTO_CP_CMD_NOT_IMP: i16 = -106;
+pub(crate) const ERR_PROTO_CP_CMD_NOT_IMP: i16 = -106;
 
 /// Unknown command
-const ERR_PROTO_CP_CMD_UNKNOWN: i16 = -107;
+pub(crate) const ERR_PROTO_CP_CMD_UNKNOWN: i16 = -107;
 
 /// Missing parameters
-const ERR_PROTO_CP_MISSING_PARAMS: i16 = -108;
+pub(crate) const ERR_PROTO_CP_MISSING_PARAMS: i16 = -108;
 
 /// Parameter out of range
-const ERR_PROTO_CP_PARAM_OUT_RANGE: i16 = -109;
+pub(crate) const ERR_PROTO_CP_PARAM_OUT_RANGE: i16 = -109;
 
 /// CRC16 mismatch
-const ERR_PROTO_CP_CRC16_MISMATCH: i16 = -110;
+pub(crate) const ERR_PROTO_CP_CRC16_MISMATCH: i16 = -110;
 
 /// CRC16 missing
-const ERR_PROTO_CP_MISSING_CRC16: i16 = -111;
+pub(crate) const ERR_PROTO_CP_MISSING_CRC16: i16 = -111;
 
 /// Response packet error
-const ERR_PROTO_RSP_BUILDING: i16 = -200;
+pub(crate) const ERR_PROTO_RSP_BUILDING: i16 = -200;
 
 /// Too many params attempted in response packet
-const ERR_PROTO_RB_TOO_MANY_PARAMS: i16 = -201;
+pub(crate) const ERR_PROTO_RB_TOO_MANY_PARAMS: i16 = -201;
 
 /// Param buffer overflow
-const ERR_PROTO_RB_PARAM_OVERFLOW: i16 = -202;
+pub(crate) const ERR_PROTO_RB_PARAM_OVERFLOW: i16 = -202;
 
 /// ADC Error
-const ERR_ADC: i16 = -300;
+pub(crate) const ERR_ADC: i16 = -300;
 
 /// Failed to read ADC
-const ERR_ADC_READFAIL: i16 = -301;
+pub(crate) const ERR_ADC_READFAIL: i16 = -301;
 
 /// ADC Register Depth error. Occurs when attemtping to R/W ADC register with incorrect size value.
-const ERR_ADC_REGISTER_DEPTH: i16 = -302;
+pub(crate) const ERR_ADC_REGISTER_DEPTH: i16 = -302;
 
 /// Set Movetohall config
-const ERR_SMC: i16 = -400;
+pub(crate) const ERR_SMC: i16 = -400;
 
 /// Polynomial index out of range
-const ERR_SMC_POLY_INDEX_OOR: i16 = -401;
+pub(crate) const ERR_SMC_POLY_INDEX_OOR: i16 = -401;
+
+///
+/// Protocol error reported by the firmware in a response status or detected locally while
+/// parsing a response. Codes not known to this version of led_oxide are kept as Unknown.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    CmdParsing,
+    MissingStx,
+    MissingEtx,
+    MissingPsc,
+    MissingEfc,
+    CmdOverflow,
+    CmdNotImplemented,
+    CmdUnknown,
+    MissingParams,
+    ParamOutOfRange,
+    Crc16Mismatch,
+    MissingCrc16,
+    RspBuilding,
+    RspTooManyParams,
+    RspParamOverflow,
+    Adc,
+    AdcReadFail,
+    AdcRegisterDepth,
+    Smc,
+    SmcPolyIndexOutOfRange,
+    Unknown(i16),
+}
+
+impl ProtocolError {
+    ///
+    /// Returns the protocol error code. Codes are stable and match the firmware.
+    ///
+    pub fn code(&self) -> i16 {
+        match self {
+            ProtocolError::CmdParsing => ERR_PROTO_CMD_PARSING,
+            ProtocolError::MissingStx => ERR_PROTO_CP_MISSING_STX,
+            ProtocolError::MissingEtx => ERR_PROTO_CP_MISSING_ETX,
+            ProtocolError::MissingPsc => ERR_PROTO_CP_MISSING_PSC,
+            ProtocolError::MissingEfc => ERR_PROTO_CP_MISSING_EFC,
+            ProtocolError::CmdOverflow => ERR_PROTO_CP_CMD_OVERFLOW,
+            ProtocolError::CmdNotImplemented => ERR_PROTO_CP_CMD_NOT_IMP,
+            ProtocolError::CmdUnknown => ERR_PROTO_CP_CMD_UNKNOWN,
+            ProtocolError::MissingParams => ERR_PROTO_CP_MISSING_PARAMS,
+            ProtocolError::ParamOutOfRange => ERR_PROTO_CP_PARAM_OUT_RANGE,
+            ProtocolError::Crc16Mismatch => ERR_PROTO_CP_CRC16_MISMATCH,
+            ProtocolError::MissingCrc16 => ERR_PROTO_CP_MISSING_CRC16,
+            ProtocolError::RspBuilding => ERR_PROTO_RSP_BUILDING,
+            ProtocolError::RspTooManyParams => ERR_PROTO_RB_TOO_MANY_PARAMS,
+            ProtocolError::RspParamOverflow => ERR_PROTO_RB_PARAM_OVERFLOW,
+            ProtocolError::Adc => ERR_ADC,
+            ProtocolError::AdcReadFail => ERR_ADC_READFAIL,
+            ProtocolError::AdcRegisterDepth => ERR_ADC_REGISTER_DEPTH,
+            ProtocolError::Smc => ERR_SMC,
+            ProtocolError::SmcPolyIndexOutOfRange => ERR_SMC_POLY_INDEX_OOR,
+            ProtocolError::Unknown(code) => *code,
+        }
+    }
+}
+
+impl From<i16> for ProtocolError {
+    fn from(code: i16) -> Self {
+        match code {
+            ERR_PROTO_CMD_PARSING => ProtocolError::CmdParsing,
+            ERR_PROTO_CP_MISSING_STX => ProtocolError::MissingStx,
+            ERR_PROTO_CP_MISSING_ETX => ProtocolError::MissingEtx,
+            ERR_PROTO_CP_MISSING_PSC => ProtocolError::MissingPsc,
+            ERR_PROTO_CP_MISSING_EFC => ProtocolError::MissingEfc,
+            ERR_PROTO_CP_CMD_OVERFLOW => ProtocolError::CmdOverflow,
+            ERR_PROTO_CP_CMD_NOT_IMP => ProtocolError::CmdNotImplemented,
+            ERR_PROTO_CP_CMD_UNKNOWN => ProtocolError::CmdUnknown,
+            ERR_PROTO_CP_MISSING_PARAMS => ProtocolError::MissingParams,
+            ERR_PROTO_CP_PARAM_OUT_RANGE => ProtocolError::ParamOutOfRange,
+            ERR_PROTO_CP_CRC16_MISMATCH => ProtocolError::Crc16Mismatch,
+            ERR_PROTO_CP_MISSING_CRC16 => ProtocolError::MissingCrc16,
+            ERR_PROTO_RSP_BUILDING => ProtocolError::RspBuilding,
+            ERR_PROTO_RB_TOO_MANY_PARAMS => ProtocolError::RspTooManyParams,
+            ERR_PROTO_RB_PARAM_OVERFLOW => ProtocolError::RspParamOverflow,
+            ERR_ADC => ProtocolError::Adc,
+            ERR_ADC_READFAIL => ProtocolError::AdcReadFail,
+            ERR_ADC_REGISTER_DEPTH => ProtocolError::AdcRegisterDepth,
+            ERR_SMC => ProtocolError::Smc,
+            ERR_SMC_POLY_INDEX_OOR => ProtocolError::SmcPolyIndexOutOfRange,
+            _ => ProtocolError::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ProtocolError::CmdParsing => "Generic command processing error",
+            ProtocolError::MissingStx => "Missing expected STX character",
+            ProtocolError::MissingEtx => "Missing expected ETX character",
+            ProtocolError::MissingPsc => "Missing expected PSC character",
+            ProtocolError::MissingEfc => "Missing expected framing character",
+            ProtocolError::CmdOverflow => "Command buffer overflow",
+            ProtocolError::CmdNotImplemented => "Command not implemented",
+            ProtocolError::CmdUnknown => "Unknown command",
+            ProtocolError::MissingParams => "Missing parameters",
+            ProtocolError::ParamOutOfRange => "Parameter out of range",
+            ProtocolError::Crc16Mismatch => "CRC16 mismatch",
+            ProtocolError::MissingCrc16 => "CRC16 missing",
+            ProtocolError::RspBuilding => "Response packet error",
+            ProtocolError::RspTooManyParams => "Too many params in response packet",
+            ProtocolError::RspParamOverflow => "Response param buffer overflow",
+            ProtocolError::Adc => "ADC error",
+            ProtocolError::AdcReadFail => "Failed to read ADC",
+            ProtocolError::AdcRegisterDepth => "ADC register depth error",
+            ProtocolError::Smc => "Set Movetohall config error",
+            ProtocolError::SmcPolyIndexOutOfRange => "Polynomial index out of range",
+            ProtocolError::Unknown(..) => "Unknown error",
+        };
+
+        write!(f, "{} ({})", message, self.code())
+    }
+}
+
+impl std::error::Error for ProtocolError {}
 
 ///
 /// Represents possible LED Strip effects.
 ///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Effect {
     Off,
     SolidColor,
@@ -201,6 +327,53 @@ pub enum Effect {
     MaxEffect,
 }
 
+impl Effect {
+    /// Every effect. MaxEffect only marks the end of the effect ids and is left out.
+    pub const ALL: [Effect; 10] = [
+        Effect::Off,
+        Effect::SolidColor,
+        Effect::RainbowCycle,
+        Effect::Comet,
+        Effect::CometRainbow,
+        Effect::Fire,
+        Effect::FireColor,
+        Effect::SolidColorPulse,
+        Effect::BouncingBall,
+        Effect::Twinkle,
+    ];
+
+    ///
+    /// Returns the effect's name. Matches the serialized form.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Off => "off",
+            Effect::SolidColor => "solid_color",
+            Effect::RainbowCycle => "rainbow_cycle",
+            Effect::Comet => "comet",
+            Effect::CometRainbow => "comet_rainbow",
+            Effect::Fire => "fire",
+            Effect::FireColor => "fire_color",
+            Effect::SolidColorPulse => "solid_color_pulse",
+            Effect::BouncingBall => "bouncing_ball",
+            Effect::Twinkle => "twinkle",
+            Effect::MaxEffect => "max_effect",
+        }
+    }
+
+    ///
+    /// Returns the effect with the given name.
+    ///
+    pub fn from_name(name: &str) -> Option<Effect> {
+        Effect::ALL
+            .iter()
+            .copied()
+            .find(|effect| effect.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FireColorPallet {
     Heat,
     Party,
@@ -212,9 +385,51 @@ pub enum FireColorPallet {
     Cloud,
 }
 
+impl FireColorPallet {
+    /// Every fire color pallet.
+    pub const ALL: [FireColorPallet; 8] = [
+        FireColorPallet::Heat,
+        FireColorPallet::Party,
+        FireColorPallet::Rainbow,
+        FireColorPallet::RainbowStripe,
+        FireColorPallet::Forest,
+        FireColorPallet::Ocean,
+        FireColorPallet::Lava,
+        FireColorPallet::Cloud,
+    ];
+
+    ///
+    /// Returns the pallet's name. Matches the serialized form.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            FireColorPallet::Heat => "heat",
+            FireColorPallet::Party => "party",
+            FireColorPallet::Rainbow => "rainbow",
+            FireColorPallet::RainbowStripe => "rainbow_stripe",
+            FireColorPallet::Forest => "forest",
+            FireColorPallet::Ocean => "ocean",
+            FireColorPallet::Lava => "lava",
+            FireColorPallet::Cloud => "cloud",
+        }
+    }
+
+    ///
+    /// Returns the pallet with the given name.
+    ///
+    pub fn from_name(name: &str) -> Option<FireColorPallet> {
+        FireColorPallet::ALL
+            .iter()
+            .copied()
+            .find(|pallet| pallet.name() == name)
+    }
+}
+
 ///
 /// Represents possible LED Strip Controller commands
 ///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Command {
     None,
     PrintVersion,
@@ -285,6 +500,13 @@ pub fn get_protocol_version_impl_from_str(protocol_str: &str) -> Box<dyn Protoco
     }
 }
 
+///
+/// Returns the newest protocol version this software knows.
+///
+pub fn latest_protocol_version() -> Box<dyn ProtocolVersion> {
+    get_protocol_version_impl_from_str(FWV_LEDSC_TEENSY)
+}
+
 ///
 /// Represents a response packet option returned by parsing a response packet.
 ///
@@ -294,7 +516,23 @@ pub enum ResponsePacketOption {
     /// Value when a reponse pacakge is parsed correcly and represents a failure code from firmware.
     FailedRemote(ResponsePacket),
     /// Value when a response pacakge is failed to be parsed.
-    FailedLocal(i16),
+    FailedLocal(ProtocolError),
+}
+
+impl ResponsePacketOption {
+    ///
+    /// Converts the option into the successful packet or the protocol error, whether it was
+    /// reported by the firmware or found while parsing.
+    ///
+    pub fn into_result(self) -> Result<ResponsePacket, ProtocolError> {
+        match self {
+            ResponsePacketOption::Success(pkt) => Ok(pkt),
+            ResponsePacketOption::FailedRemote(pkt) => {
+                Err(pkt.error().unwrap_or(ProtocolError::CmdParsing))
+            }
+            ResponsePacketOption::FailedLocal(error) => Err(error),
+        }
+    }
 }
 
 ///
@@ -308,11 +546,72 @@ pub struct ResponsePacket {
     pub crc16_calc: u16,
 }
 
+impl ResponsePacket {
+    ///
+    /// Returns the error reported by the firmware in the status parameter, None on success. A
+    /// status that is not a number is reported as CmdParsing.
+    ///
+    pub fn error(&self) -> Option<ProtocolError> {
+        match self.parameters.first().map(|status| status.parse::<i16>()) {
+            Some(Ok(ERR_PROTO_SUCCESS)) => None,
+            Some(Ok(code)) => Some(ProtocolError::from(code)),
+            _ => Some(ProtocolError::CmdParsing),
+        }
+    }
+}
+
+///
+/// Device state reported by the get status command.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub debugging: bool,
+    pub effect: Effect,
+    pub brightness: u8,
+    pub color: color::Color24,
+    pub fire_pallet: FireColorPallet,
+}
+
+impl DeviceStatus {
+    ///
+    /// Returns the brightness as a percentage, the same scale used to set it.
+    ///
+    pub fn brightness_percent(&self) -> f32 {
+        (self.brightness as f32 / 255.0) * 100.0
+    }
+}
+
+///
+/// Reasons a get status response could not be decoded.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceStatusError {
+    /// A status field was not present in the response
+    MissingField(&'static str),
+    /// A status field was present but its value could not be decoded
+    MalformedField { field: &'static str, value: String },
+}
+
+impl fmt::Display for DeviceStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceStatusError::MissingField(field) => {
+                write!(f, "Status field {} is missing", field)
+            }
+            DeviceStatusError::MalformedField { field, value } => {
+                write!(f, "Status field {} is malformed: '{}'", field, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceStatusError {}
+
 ///
 /// Trait describing necessary functions for a given protocol version. Each known protocol version
 /// should implement this trait. The base implmentation of functions support TKJLED_Teensy_001.
 ///
-pub trait ProtocolVersion {
+pub trait ProtocolVersion: Send + Sync {
     fn get_version_code(&self) -> &str;
 
     ///
@@ -345,6 +644,36 @@ pub trait ProtocolVersion {
     ///
     fn get_fire_color_pallet_from_cmd_value(&self, pallet_id: &u8) -> FireColorPallet;
 
+    ///
+    /// Returns the supported effect with the given id. Unlike get_effect_from_cmd_value unknown
+    /// ids are rejected instead of mapped to off.
+    ///
+    fn find_effect(&self, effect_id: u8) -> Option<Effect> {
+        let effect = self.get_effect_from_cmd_value(&effect_id);
+
+        if self.get_effect_cmd_value(&effect) == effect_id
+            && effect != Effect::MaxEffect
+            && self.is_effect_supported(&effect)
+        {
+            Some(effect)
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// Returns the fire color pallet with the given id. Unknown ids are rejected.
+    ///
+    fn find_fire_color_pallet(&self, pallet_id: u8) -> Option<FireColorPallet> {
+        let pallet = self.get_fire_color_pallet_from_cmd_value(&pallet_id);
+
+        if self.get_fire_color_pallet_value(&pallet) == pallet_id {
+            Some(pallet)
+        } else {
+            None
+        }
+    }
+
     ///
     /// Returns the command string to be sent for the given command packet.
     ///
@@ -409,99 +738,170 @@ pub trait ProtocolVersion {
     }
 
     ///
-    /// Parses the input string and returns a ResponsePacket
+    /// Returns the response string the firmware sends for the given command, status code and
+    /// response parameters.
     ///
-    fn parse_response_sting(&self, response_str: String) -> ResponsePacketOption {
-        // init character iterator
-        let mut response_chars: Chars<'_> = response_str.trim().chars();
-
-        if response_chars.next() != Some(PROTO_STX) {
-            // Stop: Missing STX
-            return ResponsePacketOption::FailedLocal(ERR_PROTO_CP_MISSING_STX);
-        }
-
-        // Init Command string
-        let mut cmd: String = String::from("");
+    fn create_response_string(&self, command: &str, status: i16, params: &[String]) -> String {
+        // Start TX
+        let mut rsp_str: String = String::from(PROTO_STX);
 
-        // Init CRC16
-        let mut state_crc_16 = State::<XMODEM>::new();
-        state_crc_16.update(PROTO_STX.to_string().as_bytes());
+        // Command, status & parameters
+        rsp_str.push_str(command);
+        rsp_str.push(PROTO_PSC);
+        rsp_str.push_str(status.to_string().as_str());
 
-        // Read next char post PROTO_STX
-        let mut current_char: Option<char> = response_chars.next();
-        state_crc_16.update(current_char.unwrap().to_string().as_bytes());
+        for param in params {
+            rsp_str.push(PROTO_PSC);
+            rsp_str.push_str(param);
+        }
 
-        // Read Command
-        while current_char != Some(PROTO_PSC)
-            && current_char != Some(PROTO_ETX)
-            && current_char != None
-        {
-            cmd.push(current_char.unwrap());
+        // End TX
+        rsp_str.push(PROTO_ETX);
 
-            current_char = response_chars.next();
-            state_crc_16.update(current_char.unwrap().to_string().as_bytes());
-        }
+        // CRC16 - XMODEM
+        rsp_str.push_str(format!("{:X}", State::<XMODEM>::calculate(rsp_str.as_bytes())).as_str());
 
-        // Read parameters if present
-        let mut params_in: Vec<String> = vec![];
+        // carriage return line feed
+        rsp_str.push(PROTO_CR);
+        rsp_str.push(PROTO_NL);
 
-        while current_char == Some(PROTO_PSC) {
-            current_char = response_chars.next();
-            state_crc_16.update(current_char.unwrap().to_string().as_bytes());
+        rsp_str
+    }
 
-            let mut param: String = String::from("");
+    ///
+    /// Parses the input string and returns a ResponsePacket. Never panics, any malformed input
+    /// is reported as FailedLocal with the matching protocol error code.
+    ///
+    fn parse_response_sting(&self, response_str: String) -> ResponsePacketOption {
+        let response: &str = response_str.trim();
 
-            while current_char != Some(PROTO_PSC)
-                && current_char != Some(PROTO_ETX)
-                && current_char != None
-            {
-                param.push(current_char.unwrap());
+        let body = match response.strip_prefix(PROTO_STX) {
+            Some(body) => body,
+            // Stop: Missing STX
+            None => return ResponsePacketOption::FailedLocal(ProtocolError::MissingStx),
+        };
 
-                current_char = response_chars.next();
-                state_crc_16.update(current_char.unwrap().to_string().as_bytes());
-            }
+        // Frame runs up to the first ETX, the CRC16 follows it
+        let (frame, crc16_in_str) = match body.find(PROTO_ETX) {
+            Some(etx_index) => (&body[..etx_index], Some(&body[etx_index + 1..])),
+            None => (body, None),
+        };
 
-            params_in.push(param);
-        }
+        // Read command & parameters
+        let mut fields = frame.split(PROTO_PSC);
+        let cmd: String = String::from(fields.next().unwrap_or_default());
+        let params_in: Vec<String> = fields.map(String::from).collect();
 
-        if params_in.len() < 1 {
+        if params_in.is_empty() {
             // Should always get at minimum 1 parameter, status code.
-            return ResponsePacketOption::FailedLocal(ERR_PROTO_CP_MISSING_PARAMS);
+            return ResponsePacketOption::FailedLocal(ProtocolError::MissingParams);
         }
 
-        if current_char != Some(PROTO_ETX) {
+        let crc16_in_str = match crc16_in_str {
+            Some(crc16_in_str) => crc16_in_str.split(PROTO_CR).next().unwrap_or_default(),
             // Stop: Missing ETX
-            return ResponsePacketOption::FailedLocal(ERR_PROTO_CP_MISSING_ETX);
+            None => return ResponsePacketOption::FailedLocal(ProtocolError::MissingEtx),
+        };
+
+        if crc16_in_str.is_empty() {
+            // Stop: Missing CRC16
+            return ResponsePacketOption::FailedLocal(ProtocolError::MissingCrc16);
         }
 
-        // Read CRC16
-        current_char = response_chars.next();
-        let mut crc16_in_str: String = String::from("");
-        while current_char != Some(PROTO_CR) && current_char != None {
-            crc16_in_str.push(current_char.unwrap());
-            current_char = response_chars.next();
+        // CRC16 covers STX through ETX
+        let frame_len = PROTO_STX.len_utf8() + frame.len() + PROTO_ETX.len_utf8();
+        let crc16_calc = State::<XMODEM>::calculate(&response.as_bytes()[..frame_len]);
+        let crc16_in = match u16::from_str_radix(crc16_in_str, 16) {
+            Result::Ok(value) => value,
+            Result::Err(..) => {
+                return ResponsePacketOption::FailedLocal(ProtocolError::Crc16Mismatch)
+            }
+        };
+
+        if crc16_in != crc16_calc {
+            // Stop: Corrupted response
+            return ResponsePacketOption::FailedLocal(ProtocolError::Crc16Mismatch);
         }
 
         // Create response packet object
         let response_packet = ResponsePacket {
             command: cmd,
             parameters: params_in,
-            crc16_in: match u16::from_str_radix(crc16_in_str.as_str(), 16) {
-                Result::Ok(value) => value,
-                Result::Err(..) => 0x00,
-            },
-            crc16_calc: state_crc_16.get(),
+            crc16_in,
+            crc16_calc,
         };
 
         let success_str: String = format!("{}", ERR_PROTO_SUCCESS);
 
         // Mark ResponsePacketOption::FailedRemote() if param 1 is not OK
-        if Some(&success_str) == response_packet.parameters.get(0) {
+        if Some(&success_str) == response_packet.parameters.first() {
             // Return success
             return ResponsePacketOption::Success(response_packet);
         }
 
-        return ResponsePacketOption::FailedRemote(response_packet);
+        ResponsePacketOption::FailedRemote(response_packet)
+    }
+
+    ///
+    /// Decodes the device status from a get status response. The status parameter holds the
+    /// debug flag, effect id, brightness, RGB color and fire color pallet id as '|' separated hex
+    /// values. Fields added by newer firmware after these are ignored.
+    ///
+    fn parse_device_status(
+        &self,
+        response: &ResponsePacket,
+    ) -> Result<DeviceStatus, DeviceStatusError> {
+        let status_str = response
+            .parameters
+            .get(1)
+            .ok_or(DeviceStatusError::MissingField("status"))?;
+        let mut fields = status_str.split(PROTO_STATUS_SEPARATOR);
+
+        let mut next_field = |field: &'static str, max: u32| {
+            let value = fields
+                .next()
+                .filter(|value| !value.is_empty())
+                .ok_or(DeviceStatusError::MissingField(field))?;
+
+            match u32::from_str_radix(value, 16) {
+                Ok(parsed) if parsed <= max => Ok(parsed),
+                _ => Err(DeviceStatusError::MalformedField {
+                    field,
+                    value: String::from(value),
+                }),
+            }
+        };
+
+        let debugging = next_field("debugging", 0x01)? != 0;
+
+        let effect_id = next_field("effect_id", 0xff)? as u8;
+        let effect = self.get_effect_from_cmd_value(&effect_id);
+        if self.get_effect_cmd_value(&effect) != effect_id {
+            return Err(DeviceStatusError::MalformedField {
+                field: "effect_id",
+                value: format!("{:X}", effect_id),
+            });
+        }
+
+        let brightness = next_field("brightness", 0xff)? as u8;
+        let color = color::Color24::from_u32(next_field("color", 0xffffff)?);
+
+        let pallet_id = next_field("fire_pallet_id", 0xff)? as u8;
+        let fire_pallet = self.get_fire_color_pallet_from_cmd_value(&pallet_id);
+        if self.get_fire_color_pallet_value(&fire_pallet) != pallet_id {
+            return Err(DeviceStatusError::MalformedField {
+                field: "fire_pallet_id",
+                value: format!("{:X}", pallet_id),
+            });
+        }
+
+        Ok(DeviceStatus {
+            debugging,
+            effect,
+            brightness,
+            color,
+            fire_pallet,
+        })
     }
 }
 
@@ -526,7 +926,7 @@ impl ProtocolVersion for LedscTeensy001 {
             Command::None => false,
             Command::PrintVersion => true,
             Command::FullReset => false,
-            Command::EnterBootloader => false,
+            Command::EnterBootloader => true,
             Command::SetDebugging(..) => true,
             Command::SetEffect(effect) => self.is_effect_supported(effect),
             Command::SetColor(..) => true,
@@ -800,6 +1200,34 @@ mod test {
         );
     }
 
+    #[test]
+    fn create_response_string_test() {
+        let protocol_version = protocol::LedscTeensy001 {};
+
+        assert_eq!(
+            protocol_version.create_response_string(CMD_SET_EFFECT, 0, &[]),
+            format!(
+                "{}{}{}{}{}{}{}",
+                PROTO_STX, CMD_SET_EFFECT, PROTO_PSC, "0", PROTO_ETX, "A0D8", "\r\n"
+            )
+        );
+
+        let response = protocol_version.create_response_string(
+            CMD_PRINT_VERSION,
+            0,
+            &[String::from("LEDSC_TEENSY_001")],
+        );
+
+        match protocol_version.parse_response_sting(response) {
+            protocol::ResponsePacketOption::Success(pkt) => {
+                assert_eq!(pkt.command, CMD_PRINT_VERSION);
+                assert_eq!(pkt.parameters, vec!["0", "LEDSC_TEENSY_001"]);
+                assert_eq!(pkt.crc16_in, pkt.crc16_calc);
+            }
+            _ => panic!("Encoded print version response should parse as success"),
+        }
+    }
+
     #[test]
     fn parse_response_sting_test() {
         let protocol_version = protocol::LedscTeensy001 {};
@@ -879,6 +1307,246 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_response_sting_crc16_test() {
+        let protocol_version = protocol::LedscTeensy001 {};
+
+        let cases = [
+            ("[CSE:0]A0D9", protocol::ERR_PROTO_CP_CRC16_MISMATCH),
+            ("[CSE:1]A0D8", protocol::ERR_PROTO_CP_CRC16_MISMATCH),
+            ("[CSE:0]XYZ", protocol::ERR_PROTO_CP_CRC16_MISMATCH),
+            ("[CSE:0]", protocol::ERR_PROTO_CP_MISSING_CRC16),
+            ("[CSE:0]\r\n", protocol::ERR_PROTO_CP_MISSING_CRC16),
+        ];
+
+        for (response, err_code) in cases.iter() {
+            match protocol_version.parse_response_sting(String::from(*response)) {
+                protocol::ResponsePacketOption::FailedLocal(error) => assert_eq!(
+                    error.code(),
+                    *err_code,
+                    "Parsing '{}' should fail local with {}",
+                    response,
+                    err_code
+                ),
+                _ => panic!("Parsing '{}' should fail local", response),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_response_sting_truncated_test() {
+        let protocol_version = protocol::LedscTeensy001 {};
+
+        let cases = [
+            ("", protocol::ERR_PROTO_CP_MISSING_STX),
+            ("CSE:0]A0D8", protocol::ERR_PROTO_CP_MISSING_STX),
+            ("[", protocol::ERR_PROTO_CP_MISSING_PARAMS),
+            ("[CSE", protocol::ERR_PROTO_CP_MISSING_PARAMS),
+            ("[CSE]A0D8", protocol::ERR_PROTO_CP_MISSING_PARAMS),
+            ("[CSE:", protocol::ERR_PROTO_CP_MISSING_ETX),
+            ("[CSE:0", protocol::ERR_PROTO_CP_MISSING_ETX),
+            ("[CSE:0]", protocol::ERR_PROTO_CP_MISSING_CRC16),
+            ("[CSE:0]\rA0D8", protocol::ERR_PROTO_CP_MISSING_CRC16),
+            ("[\u{e9}:0]FFFF", protocol::ERR_PROTO_CP_CRC16_MISMATCH),
+        ];
+
+        for (response, err_code) in cases.iter() {
+            match protocol_version.parse_response_sting(String::from(*response)) {
+                protocol::ResponsePacketOption::FailedLocal(error) => assert_eq!(
+                    error.code(),
+                    *err_code,
+                    "Parsing '{}' should fail local with {}",
+                    response,
+                    err_code
+                ),
+                _ => panic!("Parsing '{}' should fail local", response),
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_error_code_test() {
+        let cases = [
+            (
+                protocol::ERR_PROTO_CMD_PARSING,
+                protocol::ProtocolError::CmdParsing,
+            ),
+            (
+                protocol::ERR_PROTO_CP_MISSING_STX,
+                protocol::ProtocolError::MissingStx,
+            ),
+            (
+                protocol::ERR_PROTO_CP_CRC16_MISMATCH,
+                protocol::ProtocolError::Crc16Mismatch,
+            ),
+            (
+                protocol::ERR_PROTO_RB_PARAM_OVERFLOW,
+                protocol::ProtocolError::RspParamOverflow,
+            ),
+            (
+                protocol::ERR_SMC_POLY_INDEX_OOR,
+                protocol::ProtocolError::SmcPolyIndexOutOfRange,
+            ),
+            (-999, protocol::ProtocolError::Unknown(-999)),
+        ];
+
+        for (code, error) in cases.iter() {
+            assert_eq!(protocol::ProtocolError::from(*code), *error);
+            assert_eq!(error.code(), *code);
+        }
+
+        assert_eq!(
+            protocol::ProtocolError::ParamOutOfRange.to_string(),
+            "Parameter out of range (-109)"
+        );
+    }
+
+    #[test]
+    fn response_packet_error_test() {
+        let protocol_version = protocol::LedscTeensy001 {};
+
+        let response = protocol_version.create_response_string("CSE", 0, &[]);
+        let result = protocol_version
+            .parse_response_sting(response)
+            .into_result();
+        assert_eq!(result.map(|pkt| pkt.error()).ok(), Some(None));
+
+        let response = protocol_version.create_response_string("CSE", -109, &[]);
+        let result = protocol_version
+            .parse_response_sting(response)
+            .into_result();
+        assert_eq!(result.err(), Some(protocol::ProtocolError::ParamOutOfRange));
+
+        let result = protocol_version
+            .parse_response_sting(String::from("[CSE:0]"))
+            .into_result();
+        assert_eq!(result.err(), Some(protocol::ProtocolError::MissingCrc16));
+    }
+
+    #[test]
+    fn parse_device_status_test() {
+        let protocol_version = protocol::LedscTeensy001 {};
+
+        let status_packet = |status: &str| protocol::ResponsePacket {
+            command: String::from(protocol::CMD_GET_STATUS),
+            parameters: vec![String::from("0"), String::from(status)],
+            crc16_in: 0,
+            crc16_calc: 0,
+        };
+
+        let status = protocol_version
+            .parse_device_status(&status_packet("1|9|80|4F2D86|6"))
+            .unwrap();
+        assert!(status.debugging);
+        assert_eq!(status.effect, Effect::Twinkle);
+        assert_eq!(status.brightness, 0x80);
+        assert_eq!(status.color, Color24::from_u32(0x4f2d86));
+        assert_eq!(status.fire_pallet, protocol::FireColorPallet::Lava);
+        assert!((status.brightness_percent() - 50.196).abs() < 0.01);
+
+        // Fields added by newer firmware are ignored
+        assert!(protocol_version
+            .parse_device_status(&status_packet("0|0|FF|0|0|1234"))
+            .is_ok());
+
+        let cases = [
+            ("", protocol::DeviceStatusError::MissingField("debugging")),
+            ("0|1|FF", protocol::DeviceStatusError::MissingField("color")),
+            (
+                "0|1||FFFFFF|0",
+                protocol::DeviceStatusError::MissingField("brightness"),
+            ),
+            (
+                "2|1|FF|FFFFFF|0",
+                protocol::DeviceStatusError::MalformedField {
+                    field: "debugging",
+                    value: String::from("2"),
+                },
+            ),
+            (
+                "0|1F|FF|FFFFFF|0",
+                protocol::DeviceStatusError::MalformedField {
+                    field: "effect_id",
+                    value: String::from("1F"),
+                },
+            ),
+            (
+                "0|1|FF|GGGGGG|0",
+                protocol::DeviceStatusError::MalformedField {
+                    field: "color",
+                    value: String::from("GGGGGG"),
+                },
+            ),
+            (
+                "0|1|FF|1FFFFFF|0",
+                protocol::DeviceStatusError::MalformedField {
+                    field: "color",
+                    value: String::from("1FFFFFF"),
+                },
+            ),
+            (
+                "0|1|FF|FFFFFF|8",
+                protocol::DeviceStatusError::MalformedField {
+                    field: "fire_pallet_id",
+                    value: String::from("8"),
+                },
+            ),
+        ];
+
+        for (status_str, error) in cases.iter() {
+            assert_eq!(
+                protocol_version.parse_device_status(&status_packet(status_str)),
+                Err(error.clone()),
+                "Status '{}'",
+                status_str
+            );
+        }
+
+        let mut no_status = status_packet("");
+        no_status.parameters.truncate(1);
+        assert_eq!(
+            protocol_version.parse_device_status(&no_status),
+            Err(protocol::DeviceStatusError::MissingField("status"))
+        );
+    }
+
+    #[test]
+    fn effect_and_pallet_names_test() {
+        for effect in protocol::Effect::ALL.iter() {
+            assert_eq!(
+                serde_json::to_string(effect).unwrap(),
+                format!("\"{}\"", effect.name())
+            );
+            assert_eq!(protocol::Effect::from_name(effect.name()), Some(*effect));
+        }
+        for pallet in protocol::FireColorPallet::ALL.iter() {
+            assert_eq!(
+                serde_json::to_string(pallet).unwrap(),
+                format!("\"{}\"", pallet.name())
+            );
+            assert_eq!(
+                protocol::FireColorPallet::from_name(pallet.name()),
+                Some(*pallet)
+            );
+        }
+        assert_eq!(protocol::Effect::from_name("max_effect"), None);
+        assert_eq!(protocol::FireColorPallet::from_name("Ocean"), None);
+    }
+
+    #[test]
+    fn find_effect_and_pallet_test() {
+        let protocol_version = protocol::latest_protocol_version();
+
+        assert_eq!(protocol_version.find_effect(0x05), Some(Effect::Fire));
+        assert_eq!(protocol_version.find_effect(0x0a), None);
+        assert_eq!(protocol_version.find_effect(0x42), None);
+        assert_eq!(
+            protocol_version.find_fire_color_pallet(0x07),
+            Some(protocol::FireColorPallet::Cloud)
+        );
+        assert_eq!(protocol_version.find_fire_color_pallet(0x08), None);
+    }
+
     #[test]
     fn get_known_protocol_version_from_str_test() {
         // Checking standard 001 all caps
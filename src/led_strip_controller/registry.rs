@@ -0,0 +1,744 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::controller::*;
+use crate::led_strip_controller::events::{DeviceEvent, EventBus};
+use crate::led_strip_controller::state::StateStore;
+use serialport::{available_ports, SerialPortInfo, SerialPortType};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lists the serial ports that may hold LEDSC devices.
+pub type PortLister =
+    Arc<dyn Fn() -> std::result::Result<Vec<SerialPortInfo>, ControllerError> + Send + Sync>;
+
+/// Opens a transport to the given serial port.
+pub type PortOpener = Arc<
+    dyn Fn(&SerialPortInfo) -> std::result::Result<BoxedTransport, ControllerError> + Send + Sync,
+>;
+
+///
+/// Returns the stable device id for a serial port. USB devices with a serial number are
+/// identified by it so the id survives the device moving to another port. Other USB devices use
+/// their VID/PID and port name. Anything else uses the port name.
+///
+pub fn device_id(port_info: &SerialPortInfo) -> String {
+    let port_file_name = Path::new(&port_info.port_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| port_info.port_name.clone());
+
+    let id = match &port_info.port_type {
+        SerialPortType::UsbPort(usb_info) => match &usb_info.serial_number {
+            Some(serial_number) if !serial_number.is_empty() => serial_number.clone(),
+            _ => format!(
+                "{:04x}-{:04x}-{}",
+                usb_info.vid, usb_info.pid, port_file_name
+            ),
+        },
+        _ => port_file_name,
+    };
+
+    // Ids are used as URL path segments
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+///
+/// Lists and opens ports and maps them to device ids.
+///
+#[derive(Clone)]
+struct PortScanner {
+    list_ports: PortLister,
+    open_port: PortOpener,
+    aliases: HashMap<String, String>,
+}
+
+impl PortScanner {
+    ///
+    /// Returns the id of the device on the given port. A configured alias for the derived id or
+    /// the port name replaces the derived id.
+    ///
+    fn id_for(&self, port_info: &SerialPortInfo) -> String {
+        let id = device_id(port_info);
+
+        self.aliases
+            .get(&id)
+            .or_else(|| self.aliases.get(&port_info.port_name))
+            .cloned()
+            .unwrap_or(id)
+    }
+
+    ///
+    /// Finds the device with the given id on the listed ports and opens it.
+    ///
+    fn detect(
+        &self,
+        id: &str,
+    ) -> std::result::Result<DetectedDevice<BoxedTransport>, ControllerError> {
+        let port_infos: Vec<SerialPortInfo> = (self.list_ports)()?
+            .into_iter()
+            .filter(|port_info| self.id_for(port_info) == id)
+            .collect();
+
+        auto_detect_ledsc_on_ports(port_infos, |port_info| (self.open_port)(port_info))
+    }
+}
+
+///
+/// Registry of every LEDSC device found on the host. Each device is held by its own
+/// DeviceManager under a stable id. Ports are first scanned when a device is requested.
+/// Connect and disconnect events are published on the registry's event bus.
+///
+pub struct DeviceRegistry {
+    scanner: PortScanner,
+    command_retries: u32,
+    devices: RwLock<BTreeMap<String, Arc<DeviceManager>>>,
+    scanned: Mutex<bool>,
+    unresponsive_ports: Mutex<HashSet<String>>,
+    events: Arc<EventBus>,
+    state_store: Option<Arc<StateStore>>,
+    restore_disabled: HashSet<String>,
+}
+
+impl DeviceRegistry {
+    ///
+    /// Creates a registry that scans the available serial ports.
+    ///
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry::with_ports(
+            || available_ports().map_err(ControllerError::NoPorts),
+            |port_info| open_serial_transport(port_info).map(|t| Box::new(t) as BoxedTransport),
+        )
+    }
+
+    ///
+    /// Creates a registry that only scans the named serial ports. Used for ports that are not
+    /// enumerated as serial devices, such as the simulator's pseudo-terminal. Ports are only
+    /// listed while their path exists.
+    ///
+    pub fn with_port_names(port_names: Vec<String>) -> DeviceRegistry {
+        let port_infos: Vec<SerialPortInfo> = port_names
+            .into_iter()
+            .map(|port_name| SerialPortInfo {
+                port_name,
+                port_type: SerialPortType::Unknown,
+            })
+            .collect();
+
+        DeviceRegistry::with_ports(
+            move || {
+                Ok(port_infos
+                    .iter()
+                    .filter(|port_info| Path::new(&port_info.port_name).exists())
+                    .cloned()
+                    .collect())
+            },
+            |port_info| open_serial_transport(port_info).map(|t| Box::new(t) as BoxedTransport),
+        )
+    }
+
+    ///
+    /// Creates a registry that lists ports with list_ports and opens them with open_port.
+    ///
+    pub fn with_ports<L, O>(list_ports: L, open_port: O) -> DeviceRegistry
+    where
+        L: Fn() -> std::result::Result<Vec<SerialPortInfo>, ControllerError>
+            + Send
+            + Sync
+            + 'static,
+        O: Fn(&SerialPortInfo) -> std::result::Result<BoxedTransport, ControllerError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        DeviceRegistry {
+            scanner: PortScanner {
+                list_ports: Arc::new(list_ports),
+                open_port: Arc::new(open_port),
+                aliases: HashMap::new(),
+            },
+            command_retries: DEFAULT_COMMAND_RETRIES,
+            devices: RwLock::new(BTreeMap::new()),
+            scanned: Mutex::new(false),
+            unresponsive_ports: Mutex::new(HashSet::new()),
+            events: Arc::new(EventBus::new()),
+            state_store: None,
+            restore_disabled: HashSet::new(),
+        }
+    }
+
+    ///
+    /// Sets an alias used as the id of a device. The key is the device's derived id or its port
+    /// name.
+    ///
+    pub fn set_alias(&mut self, key: &str, alias: &str) {
+        self.scanner
+            .aliases
+            .insert(String::from(key), String::from(alias));
+    }
+
+    ///
+    /// Sets how many times a command is resent after a corrupted exchange on every device.
+    ///
+    pub fn set_command_retries(&mut self, command_retries: u32) {
+        self.command_retries = command_retries;
+    }
+
+    ///
+    /// Sets the store keeping each device's last known state. Devices found from now on record
+    /// their settings there and have them restored when they connect or reset.
+    ///
+    pub fn set_state_store(&mut self, state_store: Arc<StateStore>) {
+        self.state_store = Some(state_store);
+    }
+
+    ///
+    /// Turns state restoring on or off for the device with the given id. On by default.
+    ///
+    pub fn set_restore_state(&mut self, id: &str, enabled: bool) {
+        if enabled {
+            self.restore_disabled.remove(id);
+        } else {
+            self.restore_disabled.insert(String::from(id));
+        }
+    }
+
+    ///
+    /// Returns the bus the registry publishes device events on.
+    ///
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    ///
+    /// Probes every listed port without a registered device and registers the LEDSC devices
+    /// found. Returns the ids of the newly registered devices.
+    ///
+    pub fn scan(&self) -> std::result::Result<Vec<String>, ControllerError> {
+        let mut scanned = self.lock_scanned();
+        *scanned = true;
+
+        self.lock_unresponsive_ports().clear();
+        let port_infos = (self.scanner.list_ports)()?;
+        Ok(self.scan_ports(port_infos))
+    }
+
+    ///
+    /// Brings the registry in line with the listed ports. Devices whose id is no longer listed
+    /// are removed. A listed device keeps its manager even when its connection was dropped after
+    /// a failed command, since the manager detects it again by id. A device that moved to
+    /// another port has its old connection closed so its next command reopens it there. New
+    /// ports are probed, except ports that did not answer as a LEDSC device before and have not
+    /// gone away since. Returns the connect and disconnect events, which are also published.
+    ///
+    pub fn sync(&self) -> std::result::Result<Vec<DeviceEvent>, ControllerError> {
+        let mut scanned = self.lock_scanned();
+        *scanned = true;
+
+        let port_infos = (self.scanner.list_ports)()?;
+
+        let listed_ports: HashMap<String, String> = port_infos
+            .iter()
+            .map(|port_info| (self.scanner.id_for(port_info), port_info.port_name.clone()))
+            .collect();
+
+        let mut events: Vec<DeviceEvent> = vec![];
+
+        let held_devices: Vec<(String, Arc<DeviceManager>)> = self
+            .read_devices()
+            .iter()
+            .map(|(id, device_manager)| (id.clone(), device_manager.clone()))
+            .collect();
+
+        for (id, device_manager) in held_devices {
+            let held_port = device_manager
+                .port_info()
+                .map(|port_info| port_info.port_name);
+
+            match (&held_port, listed_ports.get(&id)) {
+                (Some(held_port), Some(listed_port)) if held_port != listed_port => {
+                    println!("LEDSC device {} moved to {}", id, listed_port);
+                    device_manager.session().disconnect();
+                }
+                (_, Some(_)) => {}
+                (_, None) => {
+                    self.write_devices().remove(&id);
+                    println!("LEDSC device {} disconnected", id);
+                    events.push(DeviceEvent::Disconnected {
+                        device_id: id,
+                        port_name: held_port,
+                    });
+                }
+            }
+        }
+
+        // Ports that went away may hold a LEDSC device when they come back
+        self.lock_unresponsive_ports().retain(|port_name| {
+            port_infos
+                .iter()
+                .any(|port_info| &port_info.port_name == port_name)
+        });
+
+        for event in &events {
+            self.events.publish(event.clone());
+        }
+
+        let found = self.scan_ports(port_infos);
+        events.extend(
+            self.read_devices()
+                .iter()
+                .filter(|(id, _)| found.contains(id))
+                .map(|(id, device_manager)| connected_event(id, device_manager)),
+        );
+
+        Ok(events)
+    }
+
+    ///
+    /// Probes the given ports. Callers hold the scanned lock so only one scan runs at a time.
+    ///
+    fn scan_ports(&self, port_infos: Vec<SerialPortInfo>) -> Vec<String> {
+        let mut found: Vec<String> = vec![];
+
+        for port_info in port_infos {
+            let id = self.scanner.id_for(&port_info);
+            if self.contains(&id)
+                || found.contains(&id)
+                || self
+                    .lock_unresponsive_ports()
+                    .contains(&port_info.port_name)
+            {
+                continue;
+            }
+
+            let port_name = port_info.port_name.clone();
+            let detected = auto_detect_ledsc_on_ports(vec![port_info], |port_info| {
+                (self.scanner.open_port)(port_info)
+            });
+
+            if let Ok(device) = detected {
+                println!(
+                    "LEDSC device {} found on {} as {}",
+                    device.firmware_version, port_name, id
+                );
+
+                let scanner = self.scanner.clone();
+                let detector_id = id.clone();
+                let mut device_manager =
+                    DeviceManager::with_device(device, move || scanner.detect(&detector_id));
+                device_manager.set_command_retries(self.command_retries);
+                device_manager.set_event_bus(&id, self.events.clone());
+
+                let restore = match &self.state_store {
+                    Some(state_store) if !self.restore_disabled.contains(&id) => {
+                        device_manager.set_state_keeper(state_store.keeper(&id));
+                        true
+                    }
+                    _ => false,
+                };
+
+                let device_manager = Arc::new(device_manager);
+                self.write_devices()
+                    .insert(id.clone(), device_manager.clone());
+                self.events.publish(connected_event(&id, &device_manager));
+
+                // Restored after the connect event so subscribers see the state change follow it
+                if restore {
+                    if let Err(e) = device_manager.restore_state() {
+                        eprintln!("Failed to restore state of {}: {}", id, e);
+                    }
+                }
+                found.push(id);
+            } else {
+                self.lock_unresponsive_ports().insert(port_name);
+            }
+        }
+
+        found
+    }
+
+    ///
+    /// Returns the device with the given id.
+    ///
+    pub fn device(&self, id: &str) -> Option<Arc<DeviceManager>> {
+        self.scan_once().ok();
+        self.read_devices().get(id).cloned()
+    }
+
+    ///
+    /// Returns the device with the lowest id. Used by the routes that do not name a device.
+    ///
+    pub fn default_device(&self) -> std::result::Result<Arc<DeviceManager>, ControllerError> {
+        self.scan_once()?;
+
+        self.read_devices()
+            .values()
+            .next()
+            .cloned()
+            .ok_or(ControllerError::NoDevicesFound)
+    }
+
+    ///
+    /// Returns every registered device with its id, ordered by id.
+    ///
+    pub fn devices(&self) -> Vec<(String, Arc<DeviceManager>)> {
+        self.scan_once().ok();
+        self.read_devices()
+            .iter()
+            .map(|(id, device_manager)| (id.clone(), device_manager.clone()))
+            .collect()
+    }
+
+    ///
+    /// Returns if a device is registered under the given id.
+    ///
+    fn contains(&self, id: &str) -> bool {
+        self.read_devices().contains_key(id)
+    }
+
+    ///
+    /// Scans the ports if they have never been scanned.
+    ///
+    fn scan_once(&self) -> std::result::Result<(), ControllerError> {
+        let mut scanned = self.lock_scanned();
+
+        if *scanned {
+            return Ok(());
+        }
+        *scanned = true;
+
+        let port_infos = (self.scanner.list_ports)()?;
+        self.scan_ports(port_infos);
+        Ok(())
+    }
+
+    ///
+    /// Locks the scanned flag, serializing scans.
+    ///
+    fn lock_scanned(&self) -> MutexGuard<'_, bool> {
+        match self.scanned.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    ///
+    /// Locks the ports that did not answer as a LEDSC device.
+    ///
+    fn lock_unresponsive_ports(&self) -> MutexGuard<'_, HashSet<String>> {
+        match self.unresponsive_ports.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    ///
+    /// Locks the devices for reading. A poisoned lock is recovered since the map is only changed
+    /// by single inserts.
+    ///
+    fn read_devices(&self) -> RwLockReadGuard<'_, BTreeMap<String, Arc<DeviceManager>>> {
+        match self.devices.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    ///
+    /// Locks the devices for writing.
+    ///
+    fn write_devices(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Arc<DeviceManager>>> {
+        match self.devices.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+///
+/// Returns the connected event for a registered device.
+///
+fn connected_event(id: &str, device_manager: &DeviceManager) -> DeviceEvent {
+    DeviceEvent::Connected {
+        device_id: String::from(id),
+        port_name: device_manager
+            .port_info()
+            .map(|port_info| port_info.port_name)
+            .unwrap_or_default(),
+        firmware_version: device_manager.firmware_version().unwrap_or_default(),
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        DeviceRegistry::new()
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::controller::BoxedTransport;
+    use crate::led_strip_controller::events::DeviceEvent;
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::registry::{device_id, DeviceRegistry};
+    use crate::led_strip_controller::simulator::Simulator;
+    use crate::led_strip_controller::transport::MemoryTransport;
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn usb_port_info(port_name: &str, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: String::from(port_name),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x16c0,
+                pid: 0x0483,
+                serial_number: serial_number.map(String::from),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    ///
+    /// Registry over two simulated devices and a silent port
+    ///
+    fn simulated_registry() -> DeviceRegistry {
+        DeviceRegistry::with_ports(
+            || {
+                Ok(vec![
+                    usb_port_info("/dev/ttyACM0", Some("4429180")),
+                    SerialPortInfo {
+                        port_name: String::from("/dev/ttyS0"),
+                        port_type: SerialPortType::Unknown,
+                    },
+                    usb_port_info("/dev/ttyACM1", Some("1234567")),
+                ])
+            },
+            |port_info| {
+                if port_info.port_name.starts_with("/dev/ttyACM") {
+                    Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport)
+                } else {
+                    Ok(Box::new(MemoryTransport::new()) as BoxedTransport)
+                }
+            },
+        )
+    }
+
+    #[test]
+    fn device_id_test() {
+        assert_eq!(
+            device_id(&usb_port_info("/dev/ttyACM0", Some("4429180"))),
+            "4429180"
+        );
+        assert_eq!(
+            device_id(&usb_port_info("/dev/ttyACM1", None)),
+            "16c0-0483-ttyACM1"
+        );
+        assert_eq!(
+            device_id(&usb_port_info("COM3", Some("A1:B2 C3"))),
+            "A1_B2_C3"
+        );
+        assert_eq!(
+            device_id(&SerialPortInfo {
+                port_name: String::from("/tmp/ledsc_sim"),
+                port_type: SerialPortType::Unknown,
+            }),
+            "ledsc_sim"
+        );
+    }
+
+    #[test]
+    fn registry_scan_test() {
+        let mut registry = simulated_registry();
+        registry.set_alias("/dev/ttyACM1", "porch");
+
+        let ids: Vec<String> = registry.devices().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec!["4429180", "porch"]);
+
+        // Nothing new on a second scan
+        assert!(registry.scan().unwrap().is_empty());
+        assert!(registry.device("ttyS0").is_none());
+
+        // Each device keeps its own state
+        let porch = registry.device("porch").unwrap();
+        assert!(porch.send_command(Command::SetEffect(Effect::Fire)).is_ok());
+
+        let default_device = registry.default_device().unwrap();
+        assert_eq!(
+            default_device.port_info().unwrap().port_name,
+            "/dev/ttyACM0"
+        );
+
+        let protocol = default_device.protocol().unwrap();
+        let pkt = default_device.send_command(Command::GetStatus).unwrap();
+        assert_eq!(
+            protocol.parse_device_status(&pkt).unwrap().effect,
+            Effect::Off
+        );
+
+        let pkt = porch.send_command(Command::GetStatus).unwrap();
+        assert_eq!(
+            protocol.parse_device_status(&pkt).unwrap().effect,
+            Effect::Fire
+        );
+    }
+
+    #[test]
+    fn registry_sync_test() {
+        let ports = Arc::new(Mutex::new(vec![
+            usb_port_info("/dev/ttyACM0", Some("4429180")),
+            SerialPortInfo {
+                port_name: String::from("/dev/ttyS0"),
+                port_type: SerialPortType::Unknown,
+            },
+        ]));
+        let silent_opens = Arc::new(AtomicUsize::new(0));
+
+        let listed_ports = ports.clone();
+        let counted_opens = silent_opens.clone();
+        let registry = DeviceRegistry::with_ports(
+            move || Ok(listed_ports.lock().unwrap().clone()),
+            move |port_info| {
+                if port_info.port_name.starts_with("/dev/ttyACM") {
+                    Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport)
+                } else {
+                    counted_opens.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(MemoryTransport::new()) as BoxedTransport)
+                }
+            },
+        );
+
+        let events = registry.sync().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name(), "connected");
+
+        // The device shows up on another port and keeps its manager
+        let device_manager = registry.device("4429180").unwrap();
+        ports.lock().unwrap()[0] = usb_port_info("/dev/ttyACM1", Some("4429180"));
+        assert!(registry.sync().unwrap().is_empty());
+        assert!(Arc::ptr_eq(
+            &registry.device("4429180").unwrap(),
+            &device_manager
+        ));
+        assert!(device_manager.port_info().is_none());
+        assert!(device_manager.send_command(Command::GetStatus).is_ok());
+        assert_eq!(
+            device_manager.port_info().unwrap().port_name,
+            "/dev/ttyACM1"
+        );
+
+        // Its port goes away
+        let moved_port = ports.lock().unwrap().remove(0);
+        let events = registry.sync().unwrap();
+        assert_eq!(
+            events,
+            vec![DeviceEvent::Disconnected {
+                device_id: String::from("4429180"),
+                port_name: Some(String::from("/dev/ttyACM1")),
+            }]
+        );
+        assert!(registry.device("4429180").is_none());
+
+        // And comes back
+        ports.lock().unwrap().insert(0, moved_port);
+        let events = registry.sync().unwrap();
+        assert_eq!(
+            events,
+            vec![DeviceEvent::Connected {
+                device_id: String::from("4429180"),
+                port_name: String::from("/dev/ttyACM1"),
+                firmware_version: String::from("LEDSC_TEENSY_001"),
+            }]
+        );
+
+        // The silent port is only probed again after it goes away
+        assert_eq!(silent_opens.load(Ordering::SeqCst), 1);
+        ports.lock().unwrap().truncate(1);
+        assert!(registry.sync().unwrap().is_empty());
+        ports.lock().unwrap().push(SerialPortInfo {
+            port_name: String::from("/dev/ttyS0"),
+            port_type: SerialPortType::Unknown,
+        });
+        assert!(registry.sync().unwrap().is_empty());
+        assert_eq!(silent_opens.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn registry_sync_keeps_failed_device_test() {
+        let responsive = Arc::new(AtomicBool::new(true));
+
+        let answering = responsive.clone();
+        let registry = DeviceRegistry::with_ports(
+            || Ok(vec![usb_port_info("/dev/ttyACM0", Some("4429180"))]),
+            move |_port_info| {
+                let answering = answering.clone();
+                let mut simulator = Simulator::new();
+                Ok(Box::new(MemoryTransport::with_responder(move |bytes| {
+                    if answering.load(Ordering::SeqCst) {
+                        simulator.receive(bytes)
+                    } else {
+                        vec![]
+                    }
+                })) as BoxedTransport)
+            },
+        );
+
+        assert_eq!(registry.sync().unwrap().len(), 1);
+        let device_manager = registry.device("4429180").unwrap();
+
+        // A timed out command drops the connection but not the device
+        responsive.store(false, Ordering::SeqCst);
+        assert!(device_manager.send_command(Command::GetStatus).is_err());
+        assert!(device_manager.port_info().is_none());
+        assert!(registry.sync().unwrap().is_empty());
+        assert!(Arc::ptr_eq(
+            &registry.device("4429180").unwrap(),
+            &device_manager
+        ));
+
+        // The same manager reconnects once the device answers again
+        responsive.store(true, Ordering::SeqCst);
+        assert!(device_manager.send_command(Command::GetStatus).is_ok());
+        assert!(registry.sync().unwrap().is_empty());
+        assert_eq!(registry.devices().len(), 1);
+    }
+
+    #[test]
+    fn registry_no_devices_test() {
+        let registry = DeviceRegistry::with_ports(
+            || Ok(vec![]),
+            |_port_info| Ok(Box::new(MemoryTransport::new()) as BoxedTransport),
+        );
+
+        assert!(registry.devices().is_empty());
+        assert!(registry.default_device().is_err());
+    }
+}
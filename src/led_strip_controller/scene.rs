@@ -0,0 +1,408 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::controller::*;
+use crate::led_strip_controller::group::{for_each_member, MemberResult};
+use crate::led_strip_controller::registry::DeviceRegistry;
+use crate::led_strip_controller::state::DeviceState;
+use crate::led_strip_controller::store::{recover, write_atomically};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::{fmt, fs, io};
+
+/// Longest scene or schedule name accepted
+const MAX_NAME_LEN: usize = 64;
+
+///
+/// Formats scenes are exported and imported in. Both hold a table of scenes by name.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneFormat {
+    Json,
+    Toml,
+}
+
+impl SceneFormat {
+    ///
+    /// Writes a table of scenes by name in this format.
+    ///
+    pub fn write<T: Serialize>(self, scenes: &T) -> std::result::Result<String, SceneError> {
+        match self {
+            SceneFormat::Json => serde_json::to_string_pretty(scenes).map_err(SceneError::Json),
+            SceneFormat::Toml => toml::to_string(scenes).map_err(SceneError::TomlWrite),
+        }
+    }
+
+    ///
+    /// Reads a table of scenes by name written in this format.
+    ///
+    pub fn read<T: DeserializeOwned>(self, contents: &str) -> std::result::Result<T, SceneError> {
+        match self {
+            SceneFormat::Json => serde_json::from_str(contents).map_err(SceneError::Json),
+            SceneFormat::Toml => toml::from_str(contents).map_err(SceneError::TomlRead),
+        }
+    }
+}
+
+impl FromStr for SceneFormat {
+    type Err = SceneError;
+
+    fn from_str(format: &str) -> std::result::Result<SceneFormat, SceneError> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Ok(SceneFormat::Json),
+            "toml" => Ok(SceneFormat::Toml),
+            _ => Err(SceneError::UnknownFormat(String::from(format))),
+        }
+    }
+}
+
+///
+/// Errors managing scenes.
+///
+#[derive(Debug)]
+pub enum SceneError {
+    /// Scene names must pass is_valid_name
+    InvalidName(String),
+    /// A scene needs at least one setting
+    NoSettings(String),
+    /// Only JSON and TOML are supported
+    UnknownFormat(String),
+    /// Reading or writing the scenes file failed
+    Io(io::Error),
+    /// The scenes file or imported JSON is not valid
+    Json(serde_json::Error),
+    /// Imported TOML is not valid
+    TomlRead(toml::de::Error),
+    /// Scenes could not be written as TOML
+    TomlWrite(toml::ser::Error),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::InvalidName(name) => write!(f, "Invalid scene name '{}'", name),
+            SceneError::NoSettings(name) => write!(f, "Scene '{}' has no settings", name),
+            SceneError::UnknownFormat(format) => write!(f, "Unknown scene format '{}'", format),
+            SceneError::Io(e) => write!(f, "Failed to access scenes file: {}", e),
+            SceneError::Json(e) => write!(f, "Malformed scenes JSON: {}", e),
+            SceneError::TomlRead(e) => write!(f, "Malformed scenes TOML: {}", e),
+            SceneError::TomlWrite(e) => write!(f, "Failed to write scenes TOML: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneError::Io(e) => Some(e),
+            SceneError::Json(e) => Some(e),
+            SceneError::TomlRead(e) => Some(e),
+            SceneError::TomlWrite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Returns true if the name is 1 to 64 characters without '/', control characters or
+/// surrounding spaces. Such names fit in a URL path segment.
+///
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && name.trim() == name
+        && !name.chars().any(|c| c == '/' || c.is_control())
+}
+
+///
+/// Sends the settings of a scene to a device, back to back over its held connection.
+///
+pub fn apply_scene(
+    device_manager: &DeviceManager,
+    scene: &DeviceState,
+) -> std::result::Result<(), ControllerError> {
+    let mut session = device_manager.session();
+    for command in scene.commands() {
+        session.send_command(command)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Applies a scene to every member in parallel. Results are returned in member order.
+///
+pub fn apply_scene_to_group(
+    registry: &DeviceRegistry,
+    members: &[String],
+    scene: &DeviceState,
+) -> Vec<MemberResult<()>> {
+    for_each_member(registry, members, |device_manager| {
+        apply_scene(device_manager, scene)
+    })
+}
+
+///
+/// Named scenes, each a combination of effect, color, brightness and fire pallet. Scenes are
+/// saved to a JSON file, if one is given, every time they change.
+///
+pub struct SceneStore {
+    path: Option<PathBuf>,
+    scenes: RwLock<BTreeMap<String, DeviceState>>,
+}
+
+impl SceneStore {
+    ///
+    /// Creates a store that only keeps scenes in memory.
+    ///
+    pub fn new() -> SceneStore {
+        SceneStore {
+            path: None,
+            scenes: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    ///
+    /// Creates a store saved to the given file, loading the scenes already in it. A missing file
+    /// starts out with no scenes.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> std::result::Result<SceneStore, SceneError> {
+        let path = path.as_ref().to_path_buf();
+
+        let scenes = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(SceneError::Json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(SceneError::Io(e)),
+        };
+
+        Ok(SceneStore {
+            path: Some(path),
+            scenes: RwLock::new(scenes),
+        })
+    }
+
+    ///
+    /// Returns every scene, ordered by name.
+    ///
+    pub fn scenes(&self) -> Vec<(String, DeviceState)> {
+        recover(self.scenes.read())
+            .iter()
+            .map(|(name, scene)| (name.clone(), *scene))
+            .collect()
+    }
+
+    ///
+    /// Returns the named scene.
+    ///
+    pub fn scene(&self, name: &str) -> Option<DeviceState> {
+        recover(self.scenes.read()).get(name).copied()
+    }
+
+    ///
+    /// Creates or replaces a scene.
+    ///
+    pub fn set_scene(&self, name: &str, scene: DeviceState) -> std::result::Result<(), SceneError> {
+        let mut scenes = BTreeMap::new();
+        scenes.insert(String::from(name), scene);
+        self.set_scenes(scenes)
+    }
+
+    ///
+    /// Removes a scene. Returns false if there was no such scene.
+    ///
+    pub fn remove_scene(&self, name: &str) -> std::result::Result<bool, SceneError> {
+        let mut scenes = recover(self.scenes.write());
+        if !scenes.contains_key(name) {
+            return Ok(false);
+        }
+
+        let mut updated = scenes.clone();
+        updated.remove(name);
+        self.save(&updated)?;
+        *scenes = updated;
+
+        Ok(true)
+    }
+
+    ///
+    /// Creates or replaces several scenes at once, then saves them. Nothing is changed if any
+    /// scene is invalid.
+    ///
+    pub fn set_scenes(
+        &self,
+        new_scenes: BTreeMap<String, DeviceState>,
+    ) -> std::result::Result<(), SceneError> {
+        for (name, scene) in &new_scenes {
+            if !is_valid_name(name) {
+                return Err(SceneError::InvalidName(name.clone()));
+            }
+            if scene.commands().is_empty() {
+                return Err(SceneError::NoSettings(name.clone()));
+            }
+        }
+
+        let mut scenes = recover(self.scenes.write());
+        let mut updated = scenes.clone();
+        updated.extend(new_scenes);
+        self.save(&updated)?;
+        *scenes = updated;
+
+        Ok(())
+    }
+
+    ///
+    /// Writes the scenes to the store's file.
+    ///
+    fn save(&self, scenes: &BTreeMap<String, DeviceState>) -> std::result::Result<(), SceneError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = serde_json::to_string_pretty(scenes).map_err(SceneError::Json)?;
+
+        write_atomically(path, contents).map_err(SceneError::Io)
+    }
+}
+
+impl Default for SceneStore {
+    fn default() -> Self {
+        SceneStore::new()
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::controller::BoxedTransport;
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::registry::DeviceRegistry;
+    use crate::led_strip_controller::scene::*;
+    use crate::led_strip_controller::simulator::Simulator;
+    use crate::led_strip_controller::state::DeviceState;
+    use serialport::{SerialPortInfo, SerialPortType};
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    fn movie_night() -> DeviceState {
+        DeviceState {
+            effect: Some(Effect::Fire),
+            color: Some(Color24::from_u32(0xff8800)),
+            brightness: Some(40),
+            fire_pallet: Some(FireColorPallet::Lava),
+        }
+    }
+
+    #[test]
+    fn scene_store_test() {
+        let path = std::env::temp_dir().join(format!("ledsc_scenes_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = SceneStore::open(&path).unwrap();
+        store.set_scene("Movie night", movie_night()).unwrap();
+        assert!(matches!(
+            store.set_scene("Movie/night", movie_night()),
+            Err(SceneError::InvalidName(..))
+        ));
+        assert!(matches!(
+            store.set_scene("Empty", DeviceState::default()),
+            Err(SceneError::NoSettings(..))
+        ));
+
+        // Scenes survive a reload
+        let store = SceneStore::open(&path).unwrap();
+        assert_eq!(store.scene("Movie night"), Some(movie_night()));
+        assert!(store.remove_scene("Movie night").unwrap());
+        assert!(!store.remove_scene("Movie night").unwrap());
+        assert!(SceneStore::open(&path).unwrap().scenes().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn scene_format_test() {
+        let store = SceneStore::new();
+        store.set_scene("Movie night", movie_night()).unwrap();
+        let alarm_red = DeviceState {
+            effect: Some(Effect::SolidColorPulse),
+            color: Some(Color24::from_u32(0xff0000)),
+            ..DeviceState::default()
+        };
+        store.set_scene("Alarm red", alarm_red).unwrap();
+        let scenes: BTreeMap<String, DeviceState> = store.scenes().into_iter().collect();
+
+        for format in &["json", "toml"] {
+            let format: SceneFormat = format.parse().unwrap();
+            let written = format.write(&scenes).unwrap();
+
+            let read: BTreeMap<String, DeviceState> = format.read(&written).unwrap();
+            let imported = SceneStore::new();
+            imported.set_scenes(read).unwrap();
+            assert_eq!(imported.scenes(), store.scenes());
+        }
+
+        let toml = "[\"Alarm red\"]\neffect = \"solid_color_pulse\"\ncolor = \"#ff0000\"\n";
+        let read: BTreeMap<String, DeviceState> = SceneFormat::Toml.read(toml).unwrap();
+        assert_eq!(read["Alarm red"], alarm_red);
+
+        // Invalid scenes leave the store unchanged
+        let imported = SceneStore::new();
+        let mut invalid = BTreeMap::new();
+        invalid.insert(String::from("Movie night"), movie_night());
+        invalid.insert(String::new(), movie_night());
+        assert!(imported.set_scenes(invalid).is_err());
+        assert!(SceneFormat::Toml
+            .read::<BTreeMap<String, DeviceState>>("not toml")
+            .is_err());
+        assert!(imported.scenes().is_empty());
+        assert!("yaml".parse::<SceneFormat>().is_err());
+    }
+
+    #[test]
+    fn apply_scene_test() {
+        let registry = DeviceRegistry::with_ports(
+            || {
+                Ok(vec![SerialPortInfo {
+                    port_name: String::from("/dev/ttyACM0"),
+                    port_type: SerialPortType::Unknown,
+                }])
+            },
+            |_port_info| Ok(Box::new(Simulator::new().into_transport()) as BoxedTransport),
+        );
+        registry.scan().unwrap();
+
+        let members = vec![String::from("ttyACM0"), String::from("missing")];
+        let results = apply_scene_to_group(&registry, &members, &movie_night());
+        assert!(results[0].result.is_ok());
+        assert!(results[1].result.is_err());
+
+        let device_manager = registry.device("ttyACM0").unwrap();
+        let protocol = device_manager.protocol().unwrap();
+        let pkt = device_manager.send_command(Command::GetStatus).unwrap();
+        let status = protocol.parse_device_status(&pkt).unwrap();
+        assert_eq!(DeviceState::from(&status), movie_night());
+    }
+}
@@ -0,0 +1,977 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::group::{GroupStore, MemberResult};
+use crate::led_strip_controller::registry::DeviceRegistry;
+use crate::led_strip_controller::scene::{apply_scene_to_group, is_valid_name, SceneStore};
+use crate::led_strip_controller::state::DeviceState;
+use crate::led_strip_controller::store::{recover, write_atomically};
+use crate::led_strip_controller::sun::{next_sun_event, Location, SunEvent};
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use std::{fmt, fs, io, thread};
+
+/// Time between checks for due schedules
+pub const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+
+/// A run due longer ago than this was missed, because the server was down or suspended
+const MISSED_RUN_GRACE_SECS: i64 = 120;
+
+/// Days searched for the next match of a cron expression. Covers a February 29th falling on
+/// a given weekday.
+const MAX_CRON_SEARCH_DAYS: i64 = 366 * 28;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+///
+/// Cron expression in server local time: minute, hour, day of month, month and day of week.
+/// Fields take '*', numbers, ranges such as 1-5, lists such as 1,15 and steps such as */10.
+/// Months and days of week may be named, jan-dec and sun-sat, and Sunday is 0 or 7. When both
+/// day of month and day of week are restricted a day matching either runs. @hourly, @daily,
+/// @weekly, @monthly and @yearly are accepted as shorthands.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u32,
+    days_of_month: u32,
+    months: u16,
+    days_of_week: u8,
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl CronSchedule {
+    ///
+    /// Returns the first time matching the expression strictly after the given time. Local
+    /// times skipped by a daylight saving change never match.
+    ///
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = after.with_timezone(&Local).naive_local();
+        let mut date = local.date();
+        // Matching minutes up to and including the current one are already past
+        let mut earliest = Some((local.hour(), local.minute() + 1));
+
+        for _ in 0..MAX_CRON_SEARCH_DAYS {
+            if self.matches_date(date) {
+                for hour in 0..24 {
+                    if self.hours & (1 << hour) == 0 {
+                        continue;
+                    }
+                    for minute in 0..60 {
+                        if self.minutes & (1 << minute) == 0
+                            || earliest.map_or(false, |start| (hour, minute) < start)
+                        {
+                            continue;
+                        }
+
+                        let time = Local.from_local_datetime(&date.and_hms(hour, minute, 0));
+                        if let Some(time) = time.earliest() {
+                            let time = time.with_timezone(&Utc);
+                            if time > after {
+                                return Some(time);
+                            }
+                        }
+                    }
+                }
+            }
+
+            earliest = None;
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+
+    ///
+    /// Returns true if the day, month and day of week of the date match.
+    ///
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+
+        let day_of_month = self.days_of_month & (1 << date.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = ScheduleError;
+
+    fn from_str(expression: &str) -> std::result::Result<CronSchedule, ScheduleError> {
+        let invalid =
+            |reason: &str| ScheduleError::InvalidCron(format!("'{}' {}", expression, reason));
+
+        let fields: Vec<&str> = match expression.trim() {
+            "@hourly" => vec!["0", "*", "*", "*", "*"],
+            "@daily" => vec!["0", "0", "*", "*", "*"],
+            "@weekly" => vec!["0", "0", "*", "*", "0"],
+            "@monthly" => vec!["0", "0", "1", "*", "*"],
+            "@yearly" => vec!["0", "0", "1", "1", "*"],
+            expression => expression.split_whitespace().collect(),
+        };
+        if fields.len() != 5 {
+            return Err(invalid("does not have 5 fields"));
+        }
+
+        let field = |index: usize, min: u32, max: u32, names: &[&str]| {
+            parse_cron_field(fields[index], min, max, names).map_err(|reason| invalid(&reason))
+        };
+
+        let days_of_week = field(4, 0, 7, &WEEKDAY_NAMES)?;
+        Ok(CronSchedule {
+            expression: String::from(expression.trim()),
+            minutes: field(0, 0, 59, &[])?,
+            hours: field(1, 0, 23, &[])? as u32,
+            days_of_month: field(2, 1, 31, &[])? as u32,
+            months: field(3, 1, 12, &MONTH_NAMES)? as u16,
+            // Sunday is both 0 and 7
+            days_of_week: ((days_of_week | days_of_week >> 7) & 0x7f) as u8,
+            days_of_month_restricted: fields[2] != "*",
+            days_of_week_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Serialize for CronSchedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CronSchedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+///
+/// Parses a cron field into a bit set of the values it matches. Names are matched
+/// case-insensitively and stand for min plus their index.
+///
+fn parse_cron_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> std::result::Result<u64, String> {
+    let value = |text: &str| -> std::result::Result<u32, String> {
+        let lower = text.to_ascii_lowercase();
+        match names.iter().position(|name| *name == lower) {
+            Some(index) => Ok(min + index as u32),
+            None => text
+                .parse()
+                .map_err(|_| format!("has an invalid value '{}'", text)),
+        }
+    };
+
+    let mut bits: u64 = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("has an invalid step '{}'", step)),
+            },
+            None => (part, None),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else {
+            let start = value(range)?;
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!("has '{}' outside {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+///
+/// When a schedule runs.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// Every time the cron expression matches
+    Cron(CronSchedule),
+    /// Once, at the given time
+    At(DateTime<Utc>),
+    /// Every day, relative to an event of the sun at the store's location
+    Sun(SunTrigger),
+}
+
+impl Trigger {
+    ///
+    /// Returns the first run strictly after the given time. Sun triggers never run without a
+    /// location.
+    ///
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        location: Option<&Location>,
+    ) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron(cron) => cron.next_after(after),
+            Trigger::At(at) => Some(*at).filter(|at| *at > after),
+            Trigger::Sun(sun) => {
+                let offset = chrono::Duration::minutes(i64::from(sun.offset_minutes));
+                next_sun_event(location?, sun.event, after - offset).map(|time| time + offset)
+            }
+        }
+    }
+}
+
+///
+/// A daily time relative to sunrise, sunset or civil twilight.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SunTrigger {
+    pub event: SunEvent,
+    /// Minutes after the event, negative for before it
+    #[serde(default)]
+    pub offset_minutes: i32,
+}
+
+///
+/// What a schedule does when it runs.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    /// Applies the named scene
+    Scene(String),
+    /// Applies the given settings
+    State(DeviceState),
+}
+
+///
+/// Devices a schedule applies to.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTarget {
+    Device(String),
+    Group(String),
+}
+
+///
+/// What to do about runs missed while the server was down or suspended.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    /// Missed runs are dropped
+    #[default]
+    Skip,
+    /// The schedule runs once to catch up, however many runs were missed
+    RunOnce,
+}
+
+///
+/// An action run on devices at the times given by its trigger.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub trigger: Trigger,
+    pub action: ScheduleAction,
+    pub target: ScheduleTarget,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub missed_runs: MissedRuns,
+    /// Runs due up to this time have been handled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handled_until: Option<DateTime<Utc>>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Schedule {
+    ///
+    /// Returns the next time the schedule runs, None if it is disabled or will not run again.
+    ///
+    pub fn next_run(
+        &self,
+        now: DateTime<Utc>,
+        location: Option<&Location>,
+    ) -> Option<DateTime<Utc>> {
+        if !self.enabled {
+            return None;
+        }
+        self.trigger
+            .next_after(self.handled_until.unwrap_or(now), location)
+    }
+
+    ///
+    /// Returns up to count of the schedule's next runs.
+    ///
+    pub fn upcoming_runs(
+        &self,
+        now: DateTime<Utc>,
+        location: Option<&Location>,
+        count: usize,
+    ) -> Vec<DateTime<Utc>> {
+        let mut runs: Vec<DateTime<Utc>> = vec![];
+        let mut next = self.next_run(now, location);
+
+        while let Some(run) = next {
+            if runs.len() >= count {
+                break;
+            }
+            runs.push(run);
+            next = self.trigger.next_after(run, location);
+        }
+
+        runs
+    }
+}
+
+///
+/// Applies a schedule's action to its target. Members are applied in parallel and their results
+/// returned in member order.
+///
+pub fn run_schedule(
+    schedule: &Schedule,
+    registry: &DeviceRegistry,
+    groups: &GroupStore,
+    scenes: &SceneStore,
+) -> std::result::Result<Vec<MemberResult<()>>, ScheduleError> {
+    let state = match &schedule.action {
+        ScheduleAction::Scene(name) => scenes
+            .scene(name)
+            .ok_or_else(|| ScheduleError::UnknownScene(name.clone()))?,
+        ScheduleAction::State(state) => *state,
+    };
+
+    let members = match &schedule.target {
+        ScheduleTarget::Device(device_id) => vec![device_id.clone()],
+        ScheduleTarget::Group(name) => groups
+            .members(name)
+            .ok_or_else(|| ScheduleError::UnknownGroup(name.clone()))?,
+    };
+
+    Ok(apply_scene_to_group(registry, &members, &state))
+}
+
+///
+/// Errors managing or running schedules.
+///
+#[derive(Debug)]
+pub enum ScheduleError {
+    /// Schedule names follow the rules of scene names
+    InvalidName(String),
+    /// The cron expression does not parse
+    InvalidCron(String),
+    /// A state action needs at least one setting
+    NoSettings,
+    /// Sun triggers need the store to have a location
+    NoLocation,
+    /// The scene a schedule applies does not exist
+    UnknownScene(String),
+    /// The group a schedule applies to does not exist
+    UnknownGroup(String),
+    /// Reading or writing the schedules file failed
+    Io(io::Error),
+    /// The schedules file is not valid JSON
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::InvalidName(name) => write!(f, "Invalid schedule name '{}'", name),
+            ScheduleError::InvalidCron(reason) => write!(f, "Invalid cron expression {}", reason),
+            ScheduleError::NoSettings => write!(f, "Schedule has no settings to apply"),
+            ScheduleError::NoLocation => write!(f, "Sun triggers need a location"),
+            ScheduleError::UnknownScene(name) => write!(f, "Unknown scene {}", name),
+            ScheduleError::UnknownGroup(name) => write!(f, "Unknown group {}", name),
+            ScheduleError::Io(e) => write!(f, "Failed to access schedules file: {}", e),
+            ScheduleError::Format(e) => write!(f, "Malformed schedules file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScheduleError::Io(e) => Some(e),
+            ScheduleError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Named schedules. Schedules are saved to a JSON file, if one is given, every time they change
+/// or run, so missed runs are known after a restart.
+///
+pub struct ScheduleStore {
+    path: Option<PathBuf>,
+    location: Option<Location>,
+    schedules: RwLock<BTreeMap<String, Schedule>>,
+}
+
+impl ScheduleStore {
+    ///
+    /// Creates a store that only keeps schedules in memory.
+    ///
+    pub fn new() -> ScheduleStore {
+        ScheduleStore {
+            path: None,
+            location: None,
+            schedules: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    ///
+    /// Creates a store saved to the given file, loading the schedules already in it. A missing
+    /// file starts out with no schedules.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> std::result::Result<ScheduleStore, ScheduleError> {
+        let path = path.as_ref().to_path_buf();
+
+        let schedules = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(ScheduleError::Format)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(ScheduleError::Io(e)),
+        };
+
+        Ok(ScheduleStore {
+            path: Some(path),
+            location: None,
+            schedules: RwLock::new(schedules),
+        })
+    }
+
+    ///
+    /// Sets the location sun triggers are computed for.
+    ///
+    pub fn with_location(mut self, location: Location) -> ScheduleStore {
+        self.location = Some(location);
+        self
+    }
+
+    ///
+    /// Returns the location sun triggers are computed for.
+    ///
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    ///
+    /// Returns every schedule, ordered by name.
+    ///
+    pub fn schedules(&self) -> Vec<(String, Schedule)> {
+        recover(self.schedules.read())
+            .iter()
+            .map(|(name, schedule)| (name.clone(), schedule.clone()))
+            .collect()
+    }
+
+    ///
+    /// Returns the named schedule.
+    ///
+    pub fn schedule(&self, name: &str) -> Option<Schedule> {
+        recover(self.schedules.read()).get(name).cloned()
+    }
+
+    ///
+    /// Creates or replaces a schedule. Its runs are counted from now, so a new schedule never
+    /// catches up on runs before it existed.
+    ///
+    pub fn set_schedule(
+        &self,
+        name: &str,
+        mut schedule: Schedule,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<(), ScheduleError> {
+        if !is_valid_name(name) {
+            return Err(ScheduleError::InvalidName(String::from(name)));
+        }
+        if let ScheduleAction::State(state) = &schedule.action {
+            if state.commands().is_empty() {
+                return Err(ScheduleError::NoSettings);
+            }
+        }
+        if let Trigger::Sun(_) = schedule.trigger {
+            if self.location.is_none() {
+                return Err(ScheduleError::NoLocation);
+            }
+        }
+        schedule.handled_until = Some(now);
+
+        self.update(|schedules| {
+            schedules.insert(String::from(name), schedule);
+            true
+        })
+    }
+
+    ///
+    /// Enables or disables a schedule. Runs missed while a schedule was disabled are dropped.
+    /// Returns false if there is no such schedule.
+    ///
+    pub fn set_enabled(
+        &self,
+        name: &str,
+        enabled: bool,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<bool, ScheduleError> {
+        let mut found = false;
+        self.update(|schedules| match schedules.get_mut(name) {
+            Some(schedule) => {
+                found = true;
+                if schedule.enabled != enabled {
+                    schedule.enabled = enabled;
+                    schedule.handled_until = Some(now);
+                }
+                true
+            }
+            None => false,
+        })?;
+
+        Ok(found)
+    }
+
+    ///
+    /// Removes a schedule. Returns false if there was no such schedule.
+    ///
+    pub fn remove_schedule(&self, name: &str) -> std::result::Result<bool, ScheduleError> {
+        let mut found = false;
+        self.update(|schedules| {
+            found = schedules.remove(name).is_some();
+            found
+        })?;
+
+        Ok(found)
+    }
+
+    ///
+    /// Returns the schedules due at the given time and marks them handled. A run due longer ago
+    /// than the grace period was missed and is only returned if the schedule catches up on
+    /// missed runs.
+    ///
+    pub fn take_due(
+        &self,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<Vec<(String, Schedule)>, ScheduleError> {
+        let mut due: Vec<(String, Schedule)> = vec![];
+
+        let location = self.location;
+        self.update(|schedules| {
+            let mut changed = false;
+            for (name, schedule) in schedules.iter_mut() {
+                let next_run = match schedule.next_run(now, location.as_ref()) {
+                    Some(next_run) if next_run <= now => next_run,
+                    _ => continue,
+                };
+
+                schedule.handled_until = Some(now);
+                changed = true;
+                let missed = (now - next_run).num_seconds() > MISSED_RUN_GRACE_SECS;
+                if missed && schedule.missed_runs == MissedRuns::Skip {
+                    println!("Skipping missed run of schedule {} due {}", name, next_run);
+                } else {
+                    due.push((name.clone(), schedule.clone()));
+                }
+            }
+            changed
+        })?;
+
+        Ok(due)
+    }
+
+    ///
+    /// Changes the schedules and saves them if the change function returns true.
+    ///
+    fn update<F>(&self, change: F) -> std::result::Result<(), ScheduleError>
+    where
+        F: FnOnce(&mut BTreeMap<String, Schedule>) -> bool,
+    {
+        let mut schedules = recover(self.schedules.write());
+        let mut updated = schedules.clone();
+        if !change(&mut updated) {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            let contents = serde_json::to_string_pretty(&updated).map_err(ScheduleError::Format)?;
+
+            write_atomically(path, contents).map_err(ScheduleError::Io)?;
+        }
+        *schedules = updated;
+
+        Ok(())
+    }
+}
+
+impl Default for ScheduleStore {
+    fn default() -> Self {
+        ScheduleStore::new()
+    }
+}
+
+///
+/// Starts a background thread passing each due schedule to run. Schedules are checked every
+/// tick. The thread ends once the store is dropped.
+///
+pub fn spawn_scheduler<F>(
+    schedules: &Arc<ScheduleStore>,
+    tick: Duration,
+    run: F,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    F: Fn(&str, &Schedule) + Send + 'static,
+{
+    let schedules: Weak<ScheduleStore> = Arc::downgrade(schedules);
+
+    thread::Builder::new()
+        .name(String::from("scheduler"))
+        .spawn(move || {
+            while let Some(store) = schedules.upgrade() {
+                match store.take_due(Utc::now()) {
+                    Ok(due) => {
+                        for (name, schedule) in &due {
+                            run(name, schedule);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to update schedules: {}", e),
+                }
+
+                drop(store);
+                thread::sleep(tick);
+            }
+        })
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::schedule::*;
+    use crate::led_strip_controller::sun::*;
+    use chrono::Duration as TimeDelta;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Local
+            .ymd(year, month, day)
+            .and_hms(hour, minute, 0)
+            .with_timezone(&Utc)
+    }
+
+    fn lights_off(trigger: Trigger, missed_runs: MissedRuns) -> Schedule {
+        Schedule {
+            trigger,
+            action: ScheduleAction::State(DeviceState {
+                effect: Some(Effect::Off),
+                ..DeviceState::default()
+            }),
+            target: ScheduleTarget::Group(String::from("all")),
+            enabled: true,
+            missed_runs,
+            handled_until: None,
+        }
+    }
+
+    #[test]
+    fn cron_next_after_test() {
+        let cron: CronSchedule = "0 23 * * *".parse().unwrap();
+        assert_eq!(
+            cron.next_after(local(2026, 10, 16, 22, 30)),
+            Some(local(2026, 10, 16, 23, 0))
+        );
+        // A matching time is only returned strictly after the given one
+        assert_eq!(
+            cron.next_after(local(2026, 10, 16, 23, 0)),
+            Some(local(2026, 10, 17, 23, 0))
+        );
+
+        // 2026-10-16 is a Friday
+        let weekdays: CronSchedule = "0 7 * * MON-FRI".parse().unwrap();
+        assert_eq!(
+            weekdays.next_after(local(2026, 10, 16, 8, 0)),
+            Some(local(2026, 10, 19, 7, 0))
+        );
+
+        let steps: CronSchedule = "*/20 9-10 1,15 * *".parse().unwrap();
+        assert_eq!(
+            steps.next_after(local(2026, 10, 15, 10, 40)),
+            Some(local(2026, 11, 1, 9, 0))
+        );
+
+        // Restricted day of month and day of week match either
+        let either: CronSchedule = "0 0 13 * fri".parse().unwrap();
+        assert_eq!(
+            either.next_after(local(2026, 10, 12, 0, 0)),
+            Some(local(2026, 10, 13, 0, 0))
+        );
+        assert_eq!(
+            either.next_after(local(2026, 10, 13, 0, 0)),
+            Some(local(2026, 10, 16, 0, 0))
+        );
+
+        let sunday: CronSchedule = "30 6 * * 7".parse().unwrap();
+        assert_eq!(
+            sunday.next_after(local(2026, 10, 16, 0, 0)),
+            Some(local(2026, 10, 18, 6, 30))
+        );
+
+        let leap_day: CronSchedule = "@yearly".parse().unwrap();
+        assert_eq!(leap_day.to_string(), "@yearly");
+        assert_eq!(
+            leap_day.next_after(local(2026, 10, 16, 0, 0)),
+            Some(local(2027, 1, 1, 0, 0))
+        );
+        let never: CronSchedule = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(never.next_after(local(2026, 10, 16, 0, 0)), None);
+
+        for invalid in &[
+            "0 23 * *",
+            "60 * * * *",
+            "* * * * mon-xyz",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(
+                invalid.parse::<CronSchedule>().is_err(),
+                "{} should not parse",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn schedule_serde_test() {
+        let schedule: Schedule = serde_json::from_str(
+            r#"{
+                "trigger": {"cron": "0 7 * * mon-fri"},
+                "action": {"scene": "Warm white"},
+                "target": {"device": "porch"}
+            }"#,
+        )
+        .unwrap();
+        assert!(schedule.enabled);
+        assert_eq!(schedule.missed_runs, MissedRuns::Skip);
+        assert_eq!(
+            schedule.action,
+            ScheduleAction::Scene(String::from("Warm white"))
+        );
+
+        let json = serde_json::to_string(&schedule).unwrap();
+        assert_eq!(serde_json::from_str::<Schedule>(&json).unwrap(), schedule);
+
+        assert!(serde_json::from_str::<Schedule>(
+            r#"{"trigger": {"cron": "bad"}, "action": {"scene": "x"}, "target": {"device": "y"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sun_trigger_test() {
+        let london = Location::new(51.5074, -0.1278).unwrap();
+        let trigger: Trigger =
+            serde_json::from_str(r#"{"sun": {"event": "sunset", "offset_minutes": -30}}"#).unwrap();
+        let noon = Utc.ymd(2021, 6, 21).and_hms(12, 0, 0);
+        let sunset = next_sun_event(&london, SunEvent::Sunset, noon).unwrap();
+        let first_run = sunset - TimeDelta::minutes(30);
+
+        assert_eq!(trigger.next_after(noon, Some(&london)), Some(first_run));
+        assert_eq!(trigger.next_after(noon, None), None);
+        // Past the run but before the sunset, the next run is the next day's
+        let next = trigger
+            .next_after(sunset - TimeDelta::minutes(10), Some(&london))
+            .unwrap();
+        assert!(next > sunset + TimeDelta::hours(23));
+
+        let schedule = lights_off(trigger, MissedRuns::Skip);
+        let runs = schedule.upcoming_runs(noon, Some(&london), 3);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0], first_run);
+        assert!(runs.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert!(matches!(
+            ScheduleStore::new().set_schedule("dusk", schedule.clone(), noon),
+            Err(ScheduleError::NoLocation)
+        ));
+        let store = ScheduleStore::new().with_location(london);
+        store.set_schedule("dusk", schedule, noon).unwrap();
+        assert!(store
+            .take_due(first_run - TimeDelta::seconds(1))
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.take_due(first_run).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn schedule_store_take_due_test() {
+        let store = ScheduleStore::new();
+        let created = local(2026, 10, 16, 22, 0);
+        store
+            .set_schedule(
+                "off",
+                lights_off(
+                    Trigger::Cron("0 23 * * *".parse().unwrap()),
+                    MissedRuns::Skip,
+                ),
+                created,
+            )
+            .unwrap();
+        store
+            .set_schedule(
+                "once",
+                lights_off(
+                    Trigger::At(local(2026, 10, 16, 22, 30)),
+                    MissedRuns::RunOnce,
+                ),
+                created,
+            )
+            .unwrap();
+        assert!(matches!(
+            store.set_schedule(
+                "",
+                lights_off(Trigger::At(created), MissedRuns::Skip),
+                created
+            ),
+            Err(ScheduleError::InvalidName(..))
+        ));
+
+        assert!(store
+            .take_due(local(2026, 10, 16, 22, 29))
+            .unwrap()
+            .is_empty());
+        let due = store.take_due(local(2026, 10, 16, 22, 30)).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "once");
+        // One-shot schedules run once
+        assert!(store
+            .take_due(local(2026, 10, 17, 22, 30))
+            .unwrap()
+            .is_empty());
+
+        // Runs missed for longer than the grace period are skipped by default
+        let late = local(2026, 10, 17, 23, 0) + TimeDelta::minutes(10);
+        assert!(store.take_due(late).unwrap().is_empty());
+        assert_eq!(
+            store.schedule("off").unwrap().next_run(late, None),
+            Some(local(2026, 10, 18, 23, 0))
+        );
+
+        // Catching up runs once however many runs were missed
+        let mut catch_up = store.schedule("off").unwrap();
+        catch_up.missed_runs = MissedRuns::RunOnce;
+        store.set_schedule("off", catch_up, late).unwrap();
+        let due = store.take_due(local(2026, 10, 21, 12, 0)).unwrap();
+        assert_eq!(due.len(), 1);
+        assert!(store
+            .take_due(local(2026, 10, 21, 12, 1))
+            .unwrap()
+            .is_empty());
+
+        // Runs missed while disabled are dropped
+        assert!(store
+            .set_enabled("off", false, local(2026, 10, 21, 12, 1))
+            .unwrap());
+        assert_eq!(store.schedule("off").unwrap().next_run(late, None), None);
+        assert!(store
+            .take_due(local(2026, 10, 23, 12, 0))
+            .unwrap()
+            .is_empty());
+        store
+            .set_enabled("off", true, local(2026, 10, 23, 12, 0))
+            .unwrap();
+        assert!(store
+            .take_due(local(2026, 10, 23, 12, 1))
+            .unwrap()
+            .is_empty());
+        assert!(!store.set_enabled("missing", true, late).unwrap());
+
+        assert!(store.remove_schedule("off").unwrap());
+        assert!(!store.remove_schedule("off").unwrap());
+    }
+
+    #[test]
+    fn schedule_store_persists_test() {
+        let path =
+            std::env::temp_dir().join(format!("ledsc_schedules_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = ScheduleStore::open(&path).unwrap();
+        let created = local(2026, 10, 16, 22, 0);
+        store
+            .set_schedule(
+                "off",
+                lights_off(
+                    Trigger::Cron("0 23 * * *".parse().unwrap()),
+                    MissedRuns::RunOnce,
+                ),
+                created,
+            )
+            .unwrap();
+
+        // A restart after a missed run still knows it was missed
+        let store = ScheduleStore::open(&path).unwrap();
+        assert_eq!(store.schedule("off").unwrap().handled_until, Some(created));
+        assert_eq!(store.take_due(local(2026, 10, 17, 8, 0)).unwrap().len(), 1);
+        let store = ScheduleStore::open(&path).unwrap();
+        assert!(store
+            .take_due(local(2026, 10, 17, 8, 0))
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
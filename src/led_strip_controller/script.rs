@@ -0,0 +1,628 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::color::Color24;
+use crate::led_strip_controller::controller::{ControllerError, DeviceManager, TaskKind};
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::scene::is_valid_name;
+use crate::led_strip_controller::state::DeviceState;
+use crate::led_strip_controller::store::{recover, write_atomically};
+use crate::led_strip_controller::transition::{
+    current_state, hold, send_step, TRANSITION_STEP_INTERVAL,
+};
+use chrono::{Datelike, Local, Timelike};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io, thread};
+
+/// Extension of script files
+const SCRIPT_FILE_EXTENSION: &str = "rhai";
+
+/// Largest script accepted, in bytes
+pub const MAX_SCRIPT_SIZE: usize = 64 * 1024;
+
+/// Time between ticks when none is given
+pub const DEFAULT_SCRIPT_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Longest time between ticks, in milliseconds
+pub const MAX_SCRIPT_INTERVAL_MS: u32 = 86_400_000;
+
+/// Most operations a script may run in one tick
+const MAX_SCRIPT_OPERATIONS: u64 = 100_000;
+
+/// Longest time a script may run in one tick
+const SCRIPT_TIME_LIMIT: Duration = Duration::from_millis(100);
+
+/// Operations run between checks of the time limit
+const TIME_CHECK_OPERATIONS: u64 = 1000;
+
+///
+/// Errors managing and running scripts.
+///
+#[derive(Debug)]
+pub enum ScriptError {
+    /// Script names follow the rules of scene names
+    InvalidName(String),
+    /// The script is larger than MAX_SCRIPT_SIZE
+    TooLarge(usize),
+    /// The script is not valid Rhai
+    Compile(String),
+    /// Reading or writing a script file failed
+    Io(io::Error),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::InvalidName(name) => write!(f, "Invalid script name '{}'", name),
+            ScriptError::TooLarge(size) => write!(
+                f,
+                "Script is {} bytes, at most {} are allowed",
+                size, MAX_SCRIPT_SIZE
+            ),
+            ScriptError::Compile(e) => write!(f, "Script does not compile: {}", e),
+            ScriptError::Io(e) => write!(f, "Failed to access script file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScriptError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Locks a mutex shared with script bindings. A poisoned lock is recovered since a failed tick
+/// only leaves settings behind, which are replaced whole.
+///
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    recover(mutex.lock())
+}
+
+///
+/// Returns an engine with the resource limits scripts run under. Scripts cannot import modules
+/// or reach files, and are stopped once they run too many operations or for too long.
+///
+fn sandboxed_engine(deadline: Arc<Mutex<Instant>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_SCRIPT_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(4096)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .set_max_variables(256)
+        .set_max_functions(256);
+    engine.disable_symbol("eval");
+    engine.on_progress(move |operations| {
+        if operations % TIME_CHECK_OPERATIONS == 0 && Instant::now() > *lock(&deadline) {
+            Some(Dynamic::from("time limit exceeded"))
+        } else {
+            None
+        }
+    });
+    engine
+}
+
+///
+/// Compiles a script, checking it is valid without running it.
+///
+pub fn compile_script(source: &str) -> std::result::Result<AST, ScriptError> {
+    if source.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::TooLarge(source.len()));
+    }
+
+    sandboxed_engine(Arc::new(Mutex::new(Instant::now())))
+        .compile(source)
+        .map_err(|e| ScriptError::Compile(e.to_string()))
+}
+
+/// Result of a function called by scripts
+type BindingResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+///
+/// Returns the error a binding raises for an invalid argument.
+///
+fn binding_error(message: String) -> Box<EvalAltResult> {
+    message.into()
+}
+
+///
+/// Registers the functions scripts change the device with. Settings are collected in the
+/// pending state and sent once the tick ends, so only the last value of each is sent.
+///
+fn register_bindings(engine: &mut Engine, pending: &Arc<Mutex<DeviceState>>, name: &str) {
+    let state = pending.clone();
+    engine.register_fn("set_color", move |rgb: INT| -> BindingResult<()> {
+        if !(0..=0xffffff).contains(&rgb) {
+            return Err(binding_error(format!("Color {:#x} is not 24 bit RGB", rgb)));
+        }
+        lock(&state).color = Some(Color24::from_u32(rgb as u32));
+        Ok(())
+    });
+
+    let state = pending.clone();
+    engine.register_fn(
+        "set_color",
+        move |r: INT, g: INT, b: INT| -> BindingResult<()> {
+            if [r, g, b].iter().any(|channel| !(0..=255).contains(channel)) {
+                return Err(binding_error(format!(
+                    "Color ({}, {}, {}) has channels outside 0-255",
+                    r, g, b
+                )));
+            }
+            lock(&state).color = Some(Color24::from_u32((r << 16 | g << 8 | b) as u32));
+            Ok(())
+        },
+    );
+
+    let state = pending.clone();
+    engine.register_fn("set_color", move |hex: &str| -> BindingResult<()> {
+        let color: Color24 = hex
+            .parse()
+            .map_err(|_| binding_error(format!("Invalid color '{}'", hex)))?;
+        lock(&state).color = Some(color);
+        Ok(())
+    });
+
+    let state = pending.clone();
+    engine.register_fn(
+        "set_brightness",
+        move |brightness: INT| -> BindingResult<()> {
+            if !(0..=255).contains(&brightness) {
+                return Err(binding_error(format!(
+                    "Brightness {} is outside 0-255",
+                    brightness
+                )));
+            }
+            lock(&state).brightness = Some(brightness as u8);
+            Ok(())
+        },
+    );
+
+    let state = pending.clone();
+    engine.register_fn("set_effect", move |effect: &str| -> BindingResult<()> {
+        let effect = Effect::from_name(effect)
+            .ok_or_else(|| binding_error(format!("Unknown effect '{}'", effect)))?;
+        lock(&state).effect = Some(effect);
+        Ok(())
+    });
+
+    let state = pending.clone();
+    engine.register_fn(
+        "set_fire_pallet",
+        move |pallet: &str| -> BindingResult<()> {
+            let pallet = FireColorPallet::from_name(pallet)
+                .ok_or_else(|| binding_error(format!("Unknown fire pallet '{}'", pallet)))?;
+            lock(&state).fire_pallet = Some(pallet);
+            Ok(())
+        },
+    );
+
+    // xorshift64, seeded from the clock, since scripts need no more than varied choices
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let rng = Mutex::new(seed | 1);
+    engine.register_fn("random", move |max: INT| -> BindingResult<INT> {
+        if max <= 0 {
+            return Err(binding_error(format!(
+                "random({}) needs a positive bound",
+                max
+            )));
+        }
+        let mut x = lock(&rng);
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        Ok((*x % max as u64) as INT)
+    });
+
+    let name = String::from(name);
+    engine.on_print(move |text| println!("Script {}: {}", name, text));
+}
+
+///
+/// Returns the device state as a script sees it. Settings that are not known are unit.
+///
+fn state_map(state: &DeviceState) -> Map {
+    let mut map = Map::new();
+    map.insert(
+        "effect".into(),
+        state
+            .effect
+            .map_or(Dynamic::UNIT, |effect| effect.name().into()),
+    );
+    map.insert(
+        "color".into(),
+        state
+            .color
+            .map_or(Dynamic::UNIT, |color| (color.to_u32() as INT).into()),
+    );
+    map.insert(
+        "brightness".into(),
+        state
+            .brightness
+            .map_or(Dynamic::UNIT, |brightness| (brightness as INT).into()),
+    );
+    map.insert(
+        "fire_pallet".into(),
+        state
+            .fire_pallet
+            .map_or(Dynamic::UNIT, |pallet| pallet.name().into()),
+    );
+    map
+}
+
+///
+/// Runs the script on the device from a background thread every interval, cancelling the
+/// transition, sequence or script in progress. Each run is a tick and sees these variables:
+///
+/// * `tick` - the number of the tick, counting from 0
+/// * `elapsed_ms` - milliseconds since the script started
+/// * `hour`, `minute`, `second` and `weekday` - the server's local time, Monday is 0
+/// * `state` - the device's effect, color, brightness and fire_pallet
+/// * `memory` - a map kept from one tick to the next
+///
+/// The script changes the device by calling `set_color`, `set_brightness`, `set_effect` and
+/// `set_fire_pallet`. Settings are not recorded as the device's last known state. The script
+/// stops when any newer transition, sequence, script or command other than a query is sent, and
+/// when a tick fails or exceeds its limits.
+///
+/// Fails if the device's status could not be read.
+///
+pub fn run_script(
+    device_manager: &Arc<DeviceManager>,
+    name: &str,
+    ast: AST,
+    interval: Duration,
+) -> std::result::Result<thread::JoinHandle<()>, ControllerError> {
+    let id = device_manager.begin_task(TaskKind::Script);
+    let mut current = current_state(device_manager)?;
+
+    let device_manager = device_manager.clone();
+    let name = String::from(name);
+    thread::Builder::new()
+        .name(format!("script {}", name))
+        .spawn(move || {
+            let deadline = Arc::new(Mutex::new(Instant::now()));
+            let pending = Arc::new(Mutex::new(DeviceState::default()));
+            let mut engine = sandboxed_engine(deadline.clone());
+            register_bindings(&mut engine, &pending, &name);
+
+            let mut scope = Scope::new();
+            scope.push("memory", Map::new());
+            let interval = interval.max(TRANSITION_STEP_INTERVAL);
+            let start = Instant::now();
+
+            for tick in 0.. {
+                let now = Local::now();
+                let scope_size = scope.len();
+                scope
+                    .push("tick", tick as INT)
+                    .push("elapsed_ms", start.elapsed().as_millis() as INT)
+                    .push("hour", now.hour() as INT)
+                    .push("minute", now.minute() as INT)
+                    .push("second", now.second() as INT)
+                    .push("weekday", now.weekday().num_days_from_monday() as INT)
+                    .push("state", state_map(&current));
+
+                *lock(&deadline) = Instant::now() + SCRIPT_TIME_LIMIT;
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    engine.run_ast_with_scope(&mut scope, &ast)
+                }));
+                // Variables declared by the tick are dropped, only memory is kept
+                scope.rewind(scope_size);
+
+                let error = match result {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some(String::from("the script engine panicked")),
+                };
+                if let Some(e) = error {
+                    if device_manager.is_transition_current(id) {
+                        eprintln!("Script {} stopped at tick {} - {}", name, tick, e);
+                    }
+                    return;
+                }
+
+                let commands = std::mem::take(&mut *lock(&pending)).commands();
+                if !send_step(&device_manager, id, &commands, false) {
+                    return;
+                }
+                for command in &commands {
+                    current.apply(command);
+                }
+
+                let due = start + interval * (tick + 1);
+                if !hold(
+                    &device_manager,
+                    id,
+                    due.saturating_duration_since(Instant::now()),
+                ) {
+                    return;
+                }
+            }
+        })
+        // Failing to start the thread is reported like failing to write to the device
+        .map_err(ControllerError::WriteFailed)
+}
+
+///
+/// Named Rhai scripts, each kept in a file of its own in a directory. Files are read on every
+/// access, so scripts can also be edited and copied in by hand.
+///
+pub struct ScriptStore {
+    dir: PathBuf,
+}
+
+impl ScriptStore {
+    ///
+    /// Creates a store keeping scripts in the given directory. The directory is created when the
+    /// first script is saved.
+    ///
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ScriptStore {
+        ScriptStore { dir: dir.into() }
+    }
+
+    ///
+    /// Returns the name and source of every script, ordered by name.
+    ///
+    pub fn scripts(&self) -> std::result::Result<Vec<(String, String)>, ScriptError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(ScriptError::Io(e)),
+        };
+
+        let mut names: Vec<String> = vec![];
+        for entry in entries {
+            let path = entry.map_err(ScriptError::Io)?.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == SCRIPT_FILE_EXTENSION)
+            {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(String::from(name));
+                }
+            }
+        }
+        names.sort();
+
+        let mut scripts: Vec<(String, String)> = vec![];
+        for name in names {
+            if let Some(source) = self.script(&name)? {
+                scripts.push((name, source));
+            }
+        }
+
+        Ok(scripts)
+    }
+
+    ///
+    /// Returns the source of the named script, None if there is no such script.
+    ///
+    pub fn script(&self, name: &str) -> std::result::Result<Option<String>, ScriptError> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+
+        match fs::read_to_string(self.path(name)) {
+            Ok(source) => Ok(Some(source)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ScriptError::Io(e)),
+        }
+    }
+
+    ///
+    /// Creates or replaces a script, if it compiles.
+    ///
+    pub fn set_script(&self, name: &str, source: &str) -> std::result::Result<(), ScriptError> {
+        if !is_valid_name(name) {
+            return Err(ScriptError::InvalidName(String::from(name)));
+        }
+        compile_script(source)?;
+
+        fs::create_dir_all(&self.dir).map_err(ScriptError::Io)?;
+        write_atomically(self.path(name), source).map_err(ScriptError::Io)
+    }
+
+    ///
+    /// Removes a script. Returns false if there was no such script.
+    ///
+    pub fn remove_script(&self, name: &str) -> std::result::Result<bool, ScriptError> {
+        if !is_valid_name(name) {
+            return Ok(false);
+        }
+
+        match fs::remove_file(self.path(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(ScriptError::Io(e)),
+        }
+    }
+
+    ///
+    /// Returns the path of the named script's file.
+    ///
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, SCRIPT_FILE_EXTENSION))
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::controller::*;
+    use crate::led_strip_controller::script::*;
+    use crate::led_strip_controller::simulator::Simulator;
+    use serialport::{SerialPortInfo, SerialPortType};
+
+    fn simulated_device() -> Arc<DeviceManager> {
+        let port_info = SerialPortInfo {
+            port_name: String::from("/dev/sim"),
+            port_type: SerialPortType::Unknown,
+        };
+
+        Arc::new(DeviceManager::with_detector(move || {
+            auto_detect_ledsc_on_ports(vec![port_info.clone()], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(DetectedDevice::boxed)
+        }))
+    }
+
+    fn status(device_manager: &DeviceManager) -> DeviceStatus {
+        let protocol = device_manager.protocol().unwrap();
+        let response = device_manager.send_command(Command::GetStatus).unwrap();
+        protocol.parse_device_status(&response).unwrap()
+    }
+
+    ///
+    /// Starts a script on a fresh device, ticking once a minute so only the first tick runs.
+    ///
+    fn run_once(source: &str) -> (Arc<DeviceManager>, thread::JoinHandle<()>) {
+        let device_manager = simulated_device();
+        let ast = compile_script(source).unwrap();
+        let handle = run_script(&device_manager, "test", ast, Duration::from_secs(60)).unwrap();
+        (device_manager, handle)
+    }
+
+    #[test]
+    fn compile_script_test() {
+        assert!(compile_script("set_color(0xff0000);").is_ok());
+        assert!(matches!(
+            compile_script("set_color(0xff0000"),
+            Err(ScriptError::Compile(..))
+        ));
+        assert!(matches!(
+            compile_script("import \"file\" as f;"),
+            Err(ScriptError::Compile(..))
+        ));
+        assert!(matches!(
+            compile_script("eval(\"1\")"),
+            Err(ScriptError::Compile(..))
+        ));
+        let large = "1;".repeat(MAX_SCRIPT_SIZE);
+        assert!(matches!(
+            compile_script(&large),
+            Err(ScriptError::TooLarge(..))
+        ));
+    }
+
+    #[test]
+    fn run_script_test() {
+        let (device_manager, handle) = run_once(
+            r#"
+            if state.brightness != () && tick == 0 {
+                set_effect("solid_color");
+                set_color(255, 0, 128);
+                set_brightness(if hour >= 0 { 40 } else { 0 });
+                memory.ticks = 1;
+            }
+            "#,
+        );
+        thread::sleep(Duration::from_millis(200));
+        let status = status(&device_manager);
+        assert_eq!(status.effect, Effect::SolidColor);
+        assert_eq!(status.color, Color24::from_u32(0xff0080));
+        assert_eq!(status.brightness, 40);
+
+        // Stopped like a transition
+        device_manager.begin_transition();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn script_memory_test() {
+        let device_manager = simulated_device();
+        let ast = compile_script(
+            r#"
+            let step = 20;
+            if memory.level == () { memory.level = 0; }
+            memory.level += step;
+            set_brightness(memory.level);
+            "#,
+        )
+        .unwrap();
+        let handle = run_script(&device_manager, "test", ast, Duration::from_millis(100)).unwrap();
+
+        thread::sleep(Duration::from_millis(250));
+        device_manager.begin_transition();
+        handle.join().unwrap();
+        // Ticks at 0, 100 and 200 ms
+        assert_eq!(status(&device_manager).brightness, 60);
+    }
+
+    #[test]
+    fn script_failure_test() {
+        // Runtime errors, bad arguments and runaway scripts stop the script only
+        for source in [
+            "set_brightness(300);",
+            "set_effect(\"strobe\");",
+            "let x = 1 / 0;",
+            "loop { }",
+            "fn f(x) { f(x) } f(1);",
+            "let s = \"x\"; loop { s += s; }",
+        ] {
+            let (device_manager, handle) = run_once(source);
+            handle.join().unwrap();
+            assert!(device_manager.send_command(Command::GetStatus).is_ok());
+        }
+    }
+
+    #[test]
+    fn script_store_test() {
+        let dir = std::env::temp_dir().join(format!("led_oxide_scripts_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = ScriptStore::new(&dir);
+        assert!(store.scripts().unwrap().is_empty());
+
+        let source = "set_brightness(random(256));";
+        store.set_script("Flicker", source).unwrap();
+        assert!(matches!(
+            store.set_script("a/b", source),
+            Err(ScriptError::InvalidName(..))
+        ));
+        assert!(matches!(
+            store.set_script("Broken", "set_brightness("),
+            Err(ScriptError::Compile(..))
+        ));
+        assert_eq!(
+            store.scripts().unwrap(),
+            vec![(String::from("Flicker"), String::from(source))]
+        );
+
+        assert!(store.remove_script("Flicker").unwrap());
+        assert!(!store.remove_script("Flicker").unwrap());
+        assert_eq!(store.script("Flicker").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
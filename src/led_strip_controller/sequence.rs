@@ -0,0 +1,574 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::controller::{ControllerError, DeviceManager, TaskKind};
+use crate::led_strip_controller::scene::is_valid_name;
+use crate::led_strip_controller::state::DeviceState;
+use crate::led_strip_controller::store::write_atomically;
+use crate::led_strip_controller::transition::{
+    current_state, fade, hold, send_step, Easing, MAX_TRANSITION_MS, TRANSITION_STEP_INTERVAL,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, fs, io, thread};
+
+/// Extension of sequence files
+const SEQUENCE_FILE_EXTENSION: &str = "json";
+
+/// Most keyframes in a sequence
+pub const MAX_KEYFRAMES: usize = 1000;
+
+///
+/// A step of a sequence. Effect and fire pallet change when the keyframe starts, color and
+/// brightness fade from the previous keyframe. Settings left out keep their value.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keyframe {
+    #[serde(flatten)]
+    pub state: DeviceState,
+    /// Milliseconds the color and brightness take to reach this keyframe, 0 to jump
+    #[serde(default)]
+    pub fade_ms: u32,
+    #[serde(default)]
+    pub easing: Easing,
+    /// Milliseconds the keyframe is held once reached
+    #[serde(default)]
+    pub hold_ms: u32,
+}
+
+///
+/// How a sequence repeats.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayMode {
+    /// Starts over from the first keyframe after the last
+    #[default]
+    Loop,
+    /// Plays forward then backward, reversing the fades
+    PingPong,
+    /// Plays once and stays on the last keyframe
+    Once,
+}
+
+///
+/// Keyframes played on a device by led_oxide, for animations the firmware has no effect for.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sequence {
+    #[serde(default)]
+    pub mode: PlayMode,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Sequence {
+    ///
+    /// Checks the sequence can be played. Repeating sequences must take some time, or they
+    /// would send commands as fast as the device answers.
+    ///
+    pub fn validate(&self) -> std::result::Result<(), SequenceError> {
+        if self.keyframes.is_empty() || self.keyframes.len() > MAX_KEYFRAMES {
+            return Err(SequenceError::KeyframeCount(self.keyframes.len()));
+        }
+
+        for (index, keyframe) in self.keyframes.iter().enumerate() {
+            if keyframe.state.commands().is_empty() {
+                return Err(SequenceError::EmptyKeyframe(index));
+            }
+            if keyframe.fade_ms > MAX_TRANSITION_MS || keyframe.hold_ms > MAX_TRANSITION_MS {
+                return Err(SequenceError::TooLong(index));
+            }
+        }
+
+        if self.mode != PlayMode::Once && self.cycle_duration() < TRANSITION_STEP_INTERVAL {
+            return Err(SequenceError::TooShort);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns the time one pass over the keyframes takes.
+    ///
+    fn cycle_duration(&self) -> Duration {
+        let millis: u64 = self
+            .keyframes
+            .iter()
+            .map(|keyframe| u64::from(keyframe.fade_ms) + u64::from(keyframe.hold_ms))
+            .sum();
+        Duration::from_millis(millis)
+    }
+
+    ///
+    /// Returns the keyframes of one pass, each with the keyframe whose fade leads into it. Going
+    /// backward a fade is the reverse of the one leading out of the keyframe.
+    ///
+    fn pass(&self, repeat: bool) -> Vec<(usize, usize)> {
+        let count = self.keyframes.len();
+        let mut pass: Vec<(usize, usize)> = (0..count).map(|index| (index, index)).collect();
+
+        if self.mode == PlayMode::PingPong && count > 1 {
+            pass.extend((1..count - 1).rev().map(|index| (index, index + 1)));
+            if repeat {
+                // Coming back to the first keyframe from the second
+                pass[0] = (0, 1);
+            }
+        }
+
+        pass
+    }
+}
+
+///
+/// Plays the sequence on the device from a background thread, cancelling the transition or
+/// sequence in progress. Steps are not recorded as the device's last known state. Playback
+/// stops when any newer transition, sequence or command other than a query is sent, leaving the
+/// device at the last step played.
+///
+/// Fails if the device's status could not be read.
+///
+pub fn play_sequence(
+    device_manager: &Arc<DeviceManager>,
+    sequence: Sequence,
+) -> std::result::Result<thread::JoinHandle<()>, ControllerError> {
+    let id = device_manager.begin_task(TaskKind::Sequence);
+    let mut current = current_state(device_manager)?;
+
+    let device_manager = device_manager.clone();
+    thread::Builder::new()
+        .name(String::from("sequence"))
+        .spawn(move || {
+            // A sequence without duration is played once rather than spinning
+            let repeats = sequence.mode != PlayMode::Once
+                && sequence.cycle_duration() >= TRANSITION_STEP_INTERVAL;
+            let mut repeat = false;
+
+            loop {
+                for (index, fade_index) in sequence.pass(repeat) {
+                    let keyframe = &sequence.keyframes[index];
+                    let lead_in = &sequence.keyframes[fade_index];
+
+                    // Effect and pallet change at once
+                    let instant = DeviceState {
+                        effect: keyframe.state.effect,
+                        fire_pallet: keyframe.state.fire_pallet,
+                        ..DeviceState::default()
+                    };
+                    if !send_step(&device_manager, id, &instant.commands(), false) {
+                        return;
+                    }
+
+                    let faded = fade(
+                        &device_manager,
+                        id,
+                        &current,
+                        &keyframe.state,
+                        Duration::from_millis(u64::from(lead_in.fade_ms)),
+                        lead_in.easing,
+                        false,
+                    );
+                    if !faded {
+                        return;
+                    }
+                    for command in keyframe.state.commands() {
+                        current.apply(&command);
+                    }
+
+                    if !hold(
+                        &device_manager,
+                        id,
+                        Duration::from_millis(u64::from(keyframe.hold_ms)),
+                    ) {
+                        return;
+                    }
+                }
+
+                if !repeats {
+                    return;
+                }
+                repeat = true;
+            }
+        })
+        // Failing to start the thread is reported like failing to write to the device
+        .map_err(ControllerError::WriteFailed)
+}
+
+///
+/// Errors managing sequences.
+///
+#[derive(Debug)]
+pub enum SequenceError {
+    /// Sequence names follow the rules of scene names
+    InvalidName(String),
+    /// A sequence needs 1 to MAX_KEYFRAMES keyframes
+    KeyframeCount(usize),
+    /// The keyframe at the index has no settings
+    EmptyKeyframe(usize),
+    /// The fade or hold of the keyframe at the index is longer than MAX_TRANSITION_MS
+    TooLong(usize),
+    /// A repeating sequence must take at least one transition step
+    TooShort,
+    /// Reading or writing a sequence file failed
+    Io(io::Error),
+    /// A sequence file is not valid JSON
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SequenceError::InvalidName(name) => write!(f, "Invalid sequence name '{}'", name),
+            SequenceError::KeyframeCount(count) => write!(
+                f,
+                "Sequence has {} keyframes, 1 to {} are allowed",
+                count, MAX_KEYFRAMES
+            ),
+            SequenceError::EmptyKeyframe(index) => {
+                write!(f, "Keyframe {} has no settings to apply", index)
+            }
+            SequenceError::TooLong(index) => write!(
+                f,
+                "Keyframe {} fades or holds longer than {} ms",
+                index, MAX_TRANSITION_MS
+            ),
+            SequenceError::TooShort => write!(
+                f,
+                "Repeating sequence must last at least {} ms",
+                TRANSITION_STEP_INTERVAL.as_millis()
+            ),
+            SequenceError::Io(e) => write!(f, "Failed to access sequence file: {}", e),
+            SequenceError::Format(e) => write!(f, "Malformed sequence file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SequenceError::Io(e) => Some(e),
+            SequenceError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Named sequences, each kept in a JSON file of its own in a directory. Files are read on
+/// every access, so sequences can also be edited and copied in by hand.
+///
+pub struct SequenceStore {
+    dir: PathBuf,
+}
+
+impl SequenceStore {
+    ///
+    /// Creates a store keeping sequences in the given directory. The directory is created when
+    /// the first sequence is saved.
+    ///
+    pub fn new<P: Into<PathBuf>>(dir: P) -> SequenceStore {
+        SequenceStore { dir: dir.into() }
+    }
+
+    ///
+    /// Returns every sequence, ordered by name. Files that do not hold a valid sequence are
+    /// skipped.
+    ///
+    pub fn sequences(&self) -> std::result::Result<Vec<(String, Sequence)>, SequenceError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(SequenceError::Io(e)),
+        };
+
+        let mut names: Vec<String> = vec![];
+        for entry in entries {
+            let path = entry.map_err(SequenceError::Io)?.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == SEQUENCE_FILE_EXTENSION)
+            {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(String::from(name));
+                }
+            }
+        }
+        names.sort();
+
+        let mut sequences: Vec<(String, Sequence)> = vec![];
+        for name in names {
+            match self.sequence(&name) {
+                Ok(Some(sequence)) => sequences.push((name, sequence)),
+                Ok(None) => {}
+                Err(e) => eprintln!("Skipping sequence {} - {}", name, e),
+            }
+        }
+
+        Ok(sequences)
+    }
+
+    ///
+    /// Returns the named sequence, None if there is no such sequence.
+    ///
+    pub fn sequence(&self, name: &str) -> std::result::Result<Option<Sequence>, SequenceError> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+
+        match fs::read_to_string(self.path(name)) {
+            Ok(contents) => {
+                let sequence: Sequence =
+                    serde_json::from_str(&contents).map_err(SequenceError::Format)?;
+                sequence.validate()?;
+                Ok(Some(sequence))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(SequenceError::Io(e)),
+        }
+    }
+
+    ///
+    /// Creates or replaces a sequence.
+    ///
+    pub fn set_sequence(
+        &self,
+        name: &str,
+        sequence: &Sequence,
+    ) -> std::result::Result<(), SequenceError> {
+        if !is_valid_name(name) {
+            return Err(SequenceError::InvalidName(String::from(name)));
+        }
+        sequence.validate()?;
+
+        fs::create_dir_all(&self.dir).map_err(SequenceError::Io)?;
+        let contents = serde_json::to_string_pretty(sequence).map_err(SequenceError::Format)?;
+
+        write_atomically(self.path(name), contents).map_err(SequenceError::Io)
+    }
+
+    ///
+    /// Removes a sequence. Returns false if there was no such sequence.
+    ///
+    pub fn remove_sequence(&self, name: &str) -> std::result::Result<bool, SequenceError> {
+        if !is_valid_name(name) {
+            return Ok(false);
+        }
+
+        match fs::remove_file(self.path(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(SequenceError::Io(e)),
+        }
+    }
+
+    ///
+    /// Returns the path of the named sequence's file.
+    ///
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", name, SEQUENCE_FILE_EXTENSION))
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::controller::*;
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::sequence::*;
+    use crate::led_strip_controller::simulator::Simulator;
+    use serialport::{SerialPortInfo, SerialPortType};
+
+    fn simulated_device() -> Arc<DeviceManager> {
+        let port_info = SerialPortInfo {
+            port_name: String::from("/dev/sim"),
+            port_type: SerialPortType::Unknown,
+        };
+
+        Arc::new(DeviceManager::with_detector(move || {
+            auto_detect_ledsc_on_ports(vec![port_info.clone()], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(DetectedDevice::boxed)
+        }))
+    }
+
+    fn status(device_manager: &DeviceManager) -> DeviceStatus {
+        let protocol = device_manager.protocol().unwrap();
+        let response = device_manager.send_command(Command::GetStatus).unwrap();
+        protocol.parse_device_status(&response).unwrap()
+    }
+
+    fn keyframe(color: u32, fade_ms: u32, hold_ms: u32) -> Keyframe {
+        Keyframe {
+            state: DeviceState {
+                color: Some(Color24::from_u32(color)),
+                ..DeviceState::default()
+            },
+            fade_ms,
+            easing: Easing::Linear,
+            hold_ms,
+        }
+    }
+
+    #[test]
+    fn sequence_validate_test() {
+        let mut sequence = Sequence {
+            mode: PlayMode::Loop,
+            keyframes: vec![keyframe(0xff0000, 0, 500), keyframe(0x0000ff, 500, 0)],
+        };
+        assert!(sequence.validate().is_ok());
+
+        sequence.keyframes[1].state = DeviceState::default();
+        assert!(matches!(
+            sequence.validate(),
+            Err(SequenceError::EmptyKeyframe(1))
+        ));
+
+        sequence.keyframes = vec![keyframe(0xff0000, 0, 0)];
+        assert!(matches!(sequence.validate(), Err(SequenceError::TooShort)));
+        sequence.mode = PlayMode::Once;
+        assert!(sequence.validate().is_ok());
+
+        sequence.keyframes[0].hold_ms = MAX_TRANSITION_MS + 1;
+        assert!(matches!(
+            sequence.validate(),
+            Err(SequenceError::TooLong(0))
+        ));
+
+        sequence.keyframes.clear();
+        assert!(matches!(
+            sequence.validate(),
+            Err(SequenceError::KeyframeCount(0))
+        ));
+    }
+
+    #[test]
+    fn sequence_pass_test() {
+        let mut sequence = Sequence {
+            mode: PlayMode::PingPong,
+            keyframes: vec![
+                keyframe(0x000001, 100, 0),
+                keyframe(0x000002, 200, 0),
+                keyframe(0x000003, 300, 0),
+            ],
+        };
+        assert_eq!(sequence.pass(false), vec![(0, 0), (1, 1), (2, 2), (1, 2)]);
+        assert_eq!(sequence.pass(true), vec![(0, 1), (1, 1), (2, 2), (1, 2)]);
+
+        sequence.mode = PlayMode::Loop;
+        assert_eq!(sequence.pass(true), vec![(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn sequence_serde_test() {
+        let sequence: Sequence = serde_json::from_str(
+            r##"{
+                "mode": "ping_pong",
+                "keyframes": [
+                    {"effect": "solid_color", "color": "#ff0000", "hold_ms": 1000},
+                    {"color": "#0000ff", "brightness": 40, "fade_ms": 2000, "easing": "perceptual"}
+                ]
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(sequence.mode, PlayMode::PingPong);
+        assert_eq!(sequence.keyframes[0].state.effect, Some(Effect::SolidColor));
+        assert_eq!(sequence.keyframes[0].fade_ms, 0);
+        assert_eq!(sequence.keyframes[1].state.brightness, Some(40));
+        assert_eq!(sequence.keyframes[1].easing, Easing::Perceptual);
+
+        let json = serde_json::to_string(&sequence).unwrap();
+        assert_eq!(serde_json::from_str::<Sequence>(&json).unwrap(), sequence);
+    }
+
+    #[test]
+    fn sequence_store_test() {
+        let dir = std::env::temp_dir().join(format!("led_oxide_sequences_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = SequenceStore::new(&dir);
+        assert!(store.sequences().unwrap().is_empty());
+
+        let sequence = Sequence {
+            mode: PlayMode::Once,
+            keyframes: vec![keyframe(0xff8000, 1000, 0)],
+        };
+        store.set_sequence("Sunrise", &sequence).unwrap();
+        assert!(matches!(
+            store.set_sequence("a/b", &sequence),
+            Err(SequenceError::InvalidName(..))
+        ));
+        assert_eq!(store.sequence("Sunrise").unwrap(), Some(sequence.clone()));
+
+        // Hand written files are picked up and broken ones skipped
+        fs::write(dir.join("broken.json"), "{").unwrap();
+        fs::write(dir.join("notes.txt"), "not a sequence").unwrap();
+        let sequences = store.sequences().unwrap();
+        assert_eq!(sequences, vec![(String::from("Sunrise"), sequence)]);
+
+        assert!(store.remove_sequence("Sunrise").unwrap());
+        assert!(!store.remove_sequence("Sunrise").unwrap());
+        assert_eq!(store.sequence("Sunrise").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn play_sequence_test() {
+        let device_manager = simulated_device();
+        let sequence = Sequence {
+            mode: PlayMode::Loop,
+            keyframes: vec![
+                Keyframe {
+                    state: DeviceState {
+                        effect: Some(Effect::SolidColor),
+                        color: Some(Color24::from_u32(0xff0000)),
+                        ..DeviceState::default()
+                    },
+                    fade_ms: 0,
+                    easing: Easing::Linear,
+                    hold_ms: 300,
+                },
+                keyframe(0x0000ff, 0, 300),
+            ],
+        };
+        let handle = play_sequence(&device_manager, sequence).unwrap();
+
+        thread::sleep(Duration::from_millis(150));
+        let first = status(&device_manager);
+        assert_eq!(first.effect, Effect::SolidColor);
+        assert_eq!(first.color, Color24::from_u32(0xff0000));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(status(&device_manager).color, Color24::from_u32(0x0000ff));
+        // Loops back to the first keyframe
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(status(&device_manager).color, Color24::from_u32(0xff0000));
+
+        // Any other command stops it
+        device_manager
+            .send_command(Command::SetColor(Color24::from_u32(0x00ff00)))
+            .unwrap();
+        handle.join().unwrap();
+        assert_eq!(status(&device_manager).color, Color24::from_u32(0x00ff00));
+    }
+}
@@ -0,0 +1,418 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::transport::MemoryTransport;
+use crc16::*;
+
+/// Highest effect id accepted by LEDSC_TEENSY_001
+const SIM_MAX_EFFECT_ID: u32 = 0x09;
+
+/// Highest fire color pallet id accepted by LEDSC_TEENSY_001
+const SIM_MAX_FIRE_PALLET_ID: u32 = 0x07;
+
+/// Highest 24bit color value
+const SIM_MAX_COLOR: u32 = 0xffffff;
+
+/// Highest brightness value
+const SIM_MAX_BRIGHTNESS: u32 = 0xff;
+
+///
+/// Software emulation of the LEDSC_TEENSY_001 firmware. Accepts the command packets built by
+/// `ProtocolVersion::create_cmd_string` and answers with CRC'd response packets while keeping its
+/// own LED state.
+///
+pub struct Simulator {
+    protocol_instance: LedscTeensy001,
+    receive_buffer: Vec<u8>,
+    debugging: bool,
+    effect_id: u8,
+    color: u32,
+    brightness: u8,
+    fire_pallet_id: u8,
+    in_bootloader: bool,
+}
+
+impl Simulator {
+    ///
+    /// Creates a simulator in the firmware's power on state.
+    ///
+    pub fn new() -> Simulator {
+        Simulator {
+            protocol_instance: LedscTeensy001 {},
+            receive_buffer: vec![],
+            debugging: false,
+            effect_id: 0x00,
+            color: 0x000000,
+            brightness: 0xff,
+            fire_pallet_id: 0x00,
+            in_bootloader: false,
+        }
+    }
+
+    ///
+    /// Wraps the simulator in an in-memory transport so the controller can talk to it directly.
+    ///
+    pub fn into_transport(mut self) -> MemoryTransport {
+        MemoryTransport::with_responder(move |bytes| self.receive(bytes))
+    }
+
+    ///
+    /// Feeds received bytes to the simulator. Returns the response bytes for every packet
+    /// completed by these bytes. Partial packets are buffered until their line ending arrives.
+    /// Once in the bootloader nothing is answered, as the firmware is no longer running.
+    ///
+    pub fn receive(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut response_bytes: Vec<u8> = vec![];
+
+        if self.in_bootloader {
+            return response_bytes;
+        }
+
+        for byte in bytes {
+            if *byte == PROTO_CR as u8 || *byte == PROTO_NL as u8 {
+                if !self.receive_buffer.is_empty() {
+                    let packet = String::from_utf8_lossy(&self.receive_buffer).into_owned();
+                    self.receive_buffer.clear();
+                    let response = self.process_packet(&packet);
+                    if self.in_bootloader {
+                        return response_bytes;
+                    }
+                    response_bytes.extend(response.into_bytes());
+                }
+                continue;
+            }
+
+            self.receive_buffer.push(*byte);
+
+            if self.receive_buffer.len() > MAX_PROTO_PACKET_LEN as usize {
+                self.receive_buffer.clear();
+                response_bytes.extend(
+                    self.protocol_instance
+                        .create_response_string("", ERR_PROTO_CP_CMD_OVERFLOW, &[])
+                        .into_bytes(),
+                );
+            }
+        }
+
+        response_bytes
+    }
+
+    ///
+    /// Processes a single packet without its line ending and returns the response string.
+    ///
+    pub fn process_packet(&mut self, packet: &str) -> String {
+        let (cmd, result) = self.execute_packet(packet);
+
+        match result {
+            Ok(params) => {
+                self.protocol_instance
+                    .create_response_string(&cmd, ERR_PROTO_SUCCESS, &params)
+            }
+            Err(err_code) => self
+                .protocol_instance
+                .create_response_string(&cmd, err_code, &[]),
+        }
+    }
+
+    /// Returns if firmware debugging is enabled
+    pub fn debugging(&self) -> bool {
+        self.debugging
+    }
+
+    /// Returns the active effect id
+    pub fn effect_id(&self) -> u8 {
+        self.effect_id
+    }
+
+    /// Returns the active 24bit RGB color
+    pub fn color(&self) -> u32 {
+        self.color
+    }
+
+    /// Returns the active brightness
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Returns the active fire color pallet id
+    pub fn fire_pallet_id(&self) -> u8 {
+        self.fire_pallet_id
+    }
+
+    /// Returns if the simulated device rebooted into its bootloader
+    pub fn in_bootloader(&self) -> bool {
+        self.in_bootloader
+    }
+
+    ///
+    /// Validates the packet framing and CRC then executes the command. Returns the command name
+    /// to echo and either the response parameters or an error code.
+    ///
+    fn execute_packet(&mut self, packet: &str) -> (String, Result<Vec<String>, i16>) {
+        let body = match packet.strip_prefix(PROTO_STX) {
+            Some(body) => body,
+            None => return (String::new(), Err(ERR_PROTO_CP_MISSING_STX)),
+        };
+
+        let etx_index = match body.find(PROTO_ETX) {
+            Some(index) => index,
+            None => {
+                let cmd = body.split(PROTO_PSC).next().unwrap_or("");
+                return (String::from(cmd), Err(ERR_PROTO_CP_MISSING_ETX));
+            }
+        };
+
+        let mut fields = body[..etx_index].split(PROTO_PSC);
+        let cmd = String::from(fields.next().unwrap_or(""));
+        let params: Vec<&str> = fields.collect();
+
+        if cmd.len() > MAX_PROTO_CMD as usize {
+            return (String::new(), Err(ERR_PROTO_CP_CMD_OVERFLOW));
+        }
+
+        // CRC16 covers STX through ETX
+        let crc16_str = &body[etx_index + 1..];
+        if crc16_str.is_empty() {
+            return (cmd, Err(ERR_PROTO_CP_MISSING_CRC16));
+        }
+
+        let crc16_calc = State::<XMODEM>::calculate(&packet.as_bytes()[..etx_index + 2]);
+        match u16::from_str_radix(crc16_str, 16) {
+            Ok(crc16_in) if crc16_in == crc16_calc => {}
+            _ => return (cmd, Err(ERR_PROTO_CP_CRC16_MISMATCH)),
+        }
+
+        let result = self.execute_command(&cmd, &params);
+        (cmd, result)
+    }
+
+    ///
+    /// Executes a validated command and returns the response parameters or an error code.
+    ///
+    fn execute_command(&mut self, cmd: &str, params: &[&str]) -> Result<Vec<String>, i16> {
+        match cmd {
+            CMD_PRINT_VERSION => Ok(vec![String::from(FWV_LEDSC_TEENSY_001)]),
+            CMD_FULL_RESET => Err(ERR_PROTO_CP_CMD_NOT_IMP),
+            CMD_ENTER_BOOTLOADER => {
+                // The Teensy reboots at once, without answering
+                self.in_bootloader = true;
+                Ok(vec![])
+            }
+            CMD_SET_DEBUGGING => {
+                self.debugging = parse_param(params, 0x01)? != 0;
+                Ok(vec![])
+            }
+            CMD_SET_EFFECT => {
+                self.effect_id = parse_param(params, SIM_MAX_EFFECT_ID)? as u8;
+                Ok(vec![])
+            }
+            CMD_SET_COLOR => {
+                self.color = parse_param(params, SIM_MAX_COLOR)?;
+                Ok(vec![])
+            }
+            CMD_SET_BRIGHTNESS => {
+                self.brightness = parse_param(params, SIM_MAX_BRIGHTNESS)? as u8;
+                Ok(vec![])
+            }
+            CMD_SET_FIRE_PALLET => {
+                self.fire_pallet_id = parse_param(params, SIM_MAX_FIRE_PALLET_ID)? as u8;
+                Ok(vec![])
+            }
+            CMD_GET_STATUS => Ok(vec![format!(
+                "{:X}|{:X}|{:X}|{:06X}|{:X}",
+                self.debugging as u8,
+                self.effect_id,
+                self.brightness,
+                self.color,
+                self.fire_pallet_id
+            )]),
+            _ => Err(ERR_PROTO_CP_CMD_UNKNOWN),
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new()
+    }
+}
+
+///
+/// Parses the first hex parameter, with or without a 0x prefix, and checks it against max.
+///
+fn parse_param(params: &[&str], max: u32) -> Result<u32, i16> {
+    let param = match params.first() {
+        Some(param) if !param.is_empty() => param.trim_start_matches("0x"),
+        _ => return Err(ERR_PROTO_CP_MISSING_PARAMS),
+    };
+
+    match u32::from_str_radix(param, 16) {
+        Ok(value) if value <= max => Ok(value),
+        _ => Err(ERR_PROTO_CP_PARAM_OUT_RANGE),
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::simulator::Simulator;
+
+    ///
+    /// Sends a command through the simulator and parses the response.
+    ///
+    fn send(simulator: &mut Simulator, command: Command) -> ResponsePacketOption {
+        let protocol_instance = LedscTeensy001 {};
+        let cmd = protocol_instance.create_cmd_string(command);
+        let response = simulator.receive(cmd.as_bytes());
+
+        protocol_instance.parse_response_sting(String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn simulator_print_version_test() {
+        let mut simulator = Simulator::new();
+
+        match send(&mut simulator, Command::PrintVersion) {
+            ResponsePacketOption::Success(pkt) => {
+                assert_eq!(pkt.command, CMD_PRINT_VERSION);
+                assert_eq!(pkt.parameters[1], FWV_LEDSC_TEENSY_001);
+                assert_eq!(pkt.crc16_in, pkt.crc16_calc);
+            }
+            _ => panic!("Print version should succeed"),
+        }
+    }
+
+    #[test]
+    fn simulator_state_test() {
+        let mut simulator = Simulator::new();
+
+        assert!(matches!(
+            send(&mut simulator, Command::SetEffect(Effect::Twinkle)),
+            ResponsePacketOption::Success(..)
+        ));
+        assert!(matches!(
+            send(
+                &mut simulator,
+                Command::SetColor(Color24::from_u32(0x4f2d86))
+            ),
+            ResponsePacketOption::Success(..)
+        ));
+        assert!(matches!(
+            send(&mut simulator, Command::SetBrightness(0x5c)),
+            ResponsePacketOption::Success(..)
+        ));
+        assert!(matches!(
+            send(
+                &mut simulator,
+                Command::SetFireColorPallet(FireColorPallet::Lava)
+            ),
+            ResponsePacketOption::Success(..)
+        ));
+        assert!(matches!(
+            send(&mut simulator, Command::SetDebugging(true)),
+            ResponsePacketOption::Success(..)
+        ));
+
+        assert_eq!(simulator.effect_id(), 0x09);
+        assert_eq!(simulator.color(), 0x4f2d86);
+        assert_eq!(simulator.brightness(), 0x5c);
+        assert_eq!(simulator.fire_pallet_id(), 0x06);
+        assert!(simulator.debugging());
+
+        match send(&mut simulator, Command::GetStatus) {
+            ResponsePacketOption::Success(pkt) => {
+                assert_eq!(pkt.command, CMD_GET_STATUS);
+                assert_eq!(pkt.parameters[1], "1|9|5C|4F2D86|6");
+            }
+            _ => panic!("Get status should succeed"),
+        }
+    }
+
+    #[test]
+    fn simulator_error_codes_test() {
+        let mut simulator = Simulator::new();
+
+        // Not implemented by LEDSC_TEENSY_001
+        match send(&mut simulator, Command::FullReset) {
+            ResponsePacketOption::FailedRemote(pkt) => {
+                assert_eq!(pkt.parameters[0], ERR_PROTO_CP_CMD_NOT_IMP.to_string())
+            }
+            _ => panic!("Full reset should fail remote"),
+        }
+
+        // Out of range effect
+        match send(&mut simulator, Command::SetEffect(Effect::MaxEffect)) {
+            ResponsePacketOption::FailedRemote(pkt) => {
+                assert_eq!(pkt.parameters[0], ERR_PROTO_CP_PARAM_OUT_RANGE.to_string())
+            }
+            _ => panic!("Max effect should fail remote"),
+        }
+
+        let cases = [
+            ("CSE:1]1234", ERR_PROTO_CP_MISSING_STX),
+            ("[CSE:1", ERR_PROTO_CP_MISSING_ETX),
+            ("[CSE:1]", ERR_PROTO_CP_MISSING_CRC16),
+            ("[CSE:1]FFFF", ERR_PROTO_CP_CRC16_MISMATCH),
+            ("[CSE]7272", ERR_PROTO_CP_MISSING_PARAMS),
+            ("[XYZ]A346", ERR_PROTO_CP_CMD_UNKNOWN),
+        ];
+
+        for (packet, err_code) in cases.iter() {
+            let response = simulator.process_packet(packet);
+            let status = format!(":{}]", err_code);
+            assert!(
+                response.contains(status.as_str()),
+                "'{}' should answer {} but got '{}'",
+                packet,
+                err_code,
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn simulator_enter_bootloader_test() {
+        let mut simulator = Simulator::new();
+        let protocol_instance = LedscTeensy001 {};
+
+        // Nothing is answered once the device reboots into its bootloader
+        let cmd = protocol_instance.create_cmd_string(Command::EnterBootloader);
+        assert!(simulator.receive(cmd.as_bytes()).is_empty());
+        assert!(simulator.in_bootloader());
+
+        let cmd = protocol_instance.create_cmd_string(Command::GetStatus);
+        assert!(simulator.receive(cmd.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn simulator_partial_packet_test() {
+        let mut simulator = Simulator::new();
+        let cmd = LedscTeensy001 {}.create_cmd_string(Command::SetBrightness(0x22));
+        let (first, second) = cmd.as_bytes().split_at(4);
+
+        assert!(simulator.receive(first).is_empty());
+        assert!(!simulator.receive(second).is_empty());
+        assert_eq!(simulator.brightness(), 0x22);
+    }
+}
@@ -0,0 +1,362 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::color::Color24;
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::store::{recover, write_atomically};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::{fmt, fs, io};
+
+///
+/// Last settings a device accepted. Settings never applied are left unset.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color24>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fire_pallet: Option<FireColorPallet>,
+}
+
+impl DeviceState {
+    ///
+    /// Records the setting changed by a command. Returns true if the state changed.
+    ///
+    pub fn apply(&mut self, command: &Command) -> bool {
+        let previous = *self;
+
+        match *command {
+            Command::SetEffect(effect) => self.effect = Some(effect),
+            Command::SetColor(color) => self.color = Some(color),
+            Command::SetBrightness(brightness) => self.brightness = Some(brightness),
+            Command::SetFireColorPallet(pallet) => self.fire_pallet = Some(pallet),
+            _ => {}
+        }
+
+        previous != *self
+    }
+
+    ///
+    /// Returns the commands that bring a device to this state.
+    ///
+    pub fn commands(&self) -> Vec<Command> {
+        let mut commands: Vec<Command> = vec![];
+
+        if let Some(effect) = self.effect {
+            commands.push(Command::SetEffect(effect));
+        }
+        if let Some(pallet) = self.fire_pallet {
+            commands.push(Command::SetFireColorPallet(pallet));
+        }
+        if let Some(color) = self.color {
+            commands.push(Command::SetColor(color));
+        }
+        if let Some(brightness) = self.brightness {
+            commands.push(Command::SetBrightness(brightness));
+        }
+
+        commands
+    }
+
+    ///
+    /// Returns true if the reported status agrees with every setting in this state.
+    ///
+    pub fn matches(&self, status: &DeviceStatus) -> bool {
+        self.effect.map_or(true, |effect| effect == status.effect)
+            && self.color.map_or(true, |color| color == status.color)
+            && self
+                .brightness
+                .map_or(true, |brightness| brightness == status.brightness)
+            && self
+                .fire_pallet
+                .map_or(true, |pallet| pallet == status.fire_pallet)
+    }
+}
+
+impl From<&DeviceStatus> for DeviceState {
+    ///
+    /// Returns the state holding every setting of a reported status.
+    ///
+    fn from(status: &DeviceStatus) -> DeviceState {
+        DeviceState {
+            effect: Some(status.effect),
+            color: Some(status.color),
+            brightness: Some(status.brightness),
+            fire_pallet: Some(status.fire_pallet),
+        }
+    }
+}
+
+///
+/// Keeps the settings applied to a device so they can be restored when the device resets.
+///
+pub trait StateKeeper: Send + Sync {
+    ///
+    /// Returns the last known state of the device.
+    ///
+    fn last_state(&self) -> DeviceState;
+
+    ///
+    /// Called after the device accepted a command.
+    ///
+    fn command_applied(&self, command: &Command);
+}
+
+///
+/// Errors reading or writing the state file.
+///
+#[derive(Debug)]
+pub enum StateStoreError {
+    /// Reading or writing the state file failed
+    Io(io::Error),
+    /// The state file is not valid JSON
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for StateStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateStoreError::Io(e) => write!(f, "Failed to access state file: {}", e),
+            StateStoreError::Format(e) => write!(f, "Malformed state file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StateStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateStoreError::Io(e) => Some(e),
+            StateStoreError::Format(e) => Some(e),
+        }
+    }
+}
+
+///
+/// Last known state of every device by device id. The states are saved to a JSON file, if one
+/// is given, every time one changes.
+///
+pub struct StateStore {
+    path: Option<PathBuf>,
+    states: RwLock<BTreeMap<String, DeviceState>>,
+}
+
+impl StateStore {
+    ///
+    /// Creates a store that only keeps states in memory.
+    ///
+    pub fn new() -> StateStore {
+        StateStore {
+            path: None,
+            states: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    ///
+    /// Creates a store saved to the given file, loading the states already in it. A missing file
+    /// starts out with no states.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> std::result::Result<StateStore, StateStoreError> {
+        let path = path.as_ref().to_path_buf();
+
+        let states = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(StateStoreError::Format)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(StateStoreError::Io(e)),
+        };
+
+        Ok(StateStore {
+            path: Some(path),
+            states: RwLock::new(states),
+        })
+    }
+
+    ///
+    /// Returns the last known state of the device.
+    ///
+    pub fn state(&self, device_id: &str) -> DeviceState {
+        recover(self.states.read())
+            .get(device_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Records the setting changed by a command the device accepted. The file is only written
+    /// when the state changed.
+    ///
+    pub fn record(
+        &self,
+        device_id: &str,
+        command: &Command,
+    ) -> std::result::Result<(), StateStoreError> {
+        let mut states = recover(self.states.write());
+
+        let mut state = states.get(device_id).copied().unwrap_or_default();
+        if !state.apply(command) {
+            return Ok(());
+        }
+
+        let mut updated = states.clone();
+        updated.insert(String::from(device_id), state);
+        self.save(&updated)?;
+        *states = updated;
+
+        Ok(())
+    }
+
+    ///
+    /// Returns a keeper recording the given device's state in this store.
+    ///
+    pub fn keeper(self: &Arc<Self>, device_id: &str) -> Arc<dyn StateKeeper> {
+        Arc::new(StoredStateKeeper {
+            store: self.clone(),
+            device_id: String::from(device_id),
+        })
+    }
+
+    ///
+    /// Writes the states to the store's file.
+    ///
+    fn save(
+        &self,
+        states: &BTreeMap<String, DeviceState>,
+    ) -> std::result::Result<(), StateStoreError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let contents = serde_json::to_string_pretty(states).map_err(StateStoreError::Format)?;
+
+        write_atomically(path, contents).map_err(StateStoreError::Io)
+    }
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        StateStore::new()
+    }
+}
+
+///
+/// Keeps one device's state in a StateStore.
+///
+struct StoredStateKeeper {
+    store: Arc<StateStore>,
+    device_id: String,
+}
+
+impl StateKeeper for StoredStateKeeper {
+    fn last_state(&self) -> DeviceState {
+        self.store.state(&self.device_id)
+    }
+
+    fn command_applied(&self, command: &Command) {
+        if let Err(e) = self.store.record(&self.device_id, command) {
+            eprintln!("Failed to save state of {} - {}", self.device_id, e);
+        }
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::protocol::*;
+    use crate::led_strip_controller::state::{DeviceState, StateStore};
+    use std::fs;
+
+    #[test]
+    fn device_state_test() {
+        let mut state = DeviceState::default();
+        assert!(state.commands().is_empty());
+
+        assert!(state.apply(&Command::SetBrightness(128)));
+        assert!(!state.apply(&Command::SetBrightness(128)));
+        assert!(!state.apply(&Command::GetStatus));
+        assert!(state.apply(&Command::SetEffect(Effect::Fire)));
+        assert_eq!(
+            state.commands(),
+            vec![
+                Command::SetEffect(Effect::Fire),
+                Command::SetBrightness(128)
+            ]
+        );
+
+        let mut status = DeviceStatus {
+            debugging: false,
+            effect: Effect::Fire,
+            brightness: 128,
+            color: Color24::from_u32(0xff0000),
+            fire_pallet: FireColorPallet::Ocean,
+        };
+        // Settings never applied are not compared
+        assert!(state.matches(&status));
+
+        status.brightness = 255;
+        assert!(!state.matches(&status));
+    }
+
+    #[test]
+    fn state_store_test() {
+        let path =
+            std::env::temp_dir().join(format!("led_oxide_state_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = StateStore::open(&path).unwrap();
+        assert_eq!(store.state("porch"), DeviceState::default());
+
+        store
+            .record("porch", &Command::SetColor(Color24::from_u32(0x00ff80)))
+            .unwrap();
+        store
+            .record(
+                "porch",
+                &Command::SetFireColorPallet(FireColorPallet::RainbowStripe),
+            )
+            .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("\"#00ff80\""));
+        assert!(contents.contains("\"rainbow_stripe\""));
+
+        // States survive reopening the store
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.state("porch"),
+            DeviceState {
+                effect: None,
+                color: Some(Color24::from_u32(0x00ff80)),
+                brightness: None,
+                fire_pallet: Some(FireColorPallet::RainbowStripe),
+            }
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
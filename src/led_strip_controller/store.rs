@@ -0,0 +1,89 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+use std::sync::{LockResult, PoisonError};
+use std::{fs, io};
+
+///
+/// Writes the contents to the file at the given path. They are written to a temporary file next
+/// to it first, which then replaces the file in one step, so a failed write never leaves the file
+/// half written.
+///
+pub(crate) fn write_atomically<P, C>(path: P, contents: C) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    C: AsRef<[u8]>,
+{
+    let path = path.as_ref();
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
+
+///
+/// Returns the guard of a lock, even if a thread panicked while holding it. The stores only ever
+/// replace what their locks guard whole, so a poisoned lock never holds a half made change.
+///
+pub(crate) fn recover<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::store::{recover, write_atomically};
+    use std::sync::{Arc, Mutex};
+    use std::{fs, thread};
+
+    #[test]
+    fn write_atomically_test() {
+        let path =
+            std::env::temp_dir().join(format!("led_oxide_store_{}.json", std::process::id()));
+
+        write_atomically(&path, "first").unwrap();
+        write_atomically(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        assert!(fs::metadata(&temp_path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_test() {
+        let mutex = Arc::new(Mutex::new(1));
+
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisons the lock");
+        })
+        .join();
+
+        assert!(mutex.is_poisoned());
+        assert_eq!(*recover(mutex.lock()), 1);
+    }
+}
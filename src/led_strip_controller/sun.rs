@@ -0,0 +1,267 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Seconds from the Unix epoch to the J2000 epoch, 2000-01-01 12:00 UTC
+const J2000_UNIX_SECS: f64 = 946_728_000.0;
+
+/// Axial tilt of the earth in degrees
+const EARTH_OBLIQUITY: f64 = 23.4397;
+
+/// Days searched for the next sun event. Covers a polar night or midnight sun.
+const MAX_SUN_SEARCH_DAYS: i64 = 366;
+
+///
+/// Position on earth in degrees. North and east are positive.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    ///
+    /// Returns the location, None if the latitude or longitude is out of range.
+    ///
+    pub fn new(latitude: f64, longitude: f64) -> Option<Location> {
+        if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+            Some(Location {
+                latitude,
+                longitude,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+///
+/// Daily events of the sun.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    /// The sun is 6 degrees below the horizon and rising
+    CivilDawn,
+    /// The top of the sun appears above the horizon
+    Sunrise,
+    /// The top of the sun disappears below the horizon
+    Sunset,
+    /// The sun is 6 degrees below the horizon and setting
+    CivilDusk,
+}
+
+impl SunEvent {
+    pub const ALL: [SunEvent; 4] = [
+        SunEvent::CivilDawn,
+        SunEvent::Sunrise,
+        SunEvent::Sunset,
+        SunEvent::CivilDusk,
+    ];
+
+    ///
+    /// Returns the event's name.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            SunEvent::CivilDawn => "civil_dawn",
+            SunEvent::Sunrise => "sunrise",
+            SunEvent::Sunset => "sunset",
+            SunEvent::CivilDusk => "civil_dusk",
+        }
+    }
+
+    ///
+    /// Altitude of the center of the sun at the event, in degrees. Sunrise and sunset allow for
+    /// refraction and the sun's radius.
+    ///
+    fn altitude(self) -> f64 {
+        match self {
+            SunEvent::CivilDawn | SunEvent::CivilDusk => -6.0,
+            SunEvent::Sunrise | SunEvent::Sunset => -0.833,
+        }
+    }
+
+    ///
+    /// Returns true for the events before solar noon.
+    ///
+    fn is_morning(self) -> bool {
+        matches!(self, SunEvent::CivilDawn | SunEvent::Sunrise)
+    }
+}
+
+///
+/// Returns the time of the event on the given date at the location, within a minute or two.
+/// Returns None on days the sun does not reach the event's altitude, such as a polar night.
+/// Computed with the sunrise equation, no network access is needed.
+///
+pub fn sun_event_time(
+    location: &Location,
+    date: NaiveDate,
+    event: SunEvent,
+) -> Option<DateTime<Utc>> {
+    let days = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+    let mean_solar_noon = days - location.longitude / 360.0;
+
+    let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center = 1.9148 * mean_anomaly.sin()
+        + 0.02 * (2.0 * mean_anomaly).sin()
+        + 0.0003 * (3.0 * mean_anomaly).sin();
+    let ecliptic_longitude = (mean_anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let solar_transit =
+        mean_solar_noon + 0.0053 * mean_anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination_sin = ecliptic_longitude.sin() * EARTH_OBLIQUITY.to_radians().sin();
+    let declination_cos = declination_sin.asin().cos();
+    let latitude = location.latitude.to_radians();
+    let hour_angle_cos = (event.altitude().to_radians().sin() - latitude.sin() * declination_sin)
+        / (latitude.cos() * declination_cos);
+    if !(-1.0..=1.0).contains(&hour_angle_cos) {
+        return None;
+    }
+
+    let hour_angle = hour_angle_cos.acos().to_degrees() / 360.0;
+    let day = if event.is_morning() {
+        solar_transit - hour_angle
+    } else {
+        solar_transit + hour_angle
+    };
+
+    Some(Utc.timestamp((J2000_UNIX_SECS + day * 86_400.0).round() as i64, 0))
+}
+
+///
+/// Returns the first time of the event strictly after the given time. Days are counted in
+/// server local time, which is expected to be the location's time zone.
+///
+pub fn next_sun_event(
+    location: &Location,
+    event: SunEvent,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let first_day = after.with_timezone(&Local).date().naive_local().pred();
+
+    (0..MAX_SUN_SEARCH_DAYS)
+        .filter_map(|day| sun_event_time(location, first_day + Duration::days(day), event))
+        .find(|time| *time > after)
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::sun::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn assert_near(time: Option<DateTime<Utc>>, expected: DateTime<Utc>) {
+        let time = time.expect("event should occur");
+        assert!(
+            (time - expected).num_seconds().abs() <= 180,
+            "{} is not near {}",
+            time,
+            expected
+        );
+    }
+
+    #[test]
+    fn location_range_test() {
+        assert!(Location::new(51.5, -0.13).is_some());
+        assert!(Location::new(-90.0, 180.0).is_some());
+        assert!(Location::new(90.5, 0.0).is_none());
+        assert!(Location::new(0.0, -180.5).is_none());
+        assert!(Location::new(f64::NAN, 0.0).is_none());
+    }
+
+    #[test]
+    fn sun_event_time_test() {
+        let london = Location::new(51.5074, -0.1278).unwrap();
+        let midsummer = NaiveDate::from_ymd(2021, 6, 21);
+
+        assert_near(
+            sun_event_time(&london, midsummer, SunEvent::Sunrise),
+            utc(2021, 6, 21, 3, 43),
+        );
+        assert_near(
+            sun_event_time(&london, midsummer, SunEvent::Sunset),
+            utc(2021, 6, 21, 20, 21),
+        );
+        assert_near(
+            sun_event_time(&london, midsummer, SunEvent::CivilDawn),
+            utc(2021, 6, 21, 2, 56),
+        );
+        assert_near(
+            sun_event_time(&london, midsummer, SunEvent::CivilDusk),
+            utc(2021, 6, 21, 21, 8),
+        );
+
+        let sydney = Location::new(-33.8688, 151.2093).unwrap();
+        assert_near(
+            sun_event_time(&sydney, NaiveDate::from_ymd(2021, 12, 21), SunEvent::Sunset),
+            utc(2021, 12, 21, 9, 5),
+        );
+    }
+
+    #[test]
+    fn polar_sun_event_test() {
+        let tromso = Location::new(69.6492, 18.9553).unwrap();
+
+        let polar_night = NaiveDate::from_ymd(2021, 12, 21);
+        assert_eq!(
+            sun_event_time(&tromso, polar_night, SunEvent::Sunrise),
+            None
+        );
+        assert!(sun_event_time(&tromso, polar_night, SunEvent::CivilDawn).is_some());
+
+        let midnight_sun = NaiveDate::from_ymd(2021, 6, 21);
+        assert_eq!(
+            sun_event_time(&tromso, midnight_sun, SunEvent::Sunset),
+            None
+        );
+
+        // The next sunrise after midwinter is weeks away
+        let sunrise = next_sun_event(&tromso, SunEvent::Sunrise, utc(2021, 12, 21, 12, 0)).unwrap();
+        assert!(sunrise > utc(2022, 1, 10, 0, 0) && sunrise < utc(2022, 1, 20, 0, 0));
+    }
+
+    #[test]
+    fn next_sun_event_test() {
+        let london = Location::new(51.5074, -0.1278).unwrap();
+
+        let after = utc(2021, 6, 21, 12, 0);
+        let sunset = next_sun_event(&london, SunEvent::Sunset, after).unwrap();
+        assert_near(Some(sunset), utc(2021, 6, 21, 20, 21));
+
+        let next = next_sun_event(&london, SunEvent::Sunset, sunset).unwrap();
+        assert!((next - sunset).num_hours() >= 23 && (next - sunset).num_hours() <= 24);
+
+        let sunrise = next_sun_event(&london, SunEvent::Sunrise, after).unwrap();
+        assert!(sunrise > after && (sunrise - after).num_hours() < 24);
+    }
+}
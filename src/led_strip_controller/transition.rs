@@ -0,0 +1,412 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::led_strip_controller::color::Color24;
+use crate::led_strip_controller::controller::{ControllerError, DeviceManager, TaskKind};
+use crate::led_strip_controller::protocol::*;
+use crate::led_strip_controller::state::DeviceState;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time between the steps of a transition. Each step sends up to two commands, which the serial
+/// link answers well within this time.
+pub const TRANSITION_STEP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest transition accepted, one hour
+pub const MAX_TRANSITION_MS: u32 = 60 * 60 * 1000;
+
+/// Gamma of the perceptual easing. Perceived lightness is close to linear in the value raised
+/// to 1 / gamma.
+const PERCEPTUAL_GAMMA: f64 = 2.2;
+
+///
+/// How a transition moves from the start to the target value over its duration.
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    /// Changes at a constant rate
+    #[default]
+    Linear,
+    /// Starts and ends slowly
+    EaseInOut,
+    /// Changes at a constant rate as the eye sees it, so dim values change slowly
+    Perceptual,
+}
+
+impl Easing {
+    pub const ALL: [Easing; 3] = [Easing::Linear, Easing::EaseInOut, Easing::Perceptual];
+
+    ///
+    /// Returns the easing's name.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::EaseInOut => "ease_in_out",
+            Easing::Perceptual => "perceptual",
+        }
+    }
+
+    ///
+    /// Returns the easing with the given name. '-' may be used in place of '_'.
+    ///
+    pub fn from_name(name: &str) -> Option<Easing> {
+        let name = name.replace('-', "_");
+        Easing::ALL
+            .iter()
+            .copied()
+            .find(|easing| easing.name() == name)
+    }
+
+    ///
+    /// Returns the value the given fraction of the way, 0.0 to 1.0, from one value to another.
+    ///
+    pub fn interpolate(self, from: u8, to: u8, progress: f64) -> u8 {
+        let progress = progress.max(0.0).min(1.0);
+        let from = f64::from(from);
+        let to = f64::from(to);
+
+        let value = match self {
+            Easing::Linear => from + (to - from) * progress,
+            Easing::EaseInOut => from + (to - from) * (1.0 - (PI * progress).cos()) / 2.0,
+            Easing::Perceptual => {
+                let from = from.powf(1.0 / PERCEPTUAL_GAMMA);
+                let to = to.powf(1.0 / PERCEPTUAL_GAMMA);
+                (from + (to - from) * progress).powf(PERCEPTUAL_GAMMA)
+            }
+        };
+
+        value.round().max(0.0).min(255.0) as u8
+    }
+
+    ///
+    /// Returns the color the given fraction of the way from one color to another, easing each
+    /// channel.
+    ///
+    pub fn interpolate_color(self, from: Color24, to: Color24, progress: f64) -> Color24 {
+        let from = from.to_u32();
+        let to = to.to_u32();
+
+        let channel = |shift: u32| {
+            let value = self.interpolate((from >> shift) as u8, (to >> shift) as u8, progress);
+            u32::from(value) << shift
+        };
+
+        Color24::from_u32(channel(16) | channel(8) | channel(0))
+    }
+}
+
+///
+/// A gradual change of a device's color and brightness.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    /// Color to end at, the color is left alone if None
+    pub color: Option<Color24>,
+    /// Brightness to end at, the brightness is left alone if None
+    pub brightness: Option<u8>,
+    pub duration: Duration,
+    pub easing: Easing,
+}
+
+///
+/// Starts a transition from the device's current color and brightness, cancelling the one in
+/// progress. Intermediate steps are sent from a background thread and are not recorded as the
+/// last known state, only the final step is. The transition is cancelled by any newer
+/// transition or command other than a query, leaving the device at the last step sent.
+///
+/// Fails if the device's status could not be read.
+///
+pub fn start_transition(
+    device_manager: &Arc<DeviceManager>,
+    transition: Transition,
+) -> std::result::Result<thread::JoinHandle<()>, ControllerError> {
+    let id = device_manager.begin_task(TaskKind::Transition);
+    let from = current_state(device_manager)?;
+    let to = DeviceState {
+        color: transition.color,
+        brightness: transition.brightness,
+        ..DeviceState::default()
+    };
+
+    let device_manager = device_manager.clone();
+    thread::Builder::new()
+        .name(String::from("transition"))
+        .spawn(move || {
+            fade(
+                &device_manager,
+                id,
+                &from,
+                &to,
+                transition.duration,
+                transition.easing,
+                true,
+            );
+        })
+        // Failing to start the thread is reported like failing to write to the device
+        .map_err(ControllerError::WriteFailed)
+}
+
+///
+/// Reads the device's current settings. Every setting is unset if the status does not parse.
+///
+pub(crate) fn current_state(
+    device_manager: &DeviceManager,
+) -> std::result::Result<DeviceState, ControllerError> {
+    let protocol = device_manager.protocol()?;
+    let response = device_manager.send_command(Command::GetStatus)?;
+
+    Ok(protocol
+        .parse_device_status(&response)
+        .map(|status| DeviceState::from(&status))
+        .unwrap_or_default())
+}
+
+///
+/// Fades the color and brightness set in the target state, starting from the given state, or
+/// from the target where the start is unknown. Steps are sent unrecorded except the last one if
+/// record_last is set. Returns false if the transition was cancelled or a step failed.
+///
+pub(crate) fn fade(
+    device_manager: &DeviceManager,
+    id: u64,
+    from: &DeviceState,
+    to: &DeviceState,
+    duration: Duration,
+    easing: Easing,
+    record_last: bool,
+) -> bool {
+    let steps = (duration.as_millis() / TRANSITION_STEP_INTERVAL.as_millis()).max(1) as u32;
+    let start = Instant::now();
+    let mut previous: Vec<Command> = vec![];
+
+    for step in 1..=steps {
+        let due = start + duration * step / steps;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+
+        let progress = f64::from(step) / f64::from(steps);
+        let mut commands: Vec<Command> = vec![];
+        if let Some(target) = to.color {
+            let color = easing.interpolate_color(from.color.unwrap_or(target), target, progress);
+            commands.push(Command::SetColor(color));
+        }
+        if let Some(target) = to.brightness {
+            let brightness =
+                easing.interpolate(from.brightness.unwrap_or(target), target, progress);
+            commands.push(Command::SetBrightness(brightness));
+        }
+
+        let last = step == steps;
+        // Unchanged settings are not sent again, except in the last step
+        let changed: Vec<Command> = commands
+            .iter()
+            .copied()
+            .filter(|command| last || !previous.contains(command))
+            .collect();
+        if !send_step(device_manager, id, &changed, last && record_last) {
+            return false;
+        }
+        previous = commands;
+    }
+
+    true
+}
+
+///
+/// Sends the commands of a step back to back, unless the transition was cancelled. Returns
+/// false if it was cancelled or a command failed.
+///
+pub(crate) fn send_step(
+    device_manager: &DeviceManager,
+    id: u64,
+    commands: &[Command],
+    record: bool,
+) -> bool {
+    let mut session = device_manager.session();
+    if !session.is_transition_current(id) {
+        return false;
+    }
+
+    for &command in commands {
+        let result = if record {
+            session.send_command(command)
+        } else {
+            session.send_unrecorded_command(command)
+        };
+
+        if let Err(e) = result {
+            eprintln!("Transition stopped, failed to send {:?} - {}", command, e);
+            return false;
+        }
+    }
+
+    true
+}
+
+///
+/// Waits for the given time, returning false as soon as the transition is cancelled.
+///
+pub(crate) fn hold(device_manager: &DeviceManager, id: u64, duration: Duration) -> bool {
+    let end = Instant::now() + duration;
+
+    loop {
+        if !device_manager.is_transition_current(id) {
+            return false;
+        }
+        let remaining = end.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return true;
+        }
+        thread::sleep(remaining.min(TRANSITION_STEP_INTERVAL));
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::color::Color24;
+    use crate::led_strip_controller::controller::*;
+    use crate::led_strip_controller::simulator::Simulator;
+    use crate::led_strip_controller::state::StateStore;
+    use crate::led_strip_controller::transition::*;
+    use serialport::{SerialPortInfo, SerialPortType};
+
+    fn simulated_device() -> Arc<DeviceManager> {
+        let port_info = SerialPortInfo {
+            port_name: String::from("/dev/sim"),
+            port_type: SerialPortType::Unknown,
+        };
+
+        Arc::new(DeviceManager::with_detector(move || {
+            auto_detect_ledsc_on_ports(vec![port_info.clone()], |_port_info| {
+                Ok(Simulator::new().into_transport())
+            })
+            .map(DetectedDevice::boxed)
+        }))
+    }
+
+    fn brightness(device_manager: &DeviceManager) -> u8 {
+        let protocol = device_manager.protocol().unwrap();
+        let response = device_manager.send_command(Command::GetStatus).unwrap();
+        protocol.parse_device_status(&response).unwrap().brightness
+    }
+
+    #[test]
+    fn easing_test() {
+        for easing in Easing::ALL.iter() {
+            assert_eq!(Easing::from_name(easing.name()), Some(*easing));
+            assert_eq!(easing.interpolate(10, 200, 0.0), 10);
+            assert_eq!(easing.interpolate(10, 200, 1.0), 200);
+            assert_eq!(easing.interpolate(200, 10, 1.0), 10);
+            assert_eq!(easing.interpolate(10, 200, 2.0), 200);
+        }
+        assert_eq!(Easing::from_name("ease-in-out"), Some(Easing::EaseInOut));
+        assert_eq!(Easing::from_name("bounce"), None);
+
+        assert_eq!(Easing::Linear.interpolate(0, 200, 0.5), 100);
+        assert_eq!(Easing::EaseInOut.interpolate(0, 200, 0.5), 100);
+        assert!(
+            Easing::EaseInOut.interpolate(0, 200, 0.1) < Easing::Linear.interpolate(0, 200, 0.1)
+        );
+        // Perceptual easing lingers on dim values
+        assert!(Easing::Perceptual.interpolate(0, 255, 0.5) < 64);
+
+        assert_eq!(
+            Easing::Linear.interpolate_color(
+                Color24::from_u32(0x000000),
+                Color24::from_u32(0xff8040),
+                0.5
+            ),
+            Color24::from_u32(0x804020)
+        );
+    }
+
+    #[test]
+    fn transition_test() {
+        let store = Arc::new(StateStore::new());
+        let mut device_manager = simulated_device();
+        Arc::get_mut(&mut device_manager)
+            .unwrap()
+            .set_state_keeper(store.keeper("sim"));
+        device_manager
+            .send_command(Command::SetBrightness(255))
+            .unwrap();
+
+        let transition = Transition {
+            color: None,
+            brightness: Some(55),
+            duration: Duration::from_millis(500),
+            easing: Easing::Linear,
+        };
+        let handle = start_transition(&device_manager, transition).unwrap();
+
+        thread::sleep(Duration::from_millis(250));
+        let midway = brightness(&device_manager);
+        assert!(midway > 55 && midway < 255, "brightness {}", midway);
+        // Intermediate steps are not recorded, nor undone as disagreeing with the last known state
+        assert_eq!(store.state("sim").brightness, Some(255));
+        thread::sleep(Duration::from_millis(100));
+        assert!(brightness(&device_manager) < midway);
+
+        handle.join().unwrap();
+        assert_eq!(brightness(&device_manager), 55);
+        assert_eq!(store.state("sim").brightness, Some(55));
+    }
+
+    #[test]
+    fn transition_cancelled_test() {
+        let device_manager = simulated_device();
+        let transition = Transition {
+            color: Some(Color24::from_u32(0xffffff)),
+            brightness: Some(0),
+            duration: Duration::from_secs(10),
+            easing: Easing::EaseInOut,
+        };
+        let handle = start_transition(&device_manager, transition).unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        // Status reads leave the transition running, other commands cancel it
+        brightness(&device_manager);
+        device_manager
+            .send_command(Command::SetBrightness(100))
+            .unwrap();
+        handle.join().unwrap();
+        assert_eq!(brightness(&device_manager), 100);
+
+        // A newer transition cancels the one in progress
+        let first = start_transition(&device_manager, transition).unwrap();
+        let second = start_transition(
+            &device_manager,
+            Transition {
+                duration: Duration::from_millis(100),
+                ..transition
+            },
+        )
+        .unwrap();
+        first.join().unwrap();
+        second.join().unwrap();
+        assert_eq!(brightness(&device_manager), 0);
+    }
+}
@@ -0,0 +1,227 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serialport::SerialPort;
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Default baud rate. 115200 - 8 1 none
+pub const LEDSC_BAUD: u32 = 115200;
+
+///
+/// Byte transport to a LEDSC device. Implemented for real serial ports and for an in-memory
+/// transport so the controller logic can run without hardware.
+///
+pub trait Transport {
+    ///
+    /// Writes all bytes to the device.
+    ///
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    ///
+    /// Reads bytes into buf. Waits until at least one byte is available or the deadline passes.
+    /// Returns the number of bytes read, 0 if the deadline passed with nothing to read.
+    ///
+    fn read_with_deadline(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize>;
+
+    ///
+    /// Returns the number of bytes waiting to be read.
+    ///
+    fn bytes_available(&mut self) -> io::Result<u32>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn read_with_deadline(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        (**self).read_with_deadline(buf, deadline)
+    }
+
+    fn bytes_available(&mut self) -> io::Result<u32> {
+        (**self).bytes_available()
+    }
+}
+
+///
+/// Transport over a real serial port.
+///
+pub struct SerialTransport {
+    serial_port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    ///
+    /// Opens the named serial port at the LEDSC baud rate.
+    ///
+    pub fn open(port_name: &str) -> serialport::Result<SerialTransport> {
+        let serial_port = serialport::new(port_name, LEDSC_BAUD).open()?;
+        Ok(SerialTransport { serial_port })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        io::Write::write_all(&mut self.serial_port, buf)
+    }
+
+    fn read_with_deadline(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        // A passed deadline still polls once for bytes already waiting
+        self.serial_port
+            .set_timeout(deadline.saturating_duration_since(Instant::now()))?;
+
+        match io::Read::read(&mut self.serial_port, buf) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn bytes_available(&mut self) -> io::Result<u32> {
+        self.serial_port.bytes_to_read().map_err(io::Error::from)
+    }
+}
+
+///
+/// Produces the bytes a device sends back for the bytes written to it.
+///
+pub type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+///
+/// In-memory transport. Incoming bytes are queued by the test or produced by a responder each
+/// time bytes are written. Everything written is recorded.
+///
+#[derive(Default)]
+pub struct MemoryTransport {
+    incoming: VecDeque<u8>,
+    written: Vec<u8>,
+    responder: Option<Responder>,
+}
+
+impl MemoryTransport {
+    ///
+    /// Creates an in-memory transport that never answers on its own.
+    ///
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    ///
+    /// Creates an in-memory transport that answers every write with the responder's output.
+    ///
+    pub fn with_responder<F>(responder: F) -> MemoryTransport
+    where
+        F: FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        MemoryTransport {
+            responder: Some(Box::new(responder)),
+            ..MemoryTransport::default()
+        }
+    }
+
+    ///
+    /// Queues bytes to be read from the transport.
+    ///
+    pub fn push_incoming(&mut self, bytes: &[u8]) {
+        self.incoming.extend(bytes);
+    }
+
+    ///
+    /// Returns all bytes written to the transport so far.
+    ///
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.written.extend_from_slice(buf);
+
+        if let Some(responder) = self.responder.as_mut() {
+            let response = responder(buf);
+            self.incoming.extend(response);
+        }
+
+        Ok(())
+    }
+
+    fn read_with_deadline(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            // Nothing will ever arrive without a write, behave like a silent port
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep((deadline - now).min(Duration::from_millis(10)));
+            }
+            return Ok(0);
+        }
+
+        let count = buf.len().min(self.incoming.len());
+        for (slot, byte) in buf.iter_mut().zip(self.incoming.drain(..count)) {
+            *slot = byte;
+        }
+
+        Ok(count)
+    }
+
+    fn bytes_available(&mut self) -> io::Result<u32> {
+        Ok(self.incoming.len() as u32)
+    }
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::led_strip_controller::transport::{MemoryTransport, Transport};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn memory_transport_read_test() {
+        let mut transport = MemoryTransport::new();
+        transport.push_incoming(b"[CSE:0]A0D8\r\n");
+
+        assert_eq!(transport.bytes_available().unwrap(), 13);
+
+        let mut buf = [0; 8];
+        let deadline = Instant::now() + Duration::from_millis(10);
+
+        assert_eq!(transport.read_with_deadline(&mut buf, deadline).unwrap(), 8);
+        assert_eq!(&buf, b"[CSE:0]A");
+        assert_eq!(transport.read_with_deadline(&mut buf, deadline).unwrap(), 5);
+        assert_eq!(&buf[..5], b"0D8\r\n");
+        assert_eq!(transport.read_with_deadline(&mut buf, deadline).unwrap(), 0);
+    }
+
+    #[test]
+    fn memory_transport_responder_test() {
+        let mut transport = MemoryTransport::with_responder(|written| {
+            assert_eq!(written, b"ping");
+            b"pong".to_vec()
+        });
+
+        transport.write_all(b"ping").unwrap();
+
+        assert_eq!(transport.written(), b"ping");
+        assert_eq!(transport.bytes_available().unwrap(), 4);
+    }
+}
@@ -22,47 +22,111 @@
 #[macro_use]
 extern crate rocket;
 
+#[macro_use]
+mod openapi;
+mod api_v1;
+
 use led_oxide::led_strip_controller::color::*;
-use led_oxide::led_strip_controller::controller;
+use led_oxide::led_strip_controller::controller::{ControllerError, DeviceManager};
+use led_oxide::led_strip_controller::events::DeviceEvent;
+use led_oxide::led_strip_controller::firmware::{FirmwareFlasher, FlashStatus, DEFAULT_LOADER_MCU, DEFAULT_LOADER_PATH};
+use led_oxide::led_strip_controller::group::{for_each_member, send_to_group, GroupCommand, GroupError, GroupStore, MemberResult};
+use led_oxide::led_strip_controller::hotplug::{spawn_hotplug_watcher, DEFAULT_HOTPLUG_POLL_INTERVAL};
+use led_oxide::led_strip_controller::registry::DeviceRegistry;
+use led_oxide::led_strip_controller::scene::SceneStore;
+use led_oxide::led_strip_controller::script::ScriptStore;
+use led_oxide::led_strip_controller::sequence::SequenceStore;
+use led_oxide::led_strip_controller::schedule::{run_schedule, spawn_scheduler, ScheduleStore, SCHEDULER_TICK};
+use led_oxide::led_strip_controller::state::StateStore;
+use led_oxide::led_strip_controller::sun::Location;
+use led_oxide::led_strip_controller::transition::{start_transition, Easing, Transition, MAX_TRANSITION_MS};
 use led_oxide::led_strip_controller::protocol::*;
-use led_oxide::led_strip_controller::protocol::ResponsePacketOption::{ Success, FailedRemote, FailedLocal };
-use chrono::{DateTime, Utc};
-use serde::Serialize;
+use openapi::{ApiSchema, Operation, SchemaFn};
+use serde::{Deserialize, Serialize};
 use rocket::request::Form;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::response::{status, Content, Stream};
 use rocket::Data;
 use rocket::Request;
+use rocket::Rocket;
+use rocket::State;
 use rocket_contrib::json::Json;
 use rocket_contrib::serve::StaticFiles;
-use std::fs::File;
+use serde_json::Value;
 use std::io::Read;
-use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
 
-const MAX_FW_UPLOAD_SIZE: u64 = 524288;
+const EVENT_STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
 
-const ERR_STR_FAIL_TO_FIND_HW: &str = "Failed to find LEDSC Hardware";
+api_schema! {
+    ///
+    /// Simple command response data structure. Used as return value for basic commands:
+    /// set brightness, effect, color, etc...
+    ///
+    #[derive(Serialize, Debug)]
+    struct SimpleCmdResponse {
+        success: bool,
+        status_str: String,
+        error_code: Option<i16>,
+    }
+}
 
-///
-/// Simple command response data structure. Used as return value for basic commands:
-/// set brightness, effect, color, etc...
-///
-#[derive(Serialize)]
-struct SimpleCmdResponse {
-    success: bool,
-    status_str: String,
+api_schema! {
+    ///
+    /// Used as the response when getting device status.
+    /// 
+    #[derive(Serialize)]
+    struct LedStatusResponse {
+        success: bool,
+        status_str: String,
+        brightness_percent: f32,
+        effect_id: u8,
+        color: String,
+        fire_pallet_id: u8,
+        hw_debug: bool,
+        error_code: Option<i16>,
+    }
 }
 
-///
-/// Used as the response when getting device status.
-/// 
-#[derive(Serialize)]
-struct LedStatusResponse {
-    success: bool,
-    status_str: String,
-    brightness_percent: f32,
-    effect_id: u8,
-    color: String,
-    fire_pallet_id: u8,
-    hw_debug: bool,
+api_schema! {
+    ///
+    /// Used as the response when listing devices.
+    ///
+    #[derive(Serialize)]
+    struct DeviceResponse {
+        id: String,
+        port_name: Option<String>,
+        firmware_version: Option<String>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Result of a group command for one member.
+    ///
+    #[derive(Serialize)]
+    struct MemberCmdResponse {
+        device_id: String,
+        success: bool,
+        status_str: String,
+        error_code: Option<i16>,
+    }
+}
+
+api_schema! {
+    ///
+    /// Used as the response to group commands.
+    ///
+    #[derive(Serialize)]
+    struct GroupCmdResponse {
+        success: bool,
+        status_str: String,
+        members: Vec<MemberCmdResponse>,
+    }
 }
 
 ///
@@ -82,352 +146,1033 @@ fn index() -> &'static str {
 }
 
 ///
-/// Set brightness endpoint data
+/// Maps a controller error to the HTTP status returned to API clients.
 ///
-#[derive(FromForm)]
-struct FormDataBrightness {
-    brightness_percent: f32,
+fn error_status(error: &ControllerError) -> Status {
+    match error {
+        ControllerError::NoPorts(..)
+        | ControllerError::NoDevicesFound
+        | ControllerError::OpenFailed { .. } => Status::ServiceUnavailable,
+        ControllerError::UnknownDevice(..) => Status::NotFound,
+        ControllerError::Timeout => Status::GatewayTimeout,
+        ControllerError::Unsupported { .. } => Status::NotImplemented,
+        ControllerError::UnknownEffect(..) | ControllerError::UnknownFireColorPallet(..) => {
+            Status::BadRequest
+        }
+        ControllerError::Remote(..) => match error.protocol_error() {
+            // Firmware rejected the request itself
+            Some(ProtocolError::MissingParams) | Some(ProtocolError::ParamOutOfRange) => {
+                Status::BadRequest
+            }
+            Some(ProtocolError::CmdNotImplemented) | Some(ProtocolError::CmdUnknown) => {
+                Status::NotImplemented
+            }
+            _ => Status::BadGateway,
+        },
+        ControllerError::WriteFailed(..)
+        | ControllerError::ReadFailed(..)
+        | ControllerError::Local(..) => Status::BadGateway,
+    }
+}
+
+api_schema! {
+    ///
+    /// Set brightness endpoint data. With transition_ms the brightness fades over that many
+    /// milliseconds, using the named easing: linear (default), ease_in_out or perceptual.
+    ///
+    #[derive(FromForm)]
+    struct FormDataBrightness {
+        brightness_percent: f32,
+        transition_ms: Option<u32>,
+        easing: Option<String>,
+    }
 }
 
 ///
-/// Set brightness endpoint
+/// Returns the transition to the given color and brightness asked for by the transition_ms and
+/// easing form fields. None if the change should be made at once.
 ///
-#[post("/brightness", data = "<brightness_data>")]
-fn set_brightness(brightness_data: Form<FormDataBrightness>) -> Json<SimpleCmdResponse> {
+fn form_transition(
+    color: Option<Color24>,
+    brightness: Option<u8>,
+    transition_ms: Option<u32>,
+    easing: &Option<String>,
+) -> Result<Option<Transition>, String> {
+
+    let easing = match easing {
+        Some(name) => Easing::from_name(name).ok_or_else(|| format!("Unknown easing {}", name))?,
+        None => Easing::default(),
+    };
+
+    match transition_ms {
+        Some(transition_ms) if transition_ms > MAX_TRANSITION_MS => {
+            Err(format!("Transition of {} ms is longer than {} ms", transition_ms, MAX_TRANSITION_MS))
+        }
+        Some(transition_ms) if transition_ms > 0 => Ok(Some(Transition {
+            color,
+            brightness,
+            duration: Duration::from_millis(u64::from(transition_ms)),
+            easing,
+        })),
+        _ => Ok(None),
+    }
+}
+
+///
+/// Sets a color or brightness on the device, at once or through the given transition.
+///
+fn set_or_transition(
+    device_manager: &Arc<DeviceManager>,
+    command: Command,
+    transition: Option<Transition>,
+) -> Result<(), ControllerError> {
+    match transition {
+        Some(transition) => start_transition(device_manager, transition).map(|_| ()),
+        None => device_manager.send_command(command).map(|_| ()),
+    }
+}
+
+///
+/// Sets the brightness of the given device
+///
+fn set_brightness_on(
+    device: Result<Arc<DeviceManager>, ControllerError>,
+    brightness_data: &FormDataBrightness,
+) -> status::Custom<Json<SimpleCmdResponse>> {
 
     let status: String;
 
-    match controller::auto_detect_ledsc() {
-        Ok(port_info) => {
-            let brightness: u8 = ((brightness_data.brightness_percent / 100.00) * 255.00) as u8;
+    let brightness = match api_v1::brightness_from_percent(brightness_data.brightness_percent) {
+        Ok(brightness) => brightness,
+        Err(e) => return e,
+    };
 
-            let protocol_instance = LedscTeensy001 {};
-            let cmd = protocol_instance.create_cmd_string(Command::SetBrightness(brightness));
+    let transition = match form_transition(None, Some(brightness), brightness_data.transition_ms, &brightness_data.easing) {
+        Ok(transition) => transition,
+        Err(status) => {
+            println!("{}", status);
+            return status::Custom(Status::BadRequest, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            }));
+        }
+    };
 
-            match controller::send_command_wait_for_response(&port_info, cmd) {
-                Ok(_rsp_pkt) => {
-                    status = String::from("Set Brightness");
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: true, status_str: status});
-                }
-                Err(rsp_pkt) => {
-                    status = String::from(format!("Failed to set brightness - {:?}", rsp_pkt));
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: false, status_str: status});
-                }
-            }
+    match device.and_then(|device_manager| {
+        set_or_transition(&device_manager, Command::SetBrightness(brightness), transition)
+    }) {
+        Ok(()) => {
+            status = String::from("Set Brightness");
+            println!("{}", status);
+            status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                success: true,
+                status_str: status,
+                error_code: None,
+            }))
         }
-        Err(_e) => {
-            status = String::from(ERR_STR_FAIL_TO_FIND_HW);
+        Err(e) => {
+            status = format!("Failed to set brightness - {}", e);
             println!("{}", status);
-            return Json(SimpleCmdResponse { success: false, status_str: status});
+            status::Custom(error_status(&e), Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: e.protocol_error().map(|pe| pe.code()),
+            }))
         }
-    };
+    }
 }
 
 ///
-/// Set effect endpoint data
+/// Set brightness endpoint
 ///
-#[derive(FromForm)]
-struct FormDataEffect {
-    effect_id: u8,
+#[post("/brightness", data = "<brightness_data>")]
+fn set_brightness(
+    registry: State<Arc<DeviceRegistry>>,
+    brightness_data: Form<FormDataBrightness>,
+) -> status::Custom<Json<SimpleCmdResponse>> {
+    set_brightness_on(registry.default_device(), &brightness_data)
 }
 
 ///
-/// Set effect endpoint
+/// Set device brightness endpoint
 ///
-#[post("/effect", data = "<effect_data>")]
-fn set_effect(effect_data: Form<FormDataEffect>) -> Json<SimpleCmdResponse> {
+#[post("/devices/<id>/brightness", data = "<brightness_data>")]
+fn set_device_brightness(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+    brightness_data: Form<FormDataBrightness>,
+) -> Option<status::Custom<Json<SimpleCmdResponse>>> {
+    registry.device(&id).map(|device_manager| set_brightness_on(Ok(device_manager), &brightness_data))
+}
+
+api_schema! {
+    ///
+    /// Set effect endpoint data
+    ///
+    #[derive(FromForm)]
+    struct FormDataEffect {
+        effect_id: u8,
+    }
+}
+
+///
+/// Sets the effect of the given device
+///
+fn set_effect_on(
+    device: Result<Arc<DeviceManager>, ControllerError>,
+    effect_data: &FormDataEffect,
+) -> status::Custom<Json<SimpleCmdResponse>> {
 
     let status: String;
 
-    match controller::auto_detect_ledsc() {
-        Ok(port_info) => {
-            let protocol_instance = LedscTeensy001 {};
-            let cmd = protocol_instance.create_cmd_string(Command::SetEffect(
-                protocol_instance.get_effect_from_cmd_value(&effect_data.effect_id),
-            ));
+    // Effect ids are firmware version specific
+    let result = device.and_then(|device_manager| {
+        let protocol = device_manager.protocol()?;
+        match protocol.find_effect(effect_data.effect_id) {
+            Some(effect) => device_manager.send_command(Command::SetEffect(effect)).map(Some),
+            None => Ok(None),
+        }
+    });
 
-            match controller::send_command_wait_for_response(&port_info, cmd) {
-                Ok(_rsp_pkt) => {
-                    status = String::from("Set Effect");
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: true, status_str: status});
-                }
-                Err(rsp_pkt) => {
-                    status = String::from(format!("Failed to set effect - {:?}", rsp_pkt));
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: false, status_str: status});
-                }
-            }
+    match result {
+        Ok(Some(_rsp_pkt)) => {
+            status = String::from("Set Effect");
+            println!("{}", status);
+            status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                success: true,
+                status_str: status,
+                error_code: None,
+            }))
         }
-        Err(_e) => {
-            status = String::from(ERR_STR_FAIL_TO_FIND_HW);
+        Ok(None) => {
+            status = format!("Unknown effect id {}", effect_data.effect_id);
             println!("{}", status);
-            return Json(SimpleCmdResponse { success: false, status_str: status});
+            status::Custom(Status::BadRequest, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            }))
         }
-    };
+        Err(e) => {
+            status = format!("Failed to set effect - {}", e);
+            println!("{}", status);
+            status::Custom(error_status(&e), Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: e.protocol_error().map(|pe| pe.code()),
+            }))
+        }
+    }
 }
 
 ///
-/// Set color endpoint data
+/// Set effect endpoint
 ///
-#[derive(FromForm)]
-struct FormDataColor {
-    color: String,
+#[post("/effect", data = "<effect_data>")]
+fn set_effect(
+    registry: State<Arc<DeviceRegistry>>,
+    effect_data: Form<FormDataEffect>,
+) -> status::Custom<Json<SimpleCmdResponse>> {
+    set_effect_on(registry.default_device(), &effect_data)
 }
 
 ///
-/// Set color endpoint
+/// Set device effect endpoint
 ///
-#[post("/color", data = "<color_data>")]
-fn set_color(color_data: Form<FormDataColor>) -> Json<SimpleCmdResponse> {
+#[post("/devices/<id>/effect", data = "<effect_data>")]
+fn set_device_effect(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+    effect_data: Form<FormDataEffect>,
+) -> Option<status::Custom<Json<SimpleCmdResponse>>> {
+    registry.device(&id).map(|device_manager| set_effect_on(Ok(device_manager), &effect_data))
+}
+
+api_schema! {
+    ///
+    /// Set color endpoint data. With transition_ms the color fades over that many milliseconds,
+    /// using the named easing: linear (default), ease_in_out or perceptual.
+    ///
+    #[derive(FromForm)]
+    struct FormDataColor {
+        color: String,
+        transition_ms: Option<u32>,
+        easing: Option<String>,
+    }
+}
+
+///
+/// Sets the color of the given device
+///
+fn set_color_on(
+    device: Result<Arc<DeviceManager>, ControllerError>,
+    color_data: &FormDataColor,
+) -> status::Custom<Json<SimpleCmdResponse>> {
 
     let status: String;
-    
-    match controller::auto_detect_ledsc() {
-        Ok(port_info) => {
-            let color_result = u32::from_str_radix(color_data.color.as_str().trim_matches('#'), 16);
-
-            match color_result {
-                Ok(color_int) => {
-                    let protocol_instance = LedscTeensy001 {};
-                    let cmd = protocol_instance
-                        .create_cmd_string(Command::SetColor(Color24::from_u32(color_int)));
-
-                    match controller::send_command_wait_for_response(&port_info, cmd) {
-                        Ok(_rsp_pkt) => {
-                            status = String::from("Set Color");
-                            println!("{}", status);
-                            return Json(SimpleCmdResponse { success: true, status_str: status});
-                        }
-                        Err(rsp_pkt) => {
-                            status = String::from(format!("Failed to set color - {:?}", rsp_pkt));
-                            println!("{}", status);
-                            return Json(SimpleCmdResponse { success: false, status_str: status});
-                        }
-                    }
+
+    match color_data.color.parse::<Color24>() {
+        Ok(color) => {
+            let transition = match form_transition(Some(color), None, color_data.transition_ms, &color_data.easing) {
+                Ok(transition) => transition,
+                Err(status) => {
+                    println!("{}", status);
+                    return status::Custom(Status::BadRequest, Json(SimpleCmdResponse {
+                        success: false,
+                        status_str: status,
+                        error_code: None,
+                    }));
+                }
+            };
+
+            let result = device.and_then(|device_manager| {
+                set_or_transition(&device_manager, Command::SetColor(color), transition)
+            });
+
+            match result {
+                Ok(()) => {
+                    status = String::from("Set Color");
+                    println!("{}", status);
+                    status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                        success: true,
+                        status_str: status,
+                        error_code: None,
+                    }))
                 }
                 Err(e) => {
-                    status = String::from(format!("Failed to parse color parameter: {} - {}", color_data.color, e));
+                    status = format!("Failed to set color - {}", e);
                     println!("{}", status);
-                    return Json(SimpleCmdResponse { success: false, status_str: status});
+                    status::Custom(error_status(&e), Json(SimpleCmdResponse {
+                        success: false,
+                        status_str: status,
+                        error_code: e.protocol_error().map(|pe| pe.code()),
+                    }))
                 }
             }
         }
-        Err(_e) => {
-            status = String::from(ERR_STR_FAIL_TO_FIND_HW);
+        Err(e) => {
+            status = format!("Failed to parse color parameter - {}", e);
             println!("{}", status);
-            return Json(SimpleCmdResponse { success: false, status_str: status});
+            status::Custom(Status::BadRequest, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            }))
         }
-    };
+    }
+}
+
+///
+/// Set color endpoint
+///
+#[post("/color", data = "<color_data>")]
+fn set_color(
+    registry: State<Arc<DeviceRegistry>>,
+    color_data: Form<FormDataColor>,
+) -> status::Custom<Json<SimpleCmdResponse>> {
+    set_color_on(registry.default_device(), &color_data)
 }
 
 ///
-/// Set the Firepalle endpoint data
+/// Set device color endpoint
 ///
-#[derive(FromForm)]
-struct FormDataFirePallet {
-    pallet_id: u8,
+#[post("/devices/<id>/color", data = "<color_data>")]
+fn set_device_color(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+    color_data: Form<FormDataColor>,
+) -> Option<status::Custom<Json<SimpleCmdResponse>>> {
+    registry.device(&id).map(|device_manager| set_color_on(Ok(device_manager), &color_data))
+}
+
+api_schema! {
+    ///
+    /// Set the Firepalle endpoint data
+    ///
+    #[derive(FromForm)]
+    struct FormDataFirePallet {
+        pallet_id: u8,
+    }
+}
+
+///
+/// Sets the fire color pallet of the given device
+///
+fn set_fire_color_pallet_on(
+    device: Result<Arc<DeviceManager>, ControllerError>,
+    fire_pallet_data: &FormDataFirePallet,
+) -> status::Custom<Json<SimpleCmdResponse>> {
+
+    let status: String;
+
+    // Pallet ids are firmware version specific
+    let result = device.and_then(|device_manager| {
+        let protocol = device_manager.protocol()?;
+        match protocol.find_fire_color_pallet(fire_pallet_data.pallet_id) {
+            Some(pallet) => device_manager.send_command(Command::SetFireColorPallet(pallet)).map(Some),
+            None => Ok(None),
+        }
+    });
+
+    match result {
+        Ok(Some(_rsp_pkt)) => {
+            status = String::from("Set Color Fire Pallet");
+            println!("{}", status);
+            status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                success: true,
+                status_str: status,
+                error_code: None,
+            }))
+        }
+        Ok(None) => {
+            status = format!("Unknown fire pallet id {}", fire_pallet_data.pallet_id);
+            println!("{}", status);
+            status::Custom(Status::BadRequest, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            }))
+        }
+        Err(e) => {
+            status = format!("Failed to set color fire pallet - {}", e);
+            println!("{}", status);
+            status::Custom(error_status(&e), Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: e.protocol_error().map(|pe| pe.code()),
+            }))
+        }
+    }
 }
 
 ///
 /// Set the Firepalle endpoint
 ///
 #[post("/firepallet", data = "<fire_pallet_data>")]
-fn set_fire_color_pallet(fire_pallet_data: Form<FormDataFirePallet>) -> Json<SimpleCmdResponse> {
+fn set_fire_color_pallet(
+    registry: State<Arc<DeviceRegistry>>,
+    fire_pallet_data: Form<FormDataFirePallet>,
+) -> status::Custom<Json<SimpleCmdResponse>> {
+    set_fire_color_pallet_on(registry.default_device(), &fire_pallet_data)
+}
+
+///
+/// Set the device Firepalle endpoint
+///
+#[post("/devices/<id>/firepallet", data = "<fire_pallet_data>")]
+fn set_device_fire_color_pallet(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+    fire_pallet_data: Form<FormDataFirePallet>,
+) -> Option<status::Custom<Json<SimpleCmdResponse>>> {
+    registry
+        .device(&id)
+        .map(|device_manager| set_fire_color_pallet_on(Ok(device_manager), &fire_pallet_data))
+}
+
+///
+/// Gets the status & state of the given device
+///
+fn get_status_of(
+    device: Result<Arc<DeviceManager>, ControllerError>,
+) -> status::Custom<Json<LedStatusResponse>> {
 
     let status: String;
-    
-    match controller::auto_detect_ledsc() {
-        Ok(port_info) => {
-            let protocol_instance = LedscTeensy001 {};
-            let cmd = protocol_instance.create_cmd_string(Command::SetFireColorPallet(
-                protocol_instance.get_fire_color_pallet_from_cmd_value(&fire_pallet_data.pallet_id),
-            ));
-
-            match controller::send_command_wait_for_response(&port_info, cmd) {
-                Ok(_rsp_pkt) => {
-                    status = String::from("Set Color Fire Pallet");
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: true, status_str: status});
-                }
-                Err(rsp_pkt) => {
-                    status = String::from(format!("Failed to set color fire pallet - {:?}", rsp_pkt));
-                    println!("{}", status);
-                    return Json(SimpleCmdResponse { success: false, status_str: status});
-                }
+
+    let result = device.and_then(|device_manager| {
+        let protocol = device_manager.protocol()?;
+        device_manager
+            .send_command(Command::GetStatus)
+            .map(|pkt| (protocol, pkt))
+    });
+
+    match result {
+        Ok((protocol, pkt)) => match protocol.parse_device_status(&pkt) {
+            Ok(device_status) => {
+                status = String::from("Status Read");
+                println!("{}", status);
+                status::Custom(Status::Ok, Json(LedStatusResponse {
+                    success: true,
+                    status_str: status,
+                    brightness_percent: device_status.brightness_percent(),
+                    effect_id: protocol.get_effect_cmd_value(&device_status.effect),
+                    color: format!("#{:06x}", device_status.color.to_u32()),
+                    fire_pallet_id: protocol.get_fire_color_pallet_value(&device_status.fire_pallet),
+                    hw_debug: device_status.debugging,
+                    error_code: None,
+                }))
             }
-        }
-        Err(_e) => {
-            status = String::from(ERR_STR_FAIL_TO_FIND_HW);
+            Err(e) => {
+                status = format!("Get Status response could not be decoded - {}", e);
+                println!("{}", status);
+                status::Custom(Status::BadGateway, Json(LedStatusResponse {
+                    success: false,
+                    status_str: status,
+                    brightness_percent: 0.0,
+                    effect_id: 0,
+                    color: String::from("#000000"),
+                    fire_pallet_id: 0,
+                    hw_debug: false,
+                    error_code: None,
+                }))
+            }
+        },
+        Err(e) => {
+            status = format!("Failed to get status - {}", e);
             println!("{}", status);
-            return Json(SimpleCmdResponse { success: false, status_str: status});
+            status::Custom(error_status(&e), Json(LedStatusResponse {
+                success: false,
+                status_str: status,
+                brightness_percent: 0.0,
+                effect_id: 0,
+                color: String::from("#000000"),
+                fire_pallet_id: 0,
+                hw_debug: false,
+                error_code: e.protocol_error().map(|pe| pe.code()),
+            }))
         }
-    };
+    }
 }
 
 ///
 /// Gets the device status & state
 ///
 #[get("/status")]
-fn get_device_status() -> Json<LedStatusResponse> {
+fn get_device_status(registry: State<Arc<DeviceRegistry>>) -> status::Custom<Json<LedStatusResponse>> {
+    get_status_of(registry.default_device())
+}
+
+///
+/// Gets the status & state of a device
+///
+#[get("/devices/<id>/status")]
+fn get_device_status_by_id(
+    id: String,
+    registry: State<Arc<DeviceRegistry>>,
+) -> Option<status::Custom<Json<LedStatusResponse>>> {
+    registry.device(&id).map(|device_manager| get_status_of(Ok(device_manager)))
+}
+
+///
+/// Lists the detected devices
+///
+#[get("/devices")]
+fn get_devices(registry: State<Arc<DeviceRegistry>>) -> Json<Vec<DeviceResponse>> {
+    Json(
+        registry
+            .devices()
+            .into_iter()
+            .map(|(id, device_manager)| DeviceResponse {
+                id,
+                port_name: device_manager.port_info().map(|port_info| port_info.port_name),
+                firmware_version: device_manager.firmware_version(),
+            })
+            .collect(),
+    )
+}
+
+api_schema! {
+    ///
+    /// Group definition. Used as the request body when saving a group and as the response when
+    /// listing groups.
+    ///
+    #[derive(Serialize, Deserialize)]
+    struct GroupData {
+        #[serde(default)]
+        name: String,
+        members: Vec<String>,
+    }
+}
+
+///
+/// Lists the device groups
+///
+#[get("/groups")]
+fn get_groups(groups: State<Arc<GroupStore>>) -> Json<Vec<GroupData>> {
+    Json(
+        groups
+            .groups()
+            .into_iter()
+            .map(|(name, members)| GroupData { name, members })
+            .collect(),
+    )
+}
+
+///
+/// Creates or replaces a device group
+///
+#[put("/groups/<name>", format = "json", data = "<group_data>")]
+fn put_group(
+    name: String,
+    groups: State<Arc<GroupStore>>,
+    group_data: Json<GroupData>,
+) -> status::Custom<Json<SimpleCmdResponse>> {
 
     let status: String;
 
-    match controller::auto_detect_ledsc() {
-        Ok(port_info) => {
-            //let brightness: u8 = ((brightness_data.brightness_percent / 100.00) * 255.00) as u8;
-
-            let protocol_instance = LedscTeensy001 {};
-            let cmd = protocol_instance.create_cmd_string(Command::GetStatus);
-
-            match controller::send_command_wait_for_response(&port_info, cmd) {
-                Ok(rsp_pkt) => {
-                
-                    match protocol_instance.parse_response_sting(rsp_pkt) {
-                        Success(pkt) => {
-                        
-                        let status_packed: &String = &pkt.parameters[1];
-                        let split = status_packed.split('|');
-                        let mut led_status = LedStatusResponse {
-                            success: true,
-                            status_str: String::from(status_packed),
-                            brightness_percent: 0.0,
-                            effect_id: 0,
-                            color: String::from("#000000"),
-                            fire_pallet_id: 0,
-                            hw_debug: false,
-                        };
-                        
-                        let mut count = 0;
-                        
-                        for val in split {
-                        
-                            if count == 0 {
-                                // Debug enabled
-                                led_status.hw_debug = match u8::from_str_radix(val, 16) {
-                                    Ok(dbg) => dbg != 0,
-                                    Err(_) => false,
-                                };
-                            } else if count == 1 {
-                                // Active Effect ID
-                                led_status.effect_id = match u8::from_str_radix(val, 16) {
-                                    Ok(id) => id,
-                                    Err(_) => 0,
-                                };
-                            } else if count == 2 {
-                                // Brightness percent
-                                led_status.brightness_percent = match u8::from_str_radix(val, 16) {
-                                    Ok(b) => b as f32 / 255.0,
-                                    Err(_) => 0.0,
-                                };
-                            } else if count == 3 {
-                                // Color RGB
-                                led_status.color = String::from(val);
-                            } else if count == 4 {
-                                // Fire Color Pallet ID
-                                led_status.fire_pallet_id = match u8::from_str_radix(val, 16) {
-                                    Ok(id) => id,
-                                    Err(_) => 0,
-                                };
-                            }
-                            
-                            count+=1;
-                        }
-                        
-                        status = String::from("Status Read");
-                            println!("{}", status);
-                            return Json(led_status);
-                        }
-                        FailedRemote(pkt) => {
-                        status = String::from(format!("Get Status hardware reported error - {:?}", pkt));
-                            println!("{}", status);
-                            return Json(LedStatusResponse {
-                                success: false,
-                                status_str: status,
-                                brightness_percent: 0.0,
-                                effect_id: 0,
-                                color: String::from("#000000"),
-                                fire_pallet_id: 0,
-                                hw_debug: false,
-                            });
-                        }
-                        FailedLocal(errcode) => {
-                        status = String::from(format!("Get Status response failed local parsing - {}", errcode));
-                            println!("{}", status);
-                            return Json(LedStatusResponse {
-                                success: false,
-                                status_str: status,
-                                brightness_percent: 0.0,
-                                effect_id: 0,
-                                color: String::from("#000000"),
-                                fire_pallet_id: 0,
-                                hw_debug: false,
-                            });
-                        }
-                    }
-                }
-                Err(rsp_pkt) => {
-                    status = String::from(format!("Failed to get status - {:?}", rsp_pkt));
-                    println!("{}", status);
-                    return Json(LedStatusResponse {
-                        success: false,
-                        status_str: status,
-                        brightness_percent: 0.0,
-                        effect_id: 0,
-                        color: String::from("#000000"),
-                        fire_pallet_id: 0,
-                        hw_debug: false,
-                    });
+    match groups.set_group(&name, group_data.into_inner().members) {
+        Ok(()) => {
+            status = format!("Saved group {}", name);
+            println!("{}", status);
+            status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                success: true,
+                status_str: status,
+                error_code: None,
+            }))
+        }
+        Err(e) => {
+            status = format!("Failed to save group {} - {}", name, e);
+            println!("{}", status);
+            let http_status = match e {
+                GroupError::InvalidName(..) | GroupError::NoMembers => Status::BadRequest,
+                GroupError::Io(..) | GroupError::Format(..) => Status::InternalServerError,
+            };
+            status::Custom(http_status, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            }))
+        }
+    }
+}
+
+///
+/// Deletes a device group
+///
+#[delete("/groups/<name>")]
+fn delete_group(
+    name: String,
+    groups: State<Arc<GroupStore>>,
+) -> Option<status::Custom<Json<SimpleCmdResponse>>> {
+
+    let status: String;
+
+    match groups.remove_group(&name) {
+        Ok(false) => None,
+        Ok(true) => {
+            status = format!("Deleted group {}", name);
+            println!("{}", status);
+            Some(status::Custom(Status::Ok, Json(SimpleCmdResponse {
+                success: true,
+                status_str: status,
+                error_code: None,
+            })))
+        }
+        Err(e) => {
+            status = format!("Failed to delete group {} - {}", name, e);
+            println!("{}", status);
+            Some(status::Custom(Status::InternalServerError, Json(SimpleCmdResponse {
+                success: false,
+                status_str: status,
+                error_code: None,
+            })))
+        }
+    }
+}
+
+///
+/// Sends a command to every member of a group. Responds 200 if every member succeeded and 207
+/// with the per member results otherwise.
+///
+fn send_group_command(
+    name: &str,
+    registry: &DeviceRegistry,
+    groups: &GroupStore,
+    command: GroupCommand,
+    action: &str,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+
+    let members = groups.members(name)?;
+
+    let results = send_to_group(registry, &members, command);
+    Some(group_response(results, action, &action.to_lowercase(), &format!("group {}", name)))
+}
+
+///
+/// Starts a transition on every member of the group.
+///
+fn send_group_transition(
+    name: &str,
+    registry: &DeviceRegistry,
+    groups: &GroupStore,
+    transition: Transition,
+    action: &str,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+
+    let members = groups.members(name)?;
+
+    let results = for_each_member(registry, &members, |device_manager| {
+        start_transition(device_manager, transition).map(|_| ())
+    });
+    Some(group_response(results, action, &action.to_lowercase(), &format!("group {}", name)))
+}
+
+///
+/// Builds the response to an action run on every member of a target, such as a group. Members
+/// that succeeded report done, and failed ones the action that failed. Responds 207 if a member
+/// failed.
+///
+fn group_response<T>(
+    results: Vec<MemberResult<T>>,
+    done: &str,
+    action: &str,
+    target: &str,
+) -> status::Custom<Json<GroupCmdResponse>> {
+
+    let member_responses: Vec<MemberCmdResponse> = results
+        .into_iter()
+        .map(|member_result| match member_result.result {
+            Ok(_) => MemberCmdResponse {
+                device_id: member_result.device_id,
+                success: true,
+                status_str: String::from(done),
+                error_code: None,
+            },
+            Err(e) => MemberCmdResponse {
+                device_id: member_result.device_id,
+                success: false,
+                status_str: format!("Failed to {} - {}", action, e),
+                error_code: e.protocol_error().map(|pe| pe.code()),
+            },
+        })
+        .collect();
+
+    let failed = member_responses.iter().filter(|member| !member.success).count();
+    let status = format!("{} on {}, {} of {} members failed", done, target, failed, member_responses.len());
+    println!("{}", status);
+
+    let http_status = if failed == 0 { Status::Ok } else { Status::MultiStatus };
+    status::Custom(http_status, Json(GroupCmdResponse {
+        success: failed == 0,
+        status_str: status,
+        members: member_responses,
+    }))
+}
+
+///
+/// Builds the response to a group request with invalid parameters.
+///
+fn group_bad_request(status: String) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+    println!("{}", status);
+    Some(status::Custom(Status::BadRequest, Json(GroupCmdResponse {
+        success: false,
+        status_str: status,
+        members: Vec::new(),
+    })))
+}
+
+///
+/// Set group brightness endpoint
+///
+#[post("/groups/<name>/brightness", data = "<brightness_data>")]
+fn set_group_brightness(
+    name: String,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    brightness_data: Form<FormDataBrightness>,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+    let brightness = match api_v1::brightness_from_percent(brightness_data.brightness_percent) {
+        Ok(brightness) => brightness,
+        Err(e) => return group_bad_request((e.1).0.status_str),
+    };
+    match form_transition(None, Some(brightness), brightness_data.transition_ms, &brightness_data.easing) {
+        Ok(Some(transition)) => send_group_transition(&name, &registry, &groups, transition, "Set Brightness"),
+        Ok(None) => send_group_command(&name, &registry, &groups, GroupCommand::SetBrightness(brightness), "Set Brightness"),
+        Err(status) => group_bad_request(status),
+    }
+}
+
+///
+/// Set group effect endpoint
+///
+#[post("/groups/<name>/effect", data = "<effect_data>")]
+fn set_group_effect(
+    name: String,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    effect_data: Form<FormDataEffect>,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+    send_group_command(&name, &registry, &groups, GroupCommand::SetEffect(effect_data.effect_id), "Set Effect")
+}
+
+///
+/// Set group color endpoint
+///
+#[post("/groups/<name>/color", data = "<color_data>")]
+fn set_group_color(
+    name: String,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    color_data: Form<FormDataColor>,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+
+    match color_data.color.parse::<Color24>() {
+        Ok(color) => {
+            match form_transition(Some(color), None, color_data.transition_ms, &color_data.easing) {
+                Ok(Some(transition)) => send_group_transition(&name, &registry, &groups, transition, "Set Color"),
+                Ok(None) => send_group_command(&name, &registry, &groups, GroupCommand::SetColor(color), "Set Color"),
+                Err(status) => group_bad_request(status),
+            }
+        }
+        Err(e) => group_bad_request(format!("Failed to parse color parameter - {}", e)),
+    }
+}
+
+///
+/// Set group Firepalle endpoint
+///
+#[post("/groups/<name>/firepallet", data = "<fire_pallet_data>")]
+fn set_group_fire_color_pallet(
+    name: String,
+    registry: State<Arc<DeviceRegistry>>,
+    groups: State<Arc<GroupStore>>,
+    fire_pallet_data: Form<FormDataFirePallet>,
+) -> Option<status::Custom<Json<GroupCmdResponse>>> {
+    send_group_command(
+        &name,
+        &registry,
+        &groups,
+        GroupCommand::SetFireColorPallet(fire_pallet_data.pallet_id),
+        "Set Color Fire Pallet",
+    )
+}
+
+///
+/// Streams device events to a client as server-sent events. A comment is sent after a quiet
+/// period so a client that went away is noticed. Each event is followed by a WouldBlock read,
+/// which makes Rocket flush it to the client.
+///
+struct EventStream {
+    events: Receiver<DeviceEvent>,
+    pending: Vec<u8>,
+    flush: bool,
+    _slot: EventStreamSlot,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.flush {
+            self.flush = false;
+            return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+        }
+
+        while self.pending.is_empty() {
+            match self.events.recv_timeout(EVENT_STREAM_KEEP_ALIVE) {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                    self.pending = format!("event: {}\ndata: {}\n\n", event.name(), data).into_bytes();
                 }
+                Err(RecvTimeoutError::Timeout) => self.pending = b": keep-alive\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
             }
         }
-        Err(_e) => {
-            status = String::from(ERR_STR_FAIL_TO_FIND_HW);
+
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        self.flush = self.pending.is_empty();
+        Ok(count)
+    }
+}
+
+///
+/// Open event streams. Each stream holds one of Rocket's worker threads for as long as its client
+/// stays connected, so their number is capped to keep workers free for the other endpoints.
+///
+struct EventStreamSlots {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl EventStreamSlots {
+    ///
+    /// Takes a slot for a new stream, None if every slot is taken.
+    ///
+    fn take(&self) -> Option<EventStreamSlot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| if open < self.max { Some(open + 1) } else { None })
+            .ok()
+            .map(|_| EventStreamSlot(self.open.clone()))
+    }
+}
+
+///
+/// Slot of an open event stream, given back when the stream is dropped.
+///
+struct EventStreamSlot(Arc<AtomicUsize>);
+
+impl Drop for EventStreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+///
+/// Device event stream endpoint. Streams connects, disconnects, state changes and command
+/// failures of every device. Responds 503 when the maximum number of streams is already open.
+///
+#[get("/devices/events")]
+fn get_device_events(
+    registry: State<Arc<DeviceRegistry>>,
+    slots: State<EventStreamSlots>,
+) -> Result<Content<Stream<EventStream>>, status::Custom<Json<SimpleCmdResponse>>> {
+    let slot = match slots.take() {
+        Some(slot) => slot,
+        None => {
+            let status = format!("Too many event streams, at most {} can be open", slots.max);
             println!("{}", status);
-            return Json(LedStatusResponse {
+            return Err(status::Custom(Status::ServiceUnavailable, Json(SimpleCmdResponse {
                 success: false,
                 status_str: status,
-                brightness_percent: 0.0,
-                effect_id: 0,
-                color: String::from("#000000"),
-                fire_pallet_id: 0,
-                hw_debug: false,
-            });
+                error_code: None,
+            })));
         }
     };
+
+    let events = EventStream {
+        events: registry.events().subscribe(),
+        pending: Vec::new(),
+        flush: false,
+        _slot: slot,
+    };
+    Ok(Content(ContentType::new("text", "event-stream"), Stream::from(events)))
 }
 
 ///
-/// Upload fw update endpoint
+/// Upload fw update endpoint. Flashes the image onto the given device, or the default device, in
+/// the background and returns the flash's initial status.
 ///
-#[post("/upload_fw_update", format = "plain", data = "<data>")]
-fn upload_fw_update(data: Data) -> Result<String, std::io::Error> {
+#[post("/upload_fw_update?<device>", format = "plain", data = "<data>")]
+fn upload_fw_update(
+    device: Option<String>,
+    data: Data,
+    registry: State<Arc<DeviceRegistry>>,
+    flasher: State<Arc<FirmwareFlasher>>,
+) -> Result<status::Custom<Json<FlashStatus>>, status::Custom<Json<SimpleCmdResponse>>> {
+    let device_id = match device {
+        Some(device_id) => device_id,
+        None => match registry.devices().into_iter().next() {
+            Some((device_id, _)) => device_id,
+            None => {
+                let e = ControllerError::NoDevicesFound;
+                let status = format!("Failed to flash firmware - {}", e);
+                println!("{}", status);
+                return Err(status::Custom(error_status(&e), Json(SimpleCmdResponse {
+                    success: false,
+                    status_str: status,
+                    error_code: None,
+                })));
+            }
+        },
+    };
+
+    api_v1::start_flash(&registry, &flasher, &device_id, data)
+}
 
-    let mut stream = data.open().take(MAX_FW_UPLOAD_SIZE);
-    let mut stream_buffer: Vec<u8> = vec![];
-    match stream.read_to_end(&mut stream_buffer) {
-            Ok(_) => {
+/// OpenAPI document of the mounted routes, serialized once at startup
+struct OpenApiDocument(String);
 
-                let now: DateTime<Utc> = Utc::now();
-                let tmp_file_name = format!("/tmp/fw_teensy_{}.hex", now.format("%Y%m%d%H%M%S%f"));
-                let mut file = File::create(tmp_file_name)?;
+///
+/// OpenAPI document endpoint
+///
+#[get("/openapi.json")]
+fn get_openapi(document: State<OpenApiDocument>) -> Content<String> {
+    Content(ContentType::JSON, document.0.clone())
+}
 
-                match file.write_all(stream_buffer.as_slice()) {
-                        Ok(_) => { Ok("Success".to_string()) },
-                        Err(e) => Err(e)
-                    }
-            },
-            Err(e) => Err(e)
+///
+/// Returns the documentation of a route by handler name. Routes of the JSON API are documented
+/// by api_v1.
+///
+fn operation(name: &str) -> Option<Operation> {
+    const FORM: &str = "application/x-www-form-urlencoded";
+    const JSON: &str = "application/json";
+    let command = |summary, form: SchemaFn| Operation {
+        summary,
+        request: Some((FORM, form)),
+        response: (JSON, SimpleCmdResponse::schema),
+        error: Some(SimpleCmdResponse::schema),
+    };
+    let group_command = |summary, form: SchemaFn| Operation {
+        summary,
+        request: Some((FORM, form)),
+        response: (JSON, GroupCmdResponse::schema),
+        error: Some(GroupCmdResponse::schema),
+    };
+    let operation = match name {
+        "set_brightness" | "set_device_brightness" => command("Sets the brightness", FormDataBrightness::schema),
+        "set_effect" | "set_device_effect" => command("Sets the effect", FormDataEffect::schema),
+        "set_color" | "set_device_color" => command("Sets the color", FormDataColor::schema),
+        "set_fire_color_pallet" | "set_device_fire_color_pallet" => {
+            command("Sets the fire color pallet", FormDataFirePallet::schema)
         }
+        "get_device_status" | "get_device_status_by_id" => Operation {
+            summary: "Reads the status of a device",
+            request: None,
+            response: (JSON, LedStatusResponse::schema),
+            error: Some(LedStatusResponse::schema),
+        },
+        "get_devices" => Operation {
+            summary: "Lists the connected devices",
+            request: None,
+            response: (JSON, Vec::<DeviceResponse>::schema),
+            error: None,
+        },
+        "get_device_events" => Operation {
+            summary: "Streams device connects, disconnects, state changes and command failures",
+            request: None,
+            response: ("text/event-stream", DeviceEvent::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_groups" => Operation {
+            summary: "Lists the device groups",
+            request: None,
+            response: (JSON, Vec::<GroupData>::schema),
+            error: None,
+        },
+        "put_group" => Operation {
+            summary: "Creates or replaces a device group",
+            request: Some((JSON, GroupData::schema)),
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "delete_group" => Operation {
+            summary: "Removes a device group",
+            request: None,
+            response: (JSON, SimpleCmdResponse::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "set_group_brightness" => group_command("Sets the brightness of every group member", FormDataBrightness::schema),
+        "set_group_effect" => group_command("Sets the effect of every group member", FormDataEffect::schema),
+        "set_group_color" => group_command("Sets the color of every group member", FormDataColor::schema),
+        "set_group_fire_color_pallet" => {
+            group_command("Sets the fire color pallet of every group member", FormDataFirePallet::schema)
+        }
+        "upload_fw_update" => Operation {
+            summary: "Flashes an Intel HEX firmware image onto a device, the default one unless given",
+            request: Some(("text/plain", String::schema)),
+            response: (JSON, FlashStatus::schema),
+            error: Some(SimpleCmdResponse::schema),
+        },
+        "get_openapi" => Operation {
+            summary: "Returns this document",
+            request: None,
+            response: (JSON, Value::schema),
+            error: None,
+        },
+        _ => return api_v1::operation(name),
+    };
+    Some(operation)
 }
 
 ///
-/// Main Application Entry
+/// Mounts the routes and catchers. Kept apart from the fairings so the routes can be listed
+/// without opening devices.
 ///
-fn main() {
-    rocket::ignite()
+fn mount_routes(rocket: Rocket) -> Rocket {
+    rocket
         .mount(
             "/",
             routes![
@@ -437,13 +1182,221 @@ fn main() {
                 set_color,
                 set_fire_color_pallet,
                 get_device_status,
+                get_devices,
+                get_device_events,
+                set_device_brightness,
+                set_device_effect,
+                set_device_color,
+                set_device_fire_color_pallet,
+                get_device_status_by_id,
+                get_groups,
+                put_group,
+                delete_group,
+                set_group_brightness,
+                set_group_effect,
+                set_group_color,
+                set_group_fire_color_pallet,
                 upload_fw_update,
+                get_openapi,
             ],
         )
+        .mount("/api/v1", api_v1::routes())
         .mount(
             "/",
             StaticFiles::from(concat!(env!("CARGO_MANIFEST_DIR"), "/static")),
         )
         .register(catchers![not_found])
+}
+
+///
+/// Main Application Entry
+///
+fn main() {
+    mount_routes(rocket::ignite())
+        .attach(AdHoc::on_attach("Device Registry", |rocket| {
+            // ledsc_port pins the registry to one port instead of probing all serial ports
+            let mut registry = match rocket.config().get_str("ledsc_port") {
+                Ok(port_name) => DeviceRegistry::with_port_names(vec![String::from(port_name)]),
+                Err(_) => DeviceRegistry::new(),
+            };
+            if let Ok(retries) = rocket.config().get_int("ledsc_command_retries") {
+                registry.set_command_retries(retries.max(0) as u32);
+            }
+            // ledsc_aliases maps device ids or port names to the id used in /devices/<id>/
+            if let Ok(aliases) = rocket.config().get_table("ledsc_aliases") {
+                for (key, alias) in aliases {
+                    if let Some(alias) = alias.as_str() {
+                        registry.set_alias(key, alias);
+                    }
+                }
+            }
+            // ledsc_restore_state = false stops restoring device state, a table of device ids turns it off per device
+            if let Ok(devices) = rocket.config().get_table("ledsc_restore_state") {
+                for (id, enabled) in devices {
+                    if let Some(enabled) = enabled.as_bool() {
+                        registry.set_restore_state(id, enabled);
+                    }
+                }
+            }
+            if rocket.config().get_bool("ledsc_restore_state").unwrap_or(true) {
+                let state_file = rocket
+                    .config()
+                    .get_str("ledsc_state_file")
+                    .unwrap_or("state.json")
+                    .to_string();
+                match StateStore::open(&state_file) {
+                    Ok(state_store) => registry.set_state_store(Arc::new(state_store)),
+                    Err(e) => {
+                        println!("Failed to load device state from {} - {}", state_file, e);
+                        return Err(rocket);
+                    }
+                }
+            }
+            let registry = Arc::new(registry);
+            // ledsc_hotplug_poll_ms sets how often ports are checked for devices coming and going, 0 turns the watcher off
+            let poll_ms = rocket
+                .config()
+                .get_int("ledsc_hotplug_poll_ms")
+                .unwrap_or(DEFAULT_HOTPLUG_POLL_INTERVAL.as_millis() as i64);
+            if poll_ms > 0 {
+                if let Err(e) = spawn_hotplug_watcher(&registry, Duration::from_millis(poll_ms as u64)) {
+                    println!("Failed to start hotplug watcher - {}", e);
+                }
+            }
+            Ok(rocket.manage(registry))
+        }))
+        .attach(AdHoc::on_attach("Event Streams", |rocket| {
+            // ledsc_max_event_streams caps the open event streams, each holding a worker, half the workers by default
+            let max = rocket
+                .config()
+                .get_int("ledsc_max_event_streams")
+                .map(|max| max.max(0) as usize)
+                .unwrap_or_else(|_| (rocket.config().workers as usize / 2).max(1));
+            Ok(rocket.manage(EventStreamSlots { open: Arc::new(AtomicUsize::new(0)), max }))
+        }))
+        .attach(AdHoc::on_attach("Group Store", |rocket| {
+            let groups_file = rocket
+                .config()
+                .get_str("ledsc_groups_file")
+                .unwrap_or("groups.json")
+                .to_string();
+            match GroupStore::open(&groups_file) {
+                Ok(groups) => Ok(rocket.manage(Arc::new(groups))),
+                Err(e) => {
+                    println!("Failed to load groups from {} - {}", groups_file, e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Scene Store", |rocket| {
+            let scenes_file = rocket
+                .config()
+                .get_str("ledsc_scenes_file")
+                .unwrap_or("scenes.json")
+                .to_string();
+            match SceneStore::open(&scenes_file) {
+                Ok(scenes) => Ok(rocket.manage(Arc::new(scenes))),
+                Err(e) => {
+                    println!("Failed to load scenes from {} - {}", scenes_file, e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Sequence Store", |rocket| {
+            let sequences_dir = rocket
+                .config()
+                .get_str("ledsc_sequences_dir")
+                .unwrap_or("sequences")
+                .to_string();
+            Ok(rocket.manage(Arc::new(SequenceStore::new(sequences_dir))))
+        }))
+        .attach(AdHoc::on_attach("Script Store", |rocket| {
+            let scripts_dir = rocket
+                .config()
+                .get_str("ledsc_scripts_dir")
+                .unwrap_or("scripts")
+                .to_string();
+            Ok(rocket.manage(Arc::new(ScriptStore::new(scripts_dir))))
+        }))
+        .attach(AdHoc::on_attach("Firmware Flasher", |rocket| {
+            // ledsc_loader_path points at the teensy_loader_cli binary, ledsc_loader_mcu names the Teensy model
+            let loader_path = rocket.config().get_str("ledsc_loader_path").unwrap_or(DEFAULT_LOADER_PATH).to_string();
+            let mcu = rocket.config().get_str("ledsc_loader_mcu").unwrap_or(DEFAULT_LOADER_MCU).to_string();
+            let mut flasher = FirmwareFlasher::new(loader_path, &mcu);
+            // ledsc_flash_reconnect_ms sets how long a flashed device has to come back
+            if let Ok(reconnect_ms) = rocket.config().get_int("ledsc_flash_reconnect_ms") {
+                flasher.set_reconnect_timeout(Duration::from_millis(reconnect_ms.max(0) as u64));
+            }
+            Ok(rocket.manage(Arc::new(flasher)))
+        }))
+        .attach(AdHoc::on_attach("Scheduler", |rocket| {
+            let schedules_file = rocket
+                .config()
+                .get_str("ledsc_schedules_file")
+                .unwrap_or("schedules.json")
+                .to_string();
+            let mut schedules = match ScheduleStore::open(&schedules_file) {
+                Ok(schedules) => schedules,
+                Err(e) => {
+                    println!("Failed to load schedules from {} - {}", schedules_file, e);
+                    return Err(rocket);
+                }
+            };
+
+            let degrees = |name: &str| {
+                rocket.config().get_extra(name).ok().map(|value| {
+                    value.as_float().or_else(|| value.as_integer().map(|value| value as f64))
+                })
+            };
+            let latitude = degrees("ledsc_latitude");
+            let longitude = degrees("ledsc_longitude");
+            if latitude.is_some() || longitude.is_some() {
+                let location = match (latitude.flatten(), longitude.flatten()) {
+                    (Some(latitude), Some(longitude)) => Location::new(latitude, longitude),
+                    _ => None,
+                };
+                match location {
+                    Some(location) => schedules = schedules.with_location(location),
+                    None => {
+                        println!("ledsc_latitude and ledsc_longitude must both be set, within ±90 and ±180 degrees");
+                        return Err(rocket);
+                    }
+                }
+            }
+            let schedules = Arc::new(schedules);
+
+            let registry = rocket.state::<Arc<DeviceRegistry>>().cloned();
+            let groups = rocket.state::<Arc<GroupStore>>().cloned();
+            let scenes = rocket.state::<Arc<SceneStore>>().cloned();
+            if let (Some(registry), Some(groups), Some(scenes)) = (registry, groups, scenes) {
+                let scheduler = spawn_scheduler(&schedules, SCHEDULER_TICK, move |name, schedule| {
+                    match run_schedule(schedule, &registry, &groups, &scenes) {
+                        Ok(results) => {
+                            let failed: Vec<String> = results
+                                .iter()
+                                .filter_map(|member| match &member.result {
+                                    Ok(()) => None,
+                                    Err(e) => Some(format!("{} - {}", member.device_id, e)),
+                                })
+                                .collect();
+                            if failed.is_empty() {
+                                println!("Ran schedule {} on {} device(s)", name, results.len());
+                            } else {
+                                println!("Schedule {} failed on: {}", name, failed.join(", "));
+                            }
+                        }
+                        Err(e) => println!("Failed to run schedule {} - {}", name, e),
+                    }
+                });
+                if let Err(e) = scheduler {
+                    println!("Failed to start scheduler - {}", e);
+                }
+            }
+            Ok(rocket.manage(schedules))
+        }))
+        .attach(AdHoc::on_attach("OpenAPI Document", |rocket| {
+            let document = openapi::openapi_document(rocket.routes(), operation);
+            Ok(rocket.manage(OpenApiDocument(document.to_string())))
+        }))
         .launch();
 }
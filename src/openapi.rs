@@ -0,0 +1,621 @@
+/*
+   led_oxide is an http API interface to the LedStripController Firmware.
+
+   Copyright (C) 2021  Thomas G. Kenny Jr
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//!
+//! OpenAPI 3 document built from the mounted routes and the request and response types. Types
+//! declared with api_schema! describe their own JSON schema. Each route is documented by the
+//! operation table of the module defining its handler.
+//!
+
+use chrono::{DateTime, Utc};
+use led_oxide::led_strip_controller::color::Color24;
+use led_oxide::led_strip_controller::events::DeviceEvent;
+use led_oxide::led_strip_controller::firmware::{FlashStage, FlashStatus};
+use led_oxide::led_strip_controller::protocol::{Effect, FireColorPallet};
+use led_oxide::led_strip_controller::schedule::{
+    MissedRuns, ScheduleAction, ScheduleTarget, SunTrigger, Trigger,
+};
+use led_oxide::led_strip_controller::sequence::{Keyframe, PlayMode};
+use led_oxide::led_strip_controller::state::DeviceState;
+use led_oxide::led_strip_controller::sun::SunEvent;
+use led_oxide::led_strip_controller::transition::Easing;
+use rocket::Route;
+use serde_json::{json, Map, Value};
+
+/// Named schemas of the document, by schema name
+pub type Components = Map<String, Value>;
+
+/// Returns the schema of a type, registering the named schemas it uses
+pub type SchemaFn = fn(&mut Components) -> Value;
+
+///
+/// Type with a JSON schema.
+///
+pub trait ApiSchema {
+    /// False for fields that may be left out
+    const REQUIRED: bool = true;
+
+    ///
+    /// Returns the schema of the type. Named schemas are added to components and referenced.
+    ///
+    fn schema(components: &mut Components) -> Value;
+}
+
+///
+/// Declares a struct and implements ApiSchema for it. The schema is an object named after the
+/// struct with a property per field. Option fields are not required.
+///
+macro_rules! api_schema {
+    (
+        $(#[$meta:meta])*
+        struct $name:ident {
+            $($(#[$field_meta:meta])* $field:ident: $field_ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name {
+            $($(#[$field_meta])* $field: $field_ty),*
+        }
+
+        impl crate::openapi::ApiSchema for $name {
+            fn schema(components: &mut crate::openapi::Components) -> serde_json::Value {
+                crate::openapi::object_schema(components, stringify!($name), |components| {
+                    vec![$((
+                        stringify!($field),
+                        <$field_ty as crate::openapi::ApiSchema>::schema(components),
+                        <$field_ty as crate::openapi::ApiSchema>::REQUIRED,
+                    )),*]
+                })
+            }
+        }
+    };
+}
+
+///
+/// Registers a named object schema built from its properties and returns a reference to it.
+/// Each property is its name, schema and whether it is required.
+///
+pub fn object_schema<F>(components: &mut Components, name: &str, properties: F) -> Value
+where
+    F: FnOnce(&mut Components) -> Vec<(&'static str, Value, bool)>,
+{
+    if !components.contains_key(name) {
+        // Claim the name first so a type referring to itself terminates
+        components.insert(String::from(name), Value::Null);
+
+        let mut property_map = Map::new();
+        let mut required: Vec<Value> = vec![];
+        for (property, schema, is_required) in properties(components) {
+            property_map.insert(String::from(property), schema);
+            if is_required {
+                required.push(json!(property));
+            }
+        }
+
+        let mut schema = json!({ "type": "object", "properties": property_map });
+        if !required.is_empty() {
+            schema["required"] = Value::Array(required);
+        }
+        components.insert(String::from(name), schema);
+    }
+
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+impl ApiSchema for bool {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl ApiSchema for u8 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "integer", "minimum": 0, "maximum": 255 })
+    }
+}
+
+impl ApiSchema for i16 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "integer", "minimum": i16::MIN, "maximum": i16::MAX })
+    }
+}
+
+impl ApiSchema for u32 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX })
+    }
+}
+
+impl ApiSchema for usize {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "integer", "minimum": 0 })
+    }
+}
+
+impl ApiSchema for i32 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "integer", "format": "int32" })
+    }
+}
+
+impl ApiSchema for f32 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "number" })
+    }
+}
+
+impl ApiSchema for f64 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "number", "format": "double" })
+    }
+}
+
+impl ApiSchema for String {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl ApiSchema for &'static str {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl ApiSchema for Value {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "object" })
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Option<T> {
+    const REQUIRED: bool = false;
+
+    fn schema(components: &mut Components) -> Value {
+        let mut schema = T::schema(components);
+        if schema.get("$ref").is_some() {
+            // Siblings of $ref are ignored in OpenAPI 3.0
+            schema = json!({ "allOf": [schema] });
+        }
+        schema["nullable"] = json!(true);
+        schema
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Vec<T> {
+    fn schema(components: &mut Components) -> Value {
+        json!({ "type": "array", "items": T::schema(components) })
+    }
+}
+
+impl ApiSchema for Color24 {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string", "pattern": "^#?[0-9a-fA-F]{1,6}$", "example": "#ff8800" })
+    }
+}
+
+impl ApiSchema for Effect {
+    fn schema(_components: &mut Components) -> Value {
+        let names: Vec<&str> = Effect::ALL.iter().map(Effect::name).collect();
+        json!({ "type": "string", "enum": names })
+    }
+}
+
+impl ApiSchema for FireColorPallet {
+    fn schema(_components: &mut Components) -> Value {
+        let names: Vec<&str> = FireColorPallet::ALL
+            .iter()
+            .map(FireColorPallet::name)
+            .collect();
+        json!({ "type": "string", "enum": names })
+    }
+}
+
+impl ApiSchema for DeviceState {
+    fn schema(components: &mut Components) -> Value {
+        // Unset settings are left out rather than null
+        object_schema(components, "DeviceState", |components| {
+            vec![
+                ("effect", Effect::schema(components), false),
+                ("color", Color24::schema(components), false),
+                ("brightness", u8::schema(components), false),
+                ("fire_pallet", FireColorPallet::schema(components), false),
+            ]
+        })
+    }
+}
+
+impl ApiSchema for DateTime<Utc> {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string", "format": "date-time" })
+    }
+}
+
+///
+/// Returns the schema of an object with exactly one of the given properties, matching how serde
+/// writes enum variants holding a value.
+///
+fn one_property_of(variants: Vec<(&str, Value)>) -> Value {
+    let schemas: Vec<Value> = variants
+        .into_iter()
+        .map(|(name, schema)| {
+            json!({
+                "type": "object",
+                "properties": { name: schema },
+                "required": [name],
+                "additionalProperties": false,
+            })
+        })
+        .collect();
+
+    json!({ "oneOf": schemas })
+}
+
+impl ApiSchema for Trigger {
+    fn schema(components: &mut Components) -> Value {
+        one_property_of(vec![
+            (
+                "cron",
+                json!({ "type": "string", "example": "0 7 * * mon-fri" }),
+            ),
+            ("at", DateTime::<Utc>::schema(components)),
+            ("sun", SunTrigger::schema(components)),
+        ])
+    }
+}
+
+impl ApiSchema for Easing {
+    fn schema(_components: &mut Components) -> Value {
+        let names: Vec<&str> = Easing::ALL.iter().map(Easing::name).collect();
+        json!({ "type": "string", "enum": names })
+    }
+}
+
+impl ApiSchema for SunEvent {
+    fn schema(_components: &mut Components) -> Value {
+        let names: Vec<&str> = SunEvent::ALL.iter().map(SunEvent::name).collect();
+        json!({ "type": "string", "enum": names })
+    }
+}
+
+impl ApiSchema for SunTrigger {
+    fn schema(components: &mut Components) -> Value {
+        object_schema(components, "SunTrigger", |components| {
+            vec![
+                ("event", SunEvent::schema(components), true),
+                ("offset_minutes", i32::schema(components), false),
+            ]
+        })
+    }
+}
+
+impl ApiSchema for ScheduleAction {
+    fn schema(components: &mut Components) -> Value {
+        one_property_of(vec![
+            ("scene", String::schema(components)),
+            ("state", DeviceState::schema(components)),
+        ])
+    }
+}
+
+impl ApiSchema for ScheduleTarget {
+    fn schema(components: &mut Components) -> Value {
+        one_property_of(vec![
+            ("device", String::schema(components)),
+            ("group", String::schema(components)),
+        ])
+    }
+}
+
+impl ApiSchema for MissedRuns {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string", "enum": ["skip", "run_once"] })
+    }
+}
+
+impl ApiSchema for PlayMode {
+    fn schema(_components: &mut Components) -> Value {
+        json!({ "type": "string", "enum": ["loop", "ping_pong", "once"] })
+    }
+}
+
+impl ApiSchema for Keyframe {
+    fn schema(components: &mut Components) -> Value {
+        // The keyframe's settings are flattened next to its timing
+        object_schema(components, "Keyframe", |components| {
+            vec![
+                ("effect", Effect::schema(components), false),
+                ("color", Color24::schema(components), false),
+                ("brightness", u8::schema(components), false),
+                ("fire_pallet", FireColorPallet::schema(components), false),
+                ("fade_ms", u32::schema(components), false),
+                ("easing", Easing::schema(components), false),
+                ("hold_ms", u32::schema(components), false),
+            ]
+        })
+    }
+}
+
+impl ApiSchema for FlashStage {
+    fn schema(_components: &mut Components) -> Value {
+        json!({
+            "type": "string",
+            "enum": ["entering_bootloader", "flashing", "waiting_for_device", "done", "failed"],
+        })
+    }
+}
+
+impl ApiSchema for FlashStatus {
+    fn schema(components: &mut Components) -> Value {
+        object_schema(components, "FlashStatus", |components| {
+            vec![
+                ("device_id", String::schema(components), true),
+                ("stage", FlashStage::schema(components), true),
+                ("data_size", usize::schema(components), true),
+                ("started", DateTime::<Utc>::schema(components), true),
+                (
+                    "finished",
+                    Option::<DateTime<Utc>>::schema(components),
+                    true,
+                ),
+                (
+                    "firmware_version",
+                    Option::<String>::schema(components),
+                    true,
+                ),
+                ("error", Option::<String>::schema(components), true),
+                ("output", Vec::<String>::schema(components), true),
+            ]
+        })
+    }
+}
+
+impl ApiSchema for DeviceEvent {
+    fn schema(components: &mut Components) -> Value {
+        let connected = object_schema(components, "DeviceConnectedEvent", |components| {
+            vec![
+                (
+                    "event",
+                    json!({ "type": "string", "enum": ["connected"] }),
+                    true,
+                ),
+                ("device_id", String::schema(components), true),
+                ("port_name", String::schema(components), true),
+                ("firmware_version", String::schema(components), true),
+            ]
+        });
+        let disconnected = object_schema(components, "DeviceDisconnectedEvent", |components| {
+            vec![
+                (
+                    "event",
+                    json!({ "type": "string", "enum": ["disconnected"] }),
+                    true,
+                ),
+                ("device_id", String::schema(components), true),
+                ("port_name", Option::<String>::schema(components), false),
+            ]
+        });
+
+        let state_changed = object_schema(components, "DeviceStateChangedEvent", |components| {
+            vec![
+                (
+                    "event",
+                    json!({ "type": "string", "enum": ["state_changed"] }),
+                    true,
+                ),
+                ("device_id", String::schema(components), true),
+                ("state", DeviceState::schema(components), true),
+            ]
+        });
+        let command_failed = object_schema(components, "DeviceCommandFailedEvent", |components| {
+            vec![
+                (
+                    "event",
+                    json!({ "type": "string", "enum": ["command_failed"] }),
+                    true,
+                ),
+                ("device_id", String::schema(components), true),
+                ("command", String::schema(components), true),
+                ("error", String::schema(components), true),
+            ]
+        });
+
+        json!({ "oneOf": [connected, disconnected, state_changed, command_failed] })
+    }
+}
+
+///
+/// Documentation of a route.
+///
+pub struct Operation {
+    pub summary: &'static str,
+    /// Content type and schema of the request body
+    pub request: Option<(&'static str, SchemaFn)>,
+    /// Content type and schema of the successful response
+    pub response: (&'static str, SchemaFn),
+    /// Schema of error responses, if they have a body
+    pub error: Option<SchemaFn>,
+}
+
+///
+/// Builds the OpenAPI document for the given routes. Operations are looked up by route name.
+/// Routes without a name, such as static files, are left out.
+///
+pub fn openapi_document<'a, R, F>(routes: R, operation: F) -> Value
+where
+    R: Iterator<Item = &'a Route>,
+    F: Fn(&str) -> Option<Operation>,
+{
+    let mut components = Components::new();
+    let mut paths = Map::new();
+
+    for route in routes {
+        let name = match route.name {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let mut parameters: Vec<Value> = vec![];
+        let path: Vec<String> = route
+            .uri
+            .path()
+            .split('/')
+            .map(|segment| match dynamic_segment(segment) {
+                Some(parameter) => {
+                    parameters.push(json!({
+                        "name": parameter,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }));
+                    format!("{{{}}}", parameter)
+                }
+                None => String::from(segment),
+            })
+            .collect();
+
+        // Every query parameter of the API is optional
+        for segment in route.uri.query().unwrap_or("").split('&') {
+            if let Some(parameter) = dynamic_segment(segment) {
+                parameters.push(json!({
+                    "name": parameter,
+                    "in": "query",
+                    "required": false,
+                    "schema": { "type": "string" },
+                }));
+            }
+        }
+
+        let mut operation_object = json!({ "operationId": format!("{}{}", name, operation_suffix(&route.base.to_string())) });
+        if !parameters.is_empty() {
+            operation_object["parameters"] = Value::Array(parameters);
+        }
+
+        match operation(name) {
+            Some(operation) => {
+                operation_object["summary"] = json!(operation.summary);
+
+                if let Some((content_type, schema)) = operation.request {
+                    operation_object["requestBody"] = json!({
+                        "required": true,
+                        "content": { content_type: { "schema": schema(&mut components) } },
+                    });
+                }
+
+                let (content_type, schema) = operation.response;
+                let mut responses = json!({
+                    "200": {
+                        "description": "Success",
+                        "content": { content_type: { "schema": schema(&mut components) } },
+                    },
+                });
+                if let Some(error) = operation.error {
+                    responses["default"] = json!({
+                        "description": "Failure",
+                        "content": { "application/json": { "schema": error(&mut components) } },
+                    });
+                }
+                operation_object["responses"] = responses;
+            }
+            None => {
+                operation_object["responses"] =
+                    json!({ "default": { "description": "Undocumented" } });
+            }
+        }
+
+        let path_item = paths.entry(path.join("/")).or_insert_with(|| json!({}));
+        path_item[route.method.as_str().to_lowercase()] = operation_object;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "LED Oxide",
+            "description": "HTTP API of the LedStripController firmware",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": components },
+    })
+}
+
+///
+/// Returns the parameter name of a dynamic route segment such as <id>.
+///
+fn dynamic_segment(segment: &str) -> Option<&str> {
+    segment
+        .strip_prefix('<')
+        .and_then(|segment| segment.strip_suffix('>'))
+        .map(|parameter| parameter.trim_end_matches(".."))
+}
+
+///
+/// Returns the operation id suffix keeping handlers mounted at several bases unique.
+///
+fn operation_suffix(base: &str) -> String {
+    let mut suffix = String::new();
+    for segment in base.split('/').filter(|segment| !segment.is_empty()) {
+        suffix.push('_');
+        suffix.push_str(&segment.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    }
+    suffix
+}
+
+/// -----------------
+/// Unit Tests
+/// -----------------
+#[cfg(test)]
+mod test {
+    use crate::openapi::openapi_document;
+    use crate::{mount_routes, operation};
+    use rocket::config::Config;
+    use rocket::Rocket;
+    use std::{env, fs};
+
+    /// Committed document, regenerated by running the tests with UPDATE_OPENAPI set
+    const DOCUMENT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    fn rocket() -> Rocket {
+        mount_routes(rocket::custom(Config::development()))
+    }
+
+    #[test]
+    fn openapi_routes_documented_test() {
+        for route in rocket().routes() {
+            if let Some(name) = route.name {
+                assert!(operation(name).is_some(), "Route {} has no operation", name);
+            }
+        }
+    }
+
+    #[test]
+    fn openapi_document_up_to_date_test() {
+        let document = openapi_document(rocket().routes(), operation);
+        let document = serde_json::to_string_pretty(&document).unwrap() + "\n";
+        if env::var_os("UPDATE_OPENAPI").is_some() {
+            fs::write(DOCUMENT_PATH, &document).unwrap();
+        }
+
+        let committed = fs::read_to_string(DOCUMENT_PATH).unwrap_or_default();
+        assert!(
+            committed == document,
+            "openapi.json is out of date, regenerate it with UPDATE_OPENAPI=1 cargo test"
+        );
+    }
+}
@@ -39,3 +39,112 @@
 //         assert_eq!(protocol::get_effect_cmd_value(&protocol::Effect::MaxEffect), 0x0a);
 //     }
 // }
+
+//
+// Property tests for the response parser and packet encoders
+//
+use led_oxide::led_strip_controller::color::Color24;
+use led_oxide::led_strip_controller::protocol::{
+    Command, LedscTeensy001, ProtocolVersion, ResponsePacketOption,
+};
+use proptest::prelude::*;
+
+///
+/// Builds a command from a generated kind and argument. Covers every Command variant.
+///
+fn build_command(kind: u8, arg: u32) -> Command {
+    let protocol_version = LedscTeensy001 {};
+
+    match kind {
+        0 => Command::None,
+        1 => Command::PrintVersion,
+        2 => Command::FullReset,
+        3 => Command::EnterBootloader,
+        4 => Command::SetDebugging(arg & 0x01 == 0x01),
+        5 => Command::SetEffect(protocol_version.get_effect_from_cmd_value(&((arg % 11) as u8))),
+        6 => Command::SetColor(Color24::from_u32(arg & 0xffffff)),
+        7 => Command::SetBrightness(arg as u8),
+        8 => Command::SetFireColorPallet(
+            protocol_version.get_fire_color_pallet_from_cmd_value(&((arg % 8) as u8)),
+        ),
+        _ => Command::GetStatus,
+    }
+}
+
+///
+/// Splits a command string into its command name and parameter, if any.
+///
+fn split_cmd_string(cmd_str: &str) -> (String, Option<String>) {
+    let frame = &cmd_str[1..cmd_str.find(']').unwrap()];
+    let mut fields = frame.splitn(2, ':');
+
+    (
+        String::from(fields.next().unwrap()),
+        fields.next().map(String::from),
+    )
+}
+
+proptest! {
+    #[test]
+    fn cmd_string_round_trip_prop(kind in 0u8..10, arg in any::<u32>()) {
+        let protocol_version = LedscTeensy001 {};
+        let cmd_str = protocol_version.create_cmd_string(build_command(kind, arg));
+        let (cmd_name, cmd_param) = split_cmd_string(&cmd_str);
+
+        match (protocol_version.parse_response_sting(cmd_str.clone()), cmd_param) {
+            (ResponsePacketOption::Success(pkt), Some(param))
+            | (ResponsePacketOption::FailedRemote(pkt), Some(param)) => {
+                prop_assert_eq!(pkt.command, cmd_name);
+                prop_assert_eq!(pkt.parameters, vec![param]);
+                prop_assert_eq!(pkt.crc16_in, pkt.crc16_calc);
+            }
+            // Commands without parameters lack the status parameter a response needs
+            (ResponsePacketOption::FailedLocal(..), None) => {}
+            _ => prop_assert!(false, "Unexpected parse of '{}'", cmd_str.trim()),
+        }
+    }
+
+    #[test]
+    fn response_string_round_trip_prop(
+        kind in 0u8..10,
+        arg in any::<u32>(),
+        status in any::<i16>(),
+        params in prop::collection::vec("[A-Za-z0-9_|#-]{0,12}", 0..3),
+    ) {
+        let protocol_version = LedscTeensy001 {};
+        let (cmd_name, _) =
+            split_cmd_string(&protocol_version.create_cmd_string(build_command(kind, arg)));
+
+        let response = protocol_version.create_response_string(&cmd_name, status, &params);
+
+        let mut expected_params = vec![status.to_string()];
+        expected_params.extend(params);
+
+        match protocol_version.parse_response_sting(response.clone()) {
+            ResponsePacketOption::Success(pkt) => {
+                prop_assert_eq!(status, 0);
+                prop_assert_eq!(pkt.command, cmd_name);
+                prop_assert_eq!(pkt.parameters, expected_params);
+            }
+            ResponsePacketOption::FailedRemote(pkt) => {
+                prop_assert_ne!(status, 0);
+                prop_assert_eq!(pkt.error().map(|error| error.code()), Some(status));
+                prop_assert_eq!(pkt.command, cmd_name);
+                prop_assert_eq!(pkt.parameters, expected_params);
+            }
+            ResponsePacketOption::FailedLocal(error) => {
+                prop_assert!(false, "'{}' failed local with {}", response.trim(), error)
+            }
+        }
+    }
+
+    #[test]
+    fn parse_response_sting_never_panics_prop(response in any::<String>()) {
+        let _ = LedscTeensy001 {}.parse_response_sting(response);
+    }
+
+    #[test]
+    fn parse_response_sting_protocol_alphabet_prop(response in "[\\[\\]:|0-9A-Fa-z\\r\\n-]{0,40}") {
+        let _ = LedscTeensy001 {}.parse_response_sting(response);
+    }
+}